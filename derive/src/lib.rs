@@ -0,0 +1,157 @@
+//! `#[derive(ValidateClaims)]` for rapi
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Lit, LitStr};
+
+/// Implements `rapi::rules::ClaimsValidator` from `#[claim(...)]` attributes on the
+/// fields of a struct with named fields:
+///
+/// - `required`: the claim must be present
+/// - `equals = <literal>`: the claim must equal a string, number or boolean
+/// - `contains = "..."`: the claim must be an array or space separated string with it
+/// - `matches = "..."`: the claim must be a string matching the regular expression,
+///   compiled at build time so an invalid one fails the build
+/// - `name = "..."`: the claim of the field, its name by default
+#[proc_macro_derive(ValidateClaims, attributes(claim))]
+pub fn derive_validate_claims(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    input,
+                    "ValidateClaims needs named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                input,
+                "ValidateClaims needs a struct",
+            ))
+        }
+    };
+
+    let mut rules = Vec::new();
+    for field in fields {
+        let mut name = field.ident.as_ref().map(|ident| ident.to_string());
+        let mut checks = Vec::new();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("claim"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("required") {
+                    checks.push(Check::Present);
+                } else if meta.path.is_ident("name") {
+                    name = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("equals") {
+                    let value = match meta.value()?.parse::<Lit>()? {
+                        lit @ (Lit::Str(_) | Lit::Int(_) | Lit::Float(_) | Lit::Bool(_)) => lit,
+                        lit => return Err(syn::Error::new_spanned(lit, "unsupported literal")),
+                    };
+                    checks.push(Check::Equals(value));
+                } else if meta.path.is_ident("contains") {
+                    checks.push(Check::Contains(meta.value()?.parse()?));
+                } else if meta.path.is_ident("matches") {
+                    let pattern: LitStr = meta.value()?.parse()?;
+                    if let Err(e) = regex::Regex::new(&pattern.value()) {
+                        return Err(syn::Error::new_spanned(
+                            pattern,
+                            format!("invalid pattern: {}", e),
+                        ));
+                    }
+                    checks.push(Check::Matches(pattern));
+                } else {
+                    return Err(meta.error("unknown claim attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        let claim = name.unwrap_or_default();
+        rules.extend(checks.iter().map(|check| check.rule(&claim)));
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::rapi::rules::ClaimsValidator for #ident #ty_generics #where_clause {
+            fn claim_rules() -> ::std::vec::Vec<::rapi::rules::ClaimRule> {
+                ::std::vec![#(#rules),*]
+            }
+        }
+    })
+}
+
+enum Check {
+    Present,
+    Equals(Lit),
+    Contains(LitStr),
+    Matches(LitStr),
+}
+
+impl Check {
+    /// The expression constructing the `ClaimRule` of `claim`
+    fn rule(&self, claim: &str) -> TokenStream2 {
+        let rule = quote!(::rapi::rules::ClaimRule);
+        match self {
+            Check::Present => quote!(#rule::present(#claim)),
+            Check::Equals(value) => quote!(#rule::equals(#claim, #value)),
+            Check::Contains(value) => quote!(#rule::contains(#claim, #value)),
+            // Compiled by `expand` already
+            Check::Matches(pattern) => quote! {
+                #rule::matches(#claim, #pattern).unwrap()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syn::parse_quote;
+
+    #[test]
+    fn test_errors() {
+        let error = |input: DeriveInput| expand(&input).unwrap_err().to_string();
+        assert!(error(parse_quote! {
+            struct Claims {
+                #[claim(matches = "(")]
+                sub: String,
+            }
+        })
+        .starts_with("invalid pattern: regex parse error"));
+        assert_eq!(
+            error(parse_quote! {
+                struct Claims {
+                    #[claim(equal = "acme")]
+                    tenant: String,
+                }
+            }),
+            "unknown claim attribute"
+        );
+        assert_eq!(
+            error(parse_quote! { struct Claims(String); }),
+            "ValidateClaims needs named fields"
+        );
+
+        let ok: DeriveInput = parse_quote! {
+            struct Claims {
+                #[claim(matches = r"@example\.com$")]
+                email: String,
+            }
+        };
+        let expanded = expand(&ok).unwrap().to_string();
+        assert!(expanded.contains("ClaimRule :: matches (\"email\""));
+    }
+}
@@ -0,0 +1,164 @@
+//! Static API keys, for legacy clients that cannot obtain tokens
+use crate::error::AuthError;
+use crate::report::{report, Component};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::HeaderName;
+use anyhow::Context;
+use log::warn;
+use ring::digest::{digest, SHA256};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// What `ApiKeyStore::lookup` returns
+pub type LookupFuture<'a> =
+    Pin<Box<dyn Future<Output = anyhow::Result<Option<Map<String, Value>>>> + 'a>>;
+
+/// Where API keys are looked up, e.g. in process or in a database
+pub trait ApiKeyStore: Send + Sync {
+    /// The claims of the synthetic principal `key` authenticates as, if it is known
+    fn lookup<'a>(&'a self, key: &'a str) -> LookupFuture<'a>;
+}
+
+/// API keys held in process memory, by their SHA-256 so the keys themselves are
+/// not kept
+#[derive(Default)]
+pub struct MemoryApiKeys {
+    keys: HashMap<String, Map<String, Value>>,
+}
+
+fn sha256_hex(key: &str) -> String {
+    digest(&SHA256, key.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl MemoryApiKeys {
+    /// Accept `key` as the principal with `claims`, e.g. `{"sub": "legacy-billing"}`
+    pub fn insert(&mut self, key: &str, claims: Map<String, Value>) {
+        self.keys.insert(sha256_hex(key), claims);
+    }
+
+    /// Keys from a JSON object mapping the hex SHA-256 of each key to its claims
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::read(path).with_context(|| format!("reading {}", path))?;
+        let keys: HashMap<String, Map<String, Value>> = serde_json::from_slice(&file)
+            .with_context(|| format!("invalid API keys in {}", path))?;
+        Ok(MemoryApiKeys {
+            keys: keys
+                .into_iter()
+                .map(|(hash, claims)| (hash.to_ascii_lowercase(), claims))
+                .collect(),
+        })
+    }
+}
+
+impl ApiKeyStore for MemoryApiKeys {
+    fn lookup<'a>(&'a self, key: &'a str) -> LookupFuture<'a> {
+        Box::pin(async move { Ok(self.keys.get(&sha256_hex(key)).cloned()) })
+    }
+}
+
+/// Authentication by an API key header, tried by the validator for requests without
+/// a bearer token. The claims of the key's principal go through the same rules,
+/// policy and rate limits as token claims.
+#[derive(Clone)]
+pub struct ApiKeys {
+    header: HeaderName,
+    store: Arc<dyn ApiKeyStore>,
+}
+
+impl ApiKeys {
+    /// Keys in the `X-API-Key` header, looked up in `store`
+    pub fn new(store: impl ApiKeyStore + 'static) -> Self {
+        ApiKeys {
+            header: HeaderName::from_static("x-api-key"),
+            store: Arc::new(store),
+        }
+    }
+
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn header_name(&self) -> &HeaderName {
+        &self.header
+    }
+
+    /// The key presented with `req`, if any
+    pub fn presented(&self, req: &ServiceRequest) -> Option<String> {
+        let key = req.headers().get(&self.header)?.to_str().ok()?;
+        Some(key.to_string()).filter(|key| !key.is_empty())
+    }
+
+    /// The claims of the principal `key` authenticates as
+    pub async fn authenticate(&self, key: &str) -> Result<Map<String, Value>, AuthError> {
+        self.store
+            .lookup(key)
+            .await
+            .map_err(|e| {
+                warn!("API key lookup failed: {}", e);
+                report(Component::ApiKeys, &e);
+                AuthError::ApiKeyUnavailable
+            })?
+            .ok_or(AuthError::InvalidApiKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtAuth;
+    use crate::claims::TokenClaims;
+    use crate::keystore::Keys;
+    use crate::relay::BearerToken;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest};
+    use jsonwebtoken::{Algorithm, Validation};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_api_keys() {
+        let mut store = MemoryApiKeys::default();
+        let claims = json!({"sub": "legacy"});
+        store.insert("k1", claims.as_object().unwrap().clone());
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), Keys::new())
+            .api_keys(ApiKeys::new(store));
+        let mut app = test::init_service(App::new().wrap(auth.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                let extensions = req.extensions();
+                assert!(extensions.get::<BearerToken>().is_none());
+                let sub = extensions.get::<TokenClaims>().unwrap().0["sub"].clone();
+                async move { sub.as_str().unwrap().to_string() }
+            }),
+        ))
+        .await;
+
+        let req = test::TestRequest::get()
+            .header("x-api-key", "k1")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(test::read_body(resp).await, "legacy");
+
+        let req = test::TestRequest::get()
+            .header("x-api-key", "k2")
+            .to_request();
+        let err = app.call(req).await.unwrap_err();
+        let resp = err.as_response_error().error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        // Only without a bearer token
+        let req = test::TestRequest::get()
+            .header("x-api-key", "k1")
+            .header("Authorization", "Bearer a.b.c")
+            .to_request();
+        assert!(app.call(req).await.is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! The audiences tokens must be for, by request path
+use serde_json::{Map, Value};
+
+/// The audiences of the surfaces of a service, so a token for one surface is not
+/// accepted by another, e.g. `internal-api` for `/internal/**` and `public-api` for
+/// `/v1/**`. The longest matching pattern applies; requests to paths matching none
+/// are left to the `Validation`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteAudiences {
+    routes: Vec<(String, Vec<String>)>,
+}
+
+impl RouteAudiences {
+    pub fn new() -> Self {
+        RouteAudiences::default()
+    }
+
+    /// Accept `audience` for requests to `pattern`, a path or, ending in `/**`,
+    /// every path under one. Several audiences of a pattern are each accepted.
+    pub fn route(mut self, pattern: impl Into<String>, audience: impl Into<String>) -> Self {
+        let pattern = pattern.into();
+        match self.routes.iter_mut().find(|(p, _)| *p == pattern) {
+            Some((_, audiences)) => audiences.push(audience.into()),
+            None => self.routes.push((pattern, vec![audience.into()])),
+        }
+        self
+    }
+
+    /// Routes from `pattern=audience` pairs, e.g. `/internal/**=internal-api`
+    pub fn parse(pairs: &[String]) -> anyhow::Result<Self> {
+        pairs
+            .iter()
+            .try_fold(RouteAudiences::new(), |routes, pair| {
+                let (pattern, audience) = pair
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("expected path=audience, got {:?}", pair))?;
+                Ok(routes.route(pattern.trim(), audience.trim()))
+            })
+    }
+
+    /// The audiences accepted for `path`, if any pattern matches it once normalized,
+    /// so `/%69nternal` or `/v1/../internal` match `/internal/**`
+    pub fn required(&self, path: &str) -> Option<&[String]> {
+        let path = normalize(path);
+        self.routes
+            .iter()
+            .filter(|(pattern, _)| matches(pattern, &path))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, audiences)| audiences.as_slice())
+    }
+
+    /// Whether the `aud` of `claims` has an audience accepted for `path`
+    pub fn check(&self, claims: &Map<String, Value>, path: &str) -> bool {
+        let required = match self.required(path) {
+            Some(required) => required,
+            None => return true,
+        };
+        let accepted = |aud: &Value| {
+            aud.as_str()
+                .is_some_and(|aud| required.iter().any(|r| r == aud))
+        };
+        match claims.get("aud") {
+            Some(Value::Array(auds)) => auds.iter().any(accepted),
+            Some(aud) => accepted(aud),
+            None => false,
+        }
+    }
+}
+
+/// `path` percent-decoded but for `/`, `%` and `+`, as actix routes it, with its `.`
+/// and `..` segments resolved and empty ones dropped
+fn normalize(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) if !b"/%+".contains(&byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+    let mut segments = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+fn matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix("/**") {
+        Some(prefix) => path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/')),
+        None => pattern == path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_route_audiences() {
+        let routes = RouteAudiences::parse(&[
+            "/internal/**=internal-api".to_string(),
+            "/v1/**=public-api".to_string(),
+            "/v1/admin/**=admin-api".to_string(),
+            "/v1/admin/**=internal-api".to_string(),
+        ])
+        .unwrap();
+        let claims = |aud: Value| json!({ "aud": aud }).as_object().unwrap().clone();
+        let internal = claims(json!("internal-api"));
+        let public = claims(json!(["other", "public-api"]));
+
+        assert!(routes.check(&internal, "/internal"));
+        assert!(routes.check(&internal, "/internal/jobs/1"));
+        assert!(!routes.check(&public, "/internal/jobs/1"));
+        assert!(routes.check(&public, "/v1/orders"));
+        assert!(!routes.check(&internal, "/v1/orders"));
+        assert!(routes.check(&internal, "/v1/admin/users"));
+        assert!(!routes.check(&public, "/v1/admin/users"));
+        // Not under /internal, and matched by no pattern
+        assert!(routes.check(&public, "/internalx"));
+        assert!(!routes.check(&Map::new(), "/v1/orders"));
+        // Matched as routed, not as sent
+        assert!(!routes.check(&public, "/%69nternal/jobs/1"));
+        assert!(!routes.check(&public, "/v1/../internal/jobs"));
+        assert!(!routes.check(&public, "/v1/%2e%2e/internal/jobs"));
+        assert!(!routes.check(&public, "//internal/./jobs/"));
+        assert!(routes.check(&internal, "/v1/%61dmin/users"));
+        assert!(RouteAudiences::parse(&["/v1/**".to_string()]).is_err());
+    }
+}
@@ -1,119 +1,2667 @@
-use actix_web::{dev::ServiceRequest, error, Error};
+use crate::apikey::ApiKeys;
+use crate::audience::RouteAudiences;
+use crate::cache::TokenCache;
+use crate::claims::{strip_namespace, Principal, TokenClaims, UserId};
+use crate::config::ConfigHandle;
+use crate::cookie::TokenCookie;
+use crate::csrf::CsrfProtection;
+use crate::debug::{Diagnosis, ValidationReport};
+#[cfg(feature = "dangerous-dev-mode")]
+use crate::devmode::DangerousDevMode;
+use crate::error::{AuthError, Challenge, ErrorPages, Rejection};
+use crate::events::{AuthEvent, Outcome};
+use crate::extract::{Credentials, HeaderPolicy, HeaderProblem, MultipleCredentials};
+use crate::groups::Groups;
+use crate::headers::ClaimHeaders;
+use crate::keystore::{JwksStore, Keys, RefreshHandle, Refresher};
+use crate::metrics::{AuthMetrics, OTHER};
+use crate::network::IpConstraint;
+use crate::policy::{PolicyEvaluator, PolicyInput};
+use crate::proxy::TrustedProxies;
+use crate::public::{is_public, Public};
+use crate::ratelimit::RateLimit;
+use crate::redact::TokenHash;
+use crate::relay::BearerToken;
+use crate::remote::RemoteKey;
+use crate::report::{report, Component};
+use crate::resolver::KeyResolver;
+use crate::revocation::Revocations;
+use crate::rules::{ClaimRule, ClaimsValidator};
+use crate::schema::ClaimsSchema;
+use crate::scopes::ScopeClaims;
+use crate::sessions::SessionLimit;
+use crate::shutdown::Shutdown;
+use actix_web::http::{header, HeaderName};
+use actix_web::{dev::ServiceRequest, Error, HttpMessage, ResponseError};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use jsonwebtoken::DecodingKey;
-use jsonwebtoken::{decode, decode_header};
-use log::trace;
+use actix_web_httpauth::middleware::HttpAuthentication;
+use jsonwebtoken::{
+    dangerous_insecure_decode, decode, decode_header, Algorithm, DecodingKey, EncodingKey, Header,
+};
+use log::{trace, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::{hash_map::RandomState, HashMap};
+use serde_json::{Map, Value};
+use std::borrow::Cow;
 use std::future::Future;
+use std::net::IpAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 
-pub fn validator<'a>(
+/// Longest token accepted unless configured otherwise, in bytes
+pub const DEFAULT_MAX_TOKEN_LEN: usize = 8192;
+
+/// Longest accepted header segment, in base64url characters
+const MAX_HEADER_LEN: usize = 1024;
+
+/// What to do with tokens lacking a `kid` header
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum MissingKidPolicy {
+    #[default]
+    Reject,
+    /// Verify with the keys of this kid, e.g. for single key issuers
+    Default(String),
+    /// Verify with every key in the store
+    TryAll,
+}
+
+/// Token validation settings shared by every request passing the validator
+#[derive(Clone)]
+pub struct JwtAuth {
     validation: jsonwebtoken::Validation,
-    jwks: HashMap<String, DecodingKey<'a>, RandomState>,
-) -> impl Fn(
-    ServiceRequest,
-    BearerAuth,
-) -> Pin<Box<dyn Future<Output = Result<ServiceRequest, Error>> + 'a>>
-       + 'a {
-    move |req, credentials| Box::pin(v(validation.clone(), jwks.clone(), req, credentials))
+    jwks: JwksStore,
+    refresher: Option<Refresher>,
+    missing_kid: MissingKidPolicy,
+    resolver: Option<Arc<dyn KeyResolver>>,
+    remote_key: Option<Arc<dyn RemoteKey>>,
+    max_token_len: usize,
+    access_tokens: Option<AccessTokens>,
+    header_policy: HeaderPolicy,
+    multiple_credentials: MultipleCredentials,
+    require_exp: bool,
+    require_iat: bool,
+    reject_future_iat: bool,
+    token_hash_salt: Arc<[u8]>,
+    challenge: Arc<Challenge>,
+    error_pages: Arc<ErrorPages>,
+    claims_namespace: Option<String>,
+    claims_schema: Option<ClaimsSchema>,
+    groups: Option<Groups>,
+    rules: Vec<ClaimRule>,
+    ip_constraint: Option<IpConstraint>,
+    claim_headers: Option<ClaimHeaders>,
+    scope_claims: ScopeClaims,
+    required_scopes: Vec<String>,
+    live_config: Option<ConfigHandle>,
+    trusted_proxies: TrustedProxies,
+    policy: Option<Arc<dyn PolicyEvaluator>>,
+    rate_limit: Option<RateLimit>,
+    session_cookie: Option<String>,
+    token_cookie: Option<TokenCookie>,
+    revocations: Option<Revocations>,
+    session_limit: Option<SessionLimit>,
+    route_audiences: Option<RouteAudiences>,
+    token_cache: Option<TokenCache>,
+    metrics: Option<AuthMetrics>,
+    event_log: bool,
+    request_id_header: Option<HeaderName>,
+    user_id_claim: String,
+    api_keys: Option<ApiKeys>,
+    optional: bool,
+    csrf: Option<CsrfProtection>,
+    #[cfg(feature = "dangerous-dev-mode")]
+    dev_mode: Option<Arc<DangerousDevMode>>,
 }
 
-async fn v<'a>(
+impl JwtAuth {
+    /// `jwks` is either a fixed set of keys or a `JwksStore` kept up to date by a `Refresher`
+    pub fn new(validation: jsonwebtoken::Validation, jwks: impl Into<JwksStore>) -> Self {
+        JwtAuth {
+            validation,
+            jwks: jwks.into(),
+            refresher: None,
+            missing_kid: MissingKidPolicy::default(),
+            resolver: None,
+            remote_key: None,
+            max_token_len: DEFAULT_MAX_TOKEN_LEN,
+            access_tokens: None,
+            header_policy: HeaderPolicy::default(),
+            multiple_credentials: MultipleCredentials::default(),
+            require_exp: true,
+            require_iat: false,
+            reject_future_iat: false,
+            token_hash_salt: Arc::from(&b""[..]),
+            challenge: Arc::default(),
+            error_pages: Arc::default(),
+            claims_namespace: None,
+            claims_schema: None,
+            groups: None,
+            rules: Vec::new(),
+            ip_constraint: None,
+            claim_headers: None,
+            scope_claims: ScopeClaims::default(),
+            required_scopes: Vec::new(),
+            live_config: None,
+            trusted_proxies: TrustedProxies::default(),
+            policy: None,
+            rate_limit: None,
+            session_cookie: None,
+            token_cookie: None,
+            revocations: None,
+            session_limit: None,
+            route_audiences: None,
+            token_cache: None,
+            metrics: None,
+            event_log: false,
+            request_id_header: Some(HeaderName::from_static("x-request-id")),
+            user_id_claim: "sub".into(),
+            api_keys: None,
+            optional: false,
+            csrf: Some(CsrfProtection::default()),
+            #[cfg(feature = "dangerous-dev-mode")]
+            dev_mode: None,
+        }
+    }
+
+    /// The store holding the decoding keys, e.g. for `health()` or `readiness()`
+    pub fn key_store(&self) -> &JwksStore {
+        &self.jwks
+    }
+
+    /// A handle forcing a key refresh, when a `Refresher` is configured
+    pub fn refresh_handle(&self) -> Option<RefreshHandle> {
+        self.refresher.as_ref().map(Refresher::handle)
+    }
+
+    /// What stops the background refresh, to stop it along with the server
+    pub fn shutdown_handle(&self) -> Option<Shutdown> {
+        self.refresher.as_ref().map(Refresher::shutdown_handle)
+    }
+
+    /// Refetch keys through `refresher` when a token has an unknown kid
+    pub fn refresher(mut self, refresher: Refresher) -> Self {
+        self.refresher = Some(refresher);
+        self
+    }
+
+    /// How to pick keys for tokens without a kid, rejecting them by default
+    pub fn missing_kid(mut self, policy: MissingKidPolicy) -> Self {
+        self.missing_kid = policy;
+        self
+    }
+
+    /// Choose keys with `resolver` instead of by kid, e.g. by `x5t` or `iss`
+    pub fn key_resolver(mut self, resolver: impl KeyResolver + 'static) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Verify tokens of the algorithm of `key` with it, e.g. a `kms::KmsKey`, instead
+    /// of with keys by kid, so HMAC secrets never reach this process. Its algorithm is
+    /// accepted besides those of the validation, which keep verifying with local keys.
+    pub fn remote_key(mut self, key: impl RemoteKey + 'static) -> Self {
+        self.remote_key = Some(Arc::new(key));
+        self
+    }
+
+    /// Accept only tokens for `audience`, replacing the audiences validated so far
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.validation.aud = Some(std::iter::once(audience.into()).collect());
+        self
+    }
+
+    /// Read the token from this cookie when a request has no `Authorization` header,
+    /// see `middleware()`
+    pub fn session_cookie(mut self, name: impl Into<String>) -> Self {
+        self.session_cookie = Some(name.into());
+        self
+    }
+
+    /// Authenticate requests without a token by an API key instead, see `middleware()`
+    pub fn api_keys(mut self, api_keys: ApiKeys) -> Self {
+        self.api_keys = Some(api_keys);
+        self
+    }
+
+    /// Let requests without a token or API key through as `Principal::Anonymous`,
+    /// for routes serving more to authenticated clients. Invalid tokens are still
+    /// rejected.
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    /// How requests authenticated by the session cookie are protected against CSRF,
+    /// by default with the double submit cookie `csrf_token`
+    pub fn csrf(mut self, csrf: CsrfProtection) -> Self {
+        self.csrf = Some(csrf);
+        self
+    }
+
+    /// No CSRF checks, e.g. where the session cookie is `SameSite=Strict`
+    pub fn without_csrf(mut self) -> Self {
+        self.csrf = None;
+        self
+    }
+
+    /// Read the token from the cookie written by `cookie`, decrypting it if need be,
+    /// like `session_cookie()`
+    pub fn token_cookie(mut self, cookie: TokenCookie) -> Self {
+        self.session_cookie = Some(cookie.name().into());
+        self.token_cookie = Some(cookie);
+        self
+    }
+
+    /// Reject longer tokens before decoding anything
+    pub fn max_token_len(mut self, max_token_len: usize) -> Self {
+        self.max_token_len = max_token_len;
+        self
+    }
+
+    /// How the middleware answers requests without a usable `Authorization` header
+    pub fn header_policy(mut self, policy: HeaderPolicy) -> Self {
+        self.header_policy = policy;
+        self
+    }
+
+    /// What the middleware does with requests carrying several credentials
+    pub fn multiple_credentials(mut self, policy: MultipleCredentials) -> Self {
+        self.multiple_credentials = policy;
+        self
+    }
+
+    /// Reject ID tokens pasted where access tokens belong: tokens with a `nonce`, a
+    /// Cognito `token_use` other than `access`, or a `typ` header of another type
+    pub fn access_tokens_only(mut self) -> Self {
+        self.access_tokens = Some(AccessTokens { strict_typ: false });
+        self
+    }
+
+    /// Like `access_tokens_only`, also requiring the `at+jwt` type of RFC 9068
+    /// access tokens in the header
+    pub fn rfc9068_access_tokens_only(mut self) -> Self {
+        self.access_tokens = Some(AccessTokens { strict_typ: true });
+        self
+    }
+
+    /// Accept tokens without `exp`, which are otherwise rejected as `MissingExp` even
+    /// if the validation does not check expiry. Such tokens also need a validation
+    /// with `validate_exp` off.
+    pub fn allow_missing_exp(mut self) -> Self {
+        self.require_exp = false;
+        self
+    }
+
+    /// Reject tokens without an `iat` claim
+    pub fn require_iat(mut self) -> Self {
+        self.require_iat = true;
+        self
+    }
+
+    /// Reject tokens issued later than now plus the leeway of the validation, a sign
+    /// of skewed issuer clocks or forged tokens
+    pub fn reject_future_iat(mut self) -> Self {
+        self.reject_future_iat = true;
+        self
+    }
+
+    /// Salt of the `TokenHash` logged for each token and added to the request
+    pub fn token_hash_salt(mut self, salt: impl AsRef<[u8]>) -> Self {
+        self.token_hash_salt = Arc::from(salt.as_ref());
+        self
+    }
+
+    /// The `WWW-Authenticate` challenge sent along rejections
+    pub fn challenge(mut self, challenge: Challenge) -> Self {
+        self.challenge = Arc::new(challenge);
+        self
+    }
+
+    /// Formats of rejections besides plain text, negotiated with the `Accept` header
+    pub fn error_pages(mut self, pages: ErrorPages) -> Self {
+        self.error_pages = Arc::new(pages);
+        self
+    }
+
+    /// Strip this prefix from custom claim names, e.g. `https://myapp.example.com/`
+    pub fn claims_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.claims_namespace = Some(namespace.into());
+        self
+    }
+
+    /// Reject tokens whose claims do not satisfy this JSON Schema
+    pub fn claims_schema(mut self, schema: ClaimsSchema) -> Self {
+        self.claims_schema = Some(schema);
+        self
+    }
+
+    /// Require every accepted token to satisfy `rule`. Wrap a scope with a
+    /// clone carrying extra rules to apply them only there.
+    pub fn require(mut self, rule: ClaimRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Require every accepted token to grant `scope`, read with the `ScopeClaims`
+    pub fn require_scope(mut self, scope: impl Into<String>) -> Self {
+        self.required_scopes.push(scope.into());
+        self
+    }
+
+    /// Accept tokens only from the networks `constraint` allows them
+    pub fn ip_constraint(mut self, constraint: IpConstraint) -> Self {
+        self.ip_constraint = Some(constraint);
+        self
+    }
+
+    /// Take the leeway, required claims and required scopes of every request from
+    /// `config` as it is then, so a `ConfigHandle::reload` applies without a restart.
+    /// Rules and scopes required otherwise still apply.
+    pub fn live_config(mut self, config: ConfigHandle) -> Self {
+        self.live_config = Some(config);
+        self
+    }
+
+    /// This validation with the tunable settings of the live configuration: its
+    /// leeway when set, and its required claims and scopes on top of those built in
+    fn tuned(mut self) -> Self {
+        let config = match &self.live_config {
+            Some(live) => live.get(),
+            None => return self,
+        };
+        if let Some(leeway) = config.leeway_secs {
+            self.validation.leeway = leeway;
+        }
+        let required = config.required_claims.iter().flatten();
+        self.rules.extend(required.map(ClaimRule::present));
+        for scope in config.required_scopes.iter().flatten() {
+            if !self.required_scopes.contains(scope) {
+                self.required_scopes.push(scope.clone());
+            }
+        }
+        self
+    }
+
+    /// This validation `tuned`, without a copy when there is no live configuration
+    pub(crate) fn live(&self) -> Cow<'_, JwtAuth> {
+        match self.live_config {
+            Some(_) => Cow::Owned(self.clone().tuned()),
+            None => Cow::Borrowed(self),
+        }
+    }
+
+    /// Where the `Scopes` of tokens are read from, `scope` and `scp` by default
+    pub fn scope_claims(mut self, claims: ScopeClaims) -> Self {
+        self.scope_claims = claims;
+        self
+    }
+
+    /// Write claims of accepted tokens into request headers for the handlers, and
+    /// drop identity headers clients could spoof
+    pub fn claim_headers(mut self, headers: ClaimHeaders) -> Self {
+        self.claim_headers = Some(headers);
+        self
+    }
+
+    /// The proxies believed about the client address, for `ip_constraint` and events
+    pub fn trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    /// Require every accepted token to satisfy the claim rules of `T`
+    pub fn validate_claims<T: ClaimsValidator>(mut self) -> Self {
+        self.rules.extend(T::claim_rules());
+        self
+    }
+
+    /// Require tokens to be for the audience of the requested path, on top of any in
+    /// the `Validation`
+    pub fn route_audiences(mut self, routes: RouteAudiences) -> Self {
+        self.route_audiences = Some(routes);
+        self
+    }
+
+    /// Refuse new tokens of subjects with as many tokens in use as `limit` allows
+    pub fn session_limit(mut self, limit: SessionLimit) -> Self {
+        self.session_limit = Some(limit);
+        self
+    }
+
+    /// Look up the groups of tokens that left them out, before rules and policies
+    pub fn groups(mut self, groups: Groups) -> Self {
+        self.groups = Some(groups);
+        self
+    }
+
+    /// Refuse tokens of sessions and subjects revoked in `revocations`
+    pub fn revocations(mut self, revocations: Revocations) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// Skip verifying the signature of tokens seen recently, see `TokenCache`
+    pub fn token_cache(mut self, cache: TokenCache) -> Self {
+        self.token_cache = Some(cache);
+        self
+    }
+
+    /// Count the outcome of every request in `metrics`. Rejected tokens are labelled
+    /// by their kid and issuer only if the keys and validation know them, otherwise
+    /// as `other`, so that made-up tokens cannot use up the labels.
+    pub fn metrics(mut self, metrics: AuthMetrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Log every decision as a JSON `AuthEvent`
+    pub fn event_log(mut self) -> Self {
+        self.event_log = true;
+        self
+    }
+
+    /// Identify users by this claim rather than `sub`, e.g. `oid` for Azure AD, see
+    /// `UserId`
+    pub fn user_id_claim(mut self, claim: impl Into<String>) -> Self {
+        self.user_id_claim = claim.into();
+        self
+    }
+
+    /// Take the request id from this header rather than `X-Request-Id`, for events
+    /// and rejections
+    pub fn request_id_header(mut self, header: HeaderName) -> Self {
+        self.request_id_header = Some(header);
+        self
+    }
+
+    /// Ask `policy` whether a request with valid claims is allowed
+    pub fn policy(mut self, policy: impl PolicyEvaluator + 'static) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Limit requests per distinct value of a claim, answering 429 beyond it
+    pub fn rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    /// Relax validation as `mode` says, warning loudly about it. For local development
+    /// only.
+    #[cfg(feature = "dangerous-dev-mode")]
+    pub fn dangerous_dev_mode(mut self, mode: DangerousDevMode) -> Self {
+        mode.warn();
+        if mode.skips_audience() {
+            self.validation.aud = None;
+        }
+        self.dev_mode = Some(Arc::new(mode));
+        self
+    }
+
+    /// The settings tokens are verified with, for `inspect`
+    pub(crate) fn validation_report(&self) -> ValidationReport {
+        let validation = &self.validation;
+        let mut audiences: Vec<String> = validation.aud.iter().flatten().cloned().collect();
+        audiences.sort();
+        ValidationReport {
+            algorithms: validation.algorithms.clone(),
+            issuer: validation.iss.clone(),
+            audiences,
+            leeway: validation.leeway,
+            validate_exp: validation.validate_exp,
+            validate_nbf: validation.validate_nbf,
+            require_exp: self.require_exp,
+            require_iat: self.require_iat,
+            reject_future_iat: self.reject_future_iat,
+            access_tokens_only: self.access_tokens.is_some(),
+            max_token_len: self.max_token_len,
+        }
+    }
+
+    /// This verifier for ID tokens: without the access token checks, required scopes
+    /// and claim rules of the API, and with the live configuration as it is now
+    #[cfg(feature = "login")]
+    pub(crate) fn for_id_tokens(self) -> Self {
+        let mut auth = self.tuned();
+        auth.live_config = None;
+        auth.access_tokens = None;
+        auth.required_scopes.clear();
+        auth.rules.clear();
+        auth
+    }
+
+    /// What claims in the `TokenCache` were verified against: everything deciding
+    /// whether a token is accepted before the rules, which run on every request,
+    /// the keys included
+    fn cache_namespace(&self) -> String {
+        format!(
+            "{:?} sub {:?} {:?} kid {:?} keys {}",
+            self.validation_report(),
+            self.validation.sub,
+            self.access_tokens,
+            self.missing_kid,
+            self.key_source()
+        )
+    }
+
+    /// Where the keys come from: the keys of the store themselves, so that
+    /// instances loading the same keys share cache entries, and this process's
+    /// resolver, remote key or dev key, which cannot be compared another way
+    fn key_source(&self) -> String {
+        let mut source = self.jwks.fingerprint();
+        if let Some(resolver) = &self.resolver {
+            source += &format!(" resolver {:p}", Arc::as_ptr(resolver) as *const ());
+        }
+        if let Some(key) = &self.remote_key {
+            source += &format!(" remote {:p}", Arc::as_ptr(key) as *const ());
+        }
+        #[cfg(feature = "dangerous-dev-mode")]
+        if let Some(mode) = &self.dev_mode {
+            source += &format!(" dev {:p}", Arc::as_ptr(mode));
+        }
+        source
+    }
+
+    /// Verify the signature and registered claims of `token` outside of a request,
+    /// e.g. an ID token received at a login callback
+    pub async fn verify(&self, token: &str) -> Result<Map<String, Value>, AuthError> {
+        well_formed(token, self.max_token_len)?;
+        let auth = self.live();
+        decode_claims(&auth, token, &TokenHash::new(token, &auth.token_hash_salt)).await
+    }
+
+    /// Run the checks of the middleware on `token` one by one, reporting each up to
+    /// the first failure instead of rejecting, e.g. for support tooling. Checks of the
+    /// request rather than the token, such as addresses, audiences by path, sessions,
+    /// policies and rate limits, and group lookups are left out.
+    pub async fn diagnose(&self, token: &str) -> Diagnosis {
+        use jsonwebtoken::errors::ErrorKind;
+
+        let mut d = Diagnosis::default();
+        if let Err(e) = well_formed(token, self.max_token_len) {
+            return d.fail("format", &e);
+        }
+        d.pass("format", None);
+        let header = match decode_header(token) {
+            Ok(header) => header,
+            Err(_) => return d.fail("header", &AuthError::BadToken),
+        };
+        d.pass(
+            "header",
+            Some(format!("alg {:?}, kid {:?}", header.alg, header.kid)),
+        );
+        let payload = match dangerous_insecure_decode::<Map<String, Value>>(token) {
+            Ok(payload) => payload.claims,
+            Err(_) => return d.fail("payload", &AuthError::BadToken),
+        };
+        if self.require_exp {
+            if !payload.contains_key("exp") {
+                return d.fail("exp present", &AuthError::MissingExp);
+            }
+            d.pass("exp present", None);
+        }
+
+        #[cfg(feature = "dangerous-dev-mode")]
+        let dev_claims = self
+            .dev_mode
+            .as_ref()
+            .and_then(|mode| mode.local_key())
+            .filter(|(_, alg)| header.alg == *alg)
+            .and_then(|(key, alg)| {
+                let validation = jsonwebtoken::Validation {
+                    algorithms: vec![*alg],
+                    iss: None,
+                    ..self.validation.clone()
+                };
+                decode::<Map<String, Value>>(token, key, &validation).ok()
+            })
+            .map(|t| t.claims);
+        #[cfg(not(feature = "dangerous-dev-mode"))]
+        let dev_claims: Option<Map<String, Value>> = None;
+        let mut claims = match dev_claims {
+            Some(claims) => {
+                d.pass("dev key", None);
+                claims
+            }
+            None => {
+                if !self.validation.algorithms.contains(&header.alg) {
+                    return d.fail("algorithm", &AuthError::AlgorithmMismatch);
+                }
+                d.pass("algorithm", None);
+                let keys = match &self.resolver {
+                    Some(resolver) => resolver
+                        .resolve(&header, &payload)
+                        .ok_or(AuthError::UnknownKid),
+                    None => keys_for_kid(self, header.kid.clone(), token).await,
+                };
+                let keys = match keys {
+                    Ok(keys) => keys,
+                    Err(e) => return d.fail("key", &e),
+                };
+                d.pass("key", Some(format!("{} candidate keys", keys.len())));
+                // The signature alone, then the registered claims with the key it matches
+                let signature_only = jsonwebtoken::Validation {
+                    validate_exp: false,
+                    validate_nbf: false,
+                    aud: None,
+                    iss: None,
+                    sub: None,
+                    ..self.validation.clone()
+                };
+                let key = match keys
+                    .iter()
+                    .find(|key| decode::<Map<String, Value>>(token, key, &signature_only).is_ok())
+                {
+                    Some(key) => key,
+                    None => return d.fail("signature", &AuthError::InvalidToken),
+                };
+                d.pass("signature", None);
+                match decode::<Map<String, Value>>(token, key, &self.validation) {
+                    Ok(t) => t.claims,
+                    Err(e) => {
+                        let check = match e.kind() {
+                            ErrorKind::ExpiredSignature => "exp",
+                            ErrorKind::ImmatureSignature => "nbf",
+                            ErrorKind::InvalidIssuer => "iss",
+                            ErrorKind::InvalidAudience => "aud",
+                            ErrorKind::InvalidSubject => "sub",
+                            _ => "registered claims",
+                        };
+                        return d.fail(check, &AuthError::InvalidToken);
+                    }
+                }
+            }
+        };
+        d.pass("registered claims", None);
+
+        if let Some(access_tokens) = &self.access_tokens {
+            if !access_tokens.check(&header, &claims) {
+                return d.fail("access token", &AuthError::NotAccessToken);
+            }
+            d.pass("access token", None);
+        }
+        if let Err(e) = check_iat(self, &claims) {
+            return d.fail("iat", &e);
+        }
+        if self.require_iat || self.reject_future_iat {
+            d.pass("iat", None);
+        }
+        if let Some(namespace) = &self.claims_namespace {
+            claims = strip_namespace(claims, namespace);
+        }
+        if let Some(schema) = &self.claims_schema {
+            if let Err(violations) = schema.validate(&Value::Object(claims.clone())) {
+                return d.fail("schema", &AuthError::ClaimsSchema(violations));
+            }
+            d.pass("schema", None);
+        }
+        for rule in &self.rules {
+            let check = format!("rule {}", rule.claim());
+            if !rule.check(&claims) {
+                return d.fail(check, &AuthError::ClaimRule(rule.claim().into()));
+            }
+            d.pass(check, None);
+        }
+        if let Some(revocations) = &self.revocations {
+            match revocations.is_revoked(&claims).await {
+                Ok(false) => d.pass("revocation", None),
+                Ok(true) => return d.fail("revocation", &AuthError::Revoked),
+                Err(e) => {
+                    let failed = d.fail("revocation", &AuthError::RevocationUnavailable);
+                    warn!("revocation check failed: {}", e);
+                    return failed;
+                }
+            }
+        }
+        d.claims = Some(claims);
+        d
+    }
+
+    pub fn validator(
+        self,
+    ) -> impl Fn(
+        ServiceRequest,
+        BearerAuth,
+    ) -> Pin<Box<dyn Future<Output = Result<ServiceRequest, Error>>>> {
+        move |req, credentials| {
+            Box::pin(v(
+                self.clone(),
+                req,
+                Some(credentials.token().to_string()),
+                false,
+                None,
+            ))
+        }
+    }
+
+    /// Middleware passing requests without a token to the validator too, needed for
+    /// redirecting browsers to a login page, for session cookies and for API keys
+    pub fn middleware(
+        self,
+    ) -> HttpAuthentication<Credentials, impl Fn(ServiceRequest, Credentials) -> ValidatorFuture>
+    {
+        HttpAuthentication::with_fn(move |req, credentials: Credentials| {
+            let (token, from_cookie) = match credentials.token() {
+                Some(token) => (Some(token.to_string()), false),
+                None => (self.cookie_token(&req), true),
+            };
+            let auth = self.clone();
+            Box::pin(async move {
+                match (credentials.problem(), auth.multiple_credentials) {
+                    (Some(HeaderProblem::Multiple), MultipleCredentials::First)
+                    | (Some(HeaderProblem::Multiple), MultipleCredentials::TryEach)
+                        if !credentials.bearer_tokens().is_empty() =>
+                    {
+                        let token = auth.pick(credentials.bearer_tokens()).await;
+                        v(auth, req, Some(token), false, None).await
+                    }
+                    (problem, _) => v(auth, req, token, from_cookie, problem).await,
+                }
+            }) as ValidatorFuture
+        })
+    }
+
+    /// The token used of several bearer `tokens`
+    async fn pick(&self, tokens: &[String]) -> String {
+        if self.multiple_credentials == MultipleCredentials::TryEach {
+            for token in tokens {
+                if self.verify(token).await.is_ok() {
+                    return token.clone();
+                }
+            }
+        }
+        tokens[0].clone()
+    }
+
+    /// The bearer token of `req`, or else its session cookie, and whether it came from
+    /// the cookie
+    pub(crate) fn presented_token(&self, req: &ServiceRequest) -> Option<(String, bool)> {
+        match Credentials::of(req).token() {
+            Some(token) => Some((token.to_string(), false)),
+            None => self.cookie_token(req).map(|token| (token, true)),
+        }
+    }
+
+    /// Replace the claim headers of `req` with those of `claims`, or just drop the
+    /// client's values without claims
+    pub(crate) fn set_claim_headers(
+        &self,
+        req: &mut ServiceRequest,
+        claims: Option<&Map<String, Value>>,
+    ) {
+        match (&self.claim_headers, claims) {
+            (Some(headers), Some(claims)) => headers.apply(req, claims),
+            (Some(headers), None) => headers.strip(req),
+            (None, _) => {}
+        }
+    }
+
+    /// The CSRF check of requests authenticated by the session cookie
+    pub(crate) fn check_csrf(
+        &self,
+        req: &ServiceRequest,
+        claims: &Map<String, Value>,
+    ) -> Result<(), AuthError> {
+        match &self.csrf {
+            Some(csrf) => csrf.check(req, claims),
+            None => Ok(()),
+        }
+    }
+
+    fn cookie_token(&self, req: &ServiceRequest) -> Option<String> {
+        let name = self.session_cookie.as_ref()?;
+        let value = req.cookie(name)?;
+        match &self.token_cookie {
+            Some(cookie) => cookie.open(value.value()),
+            None => Some(value.value().to_string()),
+        }
+    }
+
+    /// The address of the client, behind the trusted proxies
+    pub(crate) fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        self.trusted_proxies.client_ip(req)
+    }
+
+    /// The id the request carries, if it is short and printable enough to log
+    pub(crate) fn request_id(&self, req: &ServiceRequest) -> Option<String> {
+        let value = req.headers().get(self.request_id_header.as_ref()?)?;
+        let value = value.to_str().ok()?;
+        let printable = value.bytes().all(|b| b.is_ascii_graphic());
+        Some(value.to_string()).filter(|_| printable && !value.is_empty() && value.len() <= 128)
+    }
+
+    /// The rejection challenge for `error`
+    pub(crate) fn challenge_value(&self, error: &AuthError) -> Option<String> {
+        self.challenge.header_value(error, &error.to_string())
+    }
+}
+
+/// What the validators return
+pub type ValidatorFuture = Pin<Box<dyn Future<Output = Result<ServiceRequest, Error>>>>;
+
+pub fn validator(
     validation: jsonwebtoken::Validation,
-    jwks: HashMap<String, DecodingKey<'a>, RandomState>,
+    jwks: Keys,
+) -> impl Fn(ServiceRequest, BearerAuth) -> Pin<Box<dyn Future<Output = Result<ServiceRequest, Error>>>>
+{
+    JwtAuth::new(validation, jwks).validator()
+}
+
+fn is_hmac(alg: Algorithm) -> bool {
+    matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/// Cheap structural check before decoding: three base64url segments and a short header
+fn well_formed(token: &str, max_len: usize) -> Result<(), AuthError> {
+    if token.len() > max_len {
+        return Err(AuthError::TokenTooLarge);
+    }
+    let base64url = |segment: &str| {
+        !segment.is_empty()
+            && segment
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+    };
+    let segments: Vec<_> = token.split('.').collect();
+    match segments.as_slice() {
+        [header, claims, signature]
+            if header.len() <= MAX_HEADER_LEN
+                && base64url(header)
+                && base64url(claims)
+                && base64url(signature) =>
+        {
+            Ok(())
+        }
+        _ => Err(AuthError::BadToken),
+    }
+}
+
+/// Certificate thumbprint headers, `x5t#S256` being unknown to `jsonwebtoken::Header`
+#[derive(Deserialize)]
+struct Thumbprints {
+    x5t: Option<String>,
+    #[serde(rename = "x5t#S256")]
+    x5t_s256: Option<String>,
+}
+
+/// The kid of the key matching a thumbprint in the token header
+fn thumbprint_kid(auth: &JwtAuth, token: &str) -> Option<String> {
+    let header = base64::decode_config(token.split('.').next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let thumbprints: Thumbprints = serde_json::from_slice(&header).ok()?;
+    [thumbprints.x5t_s256, thumbprints.x5t]
+        .iter()
+        .flatten()
+        .find_map(|thumbprint| auth.jwks.kid_for_thumbprint(thumbprint))
+}
+
+async fn keys_for_kid(
+    auth: &JwtAuth,
+    kid: Option<String>,
+    token: &str,
+) -> Result<Vec<DecodingKey<'static>>, AuthError> {
+    let kid = kid.or_else(|| thumbprint_kid(auth, token));
+    let kid = match (kid, &auth.missing_kid) {
+        (Some(kid), _) => Some(kid),
+        (None, MissingKidPolicy::Reject) => return Err(AuthError::MissingKid),
+        (None, MissingKidPolicy::Default(kid)) => Some(kid.clone()),
+        (None, MissingKidPolicy::TryAll) => None,
+    };
+    trace!("kid: {:?}", kid);
+
+    if auth.jwks.is_expired() {
+        warn!("keys are {}s stale", auth.jwks.staleness().as_secs());
+        return Err(AuthError::KeysExpired);
+    }
+    match kid {
+        Some(kid) => match auth.jwks.get(&kid) {
+            Some(keys) => Ok(keys),
+            None => match &auth.refresher {
+                Some(refresher) if refresher.refresh_for_kid(&kid).await => auth.jwks.get(&kid),
+                _ => None,
+            }
+            .ok_or(AuthError::UnknownKid),
+        },
+        None => Ok(auth.jwks.snapshot().into_values().flatten().collect()),
+    }
+}
+
+async fn v(
+    auth: JwtAuth,
     req: ServiceRequest,
-    credentials: BearerAuth,
+    token: Option<String>,
+    from_cookie: bool,
+    problem: Option<HeaderProblem>,
 ) -> Result<ServiceRequest, Error> {
-    let kid = decode_header(credentials.token())
-        .map_err(|_| error::ErrorBadRequest("bad token"))?
-        .kid
-        .ok_or_else(|| error::ErrorBadRequest("token missing kid"))?;
-    trace!("kid: {:?}", kid);
+    let auth = auth.tuned();
+    let policy = auth.header_policy;
+    let challenge = auth.challenge.clone();
+    let pages = auth.error_pages.clone();
+    let accept = req
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or("");
+    let format = pages.negotiate(accept);
+    let request_id = auth.request_id(&req);
+    let requested = {
+        let info = req.connection_info();
+        format!("{}://{}{}", info.scheme(), info.host(), req.uri())
+    };
+    if is_public(&req) {
+        let req = anonymous(&auth, req);
+        req.extensions_mut().insert(Public);
+        return Ok(req);
+    }
+    let result = match problem {
+        // Ambiguous even if one of them would do
+        Some(HeaderProblem::Multiple) => Err(policy.error(HeaderProblem::Multiple)),
+        _ => authenticate(auth, req, token, from_cookie).await,
+    };
+    result
+        .map_err(|error| match (error, problem) {
+            (AuthError::MissingToken, Some(problem)) => policy.error(problem),
+            (error, _) => error,
+        })
+        .map_err(|error| {
+            Rejection {
+                error,
+                challenge,
+                pages,
+                format,
+                requested,
+                request_id,
+            }
+            .into()
+        })
+}
+
+/// `req` let through without credentials
+fn anonymous(auth: &JwtAuth, mut req: ServiceRequest) -> ServiceRequest {
+    auth.set_claim_headers(&mut req, None);
+    req.extensions_mut().insert(Principal::Anonymous);
+    req
+}
+
+async fn authenticate(
+    auth: JwtAuth,
+    mut req: ServiceRequest,
+    token: Option<String>,
+    from_cookie: bool,
+) -> Result<ServiceRequest, AuthError> {
+    let (claims, hash) = match principal(&auth, &req, token.as_deref()).await {
+        Err(AuthError::MissingToken) if auth.optional => return Ok(anonymous(&auth, req)),
+        result => result?,
+    };
+    let principal = admit(&auth, &req, claims, hash, token, from_cookie)?;
+    auth.set_claim_headers(&mut req, principal.claims());
+    req.extensions_mut().insert(principal);
+    Ok(req)
+}
+
+/// The principal of `claims` found by `auth` with `token`, or else an API key, once
+/// the CSRF check of session cookies passed, adding what handlers read to the
+/// request extensions, its `Scopes` read from the configured `ScopeClaims` and
+/// holding the required ones. Shared by the middleware and the `AuthChain`, which also
+/// call `set_claim_headers`.
+pub(crate) fn admit(
+    auth: &JwtAuth,
+    req: &ServiceRequest,
+    claims: Map<String, Value>,
+    hash: TokenHash,
+    token: Option<String>,
+    from_cookie: bool,
+) -> Result<Principal, AuthError> {
+    if from_cookie && token.is_some() {
+        auth.check_csrf(req, &claims)?;
+    }
+    let scopes = auth.scope_claims.scopes(&claims);
+    scopes.require(auth.required_scopes.iter().map(String::as_str))?;
+    req.extensions_mut().insert(scopes);
+    req.extensions_mut().insert(TokenClaims(claims.clone()));
+    req.extensions_mut().insert(hash);
+    // API keys are never relayed
+    Ok(match token {
+        Some(token) => {
+            req.extensions_mut().insert(BearerToken(token));
+            Principal::Token(claims)
+        }
+        None => Principal::ApiKey(claims),
+    })
+}
+
+/// The claims `req` is authenticated with by `token` or else an API key, once they
+/// passed every check. Adds the `UserId` to the request extensions.
+pub(crate) async fn principal(
+    auth: &JwtAuth,
+    req: &ServiceRequest,
+    token: Option<&str>,
+) -> Result<(Map<String, Value>, TokenHash), AuthError> {
+    let result = checked_principal(auth, req, token).await;
+    let user_id = result
+        .as_ref()
+        .ok()
+        .and_then(|(claims, _)| UserId::from_claims(claims, &auth.user_id_claim));
+    if let Some(user_id) = &user_id {
+        req.extensions_mut().insert(user_id.clone());
+    }
+    if auth.metrics.is_none() && !auth.event_log {
+        return result;
+    }
+    let (kid, iss) = token.map(claimed_origin).unwrap_or_default();
+    if let Some(metrics) = &auth.metrics {
+        let (kid, iss) = known_origin(auth, result.is_ok(), kid.as_deref(), iss.as_deref());
+        metrics.record(result.as_ref().map(|_| ()), kid, iss);
+        if let (Ok((claims, _)), Some(_)) = (&result, token) {
+            metrics.record_expiry(claims);
+        }
+        if token.is_some() && auth.jwks.failing_for().is_some() && !auth.jwks.is_expired() {
+            metrics.record_stale_keys();
+        }
+    }
+    if auth.event_log {
+        let request_id = auth.request_id(req);
+        let client_ip = auth.client_ip(req).map(|ip| ip.to_string());
+        let hash = token.map(|token| TokenHash::new(token, &auth.token_hash_salt));
+        let error = result.as_ref().err();
+        let outcome = match error {
+            None => Outcome::Accepted,
+            Some(_) => Outcome::Rejected,
+        };
+        AuthEvent {
+            reason: error.map(AuthError::kind),
+            status: error.map(|e| e.status_code().as_u16()),
+            token: hash.as_ref().map(TokenHash::as_str),
+            request_id: request_id.as_deref(),
+            client_ip: client_ip.as_deref(),
+            kid: kid.as_deref(),
+            iss: iss.as_deref(),
+            user_id: user_id.as_ref().map(UserId::as_str),
+            ..AuthEvent::new(outcome, req.method().as_str(), req.path())
+        }
+        .emit();
+    }
+    result
+}
+
+/// The kid and issuer `token` claims, unverified
+fn claimed_origin(token: &str) -> (Option<String>, Option<String>) {
+    let kid = decode_header(token).ok().and_then(|header| header.kid);
+    let iss = dangerous_insecure_decode::<Map<String, Value>>(token)
+        .ok()
+        .and_then(|t| t.claims.get("iss")?.as_str().map(String::from));
+    (kid, iss)
+}
+
+/// The `kid` and `iss` of a token as metrics labels, `other` for ones of a rejected
+/// token neither the keys nor the validation settings know
+fn known_origin<'a>(
+    auth: &JwtAuth,
+    accepted: bool,
+    kid: Option<&'a str>,
+    iss: Option<&'a str>,
+) -> (Option<&'a str>, Option<&'a str>) {
+    if accepted {
+        return (kid, iss);
+    }
+    let kid = kid.map(|kid| if auth.jwks.contains(kid) { kid } else { OTHER });
+    let iss = iss.map(|iss| {
+        if auth.validation.iss.as_deref() == Some(iss) {
+            iss
+        } else {
+            OTHER
+        }
+    });
+    (kid, iss)
+}
+
+async fn checked_principal(
+    auth: &JwtAuth,
+    req: &ServiceRequest,
+    token: Option<&str>,
+) -> Result<(Map<String, Value>, TokenHash), AuthError> {
+    let (claims, hash) = match token {
+        Some(token) => {
+            well_formed(token, auth.max_token_len)?;
+            let hash = TokenHash::new(token, &auth.token_hash_salt);
+            let namespace = auth.cache_namespace();
+            let decoded = Decoded::of(req, &namespace, token);
+            let cached = match (&decoded, &auth.token_cache) {
+                (Some(_), _) => decoded,
+                (None, Some(cache)) => match cache.get(&namespace, token).await {
+                    Some(_) if key_withdrawn(auth, token) => {
+                        trace!("token {} cached, but its key is gone", hash);
+                        cache.invalidate(&namespace, token).await;
+                        None
+                    }
+                    claims => claims,
+                },
+                _ => None,
+            };
+            let mut claims = match cached {
+                Some(claims) => claims,
+                None => {
+                    let claims = decode_claims(auth, token, &hash).await?;
+                    if let Some(cache) = &auth.token_cache {
+                        cache.insert(&namespace, token, &claims).await;
+                    }
+                    claims
+                }
+            };
+            req.extensions_mut().insert(Decoded {
+                token: token.into(),
+                namespace,
+                claims: claims.clone(),
+            });
+            if let Some(namespace) = &auth.claims_namespace {
+                claims = strip_namespace(claims, namespace);
+            }
+            if let Some(schema) = &auth.claims_schema {
+                schema
+                    .validate(&Value::Object(claims.clone()))
+                    .map_err(AuthError::ClaimsSchema)?;
+            }
+            if let Some(groups) = &auth.groups {
+                let user = UserId::from_claims(&claims, &auth.user_id_claim);
+                groups
+                    .fill(&mut claims, user.as_ref().map(UserId::as_str))
+                    .await
+                    .map_err(|e| {
+                        warn!("group lookup failed: {}", e);
+                        report(Component::Groups, &e);
+                        AuthError::GroupsUnavailable
+                    })?;
+            }
+            (claims, hash)
+        }
+        None => {
+            let api_keys = auth.api_keys.as_ref().ok_or(AuthError::MissingToken)?;
+            let key = api_keys.presented(req).ok_or(AuthError::MissingToken)?;
+            let hash = TokenHash::new(&key, &auth.token_hash_salt);
+            let claims = api_keys
+                .authenticate(&key)
+                .await
+                .inspect_err(|_| trace!("API key {} rejected", hash))?;
+            (claims, hash)
+        }
+    };
+    if let (Some(routes), Some(_)) = (&auth.route_audiences, token) {
+        if !routes.check(&claims, req.path()) {
+            trace!("token {} not for the audience of {}", hash, req.path());
+            return Err(AuthError::WrongAudience);
+        }
+    }
+    if let Some(rule) = auth.rules.iter().find(|rule| !rule.check(&claims)) {
+        return Err(AuthError::ClaimRule(rule.claim().into()));
+    }
+    if let Some(constraint) = &auth.ip_constraint {
+        let ip = auth.client_ip(req);
+        if !constraint.check(&claims, ip) {
+            trace!("token {} used from {:?}", hash, ip);
+            return Err(AuthError::IpNotAllowed);
+        }
+    }
+    if let Some(revocations) = &auth.revocations {
+        let revoked = revocations.is_revoked(&claims).await.map_err(|e| {
+            warn!("revocation check failed: {}", e);
+            report(Component::Revocation, &e);
+            AuthError::RevocationUnavailable
+        })?;
+        if revoked {
+            trace!("token {} revoked", hash);
+            if let (Some(cache), Some(token)) = (&auth.token_cache, token) {
+                cache.invalidate(&auth.cache_namespace(), token).await;
+            }
+            return Err(AuthError::Revoked);
+        }
+    }
+    if let (Some(limit), Some(_)) = (&auth.session_limit, token) {
+        let admitted = limit.check(&claims, hash.as_str()).await.map_err(|e| {
+            warn!("session check failed: {}", e);
+            report(Component::Sessions, &e);
+            AuthError::SessionsUnavailable
+        })?;
+        if !admitted {
+            trace!("token {} beyond the session limit", hash);
+            return Err(AuthError::TooManySessions);
+        }
+    }
+    if let Some(policy) = &auth.policy {
+        let input = PolicyInput {
+            claims: &claims,
+            method: req.method().as_str(),
+            path: req.path(),
+        };
+        let allowed = policy.evaluate(&input).await.map_err(|e| {
+            warn!("policy evaluation failed: {}", e);
+            report(Component::Policy, &e);
+            AuthError::PolicyUnavailable
+        })?;
+        if !allowed {
+            return Err(AuthError::PolicyDenied);
+        }
+    }
+    if let Some(rate_limit) = &auth.rate_limit {
+        let (allowed, counter) = rate_limit.check(&claims).await.map_err(|e| {
+            warn!("rate limit check failed: {}", e);
+            report(Component::RateLimit, &e);
+            AuthError::RateLimitUnavailable
+        })?;
+        if !allowed {
+            return Err(AuthError::RateLimited {
+                limit: rate_limit.limit(),
+                reset_in: counter.reset_in,
+            });
+        }
+    }
+    Ok((claims, hash))
+}
+
+/// The claims a token was verified to carry earlier in the same request, kept in the
+/// request extensions so that nested middlewares, e.g. a scope and a resource with
+/// extra rules, decode and verify each token once. Reused only by middlewares
+/// validating like the one that decoded it, under the same `cache_namespace`.
+struct Decoded {
+    token: String,
+    namespace: String,
+    claims: Map<String, Value>,
+}
+
+impl Decoded {
+    fn of(req: &ServiceRequest, namespace: &str, token: &str) -> Option<Map<String, Value>> {
+        let extensions = req.extensions();
+        let decoded = extensions.get::<Decoded>()?;
+        let same = decoded.token == token && decoded.namespace == namespace;
+        Some(decoded.claims.clone()).filter(|_| same)
+    }
+}
+
+/// Whether the key `token` was verified with left the `JwksStore` since, removed or
+/// revoked. Keys of the `KeyResolver`, a `RemoteKey` or tried all at once are not told
+/// apart, so those entries stay until their TTL.
+fn key_withdrawn(auth: &JwtAuth, token: &str) -> bool {
+    let header = match decode_header(token) {
+        Ok(header) => header,
+        Err(_) => return true,
+    };
+    let remote = auth.remote_key.as_deref();
+    if auth.resolver.is_some() || remote.is_some_and(|key| key.alg() == header.alg) {
+        return false;
+    }
+    let kid = match (
+        header.kid.or_else(|| thumbprint_kid(auth, token)),
+        &auth.missing_kid,
+    ) {
+        (Some(kid), _) => kid,
+        (None, MissingKidPolicy::Default(kid)) => kid.clone(),
+        (None, _) => return false,
+    };
+    auth.jwks.get(&kid).is_none()
+}
+
+/// Decode and verify `token`, up to the registered claims
+async fn decode_claims(
+    auth: &JwtAuth,
+    token: &str,
+    hash: &TokenHash,
+) -> Result<Map<String, Value>, AuthError> {
+    let header = decode_header(token).map_err(|_| AuthError::BadToken)?;
+    trace!("header: {:?}", header);
+    #[cfg(feature = "dangerous-dev-mode")]
+    if let Some((key, alg)) = auth.dev_mode.as_ref().and_then(|mode| mode.local_key()) {
+        if header.alg == *alg {
+            // Self-signed, so from any issuer
+            let validation = jsonwebtoken::Validation {
+                algorithms: vec![*alg],
+                iss: None,
+                ..auth.validation.clone()
+            };
+            if let Ok(t) = decode::<Map<String, Value>>(token, key, &validation) {
+                warn!("token {} accepted with the local dev key", hash);
+                return Ok(t.claims);
+            }
+        }
+    }
+    if auth.require_exp {
+        // Checked before the signature only to tell the reason
+        let payload = dangerous_insecure_decode::<Map<String, Value>>(token)
+            .map_err(|_| AuthError::BadToken)?;
+        if !payload.claims.contains_key("exp") {
+            trace!("token {} has no exp", hash);
+            return Err(AuthError::MissingExp);
+        }
+    }
+    // The remote key verifies its own algorithm, besides those of the validation
+    let remote = auth
+        .remote_key
+        .as_deref()
+        .filter(|key| key.alg() == header.alg);
+    if remote.is_none() && !auth.validation.algorithms.contains(&header.alg) {
+        // Refuse e.g. HS256 tokens signed with a public key as secret before any key
+        // lookup; `verify_locally` also checks the family of the key
+        if is_hmac(header.alg) {
+            warn!(
+                "token {} claims {:?}, HMAC is not accepted",
+                hash, header.alg
+            );
+        }
+        return Err(AuthError::AlgorithmMismatch);
+    }
+    let t = match remote {
+        Some(key) => verify_remotely(auth, key, token).await,
+        None => verify_locally(auth, &header, token).await,
+    };
+    let claims = match t {
+        Ok(claims) => claims,
+        Err(e) => {
+            trace!("token {} rejected", hash);
+            return Err(e);
+        }
+    };
+    if let Some(access_tokens) = &auth.access_tokens {
+        if !access_tokens.check(&header, &claims) {
+            trace!("token {} is not an access token", hash);
+            return Err(AuthError::NotAccessToken);
+        }
+    }
+    check_iat(auth, &claims).inspect_err(|_| trace!("token {} has an invalid iat", hash))?;
+    #[cfg(feature = "log-claims")]
+    trace!("claims: {:?}", claims);
+    #[cfg(not(feature = "log-claims"))]
+    trace!(
+        "token {} claims: {}",
+        hash,
+        crate::redact::ClaimNames(&claims)
+    );
+    Ok(claims)
+}
+
+/// Verify the signature of `token` with `key`, then its registered claims on a copy
+/// of the payload signed with a throwaway secret, since the key itself stays remote
+async fn verify_remotely(
+    auth: &JwtAuth,
+    key: &dyn RemoteKey,
+    token: &str,
+) -> Result<Map<String, Value>, AuthError> {
+    let (message, signature) = token.rsplit_once('.').ok_or(AuthError::BadToken)?;
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| AuthError::BadToken)?;
+    match key.verify(message.as_bytes(), &signature).await {
+        Ok(true) => {}
+        Ok(false) => return Err(AuthError::InvalidToken),
+        Err(e) => {
+            warn!("remote verification failed: {:#}", e);
+            report(Component::Jwks, &e);
+            return Err(AuthError::KeysExpired);
+        }
+    }
+    let payload = message.split('.').nth(1).ok_or(AuthError::BadToken)?;
+    let message = format!(
+        "{}.{}",
+        base64::encode_config(br#"{"alg":"HS256"}"#, base64::URL_SAFE_NO_PAD),
+        payload
+    );
+    let secret: [u8; 32] = rand::random();
+    let copy = jsonwebtoken::crypto::sign(
+        &message,
+        &EncodingKey::from_secret(&secret),
+        Algorithm::HS256,
+    )
+    .map(|signature| format!("{}.{}", message, signature))
+    .map_err(|_| AuthError::BadToken)?;
+    let validation = jsonwebtoken::Validation {
+        algorithms: vec![Algorithm::HS256],
+        ..auth.validation.clone()
+    };
+    decode::<Map<String, Value>>(&copy, &DecodingKey::from_secret(&secret), &validation)
+        .map(|t| t.claims)
+        .map_err(|_| AuthError::InvalidToken)
+}
+
+/// Decode `token` with the keys of its `kid`, or those of the `KeyResolver`
+async fn verify_locally(
+    auth: &JwtAuth,
+    header: &Header,
+    token: &str,
+) -> Result<Map<String, Value>, AuthError> {
+    let keys = match &auth.resolver {
+        Some(resolver) => {
+            let hint = dangerous_insecure_decode::<Map<String, Value>>(token)
+                .map(|t| t.claims)
+                .unwrap_or_default();
+            resolver
+                .resolve(header, &hint)
+                .ok_or(AuthError::UnknownKid)?
+        }
+        None => keys_for_kid(auth, header.kid.clone(), token).await?,
+    };
+    // Debug output of decoding keys would include key material
+    trace!("{} candidate keys", keys.len());
+
+    // Only the algorithm of the header, so decoding refuses keys of another family:
+    // an RSA or EC key never verifies an HS256 token signed with its public bytes
+    let validation = jsonwebtoken::Validation {
+        algorithms: vec![header.alg],
+        ..auth.validation.clone()
+    };
+    // Several keys share the kid while the issuer rolls them over
+    let mut t = Err(AuthError::InvalidToken);
+    for key in &keys {
+        t = decode::<Map<String, Value>>(token, key, &validation)
+            .map(|t| t.claims)
+            .map_err(|e| match e.kind() {
+                jsonwebtoken::errors::ErrorKind::InvalidAlgorithm => AuthError::AlgorithmMismatch,
+                _ => AuthError::InvalidToken,
+            });
+        if t.is_ok() {
+            break;
+        }
+    }
+    if let (Err(AuthError::AlgorithmMismatch), true) = (&t, is_hmac(header.alg)) {
+        warn!("{:?} token for a key of another family", header.alg);
+    }
+    t
+}
+
+/// Tells access tokens from ID tokens
+#[derive(Clone, Copy, Debug)]
+struct AccessTokens {
+    strict_typ: bool,
+}
+
+impl AccessTokens {
+    fn check(&self, header: &Header, claims: &Map<String, Value>) -> bool {
+        let typ = header.typ.as_deref().map(str::to_ascii_lowercase);
+        let typ_ok = match typ.as_deref() {
+            Some("at+jwt") | Some("application/at+jwt") => true,
+            None | Some("jwt") => !self.strict_typ,
+            Some(_) => false,
+        };
+        let token_use = claims.get("token_use").and_then(Value::as_str);
+        typ_ok && !claims.contains_key("nonce") && token_use.is_none_or(|t| t == "access")
+    }
+}
+
+fn check_iat(auth: &JwtAuth, claims: &Map<String, Value>) -> Result<(), AuthError> {
+    if !auth.require_iat && !auth.reject_future_iat {
+        return Ok(());
+    }
+    let iat = match claims.get("iat") {
+        Some(iat) => iat.as_u64().ok_or(AuthError::InvalidIat)?,
+        None if auth.require_iat => return Err(AuthError::InvalidIat),
+        None => return Ok(()),
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if auth.reject_future_iat && iat > now + auth.validation.leeway {
+        return Err(AuthError::InvalidIat);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub exp: usize,
+    pub nbf: usize,
+    pub iss: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{RemoteSecret, TestKey};
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::web::Bytes;
+    use actix_web::{test, web, App, HttpRequest};
+    use jsonwebtoken::{encode, EncodingKey, Header, Validation};
+    use std::time::SystemTime;
+
+    lazy_static! {
+        static ref KEY: TestKey = TestKey::rsa(2048).unwrap();
+    }
+
+    fn jwks(kid: &str) -> Keys {
+        let mut jwks = Keys::new();
+        jwks.insert(kid.into(), vec![KEY.decoding_key()]);
+        jwks
+    }
+
+    fn exp() -> usize {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as usize
+            + 3600
+    }
+
+    fn token<T: Serialize>(kid: &str, claims: &T) -> String {
+        let mut h = Header::new(Algorithm::RS256);
+        h.kid = Some(kid.into());
+        encode(&h, claims, &KEY.encoding_key).unwrap()
+    }
+
+    fn request<T: Serialize>(kid: &str, claims: &T) -> test::TestRequest {
+        test::TestRequest::get()
+            .header("Authorization", format!("Bearer {}", token(kid, claims)))
+            .uri("/")
+    }
+
+    async fn error_response(err: Error) -> (StatusCode, Bytes) {
+        let mut resp = err.as_response_error().error_response();
+        let body = test::load_stream(resp.take_body()).await.unwrap();
+        (resp.status(), body)
+    }
+
+    #[actix_rt::test]
+    async fn test_no_auth() {
+        let mut app =
+            test::init_service(App::new().route("/", web::get().to(|| async { "" }))).await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_auth() {
+        let kid = "0";
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(validator(
+                    Validation::new(Algorithm::RS256),
+                    jwks(kid),
+                )))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let req = request(kid, &claims).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_user_id_claim() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0")).user_id_claim("oid");
+        let mut app = test::init_service(App::new().wrap(auth.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                let user_id = req.extensions().get::<UserId>().cloned();
+                async move { format!("{:?}", user_id.map(|id| id.0)) }
+            }),
+        ))
+        .await;
+        let claims = serde_json::json!({"sub": "pairwise", "oid": "o-1", "exp": exp()});
+        let resp = test::call_service(&mut app, request("0", &claims).to_request()).await;
+        assert_eq!(test::read_body(resp).await, "Some(\"o-1\")");
+
+        let claims = serde_json::json!({"sub": "pairwise", "exp": exp()});
+        let resp = test::call_service(&mut app, request("0", &claims).to_request()).await;
+        assert_eq!(test::read_body(resp).await, "None");
+    }
 
-    let key = jwks
-        .get(&kid)
-        .ok_or_else(|| error::ErrorBadRequest("invalid kid in token"))?;
-    trace!("key: {:?}", key);
+    #[actix_rt::test]
+    async fn test_claims_namespace() {
+        let kid = "0";
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+                        .claims_namespace("https://myapp.example.com/")
+                        .validator(),
+                ))
+                .route(
+                    "/",
+                    web::get().to(|req: HttpRequest| async move {
+                        let ext = req.extensions();
+                        assert!(ext.get::<TokenHash>().is_some());
+                        let claims = ext.get::<TokenClaims>().unwrap();
+                        claims.get("tenant").unwrap().as_str().unwrap().to_string()
+                    }),
+                ),
+        )
+        .await;
 
-    let t = decode::<Claims>(credentials.token(), key, &validation);
-    trace!("claims: {:?}", t);
-    t.map_err(|_| error::ErrorUnauthorized("invalid token"))?;
-    Ok(req)
-}
+        let claims = serde_json::json!({
+            "exp": exp(),
+            "https://myapp.example.com/tenant": "acme",
+        });
+        let req = request(kid, &claims).to_request();
+        let resp = test::read_response(&mut app, req).await;
+        assert_eq!(resp, "acme");
+    }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-    pub exp: usize,
-    pub nbf: usize,
-    pub iss: String,
-}
+    #[actix_rt::test]
+    async fn test_claims_schema() {
+        let kid = "0";
+        let schema = ClaimsSchema::new(&serde_json::json!({
+            "required": ["tenant"],
+            "properties": { "tenant": { "enum": ["acme"] } }
+        }))
+        .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+                        .claims_schema(schema)
+                        .validator(),
+                ))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{test, web, App};
-    use actix_web_httpauth::middleware::HttpAuthentication;
-    use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-    use openssl::rsa::Rsa;
-    use std::time::SystemTime;
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "initech" });
+        let req = request(kid, &claims).to_request();
+        let (status, body) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["reasons"][0]["path"], "/tenant");
+
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "acme" });
+        let req = request(kid, &claims).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+    }
 
     #[actix_rt::test]
-    async fn test_no_auth() {
-        let mut app =
-            test::init_service(App::new().route("/", web::get().to(|| async { "" }))).await;
+    async fn test_claim_rules() {
+        let kid = "0";
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+            .require(ClaimRule::equals("tenant", "acme"));
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/eng")
+                        .wrap(HttpAuthentication::bearer(
+                            auth.clone()
+                                .require(ClaimRule::contains("groups", "eng"))
+                                .validator(),
+                        ))
+                        .route("", web::get().to(|| async { "" })),
+                )
+                .service(
+                    web::scope("")
+                        .wrap(HttpAuthentication::bearer(auth.validator()))
+                        .route("/", web::get().to(|| async { "" })),
+                ),
+        )
+        .await;
 
-        let req = test::TestRequest::get().uri("/").to_request();
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "acme", "groups": ["ops"] });
+        let req = request(kid, &claims).to_request();
         let resp = test::call_service(&mut app, req).await;
         assert!(resp.status().is_success());
+
+        let req = request(kid, &claims).uri("/eng").to_request();
+        let (status, _) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "globex" });
+        let req = request(kid, &claims).to_request();
+        let (status, body) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body, "claim requirement not met: tenant");
     }
 
     #[actix_rt::test]
-    async fn test_auth() {
-        lazy_static! {
-            static ref RSA: Rsa<openssl::pkey::Private> = Rsa::generate(2048).unwrap();
-            static ref PRIVATE_KEY: Vec<u8> = RSA.private_key_to_pem().unwrap();
-            static ref PUBLIC_KEY: Vec<u8> = RSA.public_key_to_pem().unwrap();
+    async fn test_policy() {
+        struct ReadOnly;
+        impl PolicyEvaluator for ReadOnly {
+            fn evaluate<'a>(
+                &'a self,
+                input: &'a PolicyInput<'a>,
+            ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>> {
+                Box::pin(async move { Ok(input.method == "GET") })
+            }
         }
 
-        let mut jwks = std::collections::HashMap::new();
         let kid = "0";
-        jwks.insert(kid.into(), DecodingKey::from_rsa_pem(&PUBLIC_KEY).unwrap());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+                        .policy(ReadOnly)
+                        .validator(),
+                ))
+                .route("/", web::get().to(|| async { "" }))
+                .route("/", web::post().to(|| async { "" })),
+        )
+        .await;
+
+        let claims = serde_json::json!({ "exp": exp() });
+        let req = request(kid, &claims).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = request(kid, &claims)
+            .method(actix_web::http::Method::POST)
+            .to_request();
+        let (status, _) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let kid = "0";
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+                        .rate_limit(RateLimit::new("sub", 1, std::time::Duration::from_secs(60)))
+                        .validator(),
+                ))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let claims = serde_json::json!({ "exp": exp(), "sub": "alice" });
+        let req = request(kid, &claims).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = request(kid, &claims).to_request();
+        let resp = app
+            .call(req)
+            .await
+            .unwrap_err()
+            .as_response_error()
+            .error_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("X-RateLimit-Limit").unwrap(), "1");
+        assert!(resp.headers().contains_key("Retry-After"));
+    }
+
+    #[actix_rt::test]
+    async fn test_ip_constraint() {
+        let kid = "0";
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid))
+                        .ip_constraint(IpConstraint::new())
+                        .trusted_proxies(TrustedProxies::new(vec!["10.0.0.1/32".parse().unwrap()]))
+                        .validator(),
+                ))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let claims = serde_json::json!({ "exp": exp(), "cnf": {"ip": "203.0.113.0/24"} });
+        let behind_proxy = |forwarded_for| {
+            request(kid, &claims)
+                .peer_addr("10.0.0.1:443".parse().unwrap())
+                .header("x-forwarded-for", forwarded_for)
+                .to_request()
+        };
+        let resp = test::call_service(&mut app, behind_proxy("203.0.113.9")).await;
+        assert!(resp.status().is_success());
+
+        let err = app.call(behind_proxy("198.51.100.9")).await.unwrap_err();
+        let resp = err.as_response_error().error_response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn test_exp_and_iat() {
+        let kid = "0";
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks(kid));
+        let now = exp() - 3600;
+        let verify = |auth: &JwtAuth, claims: Value| {
+            let token = token(kid, &claims);
+            let auth = auth.clone();
+            async move { auth.verify(&token).await }
+        };
+
+        let future = serde_json::json!({ "exp": exp(), "iat": now + 600 });
+        assert!(verify(&auth, serde_json::json!({ "exp": exp() }))
+            .await
+            .is_ok());
+        assert!(verify(&auth, future.clone()).await.is_ok());
+
+        // Without exp, rejected for that reason rather than as invalid
+        let no_exp = serde_json::json!({ "sub": "alice", "iat": now });
+        assert!(matches!(
+            verify(&auth, no_exp.clone()).await,
+            Err(AuthError::MissingExp)
+        ));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.clone().validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let req = request(kid, &no_exp).to_request();
+        let (status, body) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, AuthError::MissingExp.status_code());
+        assert_eq!(body, AuthError::MissingExp.to_string());
+
+        let lenient = JwtAuth::new(
+            Validation {
+                validate_exp: false,
+                ..Validation::new(Algorithm::RS256)
+            },
+            jwks(kid),
+        );
+        assert!(matches!(
+            verify(&lenient, serde_json::json!({ "iat": now })).await,
+            Err(AuthError::MissingExp)
+        ));
+        let lenient = lenient.allow_missing_exp();
+        assert!(verify(&lenient, serde_json::json!({ "iat": now }))
+            .await
+            .is_ok());
+        assert!(verify(&lenient, no_exp).await.is_ok());
+        // Allowing a missing exp does not accept expired tokens
+        let expired = serde_json::json!({ "exp": now - 600 });
+        let checked = auth.clone().allow_missing_exp();
+        assert!(verify(&checked, expired).await.is_err());
+
+        let id_tokens = [
+            serde_json::json!({ "exp": exp(), "nonce": "n" }),
+            serde_json::json!({ "exp": exp(), "token_use": "id" }),
+        ];
+        let access_only = auth.clone().access_tokens_only();
+        for claims in &id_tokens {
+            assert!(verify(&auth, claims.clone()).await.is_ok());
+            assert!(matches!(
+                verify(&access_only, claims.clone()).await,
+                Err(AuthError::NotAccessToken)
+            ));
+        }
+        let access = serde_json::json!({ "exp": exp(), "token_use": "access" });
+        assert!(verify(&access_only, access.clone()).await.is_ok());
+        let strict = access_only.rfc9068_access_tokens_only();
+        assert!(matches!(
+            verify(&strict, access.clone()).await,
+            Err(AuthError::NotAccessToken)
+        ));
+        let mut h = Header::new(Algorithm::RS256);
+        h.kid = Some(kid.into());
+        h.typ = Some("at+jwt".into());
+        let at_jwt = encode(&h, &access, &KEY.encoding_key).unwrap();
+        assert!(strict.verify(&at_jwt).await.is_ok());
+
+        let auth = auth.require_iat().reject_future_iat();
+        assert!(matches!(
+            verify(&auth, serde_json::json!({ "exp": exp() })).await,
+            Err(AuthError::InvalidIat)
+        ));
+        assert!(matches!(
+            verify(&auth, future).await,
+            Err(AuthError::InvalidIat)
+        ));
+        let issued = serde_json::json!({ "exp": exp(), "iat": now });
+        assert!(verify(&auth, issued).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_single_decode() {
+        use crate::claims::Claims as Extracted;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Deserialize)]
+        struct Tenant {
+            tenant: String,
+        }
+
+        let decodes = Arc::new(AtomicUsize::new(0));
+        let counter = decodes.clone();
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), Keys::new()).key_resolver(
+            move |_: &Header, _: &Map<String, Value>| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Some(vec![KEY.decoding_key()])
+            },
+        );
+        let strict = auth.clone().require(ClaimRule::equals("tenant", "acme"));
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("/").wrap(auth.middleware()).service(
+                    web::resource("")
+                        .wrap(strict.middleware())
+                        .to(|tenant: Extracted<Tenant>| async move { tenant.0.tenant }),
+                ),
+            ),
+        )
+        .await;
+
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "acme" });
+        let req = request("any", &claims).to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "acme");
+        assert_eq!(decodes.load(Ordering::SeqCst), 1);
+
+        // The inner middleware still applies its own rules
+        let claims = serde_json::json!({ "exp": exp(), "tenant": "globex" });
+        let req = request("any", &claims).to_request();
+        assert!(app.call(req).await.is_err());
+        assert_eq!(decodes.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_nested_stricter() {
+        let keys = JwksStore::new(jwks("0"));
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), keys);
+        let strict = auth.clone().access_tokens_only().require_iat();
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("/").wrap(auth.middleware()).service(
+                    web::resource("")
+                        .wrap(strict.middleware())
+                        .to(|| async { "" }),
+                ),
+            ),
+        )
+        .await;
+
+        // Verified by the outer middleware, then checked anew by the stricter inner one
+        for claims in [
+            serde_json::json!({ "exp": exp(), "iat": 1, "nonce": "n" }),
+            serde_json::json!({ "exp": exp() }),
+        ] {
+            let err = app.call(request("0", &claims).to_request()).await;
+            assert!(err.is_err());
+        }
+        let claims = serde_json::json!({ "exp": exp(), "iat": 1 });
+        assert!(app.call(request("0", &claims).to_request()).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_token_cache() {
+        let cache = TokenCache::new(std::time::Duration::from_secs(300));
+        let a = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"))
+            .audience("a")
+            .token_cache(cache.clone());
+        let b = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"))
+            .audience("b")
+            .token_cache(cache);
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/a")
+                        .wrap(HttpAuthentication::bearer(a.clone().validator()))
+                        .route("", web::get().to(|| async { "" })),
+                )
+                .service(
+                    web::scope("/b")
+                        .wrap(HttpAuthentication::bearer(b.validator()))
+                        .route("", web::get().to(|| async { "" })),
+                ),
+        )
+        .await;
+        let claims = serde_json::json!({"sub": "alice", "aud": "a", "exp": exp()});
+        let token = token("0", &claims);
+        let get = |path: &str| {
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .uri(path)
+                .to_request()
+        };
 
+        assert!(app.call(get("/a")).await.is_ok());
+        // Cached for /a, yet not for the audience of /b
+        assert!(app.call(get("/b")).await.is_err());
+        assert!(app.call(get("/a")).await.is_ok());
+
+        a.key_store().revoke("0");
+        let (status, body) = error_response(app.call(get("/a")).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, AuthError::UnknownKid.to_string());
+    }
+
+    #[actix_rt::test]
+    async fn test_duplicate_kid() {
+        let kid = "0";
+        let mut keys = jwks(kid);
+        let other = TestKey::rsa(2048).unwrap();
+        keys.get_mut(kid).unwrap().insert(0, other.decoding_key());
         let mut app = test::init_service(
             App::new()
                 .wrap(HttpAuthentication::bearer(validator(
                     Validation::new(Algorithm::RS256),
-                    jwks,
+                    keys,
                 )))
                 .route("/", web::get().to(|| async { "" })),
         )
         .await;
 
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let req = request(kid, &claims).to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_rt::test]
+    async fn test_missing_kid() {
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let token = encode(&Header::new(Algorithm::RS256), &claims, &KEY.encoding_key).unwrap();
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"));
+
+        for (policy, ok) in [
+            (MissingKidPolicy::Reject, false),
+            (MissingKidPolicy::Default("0".into()), true),
+            (MissingKidPolicy::Default("1".into()), false),
+            (MissingKidPolicy::TryAll, true),
+        ] {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(HttpAuthentication::bearer(
+                        auth.clone().missing_kid(policy).validator(),
+                    ))
+                    .route("/", web::get().to(|| async { "" })),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .uri("/")
+                .to_request();
+            assert_eq!(app.call(req).await.is_ok(), ok);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_key_resolver() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), Keys::new()).key_resolver(
+            |_: &Header, claims: &Map<String, Value>| match claims.get("iss")?.as_str()? {
+                "known" => Some(vec![KEY.decoding_key()]),
+                _ => None,
+            },
+        );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        for (iss, ok) in [("known", true), ("other", false)] {
+            let claims = Claims {
+                exp: exp(),
+                nbf: 0,
+                iss: iss.into(),
+            };
+            let req = request("any", &claims).to_request();
+            assert_eq!(app.call(req).await.is_ok(), ok);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_remote_key() {
+        let remote = RemoteSecret::new(b"kept in a key management service");
+        // RS256 verifies locally, HS256 remotely
+        let auth =
+            JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0")).remote_key(remote.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let claims = |exp| Claims {
+            exp,
+            nbf: 0,
+            iss: "any".into(),
+        };
+        for (secret, exp, ok) in [
+            (&b"kept in a key management service"[..], exp(), true),
+            (&b"guessed"[..], exp(), false),
+            (&b"kept in a key management service"[..], 1, false),
+        ] {
+            let token = encode(
+                &Header::new(Algorithm::HS256),
+                &claims(exp),
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap();
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request();
+            assert_eq!(app.call(req).await.is_ok(), ok);
+        }
+        assert_eq!(*remote.verified.lock().unwrap(), 3);
+        let req = request("0", &claims(exp())).to_request();
+        assert!(app.call(req).await.is_ok());
+        assert_eq!(*remote.verified.lock().unwrap(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_live_config() {
+        use crate::config::Config;
+        use serde_json::json;
+
+        let live = ConfigHandle::new(Config {
+            authserver: "https://idp".into(),
+            ..Config::default()
+        });
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), Keys::new())
+            .key_resolver(|_: &Header, _: &Map<String, Value>| {
+                Some(vec![DecodingKey::from_secret(b"secret").into_static()])
+            })
+            .live_config(live.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.clone().validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let token = |claims: Value| {
+            encode(
+                &Header::new(Algorithm::HS256),
+                &claims,
+                &EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap()
+        };
+
+        // Expired half a minute ago
+        let expired = token(json!({"exp": exp() - 3630}));
+        assert!(auth.verify(&expired).await.is_err());
+        live.store(Config {
+            leeway_secs: Some(60),
+            ..(*live.get()).clone()
+        })
+        .unwrap();
+        assert!(auth.verify(&expired).await.is_ok());
+
+        live.store(Config {
+            required_claims: Some(vec!["tenant".into()]),
+            required_scopes: Some(vec!["orders:read".into()]),
+            ..(*live.get()).clone()
+        })
+        .unwrap();
+        for (claims, ok) in [
+            (
+                json!({"exp": exp(), "tenant": "t", "scope": "orders:read"}),
+                true,
+            ),
+            (json!({"exp": exp(), "scope": "orders:read"}), false),
+            (
+                json!({"exp": exp(), "tenant": "t", "scope": "orders:write"}),
+                false,
+            ),
+        ] {
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token(claims)))
+                .to_request();
+            assert_eq!(app.call(req).await.is_ok(), ok);
+        }
+
+        // Invalid configurations are refused, the last valid one staying
+        assert!(live
+            .store(Config {
+                leeway_secs: Some(3600),
+                ..(*live.get()).clone()
+            })
+            .is_err());
+        assert_eq!(live.get().leeway_secs, Some(60));
+
+        // Settings the configuration leaves unset keep those of the builder
+        let unset = ConfigHandle::new(Config {
+            authserver: "https://idp".into(),
+            required_scopes: Some(vec!["orders:read".into()]),
+            ..Config::default()
+        });
+        let validation = Validation {
+            leeway: 60,
+            ..Validation::new(Algorithm::HS256)
+        };
+        let auth = JwtAuth::new(validation, Keys::new())
+            .key_resolver(|_: &Header, _: &Map<String, Value>| {
+                Some(vec![DecodingKey::from_secret(b"secret").into_static()])
+            })
+            .require_scope("orders:write")
+            .live_config(unset);
+        assert!(auth.verify(&expired).await.is_ok());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        for (scope, ok) in [
+            ("orders:read orders:write", true),
+            ("orders:read", false),
+            ("orders:write", false),
+        ] {
+            let claims = json!({"exp": exp(), "scope": scope});
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token(claims)))
+                .to_request();
+            assert_eq!(app.call(req).await.is_ok(), ok);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_thumbprint() {
         let mut h = Header::new(Algorithm::RS256);
-        h.kid = Some(kid.into());
-        let req = test::TestRequest::get()
-            .header(
-                "Authorization",
-                "Bearer ".to_string()
-                    + &encode(
-                        &h,
-                        &Claims {
-                            exp: SystemTime::now()
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs() as usize
-                                + 3600,
-                            nbf: 0,
-                            iss: "".into(),
-                        },
-                        &EncodingKey::from_rsa_pem(&PRIVATE_KEY).unwrap(),
-                    )
-                    .unwrap(),
+        h.x5t = Some("sha1".into());
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let token = encode(&h, &claims, &KEY.encoding_key).unwrap();
+
+        let store = JwksStore::new(jwks("0"));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(
+                    JwtAuth::new(Validation::new(Algorithm::RS256), store.clone()).validator(),
+                ))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let req = || {
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .uri("/")
+                .to_request()
+        };
+        assert!(app.call(req()).await.is_err());
+
+        store.replace_thumbprints(
+            vec![("sha1".to_string(), "0".to_string())]
+                .into_iter()
+                .collect(),
+        );
+        assert!(app.call(req()).await.is_ok());
+    }
+
+    #[test]
+    fn test_well_formed() {
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let token = token("0", &claims);
+        assert!(well_formed(&token, DEFAULT_MAX_TOKEN_LEN).is_ok());
+        assert!(matches!(
+            well_formed(&token, 16),
+            Err(AuthError::TokenTooLarge)
+        ));
+        for bad in [
+            "a.b",
+            "a.b.c.d",
+            "a..c",
+            "a.b+.c",
+            &format!("{}.b.c", "a".repeat(2000)),
+        ] {
+            assert!(matches!(
+                well_formed(bad, DEFAULT_MAX_TOKEN_LEN),
+                Err(AuthError::BadToken)
+            ));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_hmac_downgrade() {
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        // The public key is public, so an attacker can sign with the bytes of its PEM
+        // file as HMAC secret, which is what a key read from one holds
+        let mut h = Header::new(Algorithm::HS256);
+        h.kid = Some("0".into());
+        let secret = EncodingKey::from_secret(&KEY.public_der);
+        let forged = encode(&h, &claims, &secret).unwrap();
+        let mut keys = Keys::new();
+        let key = DecodingKey::from_rsa_der(&KEY.public_der).into_static();
+        keys.insert("0".into(), vec![key]);
+
+        for algorithms in [
+            vec![Algorithm::RS256],
+            vec![Algorithm::RS256, Algorithm::HS256],
+        ] {
+            let validation = Validation {
+                algorithms,
+                ..Validation::default()
+            };
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(HttpAuthentication::bearer(validator(
+                        validation,
+                        keys.clone(),
+                    )))
+                    .route("/", web::get().to(|| async { "" })),
             )
-            .uri("/")
+            .await;
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", forged))
+                .uri("/")
+                .to_request();
+            let (status, body) = error_response(app.call(req).await.unwrap_err()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(body, "token algorithm not accepted");
+            // Tokens of the key's own family still verify
+            let req = request("0", &claims).to_request();
+            assert!(app.call(req).await.is_ok());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_error_pages() {
+        let auth =
+            JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0")).error_pages(ErrorPages {
+                html: Some("<h1>{error}</h1>".into()),
+                ..ErrorPages::default()
+            });
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let claims = Claims {
+            exp: 0,
+            nbf: 0,
+            iss: "".into(),
+        };
+
+        for (accept, body) in [
+            ("application/json", r#"{"error":"invalid token"}"#),
+            ("text/html", "<h1>invalid token</h1>"),
+            ("*/*", "invalid token"),
+        ] {
+            let req = request("0", &claims).header("Accept", accept).to_request();
+            let (status, resp) = error_response(app.call(req).await.unwrap_err()).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED);
+            assert_eq!(resp, body);
+        }
+
+        for (accept, body) in [
+            (
+                "application/json",
+                r#"{"error":"invalid token","request_id":"r-1"}"#,
+            ),
+            ("*/*", "invalid token (request id r-1)"),
+        ] {
+            let req = request("0", &claims)
+                .header("Accept", accept)
+                .header("X-Request-Id", "r-1")
+                .to_request();
+            let (_, resp) = error_response(app.call(req).await.unwrap_err()).await;
+            assert_eq!(resp, body);
+        }
+        // Never echoed when it could forge log lines
+        let req = request("0", &claims)
+            .header("X-Request-Id", "r 1")
+            .to_request();
+        let (_, resp) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(resp, "invalid token");
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"))
+            .request_id_header(HeaderName::from_static("x-correlation-id"))
+            .error_pages(ErrorPages {
+                html: Some("<p>{error} ({request_id})</p>".into()),
+                ..ErrorPages::default()
+            });
+        let id = |name: &str, value: &str| {
+            let req = test::TestRequest::default()
+                .header(name, value)
+                .to_srv_request();
+            auth.request_id(&req)
+        };
+        assert_eq!(id("X-Correlation-Id", "c-7").as_deref(), Some("c-7"));
+        // The default header no longer counts
+        assert_eq!(id("X-Request-Id", "r-1"), None);
+        assert_eq!(id("X-Correlation-Id", ""), None);
+        assert_eq!(id("X-Correlation-Id", &"a".repeat(129)), None);
+        assert!(id("X-Correlation-Id", &"a".repeat(128)).is_some());
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let claims = Claims {
+            exp: 0,
+            nbf: 0,
+            iss: "".into(),
+        };
+        let req = request("0", &claims)
+            .header("Accept", "text/html")
+            .header("X-Correlation-Id", "c-7")
+            .to_request();
+        let (status, resp) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(resp, "<p>invalid token (c-7)</p>");
+    }
+
+    #[actix_rt::test]
+    async fn test_login_redirect() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"))
+            .error_pages(ErrorPages::default().login_redirect("/login"));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.middleware())
+                .route("/page", web::get().to(|| async { "" })),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/page")
+            .header("Accept", "text/html")
             .to_request();
+        let resp = app
+            .call(req)
+            .await
+            .unwrap_err()
+            .as_response_error()
+            .error_response();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(
+            resp.headers().get("Location").unwrap(),
+            "/login?redirect_uri=http%3A%2F%2Flocalhost%3A8080%2Fpage"
+        );
+
+        let req = test::TestRequest::get().uri("/page").to_request();
+        let (status, body) = error_response(app.call(req).await.unwrap_err()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body, "missing token");
+
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "".into(),
+        };
+        let req = request("0", &claims).uri("/page").to_request();
+        assert!(app.call(req).await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_header_policy() {
+        use actix_web::http::HeaderValue;
+
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"));
+        let strict = auth.clone().header_policy(
+            HeaderPolicy::default()
+                .bad_request(HeaderProblem::Absent)
+                .bad_request(HeaderProblem::WrongScheme)
+                .unauthorized(HeaderProblem::Multiple),
+        );
+        let claims = serde_json::json!({ "exp": exp() });
+        let bearer = format!("Bearer {}", token("0", &claims));
+        let not_utf8 = HeaderValue::from_bytes(b"Bearer \xff").unwrap();
+        let cases: Vec<(Vec<HeaderValue>, StatusCode, StatusCode)> = vec![
+            (vec![], StatusCode::UNAUTHORIZED, StatusCode::BAD_REQUEST),
+            (
+                vec![HeaderValue::from_static("Basic YTpi")],
+                StatusCode::UNAUTHORIZED,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                vec![bearer.parse().unwrap(), bearer.parse().unwrap()],
+                StatusCode::BAD_REQUEST,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                vec![not_utf8],
+                StatusCode::BAD_REQUEST,
+                StatusCode::BAD_REQUEST,
+            ),
+            (
+                vec![bearer.replace("Bearer", "bearer").parse().unwrap()],
+                StatusCode::OK,
+                StatusCode::OK,
+            ),
+        ];
+        for (auth, strict) in [(auth, false), (strict, true)] {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(auth.middleware())
+                    .route("/", web::get().to(|| async { "" })),
+            )
+            .await;
+            for (values, default, configured) in &cases {
+                let mut req = test::TestRequest::get().uri("/").to_request();
+                for value in values {
+                    req.headers_mut()
+                        .append(header::AUTHORIZATION, value.clone());
+                }
+                let status = match app.call(req).await {
+                    Ok(resp) => resp.status(),
+                    Err(err) => err.as_response_error().error_response().status(),
+                };
+                let expected = if strict { configured } else { default };
+                assert_eq!(status, *expected, "{:?}", values);
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_multiple_credentials() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0"));
+        let claims = serde_json::json!({ "exp": exp() });
+        let valid = format!("Bearer {}", token("0", &claims));
+        let expired = token("0", &serde_json::json!({ "exp": 1 }));
+        let gateway = format!("Basic YTpi, Bearer {}", expired);
+        for (policy, expected) in [
+            (MultipleCredentials::Reject, StatusCode::BAD_REQUEST),
+            (MultipleCredentials::First, StatusCode::UNAUTHORIZED),
+            (MultipleCredentials::TryEach, StatusCode::OK),
+        ] {
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(auth.clone().multiple_credentials(policy).middleware())
+                    .route("/", web::get().to(|| async { "" })),
+            )
+            .await;
+            let req = test::TestRequest::get()
+                .uri("/")
+                .header(header::AUTHORIZATION, format!("{}, {}", gateway, valid))
+                .to_request();
+            let status = match app.call(req).await {
+                Ok(resp) => resp.status(),
+                Err(err) => err.as_response_error().error_response().status(),
+            };
+            assert_eq!(status, expected, "{:?}", policy);
+        }
+        assert!("sometimes".parse::<MultipleCredentials>().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_diagnose() {
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&["api"]);
+        let auth =
+            JwtAuth::new(validation, jwks("0")).require(ClaimRule::contains("roles", "admin"));
+        let admin = serde_json::json!({"exp": exp(), "aud": "api", "roles": ["admin"]});
+        let cases = vec![
+            (token("0", &admin), None),
+            ("junk".to_string(), Some(("format", "bad_token"))),
+            (token("1", &admin), Some(("key", "unknown_kid"))),
+            (
+                token("0", &serde_json::json!({"exp": 1, "aud": "api"})),
+                Some(("exp", "invalid_token")),
+            ),
+            (
+                token("0", &serde_json::json!({"exp": exp(), "aud": "web"})),
+                Some(("aud", "invalid_token")),
+            ),
+            (
+                token("0", &serde_json::json!({"exp": exp(), "aud": "api"})),
+                Some(("rule roles", "claim_rule")),
+            ),
+        ];
+        for (token, expected) in cases {
+            let diagnosis = auth.diagnose(&token).await;
+            let failure = diagnosis.failure().map(|step| match &step.outcome {
+                crate::debug::Outcome::Failed { error, .. } => (step.check.as_str(), *error),
+                _ => unreachable!(),
+            });
+            assert_eq!(failure, expected);
+            assert_eq!(
+                diagnosis.accepted(),
+                auth.verify(&token).await.is_ok() && expected.is_none()
+            );
+        }
+        let diagnosis = auth.diagnose(&token("0", &admin)).await;
+        let checks: Vec<&str> = diagnosis.steps.iter().map(|s| s.check.as_str()).collect();
+        assert_eq!(
+            checks,
+            [
+                "format",
+                "header",
+                "exp present",
+                "algorithm",
+                "key",
+                "signature",
+                "registered claims",
+                "rule roles"
+            ]
+        );
+        assert_eq!(
+            diagnosis.claims.unwrap()["roles"],
+            serde_json::json!(["admin"])
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_optional() {
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), jwks("0")).optional();
+        let mut app = test::init_service(App::new().wrap(auth.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                let principal = req.extensions().get::<Principal>().cloned().unwrap();
+                async move { format!("{:?}", principal.claims().map(|c| c["iss"].clone())) }
+            }),
+        ))
+        .await;
+
+        let req = test::TestRequest::get().uri("/").to_request();
         let resp = test::call_service(&mut app, req).await;
-        assert!(resp.status().is_success());
+        assert_eq!(test::read_body(resp).await, "None");
+
+        let claims = Claims {
+            exp: exp(),
+            nbf: 0,
+            iss: "me".into(),
+        };
+        let resp = test::call_service(&mut app, request("0", &claims).to_request()).await;
+        assert_eq!(test::read_body(resp).await, "Some(String(\"me\"))");
+
+        let req = test::TestRequest::get()
+            .header("Authorization", "Bearer a.b.c")
+            .to_request();
+        assert!(app.call(req).await.is_err());
     }
 }
@@ -0,0 +1,223 @@
+//! Signed requests to the JSON APIs of AWS, shared by Secrets Manager and KMS
+use serde_json::Value;
+use std::fmt;
+
+/// An error answered by AWS, e.g. `KMSInvalidSignatureException`
+#[derive(Debug)]
+pub(crate) struct AwsError {
+    pub(crate) kind: String,
+    message: String,
+    status: u16,
+}
+
+impl fmt::Display for AwsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.kind, self.message)
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+/// Credentials, region and endpoint of one AWS service
+pub(crate) struct AwsClient {
+    service: &'static str,
+    region: String,
+    access_key_id: String,
+    secret_access_key: String,
+    pub(crate) session_token: Option<String>,
+    pub(crate) endpoint: String,
+    client: reqwest::Client,
+}
+
+impl AwsClient {
+    pub(crate) fn new(
+        service: &'static str,
+        region: String,
+        access_key_id: String,
+        secret_access_key: String,
+    ) -> Self {
+        AwsClient {
+            endpoint: format!("https://{}.{}.amazonaws.com", service, region),
+            service,
+            region,
+            access_key_id,
+            secret_access_key,
+            session_token: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN`, in `region` or else `AWS_REGION`
+    pub(crate) fn from_env(service: &'static str, region: Option<&str>) -> anyhow::Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| anyhow::anyhow!("{} requests need {}", service, name))
+        };
+        let region = match region {
+            Some(region) => region.to_string(),
+            None => var("AWS_REGION")?,
+        };
+        let mut client = AwsClient::new(
+            service,
+            region,
+            var("AWS_ACCESS_KEY_ID")?,
+            var("AWS_SECRET_ACCESS_KEY")?,
+        );
+        client.session_token = std::env::var("AWS_SESSION_TOKEN").ok();
+        Ok(client)
+    }
+
+    /// POST `body` to the `target` action, e.g. `TrentService.Sign`
+    pub(crate) async fn call(&self, target: &str, body: &Value) -> anyhow::Result<Value> {
+        let body = serde_json::to_vec(body)?;
+        let url = url::Url::parse(&self.endpoint)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            (None, _) => anyhow::bail!("{} has no host", self.endpoint),
+        };
+        let date_time = time::OffsetDateTime::now_utc().format("%Y%m%dT%H%M%SZ");
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", date_time.clone()),
+            ("x-amz-target", target.to_string()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let authorization = sigv4(
+            &SigningKey {
+                access_key_id: &self.access_key_id,
+                secret_access_key: &self.secret_access_key,
+                region: &self.region,
+                service: self.service,
+            },
+            &date_time,
+            "POST",
+            &headers,
+            &body,
+        );
+        let mut request = self.client.post(url.as_str());
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.header(*name, value);
+            }
+        }
+        let resp = request
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await?;
+        let status = resp.status();
+        if status.is_success() {
+            return Ok(resp.json().await?);
+        }
+        let error: Value = resp.json().await.unwrap_or_default();
+        let text = |field: &str| error[field].as_str().unwrap_or_default().to_string();
+        Err(AwsError {
+            // Possibly prefixed by a namespace, e.g. `com.amazonaws.kms#`
+            kind: text("__type").rsplit('#').next().unwrap_or_default().into(),
+            message: match text("message") {
+                message if message.is_empty() => text("Message"),
+                message => message,
+            },
+            status: status.as_u16(),
+        }
+        .into())
+    }
+}
+
+struct SigningKey<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    region: &'a str,
+    service: &'a str,
+}
+
+/// The `Authorization` header of an AWS Signature Version 4 request to `/` without
+/// a query, signing `headers`, lowercase and sorted by name
+fn sigv4(
+    key: &SigningKey<'_>,
+    date_time: &str,
+    method: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> String {
+    use ring::{digest, hmac};
+
+    let hex = |bytes: &[u8]| {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    };
+    let sha256 = |data: &[u8]| hex(digest::digest(&digest::SHA256, data).as_ref());
+    let mac = |key: &[u8], data: &str| {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+            .as_ref()
+            .to_vec()
+    };
+
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_request = format!(
+        "{}\n/\n\n{}\n{}\n{}",
+        method,
+        canonical_headers,
+        signed_headers,
+        sha256(body)
+    );
+    let date = &date_time[..8];
+    let scope = format!("{}/{}/{}/aws4_request", date, key.region, key.service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        date_time,
+        scope,
+        sha256(canonical_request.as_bytes())
+    );
+    let signing_key = [date, key.region, key.service, "aws4_request"].iter().fold(
+        format!("AWS4{}", key.secret_access_key).into_bytes(),
+        |key, part| mac(&key, part),
+    );
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        key.access_key_id,
+        scope,
+        signed_headers,
+        hex(&mac(&signing_key, &string_to_sign))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sigv4() {
+        // get-vanilla of the AWS Signature Version 4 test suite
+        let key = SigningKey {
+            access_key_id: "AKIDEXAMPLE",
+            secret_access_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            region: "us-east-1",
+            service: "service",
+        };
+        let headers = [
+            ("host", "example.amazonaws.com".to_string()),
+            ("x-amz-date", "20150830T123600Z".to_string()),
+        ];
+        assert_eq!(
+            sigv4(&key, "20150830T123600Z", "GET", &headers, b""),
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Keys in Azure Key Vault, signing for the `issuer` module and verifying against
+//! the public key fetched from the vault, without the private key leaving it
+use crate::jwk::{Jwk, KeyParams};
+use crate::outbound::{CachedToken, ServiceTokenProvider, TokenResponse};
+use crate::remote::{RemoteFuture, RemoteKey};
+use anyhow::{bail, Context};
+use futures::lock::Mutex;
+use jsonwebtoken::Algorithm;
+use ring::digest;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+
+const API_VERSION: &str = "7.4";
+const RESOURCE: &str = "https://vault.azure.net";
+
+/// How requests to the vault get their access tokens
+enum Credentials {
+    /// The managed identity of the VM or app service, from IMDS
+    ManagedIdentity {
+        endpoint: String,
+        cached: Mutex<Option<CachedToken>>,
+    },
+    /// An app registration with the client credentials grant
+    ClientCredentials(ServiceTokenProvider),
+}
+
+/// A Key Vault key used with one of RS*, PS* or ES*, Key Vault having no HMAC keys
+pub struct AzureKey {
+    /// `https://<vault>.vault.azure.net/keys/<name>`, possibly with a version
+    key_id: String,
+    alg: Algorithm,
+    credentials: Credentials,
+    public: Mutex<Option<KeyParams>>,
+    client: reqwest::Client,
+}
+
+impl AzureKey {
+    /// The key at `key_id`, e.g. `https://rapi.vault.azure.net/keys/signing`, as the
+    /// managed identity
+    pub fn new(key_id: impl Into<String>, alg: Algorithm) -> anyhow::Result<Self> {
+        if matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            bail!("Key Vault keys cannot sign {:?}", alg);
+        }
+        Ok(AzureKey {
+            key_id: key_id.into().trim_end_matches('/').into(),
+            alg,
+            credentials: Credentials::ManagedIdentity {
+                endpoint: "http://169.254.169.254".into(),
+                cached: Mutex::default(),
+            },
+            public: Mutex::default(),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Authenticate as the app registration `client_id` of `tenant` instead
+    pub fn client_credentials(
+        mut self,
+        tenant: &str,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        let token_endpoint = format!(
+            "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
+            tenant
+        );
+        let tokens = ServiceTokenProvider::new(token_endpoint, client_id, client_secret)
+            .scope(format!("{}/.default", RESOURCE));
+        self.credentials = Credentials::ClientCredentials(tokens);
+        self
+    }
+
+    /// The instance metadata service handing out tokens of the managed identity
+    pub fn imds_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.credentials = Credentials::ManagedIdentity {
+            endpoint: endpoint.into().trim_end_matches('/').into(),
+            cached: Mutex::default(),
+        };
+        self
+    }
+
+    async fn token(&self) -> anyhow::Result<String> {
+        let (endpoint, cached) = match &self.credentials {
+            Credentials::ClientCredentials(tokens) => return tokens.token().await,
+            Credentials::ManagedIdentity { endpoint, cached } => (endpoint, cached),
+        };
+        let mut cached = cached.lock().await;
+        if let Some(token) = cached.as_ref().filter(|t| t.renew_at > Instant::now()) {
+            return Ok(token.access_token.clone());
+        }
+        let token: TokenResponse = self
+            .client
+            .get(&format!("{}/metadata/identity/oauth2/token", endpoint))
+            .query(&[("api-version", "2018-02-01"), ("resource", RESOURCE)])
+            .header("Metadata", "true")
+            .send()
+            .await?
+            .error_for_status()
+            .context("managed identity token")?
+            .json()
+            .await?;
+        let access_token = token.access_token.clone();
+        *cached = token.expires_in.map(|expires_in| CachedToken {
+            access_token: token.access_token,
+            renew_at: Instant::now() + Duration::from_secs(expires_in.saturating_sub(60)),
+        });
+        Ok(access_token)
+    }
+
+    /// The public key, fetched once
+    async fn fetch_public(&self) -> anyhow::Result<KeyParams> {
+        let mut public = self.public.lock().await;
+        if let Some(params) = public.as_ref() {
+            return Ok(params.clone());
+        }
+        let resp: Value = self
+            .client
+            .get(&self.key_id)
+            .query(&[("api-version", API_VERSION)])
+            .bearer_auth(self.token().await?)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("fetching {}", self.key_id))?
+            .json()
+            .await?;
+        let key = &resp["key"];
+        let text = |member: &str| {
+            key[member]
+                .as_str()
+                .map(String::from)
+                .with_context(|| format!("{} has no {}", self.key_id, member))
+        };
+        // Keys held in HSMs are of type RSA-HSM and EC-HSM
+        let params = match key["kty"].as_str().unwrap_or_default() {
+            "RSA" | "RSA-HSM" => KeyParams::Rsa {
+                n: text("n")?,
+                e: text("e")?,
+            },
+            "EC" | "EC-HSM" => KeyParams::Ec {
+                crv: text("crv")?,
+                x: text("x")?,
+                y: text("y")?,
+            },
+            kty => bail!("{} is of unsupported type {:?}", self.key_id, kty),
+        };
+        *public = Some(params.clone());
+        Ok(params)
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The digest Key Vault signs for `alg`
+fn digest_of(alg: Algorithm) -> &'static digest::Algorithm {
+    match alg {
+        Algorithm::RS384 | Algorithm::PS384 | Algorithm::ES384 => &digest::SHA384,
+        Algorithm::RS512 | Algorithm::PS512 => &digest::SHA512,
+        _ => &digest::SHA256,
+    }
+}
+
+impl RemoteKey for AzureKey {
+    fn alg(&self) -> Algorithm {
+        self.alg
+    }
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> RemoteFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            let digest = digest::digest(digest_of(self.alg), message);
+            let resp: Value = self
+                .client
+                .post(&format!("{}/sign", self.key_id))
+                .query(&[("api-version", API_VERSION)])
+                .bearer_auth(self.token().await?)
+                .json(&json!({
+                    "alg": format!("{:?}", self.alg),
+                    "value": base64url(digest.as_ref()),
+                }))
+                .send()
+                .await?
+                .error_for_status()
+                .with_context(|| format!("signing with {}", self.key_id))?
+                .json()
+                .await?;
+            // ECDSA signatures come as r || s, as in JWS
+            let signature = resp["value"]
+                .as_str()
+                .context("Key Vault returned no signature")?;
+            Ok(base64::decode_config(signature, base64::URL_SAFE_NO_PAD)?)
+        })
+    }
+
+    fn verify<'a>(&'a self, message: &'a [u8], signature: &'a [u8]) -> RemoteFuture<'a, bool> {
+        Box::pin(async move {
+            let jwk = Jwk {
+                params: self.fetch_public().await?,
+                kid: None,
+                key_use: None,
+                key_ops: None,
+                alg: None,
+                x5t: None,
+                x5t_s256: None,
+            };
+            let key = jwk.decoding_key()?;
+            let message = std::str::from_utf8(message)?;
+            Ok(
+                jsonwebtoken::crypto::verify(&base64url(signature), message, &key, self.alg)
+                    .unwrap_or(false),
+            )
+        })
+    }
+
+    fn public_key(&self) -> RemoteFuture<'_, Option<KeyParams>> {
+        Box::pin(async move { self.fetch_public().await.map(Some) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestKey;
+    use mockito::{mock, Matcher};
+
+    #[actix_rt::test]
+    async fn test_azure_key() {
+        let key = AzureKey::new(
+            format!("{}/keys/signing", mockito::server_url()),
+            Algorithm::RS256,
+        )
+        .unwrap()
+        .imds_endpoint(mockito::server_url());
+        let imds = mock("GET", "/metadata/identity/oauth2/token")
+            .match_header("metadata", "true")
+            .match_query(Matcher::UrlEncoded("resource".into(), RESOURCE.into()))
+            .with_body(r#"{"access_token": "vault-token", "expires_in": 3600}"#)
+            .expect(1)
+            .create();
+
+        // Key Vault signs the digest, here with a local key standing in for it
+        let local = TestKey::rsa(2048).unwrap();
+        let message = b"header.payload";
+        let signed = jsonwebtoken::crypto::sign(
+            std::str::from_utf8(message).unwrap(),
+            &local.encoding_key,
+            Algorithm::RS256,
+        )
+        .unwrap();
+        let digest = base64url(digest::digest(&digest::SHA256, message).as_ref());
+        let sign = mock("POST", "/keys/signing/sign")
+            .match_header("authorization", "Bearer vault-token")
+            .match_query(Matcher::UrlEncoded(
+                "api-version".into(),
+                API_VERSION.into(),
+            ))
+            .match_body(Matcher::Json(json!({"alg": "RS256", "value": digest})))
+            .with_body(json!({ "value": signed }).to_string())
+            .create();
+        let signature = key.sign(message).await.unwrap();
+        sign.assert();
+
+        let get = mock("GET", "/keys/signing")
+            .match_query(Matcher::Any)
+            .with_body(
+                json!({"key": {
+                    "kty": "RSA-HSM",
+                    "n": local.n,
+                    "e": local.e,
+                }})
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+        assert!(key.verify(message, &signature).await.unwrap());
+        assert!(!key.verify(b"header.forged", &signature).await.unwrap());
+        assert!(matches!(
+            key.public_key().await.unwrap(),
+            Some(KeyParams::Rsa { .. })
+        ));
+        get.assert();
+        imds.assert();
+        assert!(AzureKey::new("https://v.vault.azure.net/keys/k", Algorithm::HS256).is_err());
+    }
+}
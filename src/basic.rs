@@ -0,0 +1,280 @@
+//! HTTP Basic credentials traded for tokens, for clients that cannot do OAuth
+use crate::auth::{admit, principal, JwtAuth};
+use crate::chain::{AuthFuture, Authenticator};
+use crate::claims::Principal;
+use crate::error::AuthError;
+use crate::outbound::{CachedToken, TokenResponse};
+use crate::report::{report, Component};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header;
+use futures::lock::Mutex;
+use log::{debug, warn};
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How Basic credentials are presented to the token endpoint
+#[derive(Clone, Debug)]
+pub enum BasicGrant {
+    /// Resource owner password grant, as this client
+    Password {
+        client_id: String,
+        client_secret: Option<String>,
+    },
+    /// Client credentials grant, the user name and password being client id and secret
+    ClientCredentials,
+}
+
+/// An `Authenticator` accepting `Authorization: Basic` by obtaining a token for the
+/// credentials at the identity provider, then validating it like any bearer token.
+/// Tokens are kept by a hash of the credentials until shortly before they expire.
+#[derive(Clone)]
+pub struct BasicAuthBridge {
+    token_endpoint: String,
+    grant: BasicGrant,
+    scope: Option<String>,
+    realm: Option<String>,
+    early_refresh: Duration,
+    auth: JwtAuth,
+    client: reqwest::Client,
+    cached: Arc<Mutex<HashMap<Vec<u8>, CachedToken>>>,
+}
+
+impl BasicAuthBridge {
+    /// Tokens from `token_endpoint` checked by `auth`
+    pub fn new(token_endpoint: impl Into<String>, grant: BasicGrant, auth: JwtAuth) -> Self {
+        BasicAuthBridge {
+            token_endpoint: token_endpoint.into(),
+            grant,
+            scope: None,
+            realm: None,
+            early_refresh: Duration::from_secs(60),
+            auth,
+            client: reqwest::Client::new(),
+            cached: Arc::default(),
+        }
+    }
+
+    /// Space separated scopes requested
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+
+    /// Realm of the `WWW-Authenticate: Basic` challenge
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    /// Obtain a new token this long before the cached one expires, 60s by default
+    pub fn early_refresh(mut self, early_refresh: Duration) -> Self {
+        self.early_refresh = early_refresh;
+        self
+    }
+
+    /// A token for the credentials, from the cache if still fresh
+    async fn token(&self, user: &str, password: &str) -> Result<String, AuthError> {
+        // Never keep the password itself
+        let key = digest(&SHA256, format!("{}:{}", user, password).as_bytes())
+            .as_ref()
+            .to_vec();
+        {
+            let mut cached = self.cached.lock().await;
+            let now = Instant::now();
+            cached.retain(|_, token| token.renew_at > now);
+            if let Some(token) = cached.get(&key) {
+                return Ok(token.access_token.clone());
+            }
+        }
+        let mut form = match &self.grant {
+            BasicGrant::Password {
+                client_id,
+                client_secret,
+            } => {
+                let mut form = vec![
+                    ("grant_type", "password"),
+                    ("username", user),
+                    ("password", password),
+                    ("client_id", client_id.as_str()),
+                ];
+                if let Some(secret) = client_secret {
+                    form.push(("client_secret", secret));
+                }
+                form
+            }
+            BasicGrant::ClientCredentials => vec![
+                ("grant_type", "client_credentials"),
+                ("client_id", user),
+                ("client_secret", password),
+            ],
+        };
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        let resp = self
+            .client
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .map_err(|e| {
+                warn!("token endpoint {} failed: {}", self.token_endpoint, e);
+                report(Component::Credentials, &e);
+                AuthError::CredentialsUnavailable
+            })?;
+        if resp.status().is_client_error() {
+            return Err(AuthError::InvalidCredentials);
+        }
+        let token: TokenResponse = match resp.error_for_status() {
+            Ok(resp) => resp.json().await,
+            Err(e) => Err(e),
+        }
+        .map_err(|e| {
+            warn!("token endpoint {} failed: {}", self.token_endpoint, e);
+            report(Component::Credentials, &e);
+            AuthError::CredentialsUnavailable
+        })?;
+        debug!("obtained a token for Basic credentials of {}", user);
+        if let Some(expires_in) = token.expires_in {
+            self.cached.lock().await.insert(
+                key,
+                CachedToken {
+                    access_token: token.access_token.clone(),
+                    renew_at: Instant::now()
+                        + Duration::from_secs(expires_in).saturating_sub(self.early_refresh),
+                },
+            );
+        }
+        Ok(token.access_token)
+    }
+}
+
+/// User name and password of a Basic `Authorization` header
+fn basic_credentials(req: &ServiceRequest) -> Option<(String, String)> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let decoded = base64::decode(value.strip_prefix("Basic ")?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+impl Authenticator for BasicAuthBridge {
+    fn authenticate<'a>(&'a self, req: &'a ServiceRequest) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let (user, password) = basic_credentials(req)?;
+            let token = match self.token(&user, &password).await {
+                Ok(token) => token,
+                Err(e) => return Some(Err(e)),
+            };
+            let auth = self.auth.live();
+            Some(
+                principal(&auth, req, Some(&token))
+                    .await
+                    .and_then(|(claims, hash)| admit(&auth, req, claims, hash, Some(token), false)),
+            )
+        })
+    }
+
+    fn admitted(&self, req: &mut ServiceRequest, principal: Option<&Principal>) {
+        self.auth.admitted(req, principal);
+    }
+
+    fn challenge(&self, _: &AuthError) -> Option<String> {
+        Some(match &self.realm {
+            Some(realm) => format!("Basic realm=\"{}\"", realm.replace('"', "\\\"")),
+            None => "Basic".into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::AuthChain;
+    use crate::keystore::Keys;
+    use crate::testing::TestKey;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, web, App, HttpRequest};
+    use jsonwebtoken::{encode, Algorithm, Header, Validation};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_basic_bridge() {
+        let key = TestKey::rsa(2048).unwrap();
+        let mut keys = Keys::new();
+        keys.insert("0".into(), vec![key.decoding_key()]);
+        let header = Header {
+            kid: Some("0".into()),
+            ..Header::new(Algorithm::RS256)
+        };
+        let claims = json!({"sub": "cron", "exp": 4102444800u64});
+        let token = encode(&header, &claims, &key.encoding_key).unwrap();
+
+        let path = "/basic/token";
+        let granted = mockito::mock("POST", path)
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "client_credentials".into()),
+                mockito::Matcher::UrlEncoded("client_secret".into(), "pw".into()),
+            ]))
+            .with_body(json!({"access_token": token, "expires_in": 300}).to_string())
+            .expect(1)
+            .create();
+        let refused = mockito::mock("POST", path)
+            .match_body(mockito::Matcher::UrlEncoded(
+                "client_secret".into(),
+                "wrong".into(),
+            ))
+            .with_status(401)
+            .with_body(r#"{"error": "invalid_client"}"#)
+            .create();
+
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), keys);
+        let bridge = BasicAuthBridge::new(
+            mockito::server_url() + path,
+            BasicGrant::ClientCredentials,
+            auth,
+        )
+        .realm("cron");
+        let mut app = test::init_service(
+            App::new()
+                .wrap(AuthChain::default().with(bridge).middleware())
+                .route(
+                    "/",
+                    web::get().to(|req: HttpRequest| {
+                        let principal = req.extensions().get::<Principal>().cloned().unwrap();
+                        async move { principal.claims().unwrap()["sub"].to_string() }
+                    }),
+                ),
+        )
+        .await;
+
+        let basic = |credentials: &str| format!("Basic {}", base64::encode(credentials));
+        for _ in 0..2 {
+            let req = test::TestRequest::get()
+                .header("Authorization", basic("cron:pw"))
+                .to_request();
+            let resp = test::call_service(&mut app, req).await;
+            assert_eq!(test::read_body(resp).await, "\"cron\"");
+        }
+        granted.assert();
+
+        let req = test::TestRequest::get()
+            .header("Authorization", basic("cron:wrong"))
+            .to_request();
+        let resp = app
+            .call(req)
+            .await
+            .unwrap_err()
+            .as_response_error()
+            .error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Basic realm=\"cron\""
+        );
+        refused.assert();
+    }
+}
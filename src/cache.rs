@@ -0,0 +1,372 @@
+//! Verified tokens remembered, sparing the signature check on every request
+use crate::report::{report, Component};
+use log::warn;
+use ring::digest::{digest, SHA256};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What `TokenCacheStore::get` returns
+pub type CacheFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Option<String>>> + 'a>>;
+
+/// Where verified claims are kept, e.g. in process or in Redis or memcached shared by
+/// every instance, so that each token is verified once across the deployment. Keys
+/// cover the verifier configuration as well as the token, so one store may back
+/// services verifying for different audiences or issuers. Values are the claims
+/// serialized as JSON.
+pub trait TokenCacheStore: Send + Sync {
+    /// The value stored under `key`, unless it expired
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a>;
+
+    /// Store `value` under `key` for `ttl`
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: String,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>;
+
+    fn remove<'a>(&'a self, key: &'a str)
+        -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>;
+}
+
+/// Values by key, with when they expire
+type Entries = HashMap<String, (String, Instant)>;
+
+/// Verified claims held in process memory, of up to a number of tokens. When full,
+/// new tokens are not kept until entries expire.
+pub struct MemoryTokenCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl Default for MemoryTokenCache {
+    fn default() -> Self {
+        MemoryTokenCache::new(10000)
+    }
+}
+
+impl MemoryTokenCache {
+    pub fn new(capacity: usize) -> Self {
+        MemoryTokenCache {
+            capacity,
+            entries: Mutex::default(),
+        }
+    }
+}
+
+impl TokenCacheStore for MemoryTokenCache {
+    fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a> {
+        Box::pin(async move {
+            let entries = self.entries.lock().unwrap();
+            Ok(entries
+                .get(key)
+                .filter(|(_, until)| *until > Instant::now())
+                .map(|(value, _)| value.clone()))
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: String,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= self.capacity {
+                entries.retain(|_, (_, until)| *until > now);
+                if entries.len() >= self.capacity {
+                    return Ok(());
+                }
+            }
+            entries.insert(key.into(), (value, now + ttl));
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+        Box::pin(async move {
+            self.entries.lock().unwrap().remove(key);
+            Ok(())
+        })
+    }
+}
+
+/// The verified claims of recently seen tokens. An entry lives for the configured
+/// TTL, but never past the `exp` of its token, so caching does not extend the
+/// validity of any token. Rules, revocations, policies and rate limits still apply on
+/// every request, and entries of revoked tokens are dropped. A failing store only
+/// means verifying the token again.
+///
+/// Entries are kept per namespace, that of the verifier configuration the claims were
+/// checked against, so that a token cached by one `JwtAuth` is never taken by another
+/// expecting a different audience or issuer, or verifying with other keys. `JwtAuth` drops the entries of tokens
+/// whose key was removed or revoked from its `JwksStore` when it next sees them.
+#[derive(Clone)]
+pub struct TokenCache {
+    ttl: Duration,
+    store: Arc<dyn TokenCacheStore>,
+}
+
+impl TokenCache {
+    /// Keep verified claims for at most `ttl`, in process memory
+    pub fn new(ttl: Duration) -> Self {
+        TokenCache {
+            ttl,
+            store: Arc::new(MemoryTokenCache::default()),
+        }
+    }
+
+    pub fn store(mut self, store: impl TokenCacheStore + 'static) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// The claims of `token`, if verified recently under `namespace` and not yet
+    /// expired
+    pub async fn get(&self, namespace: &str, token: &str) -> Option<Map<String, Value>> {
+        let value = match self.store.get(&key(namespace, token)).await {
+            Ok(value) => value?,
+            Err(e) => {
+                warn!("token cache lookup failed: {}", e);
+                report(Component::TokenCache, &e);
+                return None;
+            }
+        };
+        let claims: Map<String, Value> = serde_json::from_str(&value)
+            .map_err(|e| warn!("malformed token cache entry: {}", e))
+            .ok()?;
+        // Shared stores may keep entries a little longer than asked
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if exp <= now() => None,
+            _ => Some(claims),
+        }
+    }
+
+    /// Remember the `claims` of `token` verified under `namespace`, for the TTL or
+    /// until it expires
+    pub async fn insert(&self, namespace: &str, token: &str, claims: &Map<String, Value>) {
+        let ttl = match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) => self.ttl.min(Duration::from_secs(exp.saturating_sub(now()))),
+            None => self.ttl,
+        };
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+        let value = Value::Object(claims.clone()).to_string();
+        if let Err(e) = self.store.set(&key(namespace, token), value, ttl).await {
+            warn!("token cache update failed: {}", e);
+            report(Component::TokenCache, &e);
+        }
+    }
+
+    /// Forget `token` under `namespace`, e.g. once it was found revoked
+    pub async fn invalidate(&self, namespace: &str, token: &str) {
+        if let Err(e) = self.store.remove(&key(namespace, token)).await {
+            warn!("token cache invalidation failed: {}", e);
+            report(Component::TokenCache, &e);
+        }
+    }
+}
+
+/// The namespace hashed, then the full SHA-256 of the token in hex, the short
+/// `TokenHash` being too collision prone
+fn key(namespace: &str, token: &str) -> String {
+    let namespace = hex(namespace.as_bytes());
+    format!("token:{}:{}", &namespace[..16], hex(token.as_bytes()))
+}
+
+fn hex(data: &[u8]) -> String {
+    let hash = digest(&SHA256, data);
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtAuth;
+    use crate::testing::TestKey;
+    use actix_web::dev::Service;
+    use actix_web::{test, web, App};
+    use actix_web_httpauth::middleware::HttpAuthentication;
+    use jsonwebtoken::{encode, Algorithm, Header, Validation};
+    use serde_json::json;
+
+    /// One store behind several caches, like instances sharing Redis
+    struct Shared(Arc<MemoryTokenCache>);
+
+    impl TokenCacheStore for Shared {
+        fn get<'a>(&'a self, key: &'a str) -> CacheFuture<'a> {
+            self.0.get(key)
+        }
+
+        fn set<'a>(
+            &'a self,
+            key: &'a str,
+            value: String,
+            ttl: Duration,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+            self.0.set(key, value, ttl)
+        }
+
+        fn remove<'a>(
+            &'a self,
+            key: &'a str,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+            self.0.remove(key)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_token_cache() {
+        let now = now();
+        let store = Arc::new(MemoryTokenCache::new(2));
+        let cache = TokenCache::new(Duration::from_secs(300)).store(Shared(store.clone()));
+        let other = TokenCache::new(Duration::from_secs(300)).store(Shared(store.clone()));
+        let claims = |exp: u64| {
+            json!({"sub": "alice", "exp": exp})
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+
+        cache.insert("ns", "a", &claims(now + 600)).await;
+        assert_eq!(other.get("ns", "a").await, Some(claims(now + 600)));
+        assert!(other.get("other", "a").await.is_none());
+        assert!(cache.get("ns", "b").await.is_none());
+
+        // Already expired, or about to: the TTL is cut to the remaining lifetime
+        cache.insert("ns", "b", &claims(now - 1)).await;
+        assert!(cache.get("ns", "b").await.is_none());
+        let until = |token: &str| store.entries.lock().unwrap()[&key("ns", token)].1;
+        cache.insert("ns", "c", &claims(now + 10)).await;
+        assert!(until("c") <= Instant::now() + Duration::from_secs(10));
+        assert!(until("a") > Instant::now() + Duration::from_secs(290));
+
+        // Full
+        cache.insert("ns", "d", &claims(now + 600)).await;
+        assert!(cache.get("ns", "d").await.is_none());
+        other.invalidate("ns", "a").await;
+        assert!(cache.get("ns", "a").await.is_none());
+
+        // Entries outliving their token in the store are ignored
+        let expired = Value::Object(claims(now - 1)).to_string();
+        store
+            .set(&key("ns", "e"), expired, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(cache.get("ns", "e").await.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_store() {
+        let key = TestKey::rsa(2048).unwrap();
+        let store = Arc::new(MemoryTokenCache::default());
+        let auth = |iss: Option<&str>| {
+            let mut jwks = crate::keystore::Keys::new();
+            jwks.insert("0".into(), vec![key.decoding_key()]);
+            let validation = Validation {
+                iss: iss.map(String::from),
+                ..Validation::new(Algorithm::RS256)
+            };
+            let cache = TokenCache::new(Duration::from_secs(300)).store(Shared(store.clone()));
+            JwtAuth::new(validation, jwks).token_cache(cache)
+        };
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/idp")
+                        .wrap(HttpAuthentication::bearer(
+                            auth(Some("https://idp")).validator(),
+                        ))
+                        .route("", web::get().to(|| async { "" })),
+                )
+                .service(
+                    web::scope("/any")
+                        .wrap(HttpAuthentication::bearer(auth(None).validator()))
+                        .route("", web::get().to(|| async { "" })),
+                ),
+        )
+        .await;
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("0".into());
+        let exp = now() + 600;
+        let get = |path: &str, iss: &str| {
+            let claims = json!({"sub": "alice", "iss": iss, "exp": exp});
+            let token = encode(&header, &claims, &key.encoding_key).unwrap();
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .uri(path)
+                .to_request()
+        };
+
+        // Cached by the configuration taking any issuer, not by the other
+        assert!(app.call(get("/any", "https://other")).await.is_ok());
+        assert!(app.call(get("/idp", "https://other")).await.is_err());
+        // Accepted by both, so cached by both
+        assert!(app.call(get("/any", "https://idp")).await.is_ok());
+        assert!(app.call(get("/idp", "https://idp")).await.is_ok());
+        assert_eq!(store.entries.lock().unwrap().len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_store_keys() {
+        use crate::keystore::{JwksStore, Keys};
+        use jsonwebtoken::{DecodingKey, EncodingKey};
+
+        let store = Arc::new(MemoryTokenCache::default());
+        let auth = |keys: JwksStore| {
+            let cache = TokenCache::new(Duration::from_secs(300)).store(Shared(store.clone()));
+            JwtAuth::new(Validation::new(Algorithm::HS256), keys).token_cache(cache)
+        };
+        let keys = |secret: &'static [u8]| {
+            let mut keys = Keys::new();
+            keys.insert("0".into(), vec![DecodingKey::from_secret(secret)]);
+            JwksStore::new(keys)
+        };
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("0".into());
+        let claims = json!({"sub": "alice", "exp": now() + 600});
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"ours")).unwrap();
+
+        let scope = |path: &str, auth: JwtAuth| {
+            web::scope(path)
+                .wrap(HttpAuthentication::bearer(auth.validator()))
+                .route("", web::get().to(|| async { "" }))
+        };
+        let mut app = test::init_service(
+            App::new()
+                .service(scope("/ours", auth(keys(b"ours"))))
+                .service(scope("/theirs", auth(keys(b"theirs"))))
+                .service(scope("/again", auth(keys(b"ours")))),
+        )
+        .await;
+        let get = |path: &str| {
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .uri(path)
+                .to_request()
+        };
+
+        assert!(app.call(get("/ours")).await.is_ok());
+        // Same issuer and audience, yet other keys
+        assert!(app.call(get("/theirs")).await.is_err());
+        // Another store loading the same keys, as on another instance, shares the entry
+        assert!(app.call(get("/again")).await.is_ok());
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+    }
+}
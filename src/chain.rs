@@ -0,0 +1,464 @@
+//! Accepting requests authenticated in any of several ways
+use crate::apikey::ApiKeys;
+use crate::auth::{admit, principal, JwtAuth, ValidatorFuture};
+use crate::claims::{Principal, TokenClaims};
+use crate::error::AuthError;
+use crate::extract::Credentials;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{header, HeaderName, HeaderValue, StatusCode};
+use actix_web::{Error, HttpMessage, HttpResponse, ResponseError};
+use actix_web_httpauth::middleware::HttpAuthentication;
+use log::trace;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// What `Authenticator::authenticate` returns
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Option<Result<Principal, AuthError>>> + 'a>>;
+
+/// One way of authenticating requests in an `AuthChain`
+pub trait Authenticator: Send + Sync {
+    /// The principal `req` authenticates as, or `None` when it carries no credentials
+    /// of this kind. May add to the request extensions, e.g. the `TokenHash`.
+    fn authenticate<'a>(&'a self, req: &'a ServiceRequest) -> AuthFuture<'a>;
+
+    /// The `WWW-Authenticate` challenge of this scheme answering `error`, if it has one
+    fn challenge(&self, error: &AuthError) -> Option<String>;
+
+    /// Called on every request the chain lets through, with the principal if this
+    /// authenticator found it, e.g. to replace identity headers. Does nothing by
+    /// default.
+    fn admitted(&self, _req: &mut ServiceRequest, _principal: Option<&Principal>) {}
+}
+
+/// Bearer tokens, or the session cookie, through every check of the `JwtAuth`, as
+/// tuned by its live configuration
+impl Authenticator for JwtAuth {
+    fn authenticate<'a>(&'a self, req: &'a ServiceRequest) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let (token, from_cookie) = self.presented_token(req)?;
+            let auth = self.live();
+            let result = principal(&auth, req, Some(&token))
+                .await
+                .and_then(|(claims, hash)| {
+                    admit(&auth, req, claims, hash, Some(token), from_cookie)
+                });
+            Some(result)
+        })
+    }
+
+    fn challenge(&self, error: &AuthError) -> Option<String> {
+        self.challenge_value(error)
+    }
+
+    /// Claim headers from the token, dropped whoever else authenticated the request
+    fn admitted(&self, req: &mut ServiceRequest, principal: Option<&Principal>) {
+        self.set_claim_headers(req, principal.and_then(Principal::claims));
+    }
+}
+
+/// API keys, as the principal they map to in the store
+impl Authenticator for ApiKeys {
+    fn authenticate<'a>(&'a self, req: &'a ServiceRequest) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let key = self.presented(req)?;
+            Some(
+                ApiKeys::authenticate(self, &key)
+                    .await
+                    .map(Principal::ApiKey),
+            )
+        })
+    }
+
+    fn challenge(&self, _: &AuthError) -> Option<String> {
+        Some(format!("ApiKey header=\"{}\"", self.header_name()))
+    }
+}
+
+/// The verified client certificate subject of a connection, added to the request
+/// extensions by the TLS setup, e.g. with `HttpServer::on_connect`
+#[derive(Clone, Debug)]
+pub struct PeerIdentity(pub String);
+
+/// Client certificates verified by the TLS layer, identified by their subject
+#[derive(Clone, Default)]
+pub struct ClientCertAuth {
+    header: Option<HeaderName>,
+    allowed: HashSet<String>,
+}
+
+impl ClientCertAuth {
+    /// Take the subject from this header when there is no `PeerIdentity`, for TLS
+    /// terminated at a proxy. The proxy must drop the header from client requests.
+    pub fn trusted_header(mut self, header: HeaderName) -> Self {
+        self.header = Some(header);
+        self
+    }
+
+    /// Accept only the subjects allowed this way, rather than any verified subject
+    pub fn allow(mut self, subject: impl Into<String>) -> Self {
+        self.allowed.insert(subject.into());
+        self
+    }
+
+    fn subject(&self, req: &ServiceRequest) -> Option<String> {
+        if let Some(identity) = req.extensions().get::<PeerIdentity>() {
+            return Some(identity.0.clone());
+        }
+        let value = req.headers().get(self.header.as_ref()?)?.to_str().ok()?;
+        Some(value.to_string()).filter(|value| !value.is_empty())
+    }
+}
+
+impl Authenticator for ClientCertAuth {
+    fn authenticate<'a>(&'a self, req: &'a ServiceRequest) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let subject = self.subject(req)?;
+            if !self.allowed.is_empty() && !self.allowed.contains(&subject) {
+                trace!("client certificate {} not allowed", subject);
+                return Some(Err(AuthError::UnknownCertificate));
+            }
+            let mut claims = Map::new();
+            claims.insert("sub".into(), Value::String(subject));
+            Some(Ok(Principal::Certificate(claims)))
+        })
+    }
+
+    // Client certificates are asked for during the handshake
+    fn challenge(&self, _: &AuthError) -> Option<String> {
+        None
+    }
+}
+
+/// Authenticators tried in order on each request. The first principal found is added
+/// to the request extensions as `Principal` and `TokenClaims`. Requests no
+/// authenticator accepts are rejected with the error of the first one whose
+/// credentials they carried, or as missing a token, along with the challenges of all.
+#[derive(Clone, Default)]
+pub struct AuthChain {
+    authenticators: Vec<Arc<dyn Authenticator>>,
+    optional: bool,
+}
+
+impl AuthChain {
+    pub fn with(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    /// Let requests without any credentials through as `Principal::Anonymous`.
+    /// Invalid credentials are still rejected.
+    pub fn optional(mut self) -> Self {
+        self.optional = true;
+        self
+    }
+
+    /// The middleware authenticating requests with the chain
+    pub fn middleware(
+        self,
+    ) -> HttpAuthentication<Credentials, impl Fn(ServiceRequest, Credentials) -> ValidatorFuture>
+    {
+        HttpAuthentication::with_fn(move |req, _: Credentials| {
+            let chain = self.clone();
+            Box::pin(async move { chain.authenticate(req).await }) as ValidatorFuture
+        })
+    }
+
+    async fn authenticate(&self, mut req: ServiceRequest) -> Result<ServiceRequest, Error> {
+        let mut first_error = None;
+        for (i, authenticator) in self.authenticators.iter().enumerate() {
+            match authenticator.authenticate(&req).await {
+                Some(Ok(principal)) => {
+                    if let Some(claims) = principal.claims() {
+                        req.extensions_mut().insert(TokenClaims(claims.clone()));
+                    }
+                    self.admitted(&mut req, Some((i, &principal)));
+                    req.extensions_mut().insert(principal);
+                    return Ok(req);
+                }
+                Some(Err(error)) => {
+                    first_error.get_or_insert(error);
+                }
+                None => {}
+            }
+        }
+        let error = match first_error {
+            Some(error) => error,
+            None if self.optional => {
+                self.admitted(&mut req, None);
+                req.extensions_mut().insert(Principal::Anonymous);
+                return Ok(req);
+            }
+            None => AuthError::MissingToken,
+        };
+        let challenges = self
+            .authenticators
+            .iter()
+            .filter_map(|authenticator| authenticator.challenge(&error))
+            .collect();
+        Err(ChainRejection { error, challenges }.into())
+    }
+
+    /// Tell every authenticator `req` is let through, and which found the principal
+    fn admitted(&self, req: &mut ServiceRequest, found: Option<(usize, &Principal)>) {
+        for (i, authenticator) in self.authenticators.iter().enumerate() {
+            let principal = found
+                .filter(|(j, _)| *j == i)
+                .map(|(_, principal)| principal);
+            authenticator.admitted(req, principal);
+        }
+    }
+}
+
+/// An `AuthError` answered along with the challenge of every scheme in the chain
+#[derive(Debug)]
+pub struct ChainRejection {
+    pub error: AuthError,
+    pub challenges: Vec<String>,
+}
+
+impl fmt::Display for ChainRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ResponseError for ChainRejection {
+    fn status_code(&self) -> StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut resp = self.error.error_response();
+        // A single header keeps the challenges in order of preference
+        let value = HeaderValue::from_str(&self.challenges.join(", "));
+        if let (StatusCode::UNAUTHORIZED, Ok(value)) = (self.status_code(), value) {
+            if !self.challenges.is_empty() {
+                resp.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+            }
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apikey::MemoryApiKeys;
+    use crate::keystore::Keys;
+    use actix_web::dev::Service;
+    use actix_web::{test, web, App, HttpRequest};
+    use jsonwebtoken::{Algorithm, Validation};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_chain() {
+        let mut keys = MemoryApiKeys::default();
+        keys.insert("k1", json!({"sub": "legacy"}).as_object().unwrap().clone());
+        let chain = AuthChain::default()
+            .with(JwtAuth::new(Validation::new(Algorithm::RS256), Keys::new()))
+            .with(ApiKeys::new(keys))
+            .with(
+                ClientCertAuth::default()
+                    .trusted_header(HeaderName::from_static("x-client-subject"))
+                    .allow("CN=billing"),
+            );
+        let mut app = test::init_service(App::new().wrap(chain.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| {
+                let principal = req.extensions().get::<Principal>().cloned().unwrap();
+                let kind = match principal {
+                    Principal::Token(_) => "token",
+                    Principal::ApiKey(_) => "api key",
+                    Principal::Certificate(_) => "certificate",
+                    Principal::Anonymous => "anonymous",
+                };
+                let sub = principal.claims().map(|claims| claims["sub"].clone());
+                async move { format!("{} {}", kind, sub.unwrap_or_default()) }
+            }),
+        ))
+        .await;
+
+        let req = test::TestRequest::get()
+            .header("x-api-key", "k1")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(test::read_body(resp).await, "api key \"legacy\"");
+
+        let req = test::TestRequest::get()
+            .header("x-client-subject", "CN=billing")
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(test::read_body(resp).await, "certificate \"CN=billing\"");
+
+        // A bad token does not hide a valid API key
+        let req = test::TestRequest::get()
+            .header("Authorization", "Bearer a.b.c")
+            .header("x-api-key", "k1")
+            .to_request();
+        assert!(app.call(req).await.is_ok());
+
+        let req = test::TestRequest::get()
+            .header("x-client-subject", "CN=other")
+            .to_request();
+        let resp = app
+            .call(req)
+            .await
+            .unwrap_err()
+            .as_response_error()
+            .error_response();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            r#"Bearer error="invalid_token", ApiKey header="x-api-key""#
+        );
+        let req = test::TestRequest::get().to_request();
+        assert!(app.call(req).await.is_err());
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(AuthChain::default().optional().middleware())
+                .route(
+                    "/",
+                    web::get().to(|req: HttpRequest| {
+                        let anonymous = req.extensions().get::<Principal>().unwrap().is_anonymous();
+                        async move { anonymous.to_string() }
+                    }),
+                ),
+        )
+        .await;
+        let resp = test::call_service(&mut app, test::TestRequest::get().to_request()).await;
+        assert_eq!(test::read_body(resp).await, "true");
+    }
+
+    #[actix_rt::test]
+    async fn test_tuned() {
+        use crate::config::{Config, ConfigHandle};
+        use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let live = ConfigHandle::new(Config {
+            authserver: "https://idp".into(),
+            leeway_secs: Some(60),
+            required_claims: Some(vec!["tenant".into()]),
+            required_scopes: Some(vec!["orders:read".into()]),
+            ..Config::default()
+        });
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys)
+            .require_scope("admin")
+            .live_config(live);
+        let chain = AuthChain::default().with(auth);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(chain.middleware())
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let request = |claims: Value| {
+            let header = Header {
+                kid: Some("0".into()),
+                ..Header::new(Algorithm::HS256)
+            };
+            let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request()
+        };
+
+        let scope = "admin orders:read";
+        // Expired within the live leeway
+        let claims = json!({"exp": now - 30, "tenant": "t", "scope": scope});
+        assert!(app.call(request(claims)).await.is_ok());
+        for (claims, error) in [
+            // Scopes built in and live, then live required claims
+            (
+                json!({"exp": now + 60, "tenant": "t", "scope": "orders:read"}),
+                "missing scope admin",
+            ),
+            (
+                json!({"exp": now + 60, "tenant": "t", "scope": "admin"}),
+                "missing scope orders:read",
+            ),
+            (
+                json!({"exp": now + 60, "scope": scope}),
+                "claim requirement not met: tenant",
+            ),
+        ] {
+            let err = app.call(request(claims)).await.unwrap_err();
+            let status = err.as_response_error().status_code();
+            assert_eq!(status, StatusCode::FORBIDDEN);
+            assert_eq!(err.to_string(), error);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_claim_headers() {
+        use crate::headers::ClaimHeaders;
+        use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header};
+
+        let mut api_keys = MemoryApiKeys::default();
+        api_keys.insert("k1", json!({"sub": "legacy"}).as_object().unwrap().clone());
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let headers = ClaimHeaders::parse(&["X-User-Id={sub}".to_string()])
+            .unwrap()
+            .remove("X-Forwarded-User")
+            .unwrap();
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys).claim_headers(headers);
+        // The API key goes first, so the token verifier never sees those requests
+        let chain = AuthChain::default()
+            .with(ApiKeys::new(api_keys))
+            .with(auth)
+            .optional();
+        let mut app = test::init_service(App::new().wrap(chain.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move {
+                let get = |name| {
+                    req.headers()
+                        .get(name)
+                        .map(|v| v.to_str().unwrap().to_string())
+                };
+                format!("{:?} {:?}", get("x-user-id"), get("x-forwarded-user"))
+            }),
+        ))
+        .await;
+        let spoofed = || {
+            test::TestRequest::get()
+                .header("X-User-Id", "root")
+                .header("X-Forwarded-User", "root")
+        };
+        let token = encode(
+            &Header {
+                kid: Some("0".into()),
+                ..Header::new(Algorithm::HS256)
+            },
+            &json!({"sub": "alice", "exp": 4_000_000_000u64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        let req = spoofed()
+            .header("Authorization", format!("Bearer {}", token))
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, r#"Some("alice") None"#);
+        for req in [spoofed().header("x-api-key", "k1"), spoofed()] {
+            let body = test::read_response(&mut app, req.to_request()).await;
+            assert_eq!(body, "None None");
+        }
+    }
+}
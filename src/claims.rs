@@ -0,0 +1,165 @@
+use crate::error::AuthError;
+use actix_web::dev::Payload;
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Claims of a validated token, inserted into the request extensions by the validator.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenClaims(pub Map<String, Value>);
+
+impl TokenClaims {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// Deserialize the claims into a user supplied claims struct
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<T, serde_json::Error> {
+        serde_json::from_value(Value::Object(self.0.clone()))
+    }
+}
+
+/// The claims of the request deserialized into `T`, e.g. `Claims<MyClaims>` as a
+/// handler argument. Taken from the `TokenClaims` the middleware verified, so the
+/// token is never decoded again. Fails with 401 if there are none or they do not
+/// fit `T`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims<T>(pub T);
+
+impl<T> Claims<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> std::ops::Deref for Claims<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Claims<T> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let claims = match req.extensions().get::<TokenClaims>() {
+            Some(claims) => claims.deserialize().map(Claims).map_err(|e| {
+                log::debug!("claims do not fit {}: {}", std::any::type_name::<T>(), e);
+                AuthError::InvalidToken.into()
+            }),
+            None => Err(AuthError::MissingToken.into()),
+        };
+        ready(claims)
+    }
+}
+
+/// The canonical id of the authenticated user, the value of the configured user id
+/// claim (`sub` by default, e.g. `oid` for Azure AD). Inserted into the request
+/// extensions along with the `Principal`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct UserId(pub String);
+
+impl UserId {
+    /// The value of `claim`, strings as they are and numbers in decimal
+    pub fn from_claims(claims: &Map<String, Value>, claim: &str) -> Option<Self> {
+        match claims.get(claim)? {
+            Value::String(id) if !id.is_empty() => Some(UserId(id.clone())),
+            Value::Number(id) => Some(UserId(id.to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// Who a request was authenticated as, inserted into the request extensions by the
+/// `JwtAuth` middleware and `AuthChain`, along with the `TokenClaims` unless anonymous
+#[derive(Clone, Debug, PartialEq)]
+pub enum Principal {
+    /// A request without credentials, let through in optional mode or to a public
+    /// resource
+    Anonymous,
+    /// The claims of a bearer token
+    Token(Map<String, Value>),
+    /// The synthetic claims of an API key
+    ApiKey(Map<String, Value>),
+    /// A client certificate identity, its subject as `sub`
+    Certificate(Map<String, Value>),
+}
+
+impl Principal {
+    pub fn claims(&self) -> Option<&Map<String, Value>> {
+        match self {
+            Principal::Anonymous => None,
+            Principal::Token(claims)
+            | Principal::ApiKey(claims)
+            | Principal::Certificate(claims) => Some(claims),
+        }
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        *self == Principal::Anonymous
+    }
+}
+
+/// Strip `namespace` from claim names, so `https://myapp.example.com/tenant`
+/// becomes `tenant` when the namespace is `https://myapp.example.com/`.
+/// A namespaced claim never overwrites a claim already present without the prefix.
+pub fn strip_namespace(claims: Map<String, Value>, namespace: &str) -> Map<String, Value> {
+    let (namespaced, mut plain): (Map<String, Value>, Map<String, Value>) = claims
+        .into_iter()
+        .partition(|(k, _)| k.starts_with(namespace) && k.len() > namespace.len());
+    for (k, v) in namespaced {
+        let name = &k[namespace.len()..];
+        if !plain.contains_key(name) {
+            plain.insert(name.into(), v);
+        }
+    }
+    plain
+}
+
+/// Deserialize namespaced claims into `T` after stripping `namespace`
+pub fn from_namespaced<T: DeserializeOwned>(
+    claims: Map<String, Value>,
+    namespace: &str,
+) -> Result<T, serde_json::Error> {
+    serde_json::from_value(Value::Object(strip_namespace(claims, namespace)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[test]
+    fn test_strip_namespace() {
+        #[derive(Deserialize)]
+        struct Custom {
+            iss: String,
+            tenant: String,
+        }
+
+        let claims = json!({
+            "iss": "real",
+            "https://myapp.example.com/iss": "spoofed",
+            "https://myapp.example.com/tenant": "acme",
+            "https://other.example.com/tenant": "other",
+        });
+        let ns = "https://myapp.example.com/";
+        let c: Custom = from_namespaced(claims.as_object().unwrap().clone(), ns).unwrap();
+        assert_eq!(c.iss, "real");
+        assert_eq!(c.tenant, "acme");
+
+        let stripped = strip_namespace(claims.as_object().unwrap().clone(), ns);
+        assert!(stripped.contains_key("https://other.example.com/tenant"));
+        assert!(!stripped.contains_key("https://myapp.example.com/tenant"));
+    }
+}
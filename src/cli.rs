@@ -0,0 +1,89 @@
+//! Command line flags as a layer of the configuration, e.g. for systemd units
+//! preferring `ExecStart=rapi --issuer https://idp --audience api` over environment
+//! variables
+use anyhow::{bail, Context};
+
+pub const USAGE: &str = "\
+Flags override the environment:
+    --issuer <url>       AUTHSERVER, the issuer discovered
+    --audience <aud>     AUDIENCE
+    --jwks-url <url>     JWKS_URL, fetched instead of running discovery
+    --leeway <secs>      LEEWAY_SECS
+    --set <NAME=value>   any other setting, named as its variable
+    --help               print this";
+
+/// The settings of `args`, without the program name, as variables for
+/// `ConfigLoader::vars`. Flags take their value as the next argument or after `=`.
+pub fn flags(args: impl IntoIterator<Item = String>) -> anyhow::Result<Vec<(String, String)>> {
+    let mut args = args.into_iter();
+    let mut vars = Vec::new();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value)),
+            _ => (arg.clone(), None),
+        };
+        let name = match flag.as_str() {
+            "--issuer" => "AUTHSERVER",
+            "--audience" => "AUDIENCE",
+            "--jwks-url" => "JWKS_URL",
+            "--leeway" => "LEEWAY_SECS",
+            "--set" => "",
+            _ => bail!("unknown argument {:?}\n{}", arg, USAGE),
+        };
+        let value = match inline {
+            Some(value) => value.to_string(),
+            None => args
+                .next()
+                .with_context(|| format!("{} needs a value", flag))?,
+        };
+        if name.is_empty() {
+            let (name, value) = value
+                .split_once('=')
+                .with_context(|| format!("--set {:?} is not NAME=value", value))?;
+            vars.push((name.to_ascii_uppercase(), value.to_string()));
+        } else {
+            vars.push((name.to_string(), value));
+        }
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigLoader;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags() {
+        let vars = flags(args(&[
+            "--issuer",
+            "https://idp",
+            "--audience=api",
+            "--jwks-url",
+            "https://idp/keys",
+            "--leeway",
+            "30",
+            "--set",
+            "require_iat=true",
+        ]))
+        .unwrap();
+        let config = ConfigLoader::new()
+            .set("AUDIENCE", "from the environment")
+            .vars(vars)
+            .load()
+            .unwrap();
+        assert_eq!(config.authserver, "https://idp");
+        assert_eq!(config.audience.as_deref(), Some("api"));
+        assert_eq!(config.jwks_url.as_deref(), Some("https://idp/keys"));
+        assert_eq!(config.leeway_secs, Some(30));
+        assert_eq!(config.require_iat, Some(true));
+
+        assert!(flags(args(&["--issuer"])).is_err());
+        assert!(flags(args(&["--isuer", "https://idp"])).is_err());
+        assert!(flags(args(&["--set", "require_iat"])).is_err());
+    }
+}
@@ -1,9 +1,198 @@
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use jsonwebtoken::Algorithm;
 use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use url::Url;
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, Default)]
 pub struct Config {
+    /// Profile of the environment, `dev`, `staging` or `prod`, its variables prefixed
+    /// with its name in capitals overriding the others, e.g. `DEV_AUTHSERVER`
+    pub app_env: Option<String>,
+    /// Issuer, discovered at `discovery_path` under it
     pub authserver: String,
-    pub audience: String,
+    /// Audience tokens must be for; without it tokens for any audience are accepted
+    pub audience: Option<String>,
+    /// Seconds of clock skew tolerated when checking `exp`, `nbf` and `iat`
+    pub leeway_secs: Option<u64>,
+    /// `oidc` for OpenID Connect discovery (default), or `oauth` for RFC 8414 metadata
+    pub discovery: Option<String>,
+    /// Path of the OpenID Connect discovery document under `authserver`,
+    /// `/v2.0/.well-known/openid-configuration` by default
+    pub discovery_path: Option<String>,
+    /// JWK Set fetched instead of running discovery, `authserver` being the issuer
+    pub jwks_url: Option<String>,
+    /// Comma separated accepted algorithms, RS256,RS384,RS512 by default
+    pub algorithms: Option<Vec<String>>,
+    /// Attempts at fetching discovery and keys before giving up
+    pub fetch_attempts: Option<u32>,
+    /// Seconds before a discovery or JWKS request times out
+    pub http_timeout_secs: Option<u64>,
+    pub http_connect_timeout_secs: Option<u64>,
+    /// Proxy for discovery and JWKS requests
+    pub http_proxy: Option<String>,
+    /// PEM file with extra root certificates trusted for discovery and JWKS requests
+    pub http_ca_file: Option<String>,
+    /// Comma separated SPKI pins (`sha256/<base64>`) for the authserver, needs the `pinning` feature
+    pub http_pins: Option<Vec<String>>,
+    /// Seconds between background JWKS refreshes
+    pub jwks_refresh_secs: Option<u64>,
+    /// Seconds keys are still served after refreshes start failing
+    pub jwks_max_stale_secs: Option<u64>,
+    /// Largest accepted discovery or JWKS document in bytes, 1 MiB by default
+    pub jwks_max_bytes: Option<usize>,
+    /// Most keys accepted in a JWK Set, 100 by default
+    pub jwks_max_keys: Option<usize>,
+    /// Accept RSA keys under 2048 bits and HMAC secrets shorter than their hash
+    pub allow_weak_keys: Option<bool>,
+    /// File keeping the last fetched keys, used at startup when the authserver is down
+    pub jwks_cache_file: Option<String>,
+    /// JWK Set verifying tokens instead of discovery, for deployments without network
+    /// access to the authserver; an `authserver`, if set, is the required `iss`
+    pub jwks_json: Option<String>,
+    /// PEM public key of RS256 or ES256 tokens of any kid, like `jwks_json`
+    pub public_key_pem: Option<String>,
+    /// Secret of HS256 tokens of any kid, like `jwks_json`
+    pub hs256_secret: Option<String>,
+    /// Reject ID tokens, by `nonce`, Cognito `token_use` or the `typ` header
+    pub access_tokens_only: Option<bool>,
+    /// Reject tokens without `iat`
+    pub require_iat: Option<bool>,
+    /// Reject tokens issued in the future, beyond the leeway
+    pub reject_future_iat: Option<bool>,
+    /// Longest accepted token in bytes, 8192 by default
+    pub max_token_len: Option<usize>,
+    /// Salt of the token hashes in logs, shared by every instance to correlate tokens
+    pub token_hash_salt: Option<String>,
+    /// Realm of the `WWW-Authenticate` challenge on rejections
+    pub bearer_realm: Option<String>,
+    /// Seconds before expiry from which responses carry a renewal hint header
+    pub token_renewal_hint_secs: Option<u64>,
+    /// Name of the renewal hint header, `X-Token-Expires-In` by default
+    pub token_renewal_hint_header: Option<String>,
+    /// Prefix stripped from namespaced custom claims
+    pub claims_namespace: Option<String>,
+    /// Path to a JSON Schema file the claims must satisfy
+    pub claims_schema: Option<String>,
+    /// Open Policy Agent decision URL, used with the `opa` feature
+    pub opa_url: Option<String>,
+    /// Client id for browser logins at `/login`, used with the `login` feature
+    pub login_client_id: Option<String>,
+    pub login_client_secret: Option<String>,
+    /// Absolute URL of `/callback` registered at the authserver
+    pub login_redirect_uri: Option<String>,
+    /// Space separated scopes requested at login, `openid` by default
+    pub login_scope: Option<String>,
+    /// Where the authserver sends browsers after `/logout`
+    pub login_post_logout_redirect_uri: Option<String>,
+    /// Keep refresh tokens in a cookie and trade them for access tokens at `/token/refresh`
+    pub login_token_refresh: Option<bool>,
+    /// Accept back-channel logouts from the authserver at `/backchannel-logout`
+    pub login_backchannel_logout: Option<bool>,
+    /// Key signing the login cookie, shared by every instance; random by default
+    pub login_cookie_key: Option<String>,
+    /// Client credentials of this service for its outbound calls
+    pub service_client_id: Option<String>,
+    pub service_client_secret: Option<String>,
+    /// Space separated scopes of the service token
+    pub service_scope: Option<String>,
+    /// Audience of the service token, for authservers taking an `audience` parameter
+    pub service_audience: Option<String>,
+    /// PEM public key of local self-signed tokens, accepted from any issuer; needs the
+    /// `dangerous-dev-mode` feature. Without an authserver, no discovery is attempted.
+    pub dangerous_dev_local_key: Option<String>,
+    /// Like `dangerous_dev_local_key`, for HS256 tokens signed with this secret
+    pub dangerous_dev_local_secret: Option<String>,
+    /// Accept tokens for any audience; needs the `dangerous-dev-mode` feature
+    pub dangerous_dev_skip_audience: Option<bool>,
+    /// JSON file mapping the hex SHA-256 of each accepted API key to its claims
+    pub api_keys_file: Option<String>,
+    /// Header carrying API keys, `X-API-Key` by default
+    pub api_key_header: Option<String>,
+    /// Sentry DSN receiving operational failures, used with the `sentry` feature
+    pub sentry_dsn: Option<String>,
+    /// Secret manager of settings valued `secret:<name>`: `vault`, `aws` or `gcp`, each
+    /// needing its feature; AWS credentials come from the usual `AWS_` variables
+    pub secrets_source: Option<String>,
+    pub vault_addr: Option<String>,
+    pub vault_token: Option<String>,
+    /// Mount of the KV version 2 engine, `secret` by default
+    pub vault_mount: Option<String>,
+    pub gcp_project: Option<String>,
+    /// AWS KMS key verifying HMAC tokens, so the secret stays in KMS; needs the
+    /// aws-kms feature. Its algorithm is accepted besides `algorithms`.
+    pub kms_key_arn: Option<String>,
+    /// Algorithm of `kms_key_arn`, HS256 by default
+    pub kms_key_alg: Option<String>,
+    /// PKCS#11 module of an HSM verifying tokens with the key `pkcs11_key_label` of
+    /// the token `pkcs11_token`; needs the pkcs11 feature. `pkcs11_key_alg` is
+    /// accepted besides `algorithms`.
+    pub pkcs11_module: Option<String>,
+    pub pkcs11_token: Option<String>,
+    pub pkcs11_pin: Option<String>,
+    pub pkcs11_key_label: Option<String>,
+    /// Algorithm of the PKCS#11 key, RS256 by default
+    pub pkcs11_key_alg: Option<String>,
+    /// Azure Key Vault key verifying tokens, e.g.
+    /// `https://rapi.vault.azure.net/keys/signing`; needs the azure-keyvault feature.
+    /// `azure_key_alg` is accepted besides `algorithms`. The managed identity
+    /// authenticates unless the `azure_client_*` settings are given.
+    pub azure_key_id: Option<String>,
+    /// Algorithm of the Key Vault key, RS256 by default
+    pub azure_key_alg: Option<String>,
+    pub azure_tenant_id: Option<String>,
+    pub azure_client_id: Option<String>,
+    pub azure_client_secret: Option<String>,
+    pub sentry_environment: Option<String>,
+    /// Log every authentication decision as a line of JSON, target `rapi::events`
+    pub auth_event_log: Option<bool>,
+    /// Claim listing the scopes of tokens instead of `scope` and `scp`, e.g. `permissions`
+    pub scope_claim: Option<String>,
+    /// How `scope_claim` lists them: `space`, `array` or `any` (default)
+    pub scope_format: Option<String>,
+    /// Claim identifying users, `sub` by default, e.g. `oid` for Azure AD
+    pub user_id_claim: Option<String>,
+    /// Header carrying the request id echoed in rejections, `X-Request-Id` by default
+    pub request_id_header: Option<String>,
+    /// Seconds verified tokens are remembered, capped by their expiry; off by default
+    pub token_cache_secs: Option<u64>,
+    /// Comma separated `header={claim}` pairs set on accepted requests, e.g.
+    /// `X-User-Id={sub}`; the client's values of these headers are dropped
+    pub claim_headers: Option<Vec<String>>,
+    /// Comma separated identity headers dropped from incoming requests, a trailing `*`
+    /// matching any suffix, e.g. `X-Forwarded-User,X-Auth-*`
+    pub strip_headers: Option<Vec<String>>,
+    /// Accept tokens only from the networks of their `cnf.ip` claim
+    pub ip_claim_constraint: Option<bool>,
+    /// JSON file mapping subjects to the networks they may use tokens from
+    pub ip_allow_file: Option<String>,
+    /// Comma separated networks of the proxies in front of this service
+    pub trusted_proxies: Option<Vec<String>>,
+    /// Header the proxies name the client in: `X-Forwarded-For` (default),
+    /// `Forwarded` or `X-Real-IP`
+    pub client_ip_header: Option<String>,
+    /// What to do with several Authorization credentials: `reject` (default), `first`
+    /// bearer or `try-each` bearer until one verifies
+    pub multiple_credentials: Option<String>,
+    /// Comma separated `path=audience` pairs, a trailing `/**` matching every path
+    /// under one, e.g. `/internal/**=internal-api,/v1/**=public-api`
+    pub route_audiences: Option<Vec<String>>,
+    /// Comma separated claims every token must have, applied per request by
+    /// `JwtAuth::live_config`
+    pub required_claims: Option<Vec<String>>,
+    /// Comma separated scopes every token must grant, like `required_claims`
+    pub required_scopes: Option<Vec<String>>,
+    /// Most tokens in use at a time for each subject
+    pub max_sessions_per_subject: Option<usize>,
+    /// Claim identifying the client for rate limiting, e.g. `sub` or `azp`
+    pub rate_limit_claim: Option<String>,
+    /// Requests allowed per minute for each value of `rate_limit_claim`
+    pub rate_limit_per_minute: Option<u64>,
 }
 
 // Throw the Config struct into a CONFIG lazy_static to avoid multiple processing
@@ -13,8 +202,527 @@ lazy_static! {
 
 /// Use envy to inject dotenv and env vars into the Config struct
 fn get_config() -> Config {
-    match envy::from_env::<Config>() {
+    match ConfigLoader::from_env().and_then(ConfigLoader::load) {
         Ok(config) => config,
         Err(error) => panic!("Configuration Error: {:#?}", error),
     }
 }
+
+/// Layers of settings merged into a `Config`, each overriding the ones before it,
+/// e.g. defaults, a file, the environment and then overrides of the program:
+///
+/// ```no_run
+/// # fn main() -> anyhow::Result<()> {
+/// let config = rapi::config::ConfigLoader::new()
+///     .defaults(vec![("LEEWAY_SECS".to_string(), "30".to_string())])
+///     .file("/etc/rapi.json")?
+///     .env()
+///     .set("AUDIENCE", "api")
+///     .load()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Settings are named as the environment variables are, in any case, and the
+/// `APP_ENV` profile applies to the merged result.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigLoader {
+    vars: Vec<(String, String)>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        ConfigLoader::default()
+    }
+
+    /// The layers of `CONFIG`: the environment over the JSON file named by
+    /// `CONFIG_FILE`, if any
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => Ok(ConfigLoader::new().file(path)?.env()),
+            Err(_) => Ok(ConfigLoader::new().env()),
+        }
+    }
+
+    pub fn defaults(self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.vars(vars)
+    }
+
+    /// The settings of a JSON object, e.g. `{"authserver": "https://idp", "algorithms":
+    /// ["RS256", "ES256"]}`
+    pub fn file(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let document: Value = serde_json::from_str(&text)
+            .with_context(|| format!("{} is not JSON", path.display()))?;
+        let settings = document
+            .as_object()
+            .with_context(|| format!("{} is not a JSON object", path.display()))?;
+        let mut vars = Vec::new();
+        for (name, value) in settings {
+            let value = match value {
+                Value::Null => continue,
+                Value::String(s) => s.clone(),
+                Value::Bool(_) | Value::Number(_) => value.to_string(),
+                // Lists are comma separated, as in the environment
+                Value::Array(items) => items
+                    .iter()
+                    .map(|item| match item {
+                        Value::String(s) => Ok(s.clone()),
+                        Value::Bool(_) | Value::Number(_) => Ok(item.to_string()),
+                        _ => anyhow::bail!("{} in {} is not a flat list", name, path.display()),
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?
+                    .join(","),
+                Value::Object(_) => {
+                    anyhow::bail!("{} in {} is an object", name, path.display())
+                }
+            };
+            vars.push((name.clone(), value));
+        }
+        Ok(self.vars(vars))
+    }
+
+    /// The variables of the process
+    pub fn env(self) -> Self {
+        self.vars(std::env::vars())
+    }
+
+    pub fn vars(mut self, vars: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.vars.extend(vars);
+        self
+    }
+
+    pub fn set(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.vars(std::iter::once((name.into(), value.into())))
+    }
+
+    /// The merged configuration, to hand to e.g. `JwtAuth::initialize`
+    pub fn load(self) -> anyhow::Result<Config> {
+        Config::from_vars(self.vars)
+    }
+}
+
+/// The effective configuration, replaced whole by `reload` while requests keep
+/// reading it, e.g. through `JwtAuth::live_config`. Clones share it.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<ArcSwap<Config>>,
+    loader: Arc<dyn Fn() -> anyhow::Result<Config> + Send + Sync>,
+}
+
+impl ConfigHandle {
+    /// `config`, reloaded through `ConfigLoader::from_env`. `secret:` settings are
+    /// not resolved on reloads, none of the settings read per request being secret.
+    pub fn new(config: Config) -> Self {
+        ConfigHandle {
+            current: Arc::new(ArcSwap::from_pointee(config)),
+            loader: Arc::new(|| ConfigLoader::from_env()?.load()),
+        }
+    }
+
+    /// Reload through `loader` instead, e.g. one layering command line flags
+    pub fn loader(
+        mut self,
+        loader: impl Fn() -> anyhow::Result<Config> + Send + Sync + 'static,
+    ) -> Self {
+        self.loader = Arc::new(loader);
+        self
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.current.load_full()
+    }
+
+    /// Replace the configuration with `config` if it is valid
+    pub fn store(&self, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+        self.current.store(Arc::new(config));
+        Ok(())
+    }
+
+    /// Load the configuration again, keeping the current one if loading fails
+    pub fn reload(&self) -> anyhow::Result<Arc<Config>> {
+        let config = (self.loader)()?;
+        self.store(config)?;
+        Ok(self.get())
+    }
+}
+
+/// Most clock skew tolerated, beyond which tokens would outlive their expiry by long
+pub const MAX_LEEWAY_SECS: u64 = 300;
+
+/// Every invalid setting of a configuration
+#[derive(Debug)]
+pub struct ConfigError(pub Vec<InvalidSetting>);
+
+#[derive(Debug)]
+pub struct InvalidSetting {
+    /// The variable, e.g. `AUTHSERVER`
+    pub setting: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid configuration")?;
+        for (i, invalid) in self.0.iter().enumerate() {
+            let separator = if i == 0 { ": " } else { "; " };
+            write!(f, "{}{} {}", separator, invalid.setting, invalid.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An https URL, or plain http to this host
+fn check_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("{:?} is not a URL: {}", url, e))?;
+    let local = matches!(
+        parsed.host_str(),
+        Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+    );
+    match parsed.scheme() {
+        "https" => Ok(()),
+        "http" if local => Ok(()),
+        _ => Err(format!("{:?} is not https", url)),
+    }
+}
+
+/// The environments a binary is promoted across
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    /// Tolerates a minute of clock skew by default
+    Dev,
+    Staging,
+    /// Refuses dangerous dev settings and weak keys, and rejects tokens issued in
+    /// the future by default
+    Prod,
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dev" | "development" => Ok(Profile::Dev),
+            "staging" => Ok(Profile::Staging),
+            "prod" | "production" => Ok(Profile::Prod),
+            _ => anyhow::bail!("unknown profile {:?}", s),
+        }
+    }
+}
+
+impl Profile {
+    /// Prefix of the variables overriding the base configuration
+    fn prefix(self) -> &'static str {
+        match self {
+            Profile::Dev => "DEV_",
+            Profile::Staging => "STAGING_",
+            Profile::Prod => "PROD_",
+        }
+    }
+
+    /// Fill in the defaults of the profile, and refuse settings it forbids
+    fn apply(self, config: &mut Config) -> anyhow::Result<()> {
+        match self {
+            Profile::Dev => {
+                config.leeway_secs.get_or_insert(60);
+            }
+            Profile::Staging => {}
+            Profile::Prod => {
+                let dangerous = [
+                    (
+                        "DANGEROUS_DEV_LOCAL_KEY",
+                        config.dangerous_dev_local_key.is_some(),
+                    ),
+                    (
+                        "DANGEROUS_DEV_LOCAL_SECRET",
+                        config.dangerous_dev_local_secret.is_some(),
+                    ),
+                    (
+                        "DANGEROUS_DEV_SKIP_AUDIENCE",
+                        config.dangerous_dev_skip_audience == Some(true),
+                    ),
+                    ("ALLOW_WEAK_KEYS", config.allow_weak_keys == Some(true)),
+                ];
+                if let Some((name, _)) = dangerous.iter().find(|(_, set)| *set) {
+                    anyhow::bail!("{} is not allowed in the prod profile", name);
+                }
+                config.reject_future_iat.get_or_insert(true);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    /// The configuration of environment `vars`. With `APP_ENV` naming a profile,
+    /// variables prefixed with it override the unprefixed ones before the defaults of
+    /// the profile apply.
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> anyhow::Result<Self> {
+        let vars: Vec<(String, String)> = vars.into_iter().collect();
+        let profile = vars
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("APP_ENV"))
+            .map(|(_, value)| value.parse::<Profile>())
+            .transpose()?;
+        let mut merged: Vec<(String, String)> = Vec::new();
+        let overrides = profile.map(|profile| {
+            vars.iter()
+                .filter_map(|(name, value)| {
+                    let name = name.strip_prefix(profile.prefix())?;
+                    Some((name.to_string(), value.clone()))
+                })
+                .collect::<Vec<_>>()
+        });
+        for (name, value) in vars.iter().cloned().chain(overrides.into_iter().flatten()) {
+            merged.retain(|(merged_name, _)| !merged_name.eq_ignore_ascii_case(&name));
+            merged.push((name, value));
+        }
+        let mut config: Config = envy::from_iter(merged)?;
+        if let Some(profile) = profile {
+            profile.apply(&mut config)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the settings beyond their types, reporting every invalid one at once
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        let mut invalid = |setting: &'static str, message: String| {
+            errors.push(InvalidSetting { setting, message })
+        };
+        let static_keys = self.jwks_json.is_some()
+            || self.public_key_pem.is_some()
+            || self.hs256_secret.is_some();
+        let dev_keys =
+            self.dangerous_dev_local_key.is_some() || self.dangerous_dev_local_secret.is_some();
+        // Only a required `iss` with static keys, which need no discovery
+        if self.authserver.is_empty() {
+            if !static_keys && !dev_keys {
+                invalid("AUTHSERVER", "is required".into());
+            }
+        } else if !static_keys {
+            if let Err(message) = check_url(&self.authserver) {
+                invalid("AUTHSERVER", message);
+            }
+        }
+        if let Some(url) = &self.jwks_url {
+            if let Err(message) = check_url(url) {
+                invalid("JWKS_URL", message);
+            }
+        }
+        if self
+            .audience
+            .as_ref()
+            .is_some_and(|aud| aud.trim().is_empty())
+        {
+            invalid("AUDIENCE", "must not be empty".into());
+        }
+        if let Some(secs) = self.leeway_secs.filter(|secs| *secs > MAX_LEEWAY_SECS) {
+            invalid(
+                "LEEWAY_SECS",
+                format!("{} is over the {} allowed", secs, MAX_LEEWAY_SECS),
+            );
+        }
+        if let Some(path) = self.discovery_path.as_ref().filter(|p| !p.starts_with('/')) {
+            invalid(
+                "DISCOVERY_PATH",
+                format!("{:?} does not start with /", path),
+            );
+        }
+        match self.discovery.as_deref() {
+            None | Some("oidc") | Some("oauth") => {}
+            Some(other) => invalid("DISCOVERY", format!("{:?} is not oidc or oauth", other)),
+        }
+        if self.algorithms.as_ref().is_some_and(Vec::is_empty) {
+            invalid("ALGORITHMS", "must not be empty".into());
+        }
+        let algorithms = self
+            .algorithms
+            .iter()
+            .flatten()
+            .map(|alg| ("ALGORITHMS", alg));
+        let key_algs = [
+            ("KMS_KEY_ALG", &self.kms_key_alg),
+            ("PKCS11_KEY_ALG", &self.pkcs11_key_alg),
+            ("AZURE_KEY_ALG", &self.azure_key_alg),
+        ];
+        let key_algs = key_algs
+            .iter()
+            .filter_map(|(setting, alg)| Some((*setting, alg.as_ref()?)));
+        for (setting, alg) in algorithms.chain(key_algs) {
+            if Algorithm::from_str(alg).is_err() {
+                invalid(setting, format!("{:?} is not a supported algorithm", alg));
+            }
+        }
+        // A key verifies the algorithms of its own family only
+        let mut families: Vec<_> = self
+            .algorithms
+            .iter()
+            .flatten()
+            .filter_map(|alg| Algorithm::from_str(alg).ok().map(family))
+            .collect();
+        families.sort_unstable();
+        families.dedup();
+        if families.len() > 1 {
+            invalid(
+                "ALGORITHMS",
+                format!("mixes {} keys, accept one family", families.join(" and ")),
+            );
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(ConfigError(errors)),
+        }
+    }
+
+    /// The profile named by `app_env`
+    pub fn profile(&self) -> anyhow::Result<Option<Profile>> {
+        self.app_env.as_deref().map(str::parse).transpose()
+    }
+}
+
+/// The kind of key verifying `alg`
+fn family(alg: Algorithm) -> &'static str {
+    match alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => "HMAC",
+        Algorithm::ES256 | Algorithm::ES384 => "EC",
+        _ => "RSA",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_profiles() {
+        let base = [
+            ("AUTHSERVER", "https://idp"),
+            ("AUDIENCE", "api"),
+            ("DEV_AUTHSERVER", "http://localhost:8081"),
+            ("PROD_DANGEROUS_DEV_SKIP_AUDIENCE", "true"),
+        ];
+        let config = Config::from_vars(vars(&base)).unwrap();
+        assert_eq!(config.authserver, "https://idp");
+        assert_eq!(config.leeway_secs, None);
+
+        let dev = Config::from_vars(vars(&[&base[..], &[("APP_ENV", "dev")]].concat())).unwrap();
+        assert_eq!(dev.authserver, "http://localhost:8081");
+        assert_eq!(dev.leeway_secs, Some(60));
+        assert_eq!(dev.profile().unwrap(), Some(Profile::Dev));
+
+        let prod = vars(&[&base[..], &[("APP_ENV", "production")]].concat());
+        let err = Config::from_vars(prod).unwrap_err();
+        assert!(err.to_string().contains("DANGEROUS_DEV_SKIP_AUDIENCE"));
+        let prod = Config::from_vars(vars(&[
+            ("APP_ENV", "prod"),
+            ("AUTHSERVER", "https://idp"),
+            ("AUDIENCE", "api"),
+            ("PROD_LEEWAY_SECS", "5"),
+        ]))
+        .unwrap();
+        assert_eq!(prod.leeway_secs, Some(5));
+        assert_eq!(prod.reject_future_iat, Some(true));
+
+        assert!(Config::from_vars(vars(&[("APP_ENV", "qa")])).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let minimal = Config::from_vars(vars(&[("AUTHSERVER", "https://idp")])).unwrap();
+        assert_eq!(minimal.audience, None);
+        assert_eq!(minimal.discovery_path, None);
+        let err = Config::from_vars(vars(&[("AUDIENCE", "api")])).unwrap_err();
+        assert!(err.to_string().contains("authserver"));
+        let relative = vars(&[
+            ("AUTHSERVER", "https://idp"),
+            ("DISCOVERY_PATH", ".well-known/openid-configuration"),
+        ]);
+        let err = Config::from_vars(relative).unwrap_err();
+        let err = err.downcast::<ConfigError>().unwrap();
+        assert_eq!(err.0[0].setting, "DISCOVERY_PATH");
+        let valid = vars(&[("AUTHSERVER", "https://idp"), ("AUDIENCE", "api")]);
+        assert!(Config::from_vars(valid.clone()).is_ok());
+        let local = vars(&[("AUTHSERVER", "http://localhost:8081"), ("AUDIENCE", "api")]);
+        assert!(Config::from_vars(local).is_ok());
+
+        let invalid = vars(&[
+            ("AUTHSERVER", "http://idp"),
+            ("AUDIENCE", " "),
+            ("LEEWAY_SECS", "3600"),
+            ("ALGORITHMS", "RS256,XX1"),
+            ("KMS_KEY_ALG", "HS257"),
+        ]);
+        let err = Config::from_vars([valid.clone(), invalid].concat()).unwrap_err();
+        let err = err.downcast::<ConfigError>().unwrap();
+        let settings: Vec<_> = err.0.iter().map(|invalid| invalid.setting).collect();
+        assert_eq!(
+            settings,
+            [
+                "AUTHSERVER",
+                "AUDIENCE",
+                "LEEWAY_SECS",
+                "ALGORITHMS",
+                "KMS_KEY_ALG"
+            ]
+        );
+        assert!(err.to_string().contains("XX1"));
+        let mixed = vars(&[("ALGORITHMS", "RS256,PS256,HS256,ES256")]);
+        let err = Config::from_vars([valid.clone(), mixed].concat()).unwrap_err();
+        let err = err.downcast::<ConfigError>().unwrap();
+        assert_eq!(err.0.len(), 1);
+        assert_eq!(err.0[0].setting, "ALGORITHMS");
+        assert!(err.0[0].message.contains("EC and HMAC and RSA"));
+
+        // Without discovery, the authserver is only the issuer
+        let issuer = vars(&[("AUDIENCE", "api"), ("AUTHSERVER", "me")]);
+        assert!(Config::from_vars(issuer.clone()).is_err());
+        let secret = vars(&[("HS256_SECRET", "a secret of the full 32 bytes..!")]);
+        assert!(Config::from_vars([issuer, secret].concat()).is_ok());
+    }
+
+    #[test]
+    fn test_layers() {
+        let path = std::env::temp_dir().join(format!("config-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"authserver": "https://file", "audience": "api", "leeway_secs": 10,
+                "algorithms": ["RS256", "PS256"], "require_iat": true, "bearer_realm": null}"#,
+        )
+        .unwrap();
+        let config = ConfigLoader::new()
+            .defaults(vars(&[("LEEWAY_SECS", "30"), ("MAX_TOKEN_LEN", "4096")]))
+            .file(&path)
+            .unwrap()
+            .vars(vars(&[("AUTHSERVER", "https://env")]))
+            .set("audience", "programmatic")
+            .load()
+            .unwrap();
+        assert_eq!(config.authserver, "https://env");
+        assert_eq!(config.audience.as_deref(), Some("programmatic"));
+        assert_eq!(config.leeway_secs, Some(10));
+        assert_eq!(config.max_token_len, Some(4096));
+        assert_eq!(config.require_iat, Some(true));
+        assert_eq!(
+            config.algorithms,
+            Some(vec!["RS256".to_string(), "PS256".to_string()])
+        );
+
+        std::fs::write(&path, r#"{"authserver": {"url": "https://file"}}"#).unwrap();
+        assert!(ConfigLoader::new().file(&path).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
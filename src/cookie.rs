@@ -0,0 +1,199 @@
+//! Access tokens kept in browser cookies
+use actix_web::cookie::{Cookie, SameSite};
+use anyhow::{bail, Context};
+use jsonwebtoken::dangerous_insecure_decode;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{Map, Value};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Writes tokens into cookies with safe attributes, `Secure`, `HttpOnly` and
+/// `SameSite=Lax` by default, expiring along with the token. Optionally encrypts the
+/// value, keeping the claims from the browser. Pass it to `JwtAuth::token_cookie` to
+/// read the token back.
+#[derive(Clone)]
+pub struct TokenCookie {
+    name: String,
+    secure: bool,
+    http_only: bool,
+    same_site: SameSite,
+    domain: Option<String>,
+    path: String,
+    key: Option<Arc<LessSafeKey>>,
+}
+
+impl TokenCookie {
+    pub fn new(name: impl Into<String>) -> Self {
+        TokenCookie {
+            name: name.into(),
+            secure: true,
+            http_only: true,
+            same_site: SameSite::Lax,
+            domain: None,
+            path: "/".into(),
+            key: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Send the cookie over plain HTTP too, e.g. for local development
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Let scripts read the cookie
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = same_site;
+        self
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Encrypt the value with AES-256-GCM under this 32 byte key, shared by every
+    /// instance reading the cookie
+    pub fn encrypt(mut self, key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() != 32 {
+            bail!("cookie encryption keys are 32 bytes, got {}", key.len());
+        }
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| anyhow::anyhow!("invalid key"))?;
+        self.key = Some(Arc::new(LessSafeKey::new(key)));
+        Ok(self)
+    }
+
+    /// The cookie carrying `token`, expiring when the token does. The token is not
+    /// verified here.
+    pub fn cookie(&self, token: &str) -> anyhow::Result<Cookie<'static>> {
+        let claims = dangerous_insecure_decode::<Map<String, Value>>(token)
+            .context("not a JWT")?
+            .claims;
+        let value = match &self.key {
+            Some(key) => seal(key, &self.name, token)?,
+            None => token.to_string(),
+        };
+        let mut cookie = self.build(value);
+        if let Some(exp) = claims.get("exp").and_then(Value::as_u64) {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            cookie.set_max_age(time::Duration::seconds(exp.saturating_sub(now) as i64));
+        }
+        Ok(cookie)
+    }
+
+    /// The cookie removing the token, e.g. at logout
+    pub fn removal(&self) -> Cookie<'static> {
+        let mut cookie = self.build(String::new());
+        cookie.set_max_age(time::Duration::zero());
+        cookie.set_expires(time::OffsetDateTime::now_utc() - time::Duration::days(365));
+        cookie
+    }
+
+    /// The token in a cookie `value`, unless it fails to decrypt
+    pub fn open(&self, value: &str) -> Option<String> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Some(value.to_string()),
+        };
+        let sealed = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut ciphertext = ciphertext.to_vec();
+        let token = key
+            .open_in_place(nonce, Aad::from(self.name.as_bytes()), &mut ciphertext)
+            .ok()?;
+        String::from_utf8(token.to_vec()).ok()
+    }
+
+    fn build(&self, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::build(self.name.clone(), value)
+            .path(self.path.clone())
+            .secure(self.secure)
+            .http_only(self.http_only)
+            .same_site(self.same_site)
+            .finish();
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// A random nonce followed by the ciphertext, base64url encoded. The cookie name is
+/// the associated data, so values do not pass for other cookies.
+fn seal(key: &LessSafeKey, name: &str, token: &str) -> anyhow::Result<String> {
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("no randomness"))?;
+    let mut ciphertext = token.as_bytes().to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(name.as_bytes()),
+        &mut ciphertext,
+    )
+    .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+    let sealed = [&nonce[..], &ciphertext].concat();
+    Ok(base64::encode_config(sealed, base64::URL_SAFE_NO_PAD))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    #[test]
+    fn test_token_cookie() {
+        let exp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let token = encode(
+            &Header::default(),
+            &json!({"sub": "alice", "exp": exp}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        let plain = TokenCookie::new("session").domain("example.com");
+        let cookie = plain.cookie(&token).unwrap();
+        assert_eq!(cookie.value(), token);
+        assert_eq!(
+            (cookie.secure(), cookie.http_only(), cookie.same_site()),
+            (Some(true), Some(true), Some(SameSite::Lax))
+        );
+        assert_eq!(cookie.domain(), Some("example.com"));
+        let max_age = cookie.max_age().unwrap().whole_seconds();
+        assert!((599..=600).contains(&max_age));
+
+        let sealed = TokenCookie::new("session").encrypt(&[7; 32]).unwrap();
+        let cookie = sealed.cookie(&token).unwrap();
+        assert!(!cookie.value().contains(&token[..10]));
+        assert_eq!(sealed.open(cookie.value()).unwrap(), token);
+        let other = TokenCookie::new("other").encrypt(&[7; 32]).unwrap();
+        assert!(other.open(cookie.value()).is_none());
+        assert!(sealed.open(&token).is_none());
+        assert!(TokenCookie::new("session").encrypt(b"short").is_err());
+        assert_eq!(sealed.removal().max_age(), Some(time::Duration::zero()));
+    }
+}
@@ -0,0 +1,131 @@
+//! Cross-site request forgery checks for tokens read from cookies, which browsers
+//! send along with requests forged by other sites, unlike `Authorization` headers
+use crate::error::AuthError;
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{HeaderName, Method};
+use actix_web::HttpMessage;
+use rand::RngCore;
+use ring::constant_time::verify_slices_are_equal;
+use serde_json::{Map, Value};
+
+/// The cookie of the double submit pattern, readable by scripts of the site
+pub const CSRF_COOKIE: &str = "csrf_token";
+
+/// What the CSRF header must match
+#[derive(Clone, Debug)]
+pub enum CsrfSource {
+    /// Double submit: the value of this cookie, which other sites cannot read
+    Cookie(String),
+    /// Synchronizer token: the value of this claim of the session token
+    Claim(String),
+}
+
+/// Requires requests with state-changing methods that are authenticated by a session
+/// cookie to repeat a CSRF token in a header. Safe methods are never checked.
+#[derive(Clone, Debug)]
+pub struct CsrfProtection {
+    header: HeaderName,
+    source: CsrfSource,
+}
+
+impl Default for CsrfProtection {
+    fn default() -> Self {
+        CsrfProtection::double_submit(CSRF_COOKIE)
+    }
+}
+
+impl CsrfProtection {
+    /// `X-CSRF-Token` must equal the value of `cookie`
+    pub fn double_submit(cookie: impl Into<String>) -> Self {
+        CsrfProtection {
+            header: HeaderName::from_static("x-csrf-token"),
+            source: CsrfSource::Cookie(cookie.into()),
+        }
+    }
+
+    /// `X-CSRF-Token` must equal the value of `claim` in the session token
+    pub fn claim(claim: impl Into<String>) -> Self {
+        CsrfProtection {
+            source: CsrfSource::Claim(claim.into()),
+            ..CsrfProtection::default()
+        }
+    }
+
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// A fresh random CSRF token, e.g. for the double submit cookie
+    pub fn token() -> String {
+        let mut bytes = [0; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Whether `req`, carrying `claims` in a cookie, may proceed
+    pub fn check(
+        &self,
+        req: &ServiceRequest,
+        claims: &Map<String, Value>,
+    ) -> Result<(), AuthError> {
+        if matches!(
+            *req.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+        ) {
+            return Ok(());
+        }
+        let presented = req
+            .headers()
+            .get(&self.header)
+            .map(|value| value.as_bytes().to_vec())
+            .ok_or(AuthError::CsrfMismatch)?;
+        let expected = match &self.source {
+            CsrfSource::Cookie(name) => req.cookie(name).map(|cookie| cookie.value().to_string()),
+            CsrfSource::Claim(claim) => claims.get(claim).and_then(Value::as_str).map(String::from),
+        }
+        .filter(|expected| !expected.is_empty())
+        .ok_or(AuthError::CsrfMismatch)?;
+        verify_slices_are_equal(&presented, expected.as_bytes())
+            .map_err(|_| AuthError::CsrfMismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    #[test]
+    fn test_csrf() {
+        let claims = json!({"csrf": "c1"});
+        let claims = claims.as_object().unwrap();
+        let double_submit = CsrfProtection::default();
+        let synchronizer = CsrfProtection::claim("csrf");
+
+        let req = TestRequest::get().to_srv_request();
+        assert!(double_submit.check(&req, claims).is_ok());
+
+        let req = TestRequest::post()
+            .cookie(Cookie::new(CSRF_COOKIE, "c1"))
+            .header("x-csrf-token", "c1")
+            .to_srv_request();
+        assert!(double_submit.check(&req, claims).is_ok());
+        assert!(synchronizer.check(&req, claims).is_ok());
+
+        let req = TestRequest::post()
+            .cookie(Cookie::new(CSRF_COOKIE, "c1"))
+            .header("x-csrf-token", "c2")
+            .to_srv_request();
+        assert!(double_submit.check(&req, claims).is_err());
+        assert!(synchronizer.check(&req, claims).is_err());
+
+        let req = TestRequest::delete()
+            .cookie(Cookie::new(CSRF_COOKIE, "c1"))
+            .to_srv_request();
+        assert!(double_submit.check(&req, claims).is_err());
+        assert_ne!(CsrfProtection::token(), CsrfProtection::token());
+    }
+}
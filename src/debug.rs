@@ -0,0 +1,246 @@
+//! Reports of how tokens are judged, for debugging rejections in staging
+use crate::auth::JwtAuth;
+use crate::claims::TokenClaims;
+use crate::error::AuthError;
+use crate::relay::BearerToken;
+use crate::rules::ClaimRule;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures::future::LocalBoxFuture;
+use jsonwebtoken::{dangerous_insecure_decode, decode_header, Algorithm, Header};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// The validation settings tokens are verified with
+#[derive(Clone, Debug, Serialize)]
+pub struct ValidationReport {
+    pub algorithms: Vec<Algorithm>,
+    pub issuer: Option<String>,
+    pub audiences: Vec<String>,
+    pub leeway: u64,
+    pub validate_exp: bool,
+    pub validate_nbf: bool,
+    pub require_exp: bool,
+    pub require_iat: bool,
+    pub reject_future_iat: bool,
+    pub access_tokens_only: bool,
+    pub max_token_len: usize,
+}
+
+/// How `JwtAuth::verify` judged a token. The header and claims are decoded even
+/// when it is rejected, so they are only verified when `accepted`.
+#[derive(Clone, Debug, Serialize)]
+pub struct TokenReport {
+    pub accepted: bool,
+    /// The `AuthError::kind` of the rejection
+    pub error: Option<&'static str>,
+    pub reason: Option<String>,
+    pub header: Option<Header>,
+    pub claims: Option<Map<String, Value>>,
+    pub validation: ValidationReport,
+}
+
+/// The outcome of one check of `JwtAuth::diagnose`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum Outcome {
+    Passed {
+        detail: Option<String>,
+    },
+    Failed {
+        /// The `AuthError::kind` the middleware would reject with
+        error: &'static str,
+        reason: String,
+    },
+}
+
+/// One check of the pipeline, e.g. `signature` or `rule roles`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Step {
+    pub check: String,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+/// The checks a token went through, in order, up to the first that failed
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Diagnosis {
+    pub steps: Vec<Step>,
+    /// The claims as handlers would see them, if accepted
+    pub claims: Option<Map<String, Value>>,
+}
+
+impl Diagnosis {
+    pub fn accepted(&self) -> bool {
+        self.failure().is_none()
+    }
+
+    /// The check that failed, if any
+    pub fn failure(&self) -> Option<&Step> {
+        self.steps
+            .iter()
+            .find(|step| matches!(step.outcome, Outcome::Failed { .. }))
+    }
+
+    /// Record a passed `check`
+    pub(crate) fn pass(&mut self, check: impl Into<String>, detail: Option<String>) {
+        self.steps.push(Step {
+            check: check.into(),
+            outcome: Outcome::Passed { detail },
+        });
+    }
+
+    /// Record the failure of `check`, for returning the diagnosis
+    pub(crate) fn fail(mut self, check: impl Into<String>, error: &AuthError) -> Self {
+        self.steps.push(Step {
+            check: check.into(),
+            outcome: Outcome::Failed {
+                error: error.kind(),
+                reason: error.to_string(),
+            },
+        });
+        self
+    }
+}
+
+impl JwtAuth {
+    /// Verify `token`, reporting the reason of a rejection along with what it was
+    /// judged by. Checks of the middleware beyond `verify`, e.g. claim rules, are not
+    /// part of it.
+    pub async fn inspect(&self, token: &str) -> TokenReport {
+        let result = self.verify(token).await;
+        let decodable = token.len() <= self.validation_report().max_token_len;
+        let header = Some(token)
+            .filter(|_| decodable)
+            .and_then(|t| decode_header(t).ok());
+        let (accepted, error, reason, claims) = match result {
+            Ok(claims) => (true, None, None, Some(claims)),
+            Err(e) => {
+                let claims = Some(token)
+                    .filter(|_| decodable)
+                    .and_then(|t| dangerous_insecure_decode::<Map<String, Value>>(t).ok())
+                    .map(|t| t.claims);
+                (false, Some(e.kind()), Some(e.to_string()), claims)
+            }
+        };
+        TokenReport {
+            accepted,
+            error,
+            reason,
+            header,
+            claims,
+            validation: self.validation_report(),
+        }
+    }
+
+    /// A handler answering the `TokenReport` of the token in the request body, or else
+    /// of the request's own, to callers whose claims satisfy `admin`. It belongs
+    /// behind the middleware, which authenticates the callers, and only in staging,
+    /// since reports reveal the validation settings, e.g.
+    /// `.route("/debug/token", web::post().to(auth.claims_debugger(admin)))`.
+    pub fn claims_debugger(
+        &self,
+        admin: ClaimRule,
+    ) -> impl Fn(HttpRequest, String) -> LocalBoxFuture<'static, Result<HttpResponse, Error>>
+           + Clone
+           + 'static {
+        let auth = self.clone();
+        move |req, body| {
+            let auth = auth.clone();
+            let admin = admin.clone();
+            Box::pin(async move {
+                let (allowed, own) = {
+                    let extensions = req.extensions();
+                    let allowed = extensions
+                        .get::<TokenClaims>()
+                        .map(|claims| admin.check(&claims.0));
+                    let own = extensions
+                        .get::<BearerToken>()
+                        .map(|t| t.as_str().to_string());
+                    (allowed, own)
+                };
+                match allowed {
+                    None => return Ok(HttpResponse::Unauthorized().finish()),
+                    Some(false) => return Ok(HttpResponse::Forbidden().finish()),
+                    Some(true) => {}
+                }
+                let token = match body.trim() {
+                    "" => own,
+                    token => Some(token.to_string()),
+                };
+                Ok(match token {
+                    Some(token) => HttpResponse::Ok().json(auth.inspect(&token).await),
+                    None => HttpResponse::BadRequest().body("no token to inspect"),
+                })
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::Keys;
+    use crate::testing::TestKey;
+    use actix_web::{test, web, App};
+    use jsonwebtoken::{encode, Validation};
+
+    #[actix_rt::test]
+    async fn test_claims_debugger() {
+        let key = TestKey::rsa(2048).unwrap();
+        let mut keys = Keys::new();
+        keys.insert("0".into(), vec![key.decoding_key()]);
+        let auth = JwtAuth::new(Validation::new(Algorithm::RS256), keys);
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("0".into());
+        let admin = encode(
+            &header,
+            &serde_json::json!({"exp": 32503680000u64, "role": "admin"}),
+            &key.encoding_key,
+        )
+        .unwrap();
+        let expired = encode(&header, &serde_json::json!({"exp": 1}), &key.encoding_key).unwrap();
+
+        let mut app = test::init_service(App::new().service(
+            web::scope("").wrap(auth.clone().middleware()).route(
+                "/debug/token",
+                web::post().to(auth.claims_debugger(ClaimRule::equals("role", "admin"))),
+            ),
+        ))
+        .await;
+        let call = |body: &str| {
+            test::TestRequest::post()
+                .uri("/debug/token")
+                .header("Authorization", format!("Bearer {}", admin))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let report: Value = test::read_response_json(&mut app, call(&expired)).await;
+        assert_eq!(report["accepted"], false);
+        assert_eq!(report["error"], "invalid_token");
+        assert_eq!(report["header"]["kid"], "0");
+        assert_eq!(report["claims"]["exp"], 1);
+        assert_eq!(
+            report["validation"]["algorithms"],
+            serde_json::json!(["RS256"])
+        );
+
+        let report: Value = test::read_response_json(&mut app, call("")).await;
+        assert_eq!(report["accepted"], true);
+        assert_eq!(report["claims"]["role"], "admin");
+
+        let user = encode(
+            &header,
+            &serde_json::json!({"exp": 32503680000u64}),
+            &key.encoding_key,
+        )
+        .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/debug/token")
+            .header("Authorization", format!("Bearer {}", user))
+            .set_payload(admin)
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,138 @@
+//! Insecure shortcuts for local development, compiled in only with the
+//! `dangerous-dev-mode` feature. Never enable it in builds that get deployed.
+use crate::config::Config;
+use anyhow::{bail, Context};
+use jsonwebtoken::{Algorithm, DecodingKey};
+use log::warn;
+
+/// Relaxed validation for running a service without a full identity provider:
+/// accepting tokens signed by a local key, and tokens for any audience. Every
+/// setting is announced with a warning when the `JwtAuth` is built.
+#[derive(Clone, Default)]
+pub struct DangerousDevMode {
+    local_key: Option<(DecodingKey<'static>, Algorithm)>,
+    skip_audience: bool,
+}
+
+impl DangerousDevMode {
+    /// Accept RS256 tokens signed by the private half of this PEM public key, from
+    /// any issuer
+    pub fn local_rsa_pem(mut self, pem: &[u8]) -> anyhow::Result<Self> {
+        let key = DecodingKey::from_rsa_pem(pem)?.into_static();
+        self.local_key = Some((key, Algorithm::RS256));
+        Ok(self)
+    }
+
+    /// Accept ES256 tokens signed by the private half of this PEM public key, from
+    /// any issuer
+    pub fn local_ec_pem(mut self, pem: &[u8]) -> anyhow::Result<Self> {
+        let key = DecodingKey::from_ec_pem(pem)?.into_static();
+        self.local_key = Some((key, Algorithm::ES256));
+        Ok(self)
+    }
+
+    /// Accept HS256 tokens signed with this secret, from any issuer
+    pub fn local_secret(mut self, secret: &[u8]) -> Self {
+        let key = DecodingKey::from_secret(secret).into_static();
+        self.local_key = Some((key, Algorithm::HS256));
+        self
+    }
+
+    /// Accept tokens whatever their `aud`
+    pub fn skip_audience(mut self) -> Self {
+        self.skip_audience = true;
+        self
+    }
+
+    /// The dev mode configured by `DANGEROUS_DEV_*` settings, if any
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let mut mode = DangerousDevMode::default();
+        let mut configured = false;
+        if let Some(path) = &config.dangerous_dev_local_key {
+            let pem = std::fs::read(path).with_context(|| format!("reading {}", path))?;
+            // SPKI documents look alike for RSA and EC keys
+            mode = mode
+                .clone()
+                .local_rsa_pem(&pem)
+                .or_else(|_| mode.local_ec_pem(&pem))
+                .with_context(|| format!("no RSA or P-256 public key in {}", path))?;
+            configured = true;
+        }
+        if let Some(secret) = &config.dangerous_dev_local_secret {
+            if mode.local_key.is_some() {
+                bail!("DANGEROUS_DEV_LOCAL_KEY and DANGEROUS_DEV_LOCAL_SECRET exclude each other");
+            }
+            mode = mode.local_secret(secret.as_bytes());
+            configured = true;
+        }
+        if config.dangerous_dev_skip_audience == Some(true) {
+            mode = mode.skip_audience();
+            configured = true;
+        }
+        Ok(Some(mode).filter(|_| configured))
+    }
+
+    pub(crate) fn local_key(&self) -> Option<&(DecodingKey<'static>, Algorithm)> {
+        self.local_key.as_ref()
+    }
+
+    pub(crate) fn skips_audience(&self) -> bool {
+        self.skip_audience
+    }
+
+    pub(crate) fn warn(&self) {
+        warn!("!!! DANGEROUS DEV MODE: token validation is relaxed, never deploy this build !!!");
+        if let Some((_, alg)) = &self.local_key {
+            warn!("!!! DANGEROUS DEV MODE: accepting {:?} tokens signed by a local key, from any issuer !!!", alg);
+        }
+        if self.skip_audience {
+            warn!("!!! DANGEROUS DEV MODE: accepting tokens for any audience !!!");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtAuth;
+    use crate::keystore::Keys;
+    use jsonwebtoken::{encode, EncodingKey, Header, Validation};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_dev_mode() {
+        let claims = json!({"sub": "dev", "aud": "other", "iss": "laptop", "exp": 4102444800u64});
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(b"dev"),
+        )
+        .unwrap();
+        let validation = Validation {
+            aud: Some(std::iter::once("api".to_string()).collect()),
+            iss: Some("me".into()),
+            ..Validation::new(Algorithm::RS256)
+        };
+        let auth = JwtAuth::new(validation, Keys::new());
+        assert!(auth.verify(&token).await.is_err());
+
+        let local = DangerousDevMode::default().local_secret(b"dev");
+        let dev = auth.clone().dangerous_dev_mode(local.clone());
+        assert!(dev.verify(&token).await.is_err());
+        let dev = auth.dangerous_dev_mode(local.skip_audience());
+        assert_eq!(dev.verify(&token).await.unwrap()["sub"], "dev");
+
+        // No discovery without an authserver
+        let config = Config {
+            audience: Some("api".into()),
+            dangerous_dev_local_secret: Some("dev".into()),
+            dangerous_dev_skip_audience: Some(true),
+            ..Config::default()
+        };
+        let auth = JwtAuth::initialize(&config).await.unwrap();
+        assert_eq!(auth.verify(&token).await.unwrap()["iss"], "laptop");
+        assert!(DangerousDevMode::from_config(&Config::default())
+            .unwrap()
+            .is_none());
+    }
+}
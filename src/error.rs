@@ -0,0 +1,558 @@
+use crate::extract::HeaderProblem;
+use actix_web::http::{header, HeaderValue, StatusCode};
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Reasons a request is rejected by the validator
+#[derive(Debug)]
+pub enum AuthError {
+    MissingToken,
+    /// No bearer token for a reason the `HeaderPolicy` answers as configured
+    MalformedHeader {
+        problem: HeaderProblem,
+        bad_request: bool,
+    },
+    BadToken,
+    TokenTooLarge,
+    MissingKid,
+    UnknownKid,
+    KeysExpired,
+    /// The token declares an algorithm that is not accepted, e.g. HS256 where RS256 is expected
+    AlgorithmMismatch,
+    InvalidToken,
+    /// An ID token or other token that is not an access token
+    NotAccessToken,
+    /// The token has no `exp`, so would be valid forever
+    MissingExp,
+    /// The token lacks `iat` where it is required, or was issued in the future
+    InvalidIat,
+    /// The token is not for the audience of the requested path
+    WrongAudience,
+    /// The API key presented instead of a token is not known
+    InvalidApiKey,
+    ApiKeyUnavailable,
+    /// The identity provider refused the Basic credentials
+    InvalidCredentials,
+    CredentialsUnavailable,
+    /// A request authenticated by a cookie lacks the matching CSRF token
+    CsrfMismatch,
+    /// The token lacks the space separated scopes
+    InsufficientScope(String),
+    /// The token is used from a network it is not allowed from
+    IpNotAllowed,
+    /// The client certificate identity is not among the allowed ones
+    UnknownCertificate,
+    /// The session or subject of the token was revoked, e.g. by a logout
+    Revoked,
+    RevocationUnavailable,
+    /// The subject already has as many sessions as allowed
+    TooManySessions,
+    SessionsUnavailable,
+    /// The groups left out of the token could not be looked up
+    GroupsUnavailable,
+    ClaimsSchema(Vec<SchemaViolation>),
+    ClaimRule(String),
+    PolicyDenied,
+    PolicyUnavailable,
+    RateLimited {
+        limit: u64,
+        reset_in: Duration,
+    },
+    RateLimitUnavailable,
+}
+
+/// A single reason the claims did not satisfy the configured JSON Schema
+#[derive(Clone, Debug, Serialize)]
+pub struct SchemaViolation {
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: String,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    reasons: &'a [SchemaViolation],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingToken => write!(f, "missing token"),
+            AuthError::MalformedHeader { problem, .. } => write!(f, "{}", problem),
+            AuthError::BadToken => write!(f, "bad token"),
+            AuthError::TokenTooLarge => write!(f, "token too large"),
+            AuthError::MissingKid => write!(f, "token missing kid"),
+            AuthError::UnknownKid => write!(f, "invalid kid in token"),
+            AuthError::KeysExpired => write!(f, "signing keys unavailable"),
+            AuthError::AlgorithmMismatch => write!(f, "token algorithm not accepted"),
+            AuthError::InvalidToken => write!(f, "invalid token"),
+            AuthError::NotAccessToken => write!(f, "not an access token"),
+            AuthError::MissingExp => write!(f, "token without expiry"),
+            AuthError::InvalidIat => write!(f, "invalid issue time"),
+            AuthError::WrongAudience => write!(f, "token not for this audience"),
+            AuthError::InvalidApiKey => write!(f, "invalid api key"),
+            AuthError::ApiKeyUnavailable => write!(f, "api key check failed"),
+            AuthError::InvalidCredentials => write!(f, "invalid credentials"),
+            AuthError::CredentialsUnavailable => write!(f, "credentials check failed"),
+            AuthError::UnknownCertificate => write!(f, "client certificate not accepted"),
+            AuthError::CsrfMismatch => write!(f, "csrf token mismatch"),
+            AuthError::IpNotAllowed => write!(f, "token not allowed from this address"),
+            AuthError::InsufficientScope(scope) => write!(f, "missing scope {}", scope),
+            AuthError::Revoked => write!(f, "token revoked"),
+            AuthError::RevocationUnavailable => write!(f, "revocation check failed"),
+            AuthError::TooManySessions => write!(f, "too many concurrent sessions"),
+            AuthError::SessionsUnavailable => write!(f, "session check failed"),
+            AuthError::GroupsUnavailable => write!(f, "group lookup failed"),
+            AuthError::ClaimsSchema(_) => write!(f, "claims do not match schema"),
+            AuthError::ClaimRule(claim) => write!(f, "claim requirement not met: {}", claim),
+            AuthError::PolicyDenied => write!(f, "denied by policy"),
+            AuthError::PolicyUnavailable => write!(f, "policy evaluation failed"),
+            AuthError::RateLimited { .. } => write!(f, "rate limit exceeded"),
+            AuthError::RateLimitUnavailable => write!(f, "rate limit check failed"),
+        }
+    }
+}
+
+impl AuthError {
+    /// A short snake_case name of the reason, e.g. for metric labels
+    pub fn kind(&self) -> &'static str {
+        match self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::MalformedHeader { .. } => "malformed_header",
+            AuthError::BadToken => "bad_token",
+            AuthError::TokenTooLarge => "token_too_large",
+            AuthError::MissingKid => "missing_kid",
+            AuthError::UnknownKid => "unknown_kid",
+            AuthError::KeysExpired => "keys_expired",
+            AuthError::AlgorithmMismatch => "algorithm_mismatch",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::NotAccessToken => "not_access_token",
+            AuthError::MissingExp => "missing_exp",
+            AuthError::InvalidIat => "invalid_iat",
+            AuthError::WrongAudience => "wrong_audience",
+            AuthError::InvalidApiKey => "invalid_api_key",
+            AuthError::ApiKeyUnavailable => "api_key_unavailable",
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::CredentialsUnavailable => "credentials_unavailable",
+            AuthError::UnknownCertificate => "unknown_certificate",
+            AuthError::CsrfMismatch => "csrf_mismatch",
+            AuthError::IpNotAllowed => "ip_not_allowed",
+            AuthError::InsufficientScope(_) => "insufficient_scope",
+            AuthError::Revoked => "revoked",
+            AuthError::RevocationUnavailable => "revocation_unavailable",
+            AuthError::TooManySessions => "too_many_sessions",
+            AuthError::SessionsUnavailable => "sessions_unavailable",
+            AuthError::GroupsUnavailable => "groups_unavailable",
+            AuthError::ClaimsSchema(_) => "claims_schema",
+            AuthError::ClaimRule(_) => "claim_rule",
+            AuthError::PolicyDenied => "policy_denied",
+            AuthError::PolicyUnavailable => "policy_unavailable",
+            AuthError::RateLimited { .. } => "rate_limited",
+            AuthError::RateLimitUnavailable => "rate_limit_unavailable",
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MalformedHeader {
+                bad_request: true, ..
+            } => StatusCode::BAD_REQUEST,
+            AuthError::MalformedHeader { .. } => StatusCode::UNAUTHORIZED,
+            AuthError::BadToken
+            | AuthError::TokenTooLarge
+            | AuthError::MissingKid
+            | AuthError::UnknownKid => StatusCode::BAD_REQUEST,
+            AuthError::MissingToken
+            | AuthError::AlgorithmMismatch
+            | AuthError::InvalidToken
+            | AuthError::NotAccessToken
+            | AuthError::MissingExp
+            | AuthError::InvalidIat
+            | AuthError::WrongAudience
+            | AuthError::InvalidApiKey
+            | AuthError::UnknownCertificate
+            | AuthError::InvalidCredentials
+            | AuthError::Revoked
+            | AuthError::ClaimsSchema(_) => StatusCode::UNAUTHORIZED,
+            AuthError::ClaimRule(_)
+            | AuthError::PolicyDenied
+            | AuthError::CsrfMismatch
+            | AuthError::IpNotAllowed
+            | AuthError::InsufficientScope(_)
+            | AuthError::TooManySessions => StatusCode::FORBIDDEN,
+            AuthError::KeysExpired
+            | AuthError::ApiKeyUnavailable
+            | AuthError::CredentialsUnavailable
+            | AuthError::RevocationUnavailable
+            | AuthError::GroupsUnavailable
+            | AuthError::SessionsUnavailable
+            | AuthError::PolicyUnavailable
+            | AuthError::RateLimitUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            AuthError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AuthError::ClaimsSchema(reasons) => {
+                HttpResponse::build(self.status_code()).json(ErrorBody {
+                    error: self.to_string(),
+                    reasons,
+                    request_id: None,
+                })
+            }
+            AuthError::RateLimited { limit, reset_in } => {
+                let reset = reset_in.as_secs() + u64::from(reset_in.subsec_nanos() > 0);
+                HttpResponse::build(self.status_code())
+                    .header("X-RateLimit-Limit", limit.to_string())
+                    .header("X-RateLimit-Remaining", "0")
+                    .header("X-RateLimit-Reset", reset.to_string())
+                    .header("Retry-After", reset.to_string())
+                    .content_type("text/plain; charset=utf-8")
+                    .body(self.to_string())
+            }
+            AuthError::InsufficientScope(scope) => {
+                let mut resp = HttpResponse::build(self.status_code());
+                if let Some(challenge) = Challenge::default()
+                    .scope(scope.as_str())
+                    .header_value(self, "")
+                {
+                    resp.header(header::WWW_AUTHENTICATE, challenge);
+                }
+                resp.content_type("text/plain; charset=utf-8")
+                    .body(self.to_string())
+            }
+            _ => HttpResponse::build(self.status_code())
+                .content_type("text/plain; charset=utf-8")
+                .body(self.to_string()),
+        }
+    }
+}
+
+/// Parameters of the `WWW-Authenticate: Bearer` challenge (RFC 6750) sent with
+/// 400, 401 and 403 rejections
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    pub realm: Option<String>,
+    /// Scope needed for access, e.g. `read write`
+    pub scope: Option<String>,
+    /// Include `error`, e.g. `invalid_token`
+    pub error: bool,
+    /// Include `error_description`, the text of the rejection
+    pub error_description: bool,
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Challenge {
+            realm: None,
+            scope: None,
+            error: true,
+            error_description: false,
+        }
+    }
+}
+
+impl Challenge {
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+
+    /// The header value for a rejection with `error`, if it gets a challenge. As in
+    /// RFC 6750, requests without credentials get no `error`, and only a lacking
+    /// scope is `insufficient_scope`, other refusals getting none either.
+    pub fn header_value(&self, error: &AuthError, description: &str) -> Option<String> {
+        let error = match (error, error.status_code()) {
+            (AuthError::MissingToken, _) => None,
+            (AuthError::InsufficientScope(_), _) => Some("insufficient_scope"),
+            (_, StatusCode::BAD_REQUEST) => Some("invalid_request"),
+            (_, StatusCode::UNAUTHORIZED) => Some("invalid_token"),
+            (_, StatusCode::FORBIDDEN) => None,
+            _ => return None,
+        };
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut params = Vec::new();
+        if let Some(realm) = &self.realm {
+            params.push(format!("realm={}", quote(realm)));
+        }
+        if let Some(scope) = &self.scope {
+            params.push(format!("scope={}", quote(scope)));
+        }
+        if let Some(error) = error.filter(|_| self.error) {
+            params.push(format!("error={}", quote(error)));
+            if self.error_description {
+                params.push(format!("error_description={}", quote(description)));
+            }
+        }
+        Some(if params.is_empty() {
+            "Bearer".into()
+        } else {
+            format!("Bearer {}", params.join(", "))
+        })
+    }
+}
+
+/// How rejections are answered, chosen by the `Accept` header of the request:
+/// JSON for `application/json`, plain text otherwise, and for browsers asking
+/// for `text/html` a page or a redirect when configured
+#[derive(Clone, Debug)]
+pub struct ErrorPages {
+    /// Answer `{"error": ...}` to clients accepting JSON
+    pub json: bool,
+    /// HTML page for browsers, `{error}` is replaced with the reason and
+    /// `{request_id}` with the request id
+    pub html: Option<String>,
+    /// Send browsers lacking a valid token here instead, e.g. a login page
+    pub login_redirect: Option<String>,
+    /// Query parameter of the login redirect carrying the requested URL back
+    pub redirect_param: String,
+}
+
+impl Default for ErrorPages {
+    fn default() -> Self {
+        ErrorPages {
+            json: true,
+            html: None,
+            login_redirect: None,
+            redirect_param: "redirect_uri".into(),
+        }
+    }
+}
+
+/// The body format of a rejection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+    Html,
+}
+
+impl ErrorPages {
+    /// Answer browsers without a valid token with a redirect to `url`
+    pub fn login_redirect(mut self, url: impl Into<String>) -> Self {
+        self.login_redirect = Some(url.into());
+        self
+    }
+
+    /// The login URL leading back to `requested` after login
+    pub fn login_location(&self, requested: &str) -> Option<String> {
+        let login = self.login_redirect.as_ref()?;
+        let separator = if login.contains('?') { '&' } else { '?' };
+        let back: String = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair(&self.redirect_param, requested)
+            .finish();
+        Some(format!("{}{}{}", login, separator, back))
+    }
+
+    /// The first format in `accept` this configuration can answer with
+    pub fn negotiate(&self, accept: &str) -> ErrorFormat {
+        let browser = self.html.is_some() || self.login_redirect.is_some();
+        for media in accept.split(',') {
+            match media.split(';').next().unwrap_or("").trim() {
+                "application/json" if self.json => return ErrorFormat::Json,
+                "text/html" if browser => return ErrorFormat::Html,
+                "text/plain" => return ErrorFormat::Text,
+                _ => {}
+            }
+        }
+        ErrorFormat::Text
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `resp` with its body replaced, keeping status and headers
+fn with_body(resp: HttpResponse, content_type: &str, body: String) -> HttpResponse {
+    let mut new = HttpResponse::build(resp.status())
+        .content_type(content_type)
+        .body(body);
+    for (name, value) in resp.headers() {
+        if name != header::CONTENT_TYPE {
+            new.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    new
+}
+
+/// An `AuthError` answered along with the configured challenge, in the negotiated format
+#[derive(Debug)]
+pub struct Rejection {
+    pub error: AuthError,
+    pub challenge: Arc<Challenge>,
+    pub pages: Arc<ErrorPages>,
+    pub format: ErrorFormat,
+    /// The absolute URL of the rejected request
+    pub requested: String,
+    /// Echoed in the body for support to find the request in the logs
+    pub request_id: Option<String>,
+}
+
+impl Rejection {
+    /// `resp` with a JSON or plain text body mentioning the request id
+    fn with_request_id(&self, resp: HttpResponse, json: bool) -> HttpResponse {
+        let reasons = match &self.error {
+            AuthError::ClaimsSchema(reasons) => &reasons[..],
+            _ => &[],
+        };
+        // Schema violations are always answered in JSON
+        if json || matches!(self.error, AuthError::ClaimsSchema(_)) {
+            let body = ErrorBody {
+                error: self.error.to_string(),
+                reasons,
+                request_id: self.request_id.as_deref(),
+            };
+            let body = serde_json::to_string(&body).unwrap_or_default();
+            return with_body(resp, "application/json", body);
+        }
+        match &self.request_id {
+            Some(id) => {
+                let body = format!("{} (request id {})", self.error, id);
+                with_body(resp, "text/plain; charset=utf-8", body)
+            }
+            None => resp,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ResponseError for Rejection {
+    fn status_code(&self) -> StatusCode {
+        self.error.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let resp = self.error.error_response();
+        let mut resp = match self.format {
+            ErrorFormat::Html => {
+                match (self.pages.login_location(&self.requested), &self.pages.html) {
+                    (Some(location), _) if self.status_code() == StatusCode::UNAUTHORIZED => {
+                        return HttpResponse::Found()
+                            .header(header::LOCATION, location)
+                            .finish();
+                    }
+                    (_, Some(page)) => {
+                        let page = page
+                            .replace("{error}", &escape_html(&self.error.to_string()))
+                            .replace(
+                                "{request_id}",
+                                &escape_html(self.request_id.as_deref().unwrap_or_default()),
+                            );
+                        with_body(resp, "text/html; charset=utf-8", page)
+                    }
+                    _ => self.with_request_id(resp, false),
+                }
+            }
+            ErrorFormat::Json => self.with_request_id(resp, true),
+            ErrorFormat::Text => self.with_request_id(resp, false),
+        };
+        let value = self
+            .challenge
+            .header_value(&self.error, &self.error.to_string())
+            .and_then(|value| HeaderValue::from_str(&value).ok());
+        if let Some(value) = value {
+            resp.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        resp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge() {
+        let challenge = Challenge {
+            error_description: true,
+            ..Challenge::default()
+        }
+        .realm("api \"gw\"")
+        .scope("read");
+        assert_eq!(
+            challenge.header_value(&AuthError::InvalidToken, "invalid token"),
+            Some(
+                r#"Bearer realm="api \"gw\"", scope="read", error="invalid_token", error_description="invalid token""#
+                    .into()
+            )
+        );
+        assert_eq!(
+            challenge.header_value(&AuthError::MissingToken, "missing token"),
+            Some(r#"Bearer realm="api \"gw\"", scope="read""#.into())
+        );
+        let challenge = Challenge::default();
+        assert_eq!(
+            challenge.header_value(&AuthError::UnknownKid, ""),
+            Some(r#"Bearer error="invalid_request""#.into())
+        );
+        assert_eq!(
+            challenge.header_value(&AuthError::InsufficientScope("read".into()), ""),
+            Some(r#"Bearer error="insufficient_scope""#.into())
+        );
+        for error in &[AuthError::CsrfMismatch, AuthError::IpNotAllowed] {
+            assert_eq!(challenge.header_value(error, ""), Some("Bearer".into()));
+        }
+        let challenge = Challenge {
+            error: false,
+            ..Challenge::default()
+        };
+        assert_eq!(
+            challenge.header_value(&AuthError::InsufficientScope("read".into()), ""),
+            Some("Bearer".into())
+        );
+        let limited = AuthError::RateLimited {
+            limit: 1,
+            reset_in: std::time::Duration::from_secs(1),
+        };
+        assert_eq!(challenge.header_value(&limited, ""), None);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let pages = ErrorPages::default();
+        assert_eq!(pages.negotiate("application/json"), ErrorFormat::Json);
+        assert_eq!(pages.negotiate("text/html, */*"), ErrorFormat::Text);
+        let pages = ErrorPages {
+            html: Some("<p>{error}</p>".into()),
+            ..ErrorPages::default()
+        };
+        assert_eq!(
+            pages.negotiate("text/html,application/json;q=0.9"),
+            ErrorFormat::Html
+        );
+        assert_eq!(pages.negotiate(""), ErrorFormat::Text);
+    }
+
+    #[test]
+    fn test_login_location() {
+        let pages = ErrorPages::default().login_redirect("/login?app=1");
+        assert_eq!(
+            pages.login_location("https://example.com/a?b=c").unwrap(),
+            "/login?app=1&redirect_uri=https%3A%2F%2Fexample.com%2Fa%3Fb%3Dc"
+        );
+        assert_eq!(ErrorPages::default().login_location("/"), None);
+    }
+}
@@ -0,0 +1,109 @@
+//! Authentication decisions as JSON events, for log pipelines such as ELK
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Log target of the events, e.g. to route them to their own output
+pub const EVENT_TARGET: &str = "rapi::events";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+}
+
+/// One authentication decision, logged as a single line of JSON at info level.
+/// Tokens appear only as their `TokenHash`; kid and issuer of rejected tokens are
+/// as claimed, unverified.
+#[derive(Clone, Debug, Serialize)]
+pub struct AuthEvent<'a> {
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub outcome: Outcome,
+    /// The `AuthError::kind` of rejections
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+    /// The status rejections are answered with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    pub method: &'a str,
+    pub path: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+    /// As the `TrustedProxies` tell it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iss: Option<&'a str>,
+    /// The `UserId` of accepted principals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<&'a str>,
+}
+
+impl<'a> AuthEvent<'a> {
+    /// An event at the current time, the remaining fields left out
+    pub fn new(outcome: Outcome, method: &'a str, path: &'a str) -> Self {
+        AuthEvent {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            outcome,
+            reason: None,
+            status: None,
+            method,
+            path,
+            request_id: None,
+            client_ip: None,
+            token: None,
+            kid: None,
+            iss: None,
+            user_id: None,
+        }
+    }
+
+    pub fn emit(&self) {
+        match serde_json::to_string(self) {
+            Ok(line) => log::info!(target: EVENT_TARGET, "{}", line),
+            Err(e) => log::warn!("unserializable auth event: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_auth_event() {
+        let event = AuthEvent {
+            reason: Some("invalid_token"),
+            status: Some(401),
+            kid: Some("k1"),
+            request_id: Some("r-1"),
+            client_ip: Some("10.0.0.5"),
+            ..AuthEvent::new(Outcome::Rejected, "GET", "/orders")
+        };
+        let mut line: Value =
+            serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+        assert!(line["timestamp"].as_u64().unwrap() > 0);
+        line.as_object_mut().unwrap().remove("timestamp");
+        assert_eq!(
+            line,
+            json!({
+                "outcome": "rejected",
+                "reason": "invalid_token",
+                "status": 401,
+                "method": "GET",
+                "path": "/orders",
+                "request_id": "r-1",
+                "client_ip": "10.0.0.5",
+                "kid": "k1",
+            })
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::error::AuthError;
+use crate::redact::TokenHash;
+use crate::relay::BearerToken;
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::http::header;
+use actix_web::{Error, FromRequest, HttpRequest};
+use actix_web_httpauth::extractors::AuthExtractor;
+use futures::future::{ready, Ready};
+use std::fmt;
+use std::str::FromStr;
+
+/// Why a request has no bearer token in its `Authorization` header
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HeaderProblem {
+    Absent,
+    /// Another scheme than `Bearer`, e.g. `Basic`
+    WrongScheme,
+    /// More than one `Authorization` header
+    Multiple,
+    NotUtf8,
+}
+
+impl fmt::Display for HeaderProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HeaderProblem::Absent => "no authorization header",
+            HeaderProblem::WrongScheme => "authorization scheme is not bearer",
+            HeaderProblem::Multiple => "multiple authorization headers",
+            HeaderProblem::NotUtf8 => "authorization header is not utf-8",
+        })
+    }
+}
+
+/// The status each `HeaderProblem` of a rejected request is answered with, 401 by
+/// default except 400 for multiple and non UTF-8 headers. Applies to the
+/// `JwtAuth::middleware`; the `BearerAuth` of `JwtAuth::validator` answers on its own.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeaderPolicy {
+    bad_request: [bool; 4],
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        HeaderPolicy {
+            bad_request: [false; 4],
+        }
+        .bad_request(HeaderProblem::Multiple)
+        .bad_request(HeaderProblem::NotUtf8)
+    }
+}
+
+impl HeaderPolicy {
+    /// Answer `problem` with 400
+    pub fn bad_request(mut self, problem: HeaderProblem) -> Self {
+        self.bad_request[problem as usize] = true;
+        self
+    }
+
+    /// Answer `problem` with 401 and a challenge
+    pub fn unauthorized(mut self, problem: HeaderProblem) -> Self {
+        self.bad_request[problem as usize] = false;
+        self
+    }
+
+    /// The rejection of a request without credentials for `problem`
+    pub fn error(&self, problem: HeaderProblem) -> AuthError {
+        match (problem, self.bad_request[problem as usize]) {
+            (HeaderProblem::Absent, false) => AuthError::MissingToken,
+            (problem, bad_request) => AuthError::MalformedHeader {
+                problem,
+                bad_request,
+            },
+        }
+    }
+}
+
+/// What the middleware does with requests carrying several credentials, in several
+/// `Authorization` headers or comma separated in one, e.g. after a gateway appended
+/// its own
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MultipleCredentials {
+    /// Reject them as `HeaderProblem::Multiple`, the default
+    #[default]
+    Reject,
+    /// Use the first `Bearer` credential, ignoring the rest
+    First,
+    /// Use the first `Bearer` credential that verifies, or else the first one
+    TryEach,
+}
+
+impl FromStr for MultipleCredentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(MultipleCredentials::Reject),
+            "first" => Ok(MultipleCredentials::First),
+            "try-each" => Ok(MultipleCredentials::TryEach),
+            _ => anyhow::bail!("unknown multiple credentials policy {:?}", s),
+        }
+    }
+}
+
+/// The bearer token of a request, if any. Unlike `BearerAuth` a missing token
+/// reaches the validator, which can then e.g. redirect browsers to a login page.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    token: Option<String>,
+    problem: Option<HeaderProblem>,
+    bearers: Vec<String>,
+}
+
+impl Credentials {
+    /// The bearer token in the `Authorization` header of `req`
+    pub fn of(req: &ServiceRequest) -> Self {
+        let values: Result<Vec<&str>, _> = req
+            .headers()
+            .get_all(header::AUTHORIZATION)
+            .map(|value| value.to_str())
+            .collect();
+        let credentials: Vec<&str> = match &values {
+            Ok(values) => values
+                .iter()
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|credential| !credential.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let bearers: Vec<String> = credentials
+            .iter()
+            .filter_map(|credential| bearer(credential))
+            .map(String::from)
+            .collect();
+        let problem = match (&values, credentials.len(), bearers.len()) {
+            (Err(_), _, _) => Some(HeaderProblem::NotUtf8),
+            (Ok(values), 0, _) if values.is_empty() => Some(HeaderProblem::Absent),
+            (Ok(_), 0, _) => Some(HeaderProblem::WrongScheme),
+            (Ok(_), 1, 1) => None,
+            (Ok(_), 1, _) => Some(HeaderProblem::WrongScheme),
+            (Ok(_), _, _) => Some(HeaderProblem::Multiple),
+        };
+        Credentials {
+            token: bearers.first().cloned().filter(|_| problem.is_none()),
+            problem,
+            bearers,
+        }
+    }
+
+    /// Every `Bearer` credential, in order, also when there are several
+    pub fn bearer_tokens(&self) -> &[String] {
+        &self.bearers
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    /// Why there is no token
+    pub fn problem(&self) -> Option<HeaderProblem> {
+        self.problem
+    }
+}
+
+/// The token of a `Bearer` credential, the scheme matched case-insensitively
+fn bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    Some(token.trim()).filter(|_| scheme.eq_ignore_ascii_case("bearer"))
+}
+
+impl AuthExtractor for Credentials {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+
+    fn from_service_request(req: &ServiceRequest) -> Self::Future {
+        ready(Ok(Credentials::of(req)))
+    }
+}
+
+/// The exact token the request was accepted with, and its hash for logs, e.g. to
+/// forward it upstream. Extracting it from a request authenticated otherwise, by an
+/// API key or not at all, fails with 401.
+#[derive(Clone)]
+pub struct RawToken {
+    token: String,
+    hash: TokenHash,
+}
+
+impl RawToken {
+    pub fn as_str(&self) -> &str {
+        &self.token
+    }
+
+    pub fn hash(&self) -> &TokenHash {
+        &self.hash
+    }
+}
+
+// Never log the token itself
+impl fmt::Debug for RawToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RawToken({})", self.hash)
+    }
+}
+
+impl FromRequest for RawToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let extensions = req.extensions();
+        let token = match (
+            extensions.get::<BearerToken>(),
+            extensions.get::<TokenHash>(),
+        ) {
+            (Some(token), Some(hash)) => Ok(RawToken {
+                token: token.as_str().into(),
+                hash: hash.clone(),
+            }),
+            _ => Err(AuthError::MissingToken.into()),
+        };
+        ready(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+
+    #[actix_rt::test]
+    async fn test_raw_token() {
+        let req = TestRequest::default().to_http_request();
+        let err = RawToken::extract(&req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let hash = TokenHash::new("a.b.c", b"");
+        req.extensions_mut().insert(BearerToken("a.b.c".into()));
+        req.extensions_mut().insert(hash.clone());
+        let token = RawToken::extract(&req).await.unwrap();
+        assert_eq!(token.as_str(), "a.b.c");
+        assert_eq!(token.hash(), &hash);
+        assert_eq!(format!("{:?}", token), format!("RawToken({})", hash));
+    }
+}
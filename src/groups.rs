@@ -0,0 +1,337 @@
+//! Group memberships missing from tokens, e.g. after Azure AD group overage
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// What `GroupResolver::groups` returns
+pub type GroupsFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<String>>> + 'a>>;
+
+/// Looks up the groups of a user, e.g. in Microsoft Graph or, with the ldap feature,
+/// in an LDAP directory through `ldap::LdapGroups`
+pub trait GroupResolver: Send + Sync {
+    /// The groups of the user the verified `claims` belong to
+    fn groups<'a>(&'a self, claims: &'a Map<String, Value>) -> GroupsFuture<'a>;
+}
+
+/// Whether the token left its groups out for being too many: Azure AD then sets
+/// `hasgroups`, or points `_claim_names.groups` to a claim source instead
+pub fn has_group_overage(claims: &Map<String, Value>) -> bool {
+    claims.get("hasgroups") == Some(&Value::Bool(true))
+        || claims
+            .get("_claim_names")
+            .and_then(|names| names.get("groups"))
+            .is_some()
+}
+
+/// Groups by user, with when they expire from the cache
+type Cached = HashMap<String, (Vec<String>, Instant)>;
+
+/// Fills in the groups claim of tokens with group overage from a `GroupResolver`,
+/// before any rule or policy sees the claims, so that e.g. `ClaimRule::contains`
+/// combines token and directory data. Groups are kept per user for a while, one hour
+/// by default.
+#[derive(Clone)]
+pub struct Groups {
+    claim: String,
+    when_absent: bool,
+    ttl: Duration,
+    resolver: Arc<dyn GroupResolver>,
+    cached: Arc<Mutex<Cached>>,
+}
+
+impl Groups {
+    pub fn new(resolver: impl GroupResolver + 'static) -> Self {
+        Groups {
+            claim: "groups".into(),
+            when_absent: false,
+            ttl: Duration::from_secs(3600),
+            resolver: Arc::new(resolver),
+            cached: Arc::default(),
+        }
+    }
+
+    /// The claim holding the groups, `groups` by default
+    pub fn claim(mut self, claim: impl Into<String>) -> Self {
+        self.claim = claim.into();
+        self
+    }
+
+    /// Look the groups up for every token without the claim, not only on overage,
+    /// for issuers that never include groups
+    pub fn when_absent(mut self) -> Self {
+        self.when_absent = true;
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Add the groups to `claims` if the token left them out. `user` keys the cache,
+    /// and without it the groups are looked up every time.
+    pub async fn fill(
+        &self,
+        claims: &mut Map<String, Value>,
+        user: Option<&str>,
+    ) -> anyhow::Result<()> {
+        if claims.contains_key(&self.claim) || !(self.when_absent || has_group_overage(claims)) {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let cached = user.and_then(|user| {
+            let mut cached = self.cached.lock().unwrap();
+            cached.retain(|_, (_, until)| *until > now);
+            cached.get(user).map(|(groups, _)| groups.clone())
+        });
+        let groups = match cached {
+            Some(groups) => groups,
+            None => {
+                let groups = self.resolver.groups(claims).await?;
+                if let Some(user) = user {
+                    self.cached
+                        .lock()
+                        .unwrap()
+                        .insert(user.into(), (groups.clone(), now + self.ttl));
+                }
+                groups
+            }
+        };
+        claims.insert(
+            self.claim.clone(),
+            groups.into_iter().map(Value::String).collect(),
+        );
+        Ok(())
+    }
+}
+
+/// The groups of users from an HTTP API answering either a JSON array of group names
+/// or an object with a `groups` array
+#[cfg(feature = "reqwest")]
+#[derive(Clone)]
+pub struct HttpGroups {
+    url: String,
+    user_claim: String,
+    tokens: Option<crate::outbound::ServiceTokenProvider>,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpGroups {
+    /// GET `url`, in which `{user}` is replaced with the user id
+    pub fn new(url: impl Into<String>) -> Self {
+        HttpGroups {
+            url: url.into(),
+            user_claim: "sub".into(),
+            tokens: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The claim with the user id, `sub` by default
+    pub fn user_claim(mut self, claim: impl Into<String>) -> Self {
+        self.user_claim = claim.into();
+        self
+    }
+
+    /// Authenticate with tokens of this service
+    pub fn tokens(mut self, tokens: crate::outbound::ServiceTokenProvider) -> Self {
+        self.tokens = Some(tokens);
+        self
+    }
+}
+
+#[cfg(feature = "reqwest")]
+impl GroupResolver for HttpGroups {
+    fn groups<'a>(&'a self, claims: &'a Map<String, Value>) -> GroupsFuture<'a> {
+        Box::pin(async move {
+            #[derive(serde::Deserialize)]
+            #[serde(untagged)]
+            enum Answer {
+                List(Vec<String>),
+                Object { groups: Vec<String> },
+            }
+
+            let user = crate::claims::UserId::from_claims(claims, &self.user_claim)
+                .ok_or_else(|| anyhow::anyhow!("no {} to look up groups for", self.user_claim))?;
+            let user: String =
+                url::form_urlencoded::byte_serialize(user.as_str().as_bytes()).collect();
+            let mut request = self.client.get(&self.url.replace("{user}", &user));
+            if let Some(tokens) = &self.tokens {
+                request = request.bearer_auth(tokens.token().await?);
+            }
+            let answer: Answer = request.send().await?.error_for_status()?.json().await?;
+            Ok(match answer {
+                Answer::List(groups) | Answer::Object { groups } => groups,
+            })
+        })
+    }
+}
+
+/// The security groups of Azure AD users by their `oid`, from Microsoft Graph with
+/// an application token. The claim source URL in the token itself is never called.
+#[cfg(feature = "msgraph")]
+#[derive(Clone)]
+pub struct GraphGroups {
+    base_url: String,
+    security_enabled_only: bool,
+    tokens: crate::outbound::ServiceTokenProvider,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "msgraph")]
+impl GraphGroups {
+    /// Query Graph with tokens from `tokens`, which needs the
+    /// `https://graph.microsoft.com/.default` scope and `GroupMember.Read.All`
+    pub fn new(tokens: crate::outbound::ServiceTokenProvider) -> Self {
+        GraphGroups {
+            base_url: "https://graph.microsoft.com/v1.0".into(),
+            security_enabled_only: true,
+            tokens,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Graph endpoint other than the global one, e.g. of a national cloud
+    pub fn base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Include distribution groups, not only security groups
+    pub fn all_groups(mut self) -> Self {
+        self.security_enabled_only = false;
+        self
+    }
+}
+
+#[cfg(feature = "msgraph")]
+impl GroupResolver for GraphGroups {
+    fn groups<'a>(&'a self, claims: &'a Map<String, Value>) -> GroupsFuture<'a> {
+        Box::pin(async move {
+            #[derive(serde::Deserialize)]
+            struct MemberGroups {
+                value: Vec<String>,
+            }
+
+            let oid = claims
+                .get("oid")
+                .and_then(Value::as_str)
+                .ok_or_else(|| anyhow::anyhow!("no oid to look up groups for"))?;
+            let url = format!(
+                "{}/users/{}/getMemberGroups",
+                self.base_url,
+                url::form_urlencoded::byte_serialize(oid.as_bytes()).collect::<String>()
+            );
+            let groups: MemberGroups = self
+                .client
+                .post(&url)
+                .bearer_auth(self.tokens.token().await?)
+                .json(&serde_json::json!({"securityEnabledOnly": self.security_enabled_only}))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(groups.value)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Directory(AtomicUsize);
+
+    impl GroupResolver for Directory {
+        fn groups<'a>(&'a self, _: &'a Map<String, Value>) -> GroupsFuture<'a> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(vec!["g1".to_string(), "g2".to_string()]) })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_group_overage() {
+        let directory = Arc::new(Directory::default());
+        struct Shared(Arc<Directory>);
+        impl GroupResolver for Shared {
+            fn groups<'a>(&'a self, claims: &'a Map<String, Value>) -> GroupsFuture<'a> {
+                self.0.groups(claims)
+            }
+        }
+        let groups = Groups::new(Shared(directory.clone()));
+
+        let overage = json!({"oid": "o-1", "_claim_names": {"groups": "src1"}});
+        for _ in 0..2 {
+            let mut claims = overage.as_object().unwrap().clone();
+            groups.fill(&mut claims, Some("o-1")).await.unwrap();
+            assert_eq!(claims["groups"], json!(["g1", "g2"]));
+        }
+        assert_eq!(directory.0.load(Ordering::SeqCst), 1);
+
+        let mut claims = json!({"oid": "o-2", "hasgroups": true})
+            .as_object()
+            .unwrap()
+            .clone();
+        groups.fill(&mut claims, None).await.unwrap();
+        assert_eq!(directory.0.load(Ordering::SeqCst), 2);
+
+        // Groups in the token, or no overage
+        for token in [
+            json!({"groups": ["g3"], "hasgroups": true}),
+            json!({"oid": "o-3"}),
+        ] {
+            let mut claims = token.as_object().unwrap().clone();
+            groups.fill(&mut claims, Some("o-3")).await.unwrap();
+            assert_eq!(claims, *token.as_object().unwrap());
+        }
+        assert_eq!(directory.0.load(Ordering::SeqCst), 2);
+
+        let groups = groups.when_absent();
+        let mut claims = json!({"oid": "o-3"}).as_object().unwrap().clone();
+        groups.fill(&mut claims, Some("o-3")).await.unwrap();
+        assert_eq!(claims["groups"], json!(["g1", "g2"]));
+
+        #[cfg(feature = "reqwest")]
+        {
+            let directory = mockito::mock("GET", "/directory/users/alice%40example.com/groups")
+                .with_body(r#"{"groups": ["eng"]}"#)
+                .create();
+            let http = HttpGroups::new(mockito::server_url() + "/directory/users/{user}/groups")
+                .user_claim("email");
+            let claims = json!({"email": "alice@example.com"});
+            let claims = claims.as_object().unwrap();
+            assert_eq!(http.groups(claims).await.unwrap(), vec!["eng"]);
+            directory.assert();
+        }
+
+        #[cfg(feature = "msgraph")]
+        {
+            let token = mockito::mock("POST", "/graph/token")
+                .with_body(r#"{"access_token": "app", "expires_in": 3600}"#)
+                .create();
+            let member_groups = mockito::mock("POST", "/graph/v1.0/users/o-1/getMemberGroups")
+                .match_header("authorization", "Bearer app")
+                .match_body(mockito::Matcher::Json(json!({"securityEnabledOnly": true})))
+                .with_body(r#"{"value": ["sg1"]}"#)
+                .create();
+            let tokens = crate::outbound::ServiceTokenProvider::new(
+                mockito::server_url() + "/graph/token",
+                "api",
+                "secret",
+            );
+            let graph = GraphGroups::new(tokens).base_url(mockito::server_url() + "/graph/v1.0");
+            let claims = overage.as_object().unwrap();
+            assert_eq!(graph.groups(claims).await.unwrap(), vec!["sg1"]);
+            token.assert();
+            member_groups.assert();
+        }
+    }
+}
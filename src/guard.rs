@@ -0,0 +1,118 @@
+//! Route guards on the claims the middleware accepted
+use crate::claims::TokenClaims;
+use crate::rules::ClaimRule;
+use crate::scopes::Scopes;
+use actix_web::dev::RequestHead;
+use actix_web::guard::Guard;
+use serde_json::{Map, Value};
+
+#[derive(Clone, Debug)]
+enum Requirement {
+    Scope(String),
+    Rule(ClaimRule),
+}
+
+/// Matches requests whose token satisfies every requirement, for protecting single
+/// routes inline, e.g. `web::get().guard(JwtGuard::scope("read"))`. The `JwtAuth`
+/// middleware must wrap the routes, since guards only see claims it already accepted.
+/// A route whose guard fails does not match, so the next route is tried, or 404 is
+/// answered; checks that should answer 403 belong in `JwtAuth::require`.
+#[derive(Clone, Debug, Default)]
+pub struct JwtGuard {
+    requirements: Vec<Requirement>,
+}
+
+impl JwtGuard {
+    /// Any authenticated request
+    pub fn authenticated() -> Self {
+        JwtGuard::default()
+    }
+
+    /// Requests with `scope` among the token's scopes, in the `ScopeClaims` of the
+    /// `JwtAuth`
+    pub fn scope(scope: impl Into<String>) -> Self {
+        JwtGuard::default().and_scope(scope)
+    }
+
+    /// Requests whose claims satisfy `rule`
+    pub fn rule(rule: ClaimRule) -> Self {
+        JwtGuard::default().and_rule(rule)
+    }
+
+    pub fn and_scope(mut self, scope: impl Into<String>) -> Self {
+        self.requirements.push(Requirement::Scope(scope.into()));
+        self
+    }
+
+    pub fn and_rule(mut self, rule: ClaimRule) -> Self {
+        self.requirements.push(Requirement::Rule(rule));
+        self
+    }
+
+    fn satisfied(&self, claims: &Map<String, Value>, scopes: &Scopes) -> bool {
+        self.requirements
+            .iter()
+            .all(|requirement| match requirement {
+                Requirement::Scope(scope) => scopes.contains(scope),
+                Requirement::Rule(rule) => rule.check(claims),
+            })
+    }
+}
+
+impl Guard for JwtGuard {
+    fn check(&self, request: &RequestHead) -> bool {
+        let extensions = request.extensions();
+        match (extensions.get::<TokenClaims>(), Scopes::of(&extensions)) {
+            (Some(claims), Some(scopes)) => self.satisfied(&claims.0, &scopes),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpMessage};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_guard() {
+        let mut app = test::init_service(
+            App::new()
+                .route(
+                    "/",
+                    web::get()
+                        .guard(
+                            JwtGuard::scope("write").and_rule(ClaimRule::equals("tenant", "acme")),
+                        )
+                        .to(|| async { "write" }),
+                )
+                .route(
+                    "/",
+                    web::get()
+                        .guard(JwtGuard::scope("read"))
+                        .to(|| async { "read" }),
+                )
+                .route("/", web::get().to(|| async { "anonymous" })),
+        )
+        .await;
+
+        let call = |claims: Option<Value>| {
+            let req = test::TestRequest::get().uri("/").to_request();
+            if let Some(Value::Object(claims)) = claims {
+                req.extensions_mut().insert(TokenClaims(claims));
+            }
+            req
+        };
+        let write = json!({"scope": "read write", "tenant": "acme"});
+        let read = json!({"scp": ["read", "write"], "tenant": "globex"});
+        for (claims, expected) in [
+            (Some(write), "write"),
+            (Some(read), "read"),
+            (Some(json!({})), "anonymous"),
+            (None, "anonymous"),
+        ] {
+            assert_eq!(test::read_response(&mut app, call(claims)).await, expected);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+//! Claims written into request headers, for handlers and upstreams reading identity
+//! from headers
+use actix_web::dev::ServiceRequest;
+use actix_web::http::{HeaderName, HeaderValue};
+use log::warn;
+use serde_json::{Map, Value};
+
+/// Request headers set from the claims of accepted tokens, e.g. `X-User-Id: {sub}`.
+/// Any value of the headers the client sent is removed first, so they are only
+/// ever set from verified claims, and left out when a claim they name is absent.
+/// Other identity headers handlers trust can be removed without being set.
+#[derive(Clone, Debug, Default)]
+pub struct ClaimHeaders {
+    headers: Vec<(HeaderName, String)>,
+    removed: Vec<HeaderName>,
+    removed_prefixes: Vec<String>,
+}
+
+impl ClaimHeaders {
+    pub fn new() -> Self {
+        ClaimHeaders::default()
+    }
+
+    /// Set `name` to `template`, in which `{claim}` is replaced with the claim,
+    /// strings as they are, arrays comma separated and dots naming nested claims
+    pub fn header(mut self, name: HeaderName, template: impl Into<String>) -> Self {
+        self.headers.push((name, template.into()));
+        self
+    }
+
+    /// Drop `name` from incoming requests, e.g. `X-Forwarded-User`. A name ending in
+    /// `*` drops every header starting with the rest, e.g. `X-Auth-*`.
+    pub fn remove(mut self, name: &str) -> anyhow::Result<Self> {
+        match name.strip_suffix('*') {
+            Some(prefix) => self.removed_prefixes.push(prefix.to_ascii_lowercase()),
+            None => self.removed.push(HeaderName::from_bytes(name.as_bytes())?),
+        }
+        Ok(self)
+    }
+
+    /// Headers from `name={template}` pairs, e.g. `X-Tenant={tenant}`
+    pub fn parse(pairs: &[String]) -> anyhow::Result<Self> {
+        pairs.iter().try_fold(ClaimHeaders::new(), |headers, pair| {
+            let (name, template) = pair
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected header=template, got {:?}", pair))?;
+            let name = HeaderName::from_bytes(name.trim().as_bytes())?;
+            Ok(headers.header(name, template.trim()))
+        })
+    }
+
+    /// Remove the headers as sent by the client
+    pub(crate) fn strip(&self, req: &mut ServiceRequest) {
+        let prefixed: Vec<HeaderName> = req
+            .headers()
+            .keys()
+            .filter(|name| {
+                self.removed_prefixes
+                    .iter()
+                    .any(|prefix| name.as_str().starts_with(prefix.as_str()))
+            })
+            .cloned()
+            .collect();
+        let names = self.headers.iter().map(|(name, _)| name);
+        for name in names.chain(&self.removed).chain(&prefixed) {
+            req.headers_mut().remove(name);
+        }
+    }
+
+    /// Replace the headers of `req` with those of `claims`
+    pub(crate) fn apply(&self, req: &mut ServiceRequest, claims: &Map<String, Value>) {
+        self.strip(req);
+        for (name, template) in &self.headers {
+            let value = match render(template, claims) {
+                Some(value) => value,
+                None => continue,
+            };
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    req.headers_mut().insert(name.clone(), value);
+                }
+                Err(_) => warn!("claims of header {} are not a valid header value", name),
+            }
+        }
+    }
+}
+
+/// `template` with its `{claim}`s replaced, unless one of them is absent
+fn render(template: &str, claims: &Map<String, Value>) -> Option<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')?;
+        out.push_str(&rest[..start]);
+        out.push_str(&claim_text(claims, &rest[start + 1..end])?);
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Some(out)
+}
+
+fn claim_text(claims: &Map<String, Value>, path: &str) -> Option<String> {
+    let mut names = path.split('.');
+    let first = claims.get(names.next()?)?;
+    let value = names.try_fold(first, |value, name| value.get(name))?;
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(items) => Some(
+            items
+                .iter()
+                .map(|item| match item {
+                    Value::String(s) => s.clone(),
+                    item => item.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        ),
+        Value::Null => None,
+        value => Some(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    #[test]
+    fn test_claim_headers() {
+        let headers = ClaimHeaders::parse(&[
+            "X-User-Id={sub}".to_string(),
+            "X-Tenant=tenant/{org.id}".to_string(),
+            "X-Groups={groups}".to_string(),
+            "X-Email={email}".to_string(),
+            "X-Bad={bad}".to_string(),
+        ])
+        .unwrap()
+        .remove("X-Forwarded-User")
+        .unwrap()
+        .remove("X-Auth-*")
+        .unwrap();
+        let claims = json!({
+            "sub": "alice",
+            "org": {"id": 7},
+            "groups": ["eng", "ops"],
+            "bad": "a\nb",
+        });
+        let mut req = TestRequest::default()
+            .header("x-user-id", "admin")
+            .header("x-email", "admin@example.com")
+            .header("x-forwarded-user", "admin")
+            .header("x-auth-role", "admin")
+            .header("x-authority", "kept")
+            .to_srv_request();
+        headers.apply(&mut req, claims.as_object().unwrap());
+
+        let get = |name| req.headers().get(name).map(|v| v.to_str().unwrap());
+        assert_eq!(get("x-user-id"), Some("alice"));
+        assert_eq!(get("x-tenant"), Some("tenant/7"));
+        assert_eq!(get("x-groups"), Some("eng,ops"));
+        assert_eq!(get("x-email"), None);
+        assert_eq!(get("x-bad"), None);
+        assert_eq!(get("x-forwarded-user"), None);
+        assert_eq!(get("x-auth-role"), None);
+        assert_eq!(get("x-authority"), Some("kept"));
+        assert!(ClaimHeaders::parse(&["X-User-Id".to_string()]).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_spoofed_headers() {
+        use crate::auth::JwtAuth;
+        use crate::keystore::Keys;
+        use actix_web::{test, web, App, HttpRequest};
+        use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let headers = ClaimHeaders::parse(&["X-User-Id={sub}".to_string()])
+            .unwrap()
+            .remove("X-Forwarded-User")
+            .unwrap()
+            .remove("X-Auth-*")
+            .unwrap();
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys)
+            .claim_headers(headers)
+            .optional();
+        let mut app = test::init_service(App::new().wrap(auth.middleware()).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move {
+                let mut seen: Vec<String> = req
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| name.as_str().starts_with("x-"))
+                    .map(|(name, value)| format!("{}={}", name, value.to_str().unwrap()))
+                    .collect();
+                seen.sort();
+                seen.join(" ")
+            }),
+        ))
+        .await;
+        let spoofed = || {
+            test::TestRequest::get()
+                .uri("/")
+                .header("X-User-Id", "admin")
+                .header("X-Forwarded-User", "admin")
+                .header("X-Auth-Role", "admin")
+                .header("X-Trace", "kept")
+        };
+
+        // Anonymous requests lose them as well
+        let body = test::read_response(&mut app, spoofed().to_request()).await;
+        assert_eq!(body, "x-trace=kept");
+
+        let token = encode(
+            &Header {
+                kid: Some("0".into()),
+                ..Header::new(Algorithm::HS256)
+            },
+            &json!({"sub": "alice", "exp": 4_000_000_000u64}),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let req = spoofed()
+            .header("Authorization", format!("Bearer {}", token))
+            .to_request();
+        let body = test::read_response(&mut app, req).await;
+        assert_eq!(body, "x-trace=kept x-user-id=alice");
+    }
+}
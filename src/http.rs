@@ -0,0 +1,254 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The HTTP client used for discovery documents and key sets
+pub trait HttpFetch: Send + Sync {
+    /// GET `uri`, failing on non-success statuses and bodies over `max_len` bytes,
+    /// and return the body
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a str,
+        max_len: usize,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + 'a>>;
+}
+
+#[cfg(feature = "reqwest")]
+impl HttpFetch for reqwest::Client {
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a str,
+        max_len: usize,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + 'a>> {
+        Box::pin(async move {
+            let mut resp = self.get(uri).send().await?.error_for_status()?;
+            if resp
+                .content_length()
+                .is_some_and(|len| len > max_len as u64)
+            {
+                anyhow::bail!("{} is larger than {} bytes", uri, max_len);
+            }
+            // The length may be missing or wrong, so count while reading
+            let mut body = Vec::new();
+            while let Some(chunk) = resp.chunk().await? {
+                if body.len() + chunk.len() > max_len {
+                    anyhow::bail!("{} is larger than {} bytes", uri, max_len);
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(body)
+        })
+    }
+}
+
+/// Fetches with awc. A client is created per request since awc clients are bound to a thread.
+#[cfg(feature = "awc")]
+#[derive(Clone, Debug, Default)]
+pub struct AwcFetch {
+    pub timeout: Option<Duration>,
+    /// Accept only TLS chains with one of these SPKI pins, `sha256/<base64>`
+    #[cfg(feature = "pinning")]
+    pub pins: Vec<String>,
+}
+
+#[cfg(feature = "awc")]
+impl AwcFetch {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        AwcFetch {
+            timeout,
+            #[cfg(feature = "pinning")]
+            pins: Vec::new(),
+        }
+    }
+}
+
+#[cfg(feature = "awc")]
+impl HttpFetch for AwcFetch {
+    fn fetch<'a>(
+        &'a self,
+        uri: &'a str,
+        max_len: usize,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<u8>>> + 'a>> {
+        Box::pin(async move {
+            let mut builder = awc::Client::builder();
+            if let Some(timeout) = self.timeout {
+                builder = builder.timeout(timeout);
+            }
+            #[cfg(feature = "pinning")]
+            if !self.pins.is_empty() {
+                builder = builder.connector(
+                    awc::Connector::new()
+                        .ssl(pinned_connector(self.pins.clone())?)
+                        .finish(),
+                );
+            }
+            let mut resp = builder
+                .finish()
+                .get(uri)
+                .send()
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            if !resp.status().is_success() {
+                anyhow::bail!("{} returned {}", uri, resp.status());
+            }
+            let body = resp
+                .body()
+                .limit(max_len)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            Ok(body.to_vec())
+        })
+    }
+}
+
+/// The HPKP style pin of a public key: `sha256/` and the base64 SHA-256 of its DER SPKI
+#[cfg(feature = "pinning")]
+pub fn spki_pin<T: openssl::pkey::HasPublic>(
+    key: &openssl::pkey::PKeyRef<T>,
+) -> anyhow::Result<String> {
+    let spki = key.public_key_to_der()?;
+    Ok(format!(
+        "sha256/{}",
+        openssl::base64::encode_block(&openssl::sha::sha256(&spki))
+    ))
+}
+
+/// A TLS connector that, on top of normal verification, requires a certificate
+/// in the verified chain to have a public key matching one of `pins`
+#[cfg(feature = "pinning")]
+pub fn pinned_connector(pins: Vec<String>) -> anyhow::Result<openssl::ssl::SslConnector> {
+    use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_verify_callback(SslVerifyMode::PEER, move |preverify_ok, ctx| {
+        // The leaf is verified last, when the whole chain is known
+        if !preverify_ok || ctx.error_depth() != 0 {
+            return preverify_ok;
+        }
+        let chain = match ctx.chain() {
+            Some(chain) => chain,
+            None => return false,
+        };
+        let pinned = chain.iter().any(|cert| {
+            cert.public_key()
+                .ok()
+                .and_then(|key| spki_pin(&key).ok())
+                .is_some_and(|pin| pins.contains(&pin))
+        });
+        if !pinned {
+            log::warn!("no certificate in the chain matches a configured pin");
+        }
+        pinned
+    });
+    Ok(builder.build())
+}
+
+/// reqwest when enabled, otherwise awc
+pub fn default_fetcher() -> Arc<dyn HttpFetch> {
+    #[cfg(feature = "reqwest")]
+    return Arc::new(reqwest::Client::new());
+    #[cfg(all(feature = "awc", not(feature = "reqwest")))]
+    return Arc::new(AwcFetch::default());
+}
+
+#[cfg(not(any(feature = "reqwest", feature = "awc")))]
+compile_error!("enable the `reqwest` or `awc` feature for fetching keys");
+
+/// Settings for the client fetching discovery documents and keys
+#[derive(Clone, Debug, Default)]
+pub struct ClientConfig {
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// Proxy for all requests, e.g. `http://proxy.corp:3128`
+    pub proxy: Option<String>,
+    /// Extra trusted root certificates, PEM encoded
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "reqwest")]
+impl ClientConfig {
+    pub fn build(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        #[cfg(any(feature = "native-tls", feature = "rustls"))]
+        for pem in &self.root_certificates {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(pem)?);
+        }
+        #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+        if !self.root_certificates.is_empty() {
+            anyhow::bail!("root certificates need the `native-tls` or `rustls` feature");
+        }
+        Ok(builder.build()?)
+    }
+}
+
+#[cfg(all(test, feature = "reqwest"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_config() {
+        let config = ClientConfig {
+            timeout: Some(Duration::from_secs(5)),
+            proxy: Some("http://proxy.corp:3128".into()),
+            ..ClientConfig::default()
+        };
+        assert!(config.build().is_ok());
+
+        let config = ClientConfig {
+            root_certificates: vec![b"not a certificate".to_vec()],
+            ..ClientConfig::default()
+        };
+        assert!(config.build().is_err());
+    }
+}
+
+#[cfg(all(test, feature = "awc"))]
+mod awc_tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_awc_fetch() {
+        let path = "/awc/keys";
+        let mock = mockito::mock("GET", path)
+            .with_body(r#"{"keys": []}"#)
+            .expect(2)
+            .create();
+        let body = AwcFetch::default()
+            .fetch(&(mockito::server_url() + path), 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, br#"{"keys": []}"#);
+        assert!(AwcFetch::default()
+            .fetch(&(mockito::server_url() + path), 4)
+            .await
+            .is_err());
+        mock.assert();
+    }
+}
+
+#[cfg(all(test, feature = "pinning"))]
+mod pinning_tests {
+    use super::*;
+    use openssl::pkey::PKey;
+    use openssl::rsa::Rsa;
+
+    #[test]
+    fn test_spki_pin() {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let pin = spki_pin(&key).unwrap();
+        assert!(pin.starts_with("sha256/"));
+        assert_eq!(pin.len(), "sha256/".len() + 44);
+        assert_eq!(pin, spki_pin(&key).unwrap());
+        assert!(pinned_connector(vec![pin]).is_ok());
+    }
+}
@@ -0,0 +1,759 @@
+use crate::apikey::{ApiKeys, MemoryApiKeys};
+use crate::audience::RouteAudiences;
+use crate::auth::JwtAuth;
+use crate::cache::TokenCache;
+use crate::config::Config;
+use crate::error::Challenge;
+use crate::headers::ClaimHeaders;
+use crate::http::HttpFetch;
+use crate::jwk::{JwkSet, KeyStrength};
+use crate::keystore::{JwksStore, Refresher};
+use crate::network::{parse_net, IpConstraint};
+use crate::openid::{self, Limits, OidConf, Retry};
+use crate::persist::WarmStart;
+use crate::proxy::TrustedProxies;
+use crate::ratelimit::RateLimit;
+use crate::report::{report, Component};
+use crate::schema::ClaimsSchema;
+use crate::scopes::{ScopeClaims, ScopeFormat};
+use crate::sessions::SessionLimit;
+use actix_web::http::HeaderName;
+use anyhow::{bail, Context};
+use jsonwebtoken::{Algorithm, DecodingKey, Header, Validation};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+impl JwtAuth {
+    /// Validate `config`, run discovery and fetch the keys once, then start the
+    /// background refresh. Misconfiguration surfaces here, before the server binds,
+    /// instead of on the first request.
+    pub async fn initialize(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self::discover(config).await?.0)
+    }
+
+    /// Like `initialize`, also returning the discovered configuration, e.g. for `Login`
+    pub async fn discover(config: &Config) -> anyhow::Result<(Self, OidConf)> {
+        config.validate()?;
+        if config.audience.is_none() {
+            warn!("no audience configured, accepting tokens for any audience");
+        }
+        let algorithms = algorithms(config)?;
+        #[cfg(feature = "dangerous-dev-mode")]
+        let dev_mode = crate::devmode::DangerousDevMode::from_config(config)?;
+        #[cfg(feature = "dangerous-dev-mode")]
+        if let (true, Some(mode)) = (config.authserver.is_empty(), &dev_mode) {
+            return local_only(config, mode.clone());
+        }
+        #[cfg(not(feature = "dangerous-dev-mode"))]
+        if config.dangerous_dev_local_key.is_some()
+            || config.dangerous_dev_local_secret.is_some()
+            || config.dangerous_dev_skip_audience.is_some()
+        {
+            bail!("DANGEROUS_DEV_* settings require the dangerous-dev-mode feature");
+        }
+        if config.jwks_json.is_some()
+            || config.public_key_pem.is_some()
+            || config.hs256_secret.is_some()
+        {
+            return static_keys(config, algorithms);
+        }
+
+        let mut retry = Retry::default();
+        if let Some(attempts) = config.fetch_attempts {
+            retry.attempts = attempts;
+        }
+        let warm = config.jwks_cache_file.as_ref().and_then(|path| {
+            WarmStart::load(path)
+                .map_err(|e| info!("no cached keys in {}: {}", path, e))
+                .ok()
+        });
+        if warm.is_some() {
+            // Cached keys are at hand, so don't hold up startup retrying
+            retry.attempts = 1;
+        }
+        let mut limits = Limits::default();
+        if let Some(len) = config.jwks_max_bytes {
+            limits.max_document_len = len;
+        }
+        if let Some(keys) = config.jwks_max_keys {
+            limits.max_keys = keys;
+        }
+        if config.allow_weak_keys == Some(true) {
+            warn!("accepting RSA keys under 2048 bits and short HMAC secrets");
+            limits.key_strength = KeyStrength::allow_weak();
+        }
+        let client = fetcher(config)?;
+        let discovered = match (&config.jwks_url, config.discovery.as_deref()) {
+            (Some(jwks_url), _) => {
+                openid::get_jwks_config_with_retry(
+                    &config.authserver,
+                    jwks_url,
+                    client.as_ref(),
+                    &retry,
+                    &limits,
+                )
+                .await
+            }
+            (None, None | Some("oidc")) => {
+                openid::get_config_at_with_retry(
+                    &config.authserver,
+                    config
+                        .discovery_path
+                        .as_deref()
+                        .unwrap_or(openid::DISCOVERY_PATH),
+                    client.as_ref(),
+                    &retry,
+                    &limits,
+                )
+                .await
+            }
+            (None, Some("oauth")) => {
+                openid::get_oauth_config_with_retry(
+                    &config.authserver,
+                    client.as_ref(),
+                    &retry,
+                    &limits,
+                )
+                .await
+            }
+            (None, Some(other)) => bail!("unknown discovery {:?}, expected oidc or oauth", other),
+        };
+        let (oidc, from_cache) = match (discovered, warm) {
+            (Ok(oidc), _) => (oidc, false),
+            (Err(e), Some(warm)) => {
+                warn!("discovery failed, starting with cached keys: {:#}", e);
+                report(Component::Discovery, &e);
+                let oidc = OidConf {
+                    jwks: openid::decoding_keys(&warm.jwks, &limits.key_strength)?,
+                    jwks_document: warm.jwks,
+                    issuer: warm.issuer,
+                    jwks_uri: warm.jwks_uri,
+                    authorization_endpoint: None,
+                    token_endpoint: None,
+                    end_session_endpoint: None,
+                    introspection_endpoint: None,
+                    revocation_endpoint: None,
+                };
+                (oidc, true)
+            }
+            (Err(e), None) => return Err(e.context("discovery failed")),
+        };
+        Url::parse(&oidc.jwks_uri)
+            .with_context(|| format!("invalid jwks_uri {:?} in discovery", oidc.jwks_uri))?;
+        if oidc.jwks.is_empty() {
+            bail!("no usable keys at {}", oidc.jwks_uri);
+        }
+        info!("loaded {} keys from {}", oidc.jwks.len(), oidc.jwks_uri);
+        if let (Some(path), false) = (&config.jwks_cache_file, from_cache) {
+            let warm = WarmStart {
+                issuer: oidc.issuer.clone(),
+                jwks_uri: oidc.jwks_uri.clone(),
+                jwks: oidc.jwks_document.clone(),
+            };
+            if let Err(e) = warm.save(path) {
+                warn!("saving keys to {} failed: {}", path, e);
+                report(Component::Persistence, &e);
+            }
+        }
+
+        let validation = Validation {
+            algorithms,
+            iss: Some(oidc.issuer.clone()),
+            aud: audiences(config),
+            leeway: config.leeway_secs.unwrap_or(0),
+            ..Validation::default()
+        };
+
+        let mut store = JwksStore::new(oidc.jwks.clone());
+        store.replace_thumbprints(JwkSet::deserialize(&oidc.jwks_document)?.thumbprints());
+        if let Some(secs) = config.jwks_max_stale_secs {
+            store = store.max_staleness(Duration::from_secs(secs));
+        }
+        let mut refresher = Refresher::new(oidc.jwks_uri.clone(), store.clone())
+            .client(client)
+            .limits(limits)
+            .interval(Duration::from_secs(
+                config.jwks_refresh_secs.unwrap_or(3600),
+            ));
+        if let Some(path) = &config.jwks_cache_file {
+            refresher = refresher.persist_to(path, &oidc.issuer);
+        }
+        refresher.spawn();
+        if from_cache {
+            refresher.handle().refresh_now();
+        }
+
+        let auth = configure(JwtAuth::new(validation, store).refresher(refresher), config)?;
+        #[cfg(feature = "dangerous-dev-mode")]
+        let auth = match dev_mode {
+            Some(mode) => auth.dangerous_dev_mode(mode),
+            None => auth,
+        };
+        Ok((auth, oidc))
+    }
+}
+
+/// The settings of `config` beyond key discovery
+fn configure(mut auth: JwtAuth, config: &Config) -> anyhow::Result<JwtAuth> {
+    if config.access_tokens_only == Some(true) {
+        auth = auth.access_tokens_only();
+    }
+    if config.require_iat == Some(true) {
+        auth = auth.require_iat();
+    }
+    if config.reject_future_iat == Some(true) {
+        auth = auth.reject_future_iat();
+    }
+    if let Some(len) = config.max_token_len {
+        auth = auth.max_token_len(len);
+    }
+    if let Some(salt) = &config.token_hash_salt {
+        auth = auth.token_hash_salt(salt);
+    }
+    if let Some(realm) = &config.bearer_realm {
+        auth = auth.challenge(Challenge::default().realm(realm));
+    }
+    if let Some(namespace) = &config.claims_namespace {
+        auth = auth.claims_namespace(namespace);
+    }
+    if let Some(path) = &config.claims_schema {
+        auth = auth.claims_schema(ClaimsSchema::from_file(path)?);
+    }
+    if let Some(claim) = &config.scope_claim {
+        let format = match &config.scope_format {
+            Some(format) => format.parse()?,
+            None => ScopeFormat::Any,
+        };
+        auth = auth.scope_claims(ScopeClaims::new(claim, format));
+    }
+    if let Some(policy) = &config.multiple_credentials {
+        auth = auth.multiple_credentials(policy.parse()?);
+    }
+    if let Some(claim) = &config.user_id_claim {
+        auth = auth.user_id_claim(claim);
+    }
+    if let Some(name) = &config.request_id_header {
+        auth = auth.request_id_header(HeaderName::from_bytes(name.as_bytes())?);
+    }
+    if config.auth_event_log == Some(true) {
+        auth = auth.event_log();
+    }
+    if let Some(secs) = config.token_cache_secs {
+        auth = auth.token_cache(TokenCache::new(Duration::from_secs(secs)));
+    }
+    if config.claim_headers.is_some() || config.strip_headers.is_some() {
+        let mut headers = ClaimHeaders::parse(config.claim_headers.as_deref().unwrap_or(&[]))?;
+        for name in config.strip_headers.iter().flatten() {
+            headers = headers.remove(name)?;
+        }
+        auth = auth.claim_headers(headers);
+    }
+    if config.ip_claim_constraint == Some(true) || config.ip_allow_file.is_some() {
+        let mut constraint = IpConstraint::new();
+        if config.ip_claim_constraint != Some(true) {
+            constraint = constraint.without_claim();
+        }
+        if let Some(path) = &config.ip_allow_file {
+            constraint = constraint.allow_file(path)?;
+        }
+        auth = auth.ip_constraint(constraint);
+    }
+    if let Some(proxies) = &config.trusted_proxies {
+        let proxies = proxies
+            .iter()
+            .map(|s| parse_net(s).with_context(|| format!("invalid trusted proxy {:?}", s)))
+            .collect::<anyhow::Result<_>>()?;
+        let mut proxies = TrustedProxies::new(proxies);
+        if let Some(header) = &config.client_ip_header {
+            proxies = proxies.header(header.parse()?);
+        }
+        auth = auth.trusted_proxies(proxies);
+    }
+    if let Some(routes) = &config.route_audiences {
+        auth = auth.route_audiences(RouteAudiences::parse(routes)?);
+    }
+    if let Some(max) = config.max_sessions_per_subject {
+        auth = auth.session_limit(SessionLimit::new(max));
+    }
+    if let (Some(claim), Some(limit)) = (&config.rate_limit_claim, config.rate_limit_per_minute) {
+        auth = auth.rate_limit(RateLimit::new(claim, limit, Duration::from_secs(60)));
+    }
+    if let Some(path) = &config.api_keys_file {
+        let mut api_keys = ApiKeys::new(MemoryApiKeys::from_file(path)?);
+        if let Some(name) = &config.api_key_header {
+            api_keys = api_keys.header(HeaderName::from_bytes(name.as_bytes())?);
+        }
+        auth = auth.api_keys(api_keys);
+    }
+    if let Some(url) = &config.opa_url {
+        #[cfg(feature = "opa")]
+        {
+            auth = auth.policy(crate::policy::OpaEvaluator::new(url));
+        }
+        #[cfg(not(feature = "opa"))]
+        bail!("OPA_URL {} requires the opa feature", url);
+    }
+    let remote_keys = [
+        &config.kms_key_arn,
+        &config.pkcs11_module,
+        &config.azure_key_id,
+    ];
+    if remote_keys.iter().filter(|key| key.is_some()).count() > 1 {
+        bail!("only one of KMS_KEY_ARN, PKCS11_MODULE and AZURE_KEY_ID may be set");
+    }
+    if let Some(arn) = &config.kms_key_arn {
+        #[cfg(feature = "aws-kms")]
+        {
+            let alg = match &config.kms_key_alg {
+                Some(alg) => Algorithm::from_str(alg)
+                    .with_context(|| format!("unsupported algorithm {}", alg))?,
+                None => Algorithm::HS256,
+            };
+            if !matches!(alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+                bail!(
+                    "KMS_KEY_ALG {:?} is not HMAC, public keys verify from the JWKS",
+                    alg
+                );
+            }
+            auth = auth.remote_key(crate::kms::KmsKey::from_env(arn, alg)?);
+        }
+        #[cfg(not(feature = "aws-kms"))]
+        bail!("KMS_KEY_ARN {} requires the aws-kms feature", arn);
+    }
+    if let Some(module) = &config.pkcs11_module {
+        #[cfg(feature = "pkcs11")]
+        {
+            let alg = match &config.pkcs11_key_alg {
+                Some(alg) => Algorithm::from_str(alg)
+                    .with_context(|| format!("unsupported algorithm {}", alg))?,
+                None => Algorithm::RS256,
+            };
+            let setting = |value: &Option<String>, name: &str| {
+                value
+                    .clone()
+                    .with_context(|| format!("PKCS11_MODULE requires {}", name))
+            };
+            auth = auth.remote_key(crate::pkcs11::Pkcs11Key::open(
+                module,
+                &setting(&config.pkcs11_token, "PKCS11_TOKEN")?,
+                &setting(&config.pkcs11_pin, "PKCS11_PIN")?,
+                &setting(&config.pkcs11_key_label, "PKCS11_KEY_LABEL")?,
+                alg,
+            )?);
+        }
+        #[cfg(not(feature = "pkcs11"))]
+        bail!("PKCS11_MODULE {} requires the pkcs11 feature", module);
+    }
+    if let Some(key_id) = &config.azure_key_id {
+        #[cfg(feature = "azure-keyvault")]
+        {
+            let alg = match &config.azure_key_alg {
+                Some(alg) => Algorithm::from_str(alg)
+                    .with_context(|| format!("unsupported algorithm {}", alg))?,
+                None => Algorithm::RS256,
+            };
+            let mut key = crate::azure::AzureKey::new(key_id, alg)?;
+            match (
+                &config.azure_tenant_id,
+                &config.azure_client_id,
+                &config.azure_client_secret,
+            ) {
+                (Some(tenant), Some(client_id), Some(secret)) => {
+                    key = key.client_credentials(tenant, client_id, secret);
+                }
+                (None, None, None) => {}
+                _ => bail!("AZURE_TENANT_ID, AZURE_CLIENT_ID and AZURE_CLIENT_SECRET go together"),
+            }
+            auth = auth.remote_key(key);
+        }
+        #[cfg(not(feature = "azure-keyvault"))]
+        bail!(
+            "AZURE_KEY_ID {} requires the azure-keyvault feature",
+            key_id
+        );
+    }
+    #[cfg(not(feature = "login"))]
+    if let Some(client_id) = &config.login_client_id {
+        bail!("LOGIN_CLIENT_ID {} requires the login feature", client_id);
+    }
+    Ok(auth)
+}
+
+/// Validation of tokens signed by the local dev key only, for running without an
+/// authserver
+#[cfg(feature = "dangerous-dev-mode")]
+fn local_only(
+    config: &Config,
+    mode: crate::devmode::DangerousDevMode,
+) -> anyhow::Result<(JwtAuth, OidConf)> {
+    let alg = match mode.local_key() {
+        Some((_, alg)) => *alg,
+        None => bail!("without an authserver, DANGEROUS_DEV_LOCAL_KEY or _SECRET is required"),
+    };
+    warn!("!!! DANGEROUS DEV MODE: no authserver, accepting local tokens only !!!");
+    let validation = Validation {
+        algorithms: vec![alg],
+        aud: audiences(config),
+        leeway: config.leeway_secs.unwrap_or(0),
+        ..Validation::default()
+    };
+    let auth = configure(
+        JwtAuth::new(validation, JwksStore::new(Default::default())),
+        config,
+    )?;
+    Ok((
+        auth.dangerous_dev_mode(mode),
+        undiscovered(serde_json::json!({"keys": []})),
+    ))
+}
+
+/// Validation with the keys of `JWKS_JSON`, `PUBLIC_KEY_PEM` and `HS256_SECRET`,
+/// sending no request at all
+fn static_keys(
+    config: &Config,
+    mut algorithms: Vec<Algorithm>,
+) -> anyhow::Result<(JwtAuth, OidConf)> {
+    let strength = match config.allow_weak_keys {
+        Some(true) => KeyStrength::allow_weak(),
+        _ => KeyStrength::default(),
+    };
+    let document = match &config.jwks_json {
+        Some(json) => serde_json::from_str(json).context("JWKS_JSON is not a JWK Set")?,
+        None => serde_json::json!({"keys": []}),
+    };
+    let jwks = openid::decoding_keys(&document, &strength)?;
+    if config.jwks_json.is_some() && jwks.is_empty() {
+        bail!("no usable keys in JWKS_JSON");
+    }
+    let public = match &config.public_key_pem {
+        Some(pem) => {
+            // Newlines are often escaped to fit the PEM in one line
+            let pem = pem.replace("\\n", "\n");
+            // SPKI documents look alike for RSA and EC keys
+            let key = DecodingKey::from_rsa_pem(pem.as_bytes())
+                .map(|key| (key.into_static(), Algorithm::RS256))
+                .or_else(|_| {
+                    DecodingKey::from_ec_pem(pem.as_bytes())
+                        .map(|key| (key.into_static(), Algorithm::ES256))
+                })
+                .context("no RSA or P-256 public key in PUBLIC_KEY_PEM")?;
+            Some(key)
+        }
+        None => None,
+    };
+    let secret = match &config.hs256_secret {
+        // jsonwebtoken verifies with keys of one family per validation
+        Some(_) if config.jwks_json.is_some() || public.is_some() => {
+            bail!("HS256_SECRET excludes JWKS_JSON and PUBLIC_KEY_PEM")
+        }
+        Some(secret) if secret.len() < 32 && !strength.allow_short_secrets => {
+            bail!("HS256_SECRET is shorter than 32 bytes")
+        }
+        Some(secret) => Some(DecodingKey::from_secret(secret.as_bytes()).into_static()),
+        None => None,
+    };
+    if config.algorithms.is_none() {
+        // The defaults are RSA's
+        match (&public, &secret) {
+            (_, Some(_)) => algorithms = vec![Algorithm::HS256],
+            (Some((_, Algorithm::ES256)), None) => algorithms = vec![Algorithm::ES256],
+            _ => {}
+        }
+    }
+    info!(
+        "verifying with {} configured keys, without discovery",
+        jwks.len() + public.iter().count() + secret.iter().count()
+    );
+
+    let validation = Validation {
+        algorithms,
+        iss: Some(config.authserver.clone()).filter(|iss| !iss.is_empty()),
+        aud: audiences(config),
+        leeway: config.leeway_secs.unwrap_or(0),
+        ..Validation::default()
+    };
+    let store = JwksStore::new(jwks);
+    let mut auth = JwtAuth::new(validation, store.clone());
+    if public.is_some() || secret.is_some() {
+        auth = auth.key_resolver(move |header: &Header, _: &Map<String, Value>| {
+            if let Some(secret) = &secret {
+                return Some(vec![secret.clone()]);
+            }
+            let mut keys = header
+                .kid
+                .as_deref()
+                .and_then(|kid| store.get(kid))
+                .unwrap_or_default();
+            keys.extend(public.iter().map(|(key, _)| key.clone()));
+            Some(keys).filter(|keys| !keys.is_empty())
+        });
+    }
+    Ok((configure(auth, config)?, undiscovered(document)))
+}
+
+/// The stand-in for the discovered configuration when there was no discovery
+fn undiscovered(jwks_document: Value) -> OidConf {
+    OidConf {
+        jwks: Default::default(),
+        issuer: String::new(),
+        jwks_uri: String::new(),
+        jwks_document,
+        authorization_endpoint: None,
+        token_endpoint: None,
+        end_session_endpoint: None,
+        introspection_endpoint: None,
+        revocation_endpoint: None,
+    }
+}
+
+/// The audience tokens must be for, any without `audience`
+fn audiences(config: &Config) -> Option<HashSet<String>> {
+    config
+        .audience
+        .as_ref()
+        .map(|aud| std::iter::once(aud.clone()).collect())
+}
+
+/// The configured algorithms, RS256, RS384 and RS512 by default
+fn algorithms(config: &Config) -> anyhow::Result<Vec<Algorithm>> {
+    match &config.algorithms {
+        None => Ok(vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512]),
+        Some(names) if names.is_empty() => bail!("algorithms must not be empty"),
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                Algorithm::from_str(name).with_context(|| format!("unsupported algorithm {}", name))
+            })
+            .collect(),
+    }
+}
+
+fn fetcher(config: &Config) -> anyhow::Result<Arc<dyn HttpFetch>> {
+    let timeout = config.http_timeout_secs.map(Duration::from_secs);
+    if let Some(pins) = &config.http_pins {
+        #[cfg(feature = "pinning")]
+        return Ok(Arc::new(crate::http::AwcFetch {
+            pins: pins.clone(),
+            ..crate::http::AwcFetch::new(timeout)
+        }));
+        #[cfg(not(feature = "pinning"))]
+        bail!("{} HTTP_PINS require the pinning feature", pins.len());
+    }
+    #[cfg(feature = "reqwest")]
+    return Ok(Arc::new(
+        crate::http::ClientConfig {
+            timeout,
+            connect_timeout: config.http_connect_timeout_secs.map(Duration::from_secs),
+            proxy: config.http_proxy.clone(),
+            root_certificates: match &config.http_ca_file {
+                Some(path) => vec![std::fs::read(path)?],
+                None => Vec::new(),
+            },
+        }
+        .build()?,
+    ));
+    #[cfg(not(feature = "reqwest"))]
+    Ok(Arc::new(crate::http::AwcFetch::new(timeout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            authserver: mockito::server_url(),
+            audience: Some("api".into()),
+            fetch_attempts: Some(1),
+            ..Config::default()
+        }
+    }
+
+    /// A 2048 bit RSA modulus
+    fn n() -> String {
+        base64::encode_config([0xc5; 256], base64::URL_SAFE_NO_PAD)
+    }
+
+    #[actix_rt::test]
+    async fn test_initialize() {
+        let disc = "/v2.0/.well-known/openid-configuration";
+        let jwks = "/init/keys";
+        let disc_mock = mockito::mock("GET", disc)
+            .with_body(format!(
+                r#"{{"jwks_uri": "{}{}", "issuer": "me"}}"#,
+                mockito::server_url(),
+                jwks
+            ))
+            .create();
+        let jwks_mock = mockito::mock("GET", jwks)
+            .with_body(
+                serde_json::json!({"keys": [{"kty": "RSA", "kid": "0", "n": n(), "e": "AQAB"}]})
+                    .to_string(),
+            )
+            .create();
+
+        assert!(JwtAuth::initialize(&config()).await.is_ok());
+        disc_mock.assert();
+        jwks_mock.assert();
+
+        // The standard path, for tokens of any audience
+        let disc_mock = mockito::mock("GET", "/.well-known/openid-configuration")
+            .with_body(format!(
+                r#"{{"jwks_uri": "{}{}", "issuer": "me"}}"#,
+                mockito::server_url(),
+                jwks
+            ))
+            .create();
+        let standard = Config {
+            audience: None,
+            discovery_path: Some("/.well-known/openid-configuration".into()),
+            ..config()
+        };
+        let (auth, _) = JwtAuth::discover(&standard).await.unwrap();
+        assert!(auth.validation_report().audiences.is_empty());
+        disc_mock.assert();
+
+        // Straight to the keys, without discovery
+        let jwks_mock = mockito::mock("GET", "/init/direct-keys")
+            .with_body(
+                serde_json::json!({"keys": [{"kty": "RSA", "kid": "0", "n": n(), "e": "AQAB"}]})
+                    .to_string(),
+            )
+            .create();
+        let (_, oidc) = JwtAuth::discover(&Config {
+            jwks_url: Some(mockito::server_url() + "/init/direct-keys"),
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert_eq!(oidc.issuer, mockito::server_url());
+        jwks_mock.assert();
+
+        let err = JwtAuth::initialize(&Config {
+            algorithms: Some(vec!["RS256".into(), "XX1".into()]),
+            ..config()
+        })
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("XX1"));
+    }
+
+    #[actix_rt::test]
+    async fn test_initialize_invalid_config() {
+        let err = JwtAuth::initialize(&Config {
+            audience: Some(" ".into()),
+            ..config()
+        })
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("AUDIENCE"));
+
+        let err = JwtAuth::initialize(&Config {
+            authserver: "not a url".into(),
+            ..config()
+        })
+        .await
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("AUTHSERVER"));
+    }
+
+    #[actix_rt::test]
+    async fn test_initialize_from_cache() {
+        let path = std::env::temp_dir().join(format!("init-cache-{}.json", std::process::id()));
+        WarmStart {
+            issuer: "me".into(),
+            jwks_uri: "https://example.com/keys".into(),
+            jwks: serde_json::json!({"keys": [{"kty": "RSA", "kid": "0", "n": n(), "e": "AQAB"}]}),
+        }
+        .save(&path)
+        .unwrap();
+
+        let auth = JwtAuth::initialize(&Config {
+            authserver: "http://127.0.0.1:1".into(),
+            jwks_cache_file: Some(path.to_string_lossy().into()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert!(auth.key_store().get("0").is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn test_static_keys() {
+        use crate::testing::TestKey;
+        use jsonwebtoken::{encode, EncodingKey};
+
+        let claims = serde_json::json!({"exp": 4_000_000_000u64, "iss": "me", "aud": "api"});
+        let key = TestKey::rsa(2048).unwrap();
+        let jwks = serde_json::json!({ "keys": [key.jwk("k1")] }).to_string();
+        let (auth, _) = JwtAuth::discover(&Config {
+            authserver: "me".into(),
+            jwks_json: Some(jwks.clone()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("k1".into());
+        let token = encode(&header, &claims, &key.encoding_key).unwrap();
+        assert!(auth.verify(&token).await.is_ok());
+
+        // Any audience is accepted only when none is configured
+        let elsewhere = serde_json::json!({"exp": 4_000_000_000u64, "iss": "me", "aud": "other"});
+        let elsewhere = encode(&header, &elsewhere, &key.encoding_key).unwrap();
+        assert!(auth.verify(&elsewhere).await.is_err());
+        let (any, _) = JwtAuth::discover(&Config {
+            authserver: "me".into(),
+            audience: None,
+            jwks_json: Some(jwks.clone()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        assert!(any.verify(&elsewhere).await.is_ok());
+        assert!(any.verify(&token).await.is_ok());
+
+        let secret = "a secret of the full 32 bytes..!";
+        let auth = JwtAuth::initialize(&Config {
+            authserver: "me".into(),
+            hs256_secret: Some(secret.into()),
+            ..config()
+        })
+        .await
+        .unwrap();
+        for (secret, ok) in [(secret, true), ("some other secret of 32 bytes..!", false)] {
+            let header = Header::new(Algorithm::HS256);
+            let token = encode(
+                &header,
+                &claims,
+                &EncodingKey::from_secret(secret.as_bytes()),
+            );
+            assert_eq!(auth.verify(&token.unwrap()).await.is_ok(), ok);
+        }
+
+        for (hs256_secret, jwks_json, error) in [
+            ("short", None, "32 bytes"),
+            (secret, Some(jwks), "excludes"),
+        ] {
+            let err = JwtAuth::initialize(&Config {
+                hs256_secret: Some(hs256_secret.into()),
+                jwks_json,
+                ..config()
+            })
+            .await
+            .err()
+            .unwrap();
+            assert!(err.to_string().contains(error));
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! JSON Web Key (RFC 7517) types, for key sets fetched by this crate or by users themselves
+use crate::keystore::Keys;
+use jsonwebtoken::DecodingKey;
+use log::{debug, warn};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A JWK Set. Keys that cannot be parsed, e.g. of an unknown type, are skipped.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct JwkSet {
+    #[serde(deserialize_with = "parsable_keys")]
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Jwk {
+    #[serde(flatten)]
+    pub params: KeyParams,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    /// `sig` or `enc`
+    #[serde(rename = "use", skip_serializing_if = "Option::is_none")]
+    pub key_use: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_ops: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+    /// SHA-1 thumbprint of the certificate, base64url encoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x5t: Option<String>,
+    /// SHA-256 thumbprint of the certificate, base64url encoded
+    #[serde(rename = "x5t#S256", skip_serializing_if = "Option::is_none")]
+    pub x5t_s256: Option<String>,
+}
+
+/// The key type and its type specific members, base64url encoded
+#[derive(Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "kty")]
+pub enum KeyParams {
+    #[serde(rename = "RSA")]
+    Rsa { n: String, e: String },
+    #[serde(rename = "EC")]
+    Ec { crv: String, x: String, y: String },
+    #[serde(rename = "OKP")]
+    Okp { crv: String, x: String },
+    #[serde(rename = "oct")]
+    Oct { k: String },
+}
+
+impl fmt::Debug for KeyParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyParams::Rsa { n, e } => f.debug_struct("Rsa").field("n", n).field("e", e).finish(),
+            KeyParams::Ec { crv, x, y } => f
+                .debug_struct("Ec")
+                .field("crv", crv)
+                .field("x", x)
+                .field("y", y)
+                .finish(),
+            KeyParams::Okp { crv, x } => f
+                .debug_struct("Okp")
+                .field("crv", crv)
+                .field("x", x)
+                .finish(),
+            KeyParams::Oct { .. } => f.debug_struct("Oct").field("k", &"<redacted>").finish(),
+        }
+    }
+}
+
+fn parsable_keys<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Jwk>, D::Error> {
+    Ok(Vec::<Value>::deserialize(deserializer)?
+        .into_iter()
+        .filter_map(|key| match Jwk::deserialize(&key) {
+            Ok(jwk) => Some(jwk),
+            Err(e) => {
+                debug!("skipping unparsable key {}: {}", key["kid"], e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Smallest keys accepted. Lower them only for issuers that cannot be fixed.
+#[derive(Clone, Debug)]
+pub struct KeyStrength {
+    pub min_rsa_bits: usize,
+    /// Accept HMAC secrets shorter than the output of the hash
+    pub allow_short_secrets: bool,
+}
+
+impl Default for KeyStrength {
+    fn default() -> Self {
+        KeyStrength {
+            min_rsa_bits: 2048,
+            allow_short_secrets: false,
+        }
+    }
+}
+
+impl KeyStrength {
+    /// Accept keys of any size
+    pub fn allow_weak() -> Self {
+        KeyStrength {
+            min_rsa_bits: 0,
+            allow_short_secrets: true,
+        }
+    }
+}
+
+/// The RFC 7638 thumbprint of a JWK in any JSON form, e.g. the `jwk` header of a DPoP
+/// proof to compare with `cnf.jkt`: the SHA-256 of the members required for its `kty`,
+/// in lexicographic order and without whitespace, base64url encoded. Other members
+/// do not change the thumbprint.
+pub fn thumbprint(jwk: &Value) -> anyhow::Result<String> {
+    let required: &[&str] = match jwk["kty"].as_str() {
+        Some("RSA") => &["e", "kty", "n"],
+        Some("EC") => &["crv", "kty", "x", "y"],
+        Some("OKP") => &["crv", "kty", "x"],
+        Some("oct") => &["k", "kty"],
+        other => anyhow::bail!("no thumbprint for key type {:?}", other),
+    };
+    let members = required
+        .iter()
+        .map(|member| match jwk[*member].as_str() {
+            Some(value) => Ok(format!("{}:{}", Value::from(*member), Value::from(value))),
+            None => Err(anyhow::anyhow!("key without {}", member)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let canonical = format!("{{{}}}", members.join(","));
+    let hash = ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes());
+    Ok(base64::encode_config(
+        hash.as_ref(),
+        base64::URL_SAFE_NO_PAD,
+    ))
+}
+
+fn b64(s: &str) -> anyhow::Result<Vec<u8>> {
+    Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+impl Jwk {
+    /// The RFC 7638 thumbprint, see `thumbprint()`. A stable kid for keys without one.
+    pub fn thumbprint(&self) -> String {
+        // The params always hold the required members
+        serde_json::to_value(&self.params)
+            .map_err(anyhow::Error::from)
+            .and_then(|params| thumbprint(&params))
+            .unwrap_or_default()
+    }
+
+    /// Whether this is a public key for verifying signatures, as opposed to a shared secret
+    pub fn is_asymmetric(&self) -> bool {
+        !matches!(self.params, KeyParams::Oct { .. })
+    }
+
+    /// Whether the key may verify signatures according to `use` and `key_ops`
+    pub fn is_signing(&self) -> bool {
+        self.key_use.as_deref().is_none_or(|u| u == "sig")
+            && self
+                .key_ops
+                .as_ref()
+                .is_none_or(|ops| ops.iter().any(|op| op == "verify"))
+    }
+
+    /// The kid, or the certificate thumbprint for keys published without one
+    pub fn id(&self) -> Option<&String> {
+        self.kid.as_ref().or(self.x5t.as_ref())
+    }
+
+    /// Fail for keys weaker than `strength` allows
+    pub fn check_strength(&self, strength: &KeyStrength) -> anyhow::Result<()> {
+        match &self.params {
+            KeyParams::Rsa { n, .. } => {
+                let n = b64(n)?;
+                let n = &n[n.iter().take_while(|b| **b == 0).count()..];
+                let bits = (n.len() * 8)
+                    .saturating_sub(n.first().map_or(0, |b| b.leading_zeros()) as usize);
+                if bits < strength.min_rsa_bits {
+                    anyhow::bail!(
+                        "{} bit RSA key is below {} bits",
+                        bits,
+                        strength.min_rsa_bits
+                    );
+                }
+            }
+            KeyParams::Oct { k } if !strength.allow_short_secrets => {
+                let min = match self.alg.as_deref() {
+                    Some("HS384") => 48,
+                    Some("HS512") => 64,
+                    _ => 32,
+                };
+                if b64(k)?.len() < min {
+                    anyhow::bail!("HMAC secret is shorter than {} bytes", min);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The key for verifying signatures, if it is at least as strong as the default `KeyStrength`
+    pub fn decoding_key(&self) -> anyhow::Result<DecodingKey<'static>> {
+        self.decoding_key_with(&KeyStrength::default())
+    }
+
+    pub fn decoding_key_with(
+        &self,
+        strength: &KeyStrength,
+    ) -> anyhow::Result<DecodingKey<'static>> {
+        self.check_strength(strength)?;
+        match &self.params {
+            KeyParams::Rsa { n, e } => Ok(DecodingKey::from_rsa_components(n, e).into_static()),
+            KeyParams::Ec { crv, x, y } => {
+                let (x, y) = (b64(x)?, b64(y)?);
+                let len = match crv.as_str() {
+                    "P-256" => 32,
+                    "P-384" => 48,
+                    _ => anyhow::bail!("unsupported curve {}", crv),
+                };
+                if x.len() != len || y.len() != len {
+                    anyhow::bail!("invalid {} coordinates", crv);
+                }
+                // Uncompressed point, as expected for EC verification
+                let point = [&[4u8][..], &x, &y].concat();
+                Ok(DecodingKey::from_ec_der(&point).into_static())
+            }
+            KeyParams::Okp { crv, .. } => anyhow::bail!("unsupported OKP curve {}", crv),
+            KeyParams::Oct { k } => Ok(DecodingKey::from_secret(&b64(k)?).into_static()),
+        }
+    }
+}
+
+impl JwkSet {
+    /// Decoding keys of the public signing keys by `id()`. Shared secrets are never included.
+    /// Keys published under the same kid, e.g. during a rollover, are all kept.
+    pub fn decoding_keys(&self) -> Keys {
+        self.decoding_keys_with(&KeyStrength::default())
+    }
+
+    /// `decoding_keys` skipping keys weaker than `strength`
+    pub fn decoding_keys_with(&self, strength: &KeyStrength) -> Keys {
+        let mut keys = Keys::new();
+        let usable = self
+            .keys
+            .iter()
+            .filter(|jwk| jwk.is_asymmetric())
+            .filter(|jwk| {
+                let signing = jwk.is_signing();
+                if !signing {
+                    warn!(
+                        "skipping key {:?} not meant for signatures (use {:?}, key_ops {:?})",
+                        jwk.kid, jwk.key_use, jwk.key_ops
+                    );
+                }
+                signing
+            })
+            .filter_map(|jwk| {
+                let kid = jwk.id()?;
+                match jwk.decoding_key_with(strength) {
+                    Ok(key) => Some((kid.clone(), key)),
+                    Err(e) => {
+                        warn!("skipping key {}: {}", kid, e);
+                        None
+                    }
+                }
+            });
+        for (kid, key) in usable {
+            keys.entry(kid).or_default().push(key);
+        }
+        keys
+    }
+
+    /// The `id()` of each key by its `x5t` and `x5t#S256` thumbprints
+    pub fn thumbprints(&self) -> HashMap<String, String> {
+        self.keys
+            .iter()
+            .filter_map(|jwk| Some((jwk, jwk.id()?)))
+            .flat_map(|(jwk, id)| {
+                vec![jwk.x5t.clone(), jwk.x5t_s256.clone()]
+                    .into_iter()
+                    .flatten()
+                    .map(move |thumbprint| (thumbprint, id.clone()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jwk_set() {
+        let n = |b| base64::encode_config([b; 256], base64::URL_SAFE_NO_PAD);
+        let document = r#"{"keys": [
+                {"kty": "RSA", "kid": "rsa", "use": "sig", "alg": "RS256", "n": "N1", "e": "AQAB"},
+                {"kty": "EC", "kid": "ec", "crv": "P-256",
+                 "x": "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU",
+                 "y": "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0"},
+                {"kty": "EC", "kid": "short", "crv": "P-256", "x": "AQAB", "y": "AQAB"},
+                {"kty": "OKP", "kid": "ed", "crv": "Ed25519", "x": "11qYAYKxCrfVS_7TyWQHOg7hcvPapiMlrwIaaPcHURo"},
+                {"kty": "oct", "kid": "hmac", "k": "c2VjcmV0"},
+                {"kty": "unknown", "kid": "?"},
+                {"kty": "RSA", "n": "N1", "e": "AQAB"},
+                {"kty": "RSA", "kid": "enc", "use": "enc", "n": "N1", "e": "AQAB"},
+                {"kty": "RSA", "kid": "wrap", "key_ops": ["wrapKey"], "n": "N1", "e": "AQAB"},
+                {"kty": "RSA", "kid": "verify", "key_ops": ["verify"], "n": "N1", "e": "AQAB"},
+                {"kty": "RSA", "kid": "rsa", "n": "N2", "e": "AQAB"},
+                {"kty": "RSA", "x5t": "sha1", "x5t#S256": "sha256", "n": "N1", "e": "AQAB"},
+                {"kty": "RSA", "kid": "weak", "n": "AQAB", "e": "AQAB"}
+            ]}"#
+        .replace("N1", &n(0xc5))
+        .replace("N2", &n(0xc6));
+        let set: JwkSet = serde_json::from_str(&document).unwrap();
+        assert_eq!(set.keys.len(), 12);
+        assert_eq!(set.keys[0].key_use.as_deref(), Some("sig"));
+        assert_eq!(set.keys[0].alg.as_deref(), Some("RS256"));
+
+        let keys = set.decoding_keys();
+        let mut kids: Vec<_> = keys.keys().collect();
+        kids.sort();
+        assert_eq!(kids, vec!["ec", "rsa", "sha1", "verify"]);
+        assert_eq!(keys["rsa"].len(), 2);
+        assert_eq!(set.thumbprints()["sha256"], "sha1");
+
+        let json = serde_json::to_value(&set.keys[0]).unwrap();
+        assert_eq!(json["kty"], "RSA");
+        assert_eq!(json["use"], "sig");
+    }
+
+    #[test]
+    fn test_debug_redacts_secrets() {
+        let jwk: Jwk =
+            serde_json::from_str(r#"{"kty": "oct", "kid": "hmac", "k": "c2VjcmV0"}"#).unwrap();
+        let debug = format!("{:?}", jwk);
+        assert!(debug.contains("hmac") && !debug.contains("c2VjcmV0"));
+    }
+
+    #[test]
+    fn test_thumbprint() {
+        // RFC 7638 section 3.1
+        let jwk: Jwk = serde_json::from_value(serde_json::json!({
+            "kty": "RSA",
+            "n": "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw",
+            "e": "AQAB",
+            "alg": "RS256",
+            "kid": "2011-04-29"
+        }))
+        .unwrap();
+        assert_eq!(
+            jwk.thumbprint(),
+            "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"
+        );
+        let mut raw = serde_json::to_value(&jwk).unwrap();
+        raw["x5c"] = serde_json::json!(["MIIC"]);
+        assert_eq!(thumbprint(&raw).unwrap(), jwk.thumbprint());
+        let partial = serde_json::json!({"kty": "EC", "crv": "P-256", "x": "AA"});
+        assert!(thumbprint(&partial).is_err());
+        assert!(thumbprint(&serde_json::json!({"kty": "XX"})).is_err());
+    }
+
+    #[test]
+    fn test_key_strength() {
+        let jwk = |params| Jwk {
+            params,
+            kid: None,
+            key_use: None,
+            key_ops: None,
+            alg: Some("HS512".into()),
+            x5t: None,
+            x5t_s256: None,
+        };
+        let n = |bytes: &[u8]| base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+        let rsa = |bytes: &[u8]| {
+            jwk(KeyParams::Rsa {
+                n: n(bytes),
+                e: "AQAB".into(),
+            })
+        };
+        let secret = |len: usize| {
+            jwk(KeyParams::Oct {
+                k: n(&vec![1; len]),
+            })
+        };
+        let strong = KeyStrength::default();
+
+        assert!(rsa(&[0xff; 256]).check_strength(&strong).is_ok());
+        assert!(rsa(&[&[0u8, 0x7f][..], &[0xff; 255]].concat())
+            .check_strength(&strong)
+            .is_err());
+        assert!(secret(64).check_strength(&strong).is_ok());
+        assert!(secret(32).check_strength(&strong).is_err());
+        assert!(secret(32)
+            .check_strength(&KeyStrength::allow_weak())
+            .is_ok());
+        assert!(rsa(&[1; 64])
+            .check_strength(&KeyStrength::allow_weak())
+            .is_ok());
+    }
+}
@@ -0,0 +1,717 @@
+use crate::breaker::CircuitBreaker;
+use crate::http::{default_fetcher, HttpFetch};
+use crate::jwk::JwkSet;
+use crate::openid::{self, Limits, Retry};
+use crate::persist::WarmStart;
+use crate::report::{report, Component};
+use crate::shutdown::Shutdown;
+use actix_web::HttpResponse;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::{ready, select, Either, FutureExt, Ready};
+use futures::StreamExt;
+use jsonwebtoken::DecodingKey;
+use log::{info, warn};
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Decoding keys by kid. A kid can have several keys while an issuer rolls them over.
+pub type Keys = HashMap<String, Vec<DecodingKey<'static>>>;
+
+struct Inner {
+    keys: Keys,
+    /// Inserted at runtime, kept across refreshes
+    manual: Keys,
+    /// Never loaded again by refreshes
+    revoked: HashSet<String>,
+    /// Kids by certificate thumbprint
+    thumbprints: HashMap<String, String>,
+    updated: Instant,
+    updated_at: SystemTime,
+    last_error: Option<String>,
+    failures: u32,
+    /// When the refreshes failing since began to
+    failing_since: Option<Instant>,
+    /// Of `keys`, updated with them
+    fingerprint: String,
+}
+
+impl Inner {
+    fn set_keys(&mut self, keys: Keys) {
+        self.fingerprint = fingerprint(&keys);
+        self.keys = keys;
+    }
+}
+
+/// SHA-256 of the keys in kid order, the same for the same keys in any process
+fn fingerprint(keys: &Keys) -> String {
+    let kids: std::collections::BTreeMap<_, _> = keys.iter().collect();
+    // Debug output of decoding keys has their material
+    let hash = digest(&SHA256, format!("{:?}", kids).as_bytes());
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// Key store state for readiness probes and alerting
+#[derive(Clone, Debug, Serialize)]
+pub struct Health {
+    pub ready: bool,
+    pub keys: usize,
+    /// Unix time of the last successful load of the keys
+    pub last_refresh: u64,
+    pub stale_secs: u64,
+    pub last_error: Option<String>,
+    /// Refreshes failed since the last successful one
+    pub consecutive_failures: u32,
+}
+
+/// Decoding keys by kid, shared between workers and replaced on refresh.
+///
+/// Keys can also be managed at runtime, e.g. from an admin task: inserted keys
+/// survive refreshes, and revoked kids are dropped from every later refresh.
+#[derive(Clone)]
+pub struct JwksStore {
+    inner: Arc<RwLock<Inner>>,
+    max_staleness: Option<Duration>,
+}
+
+impl Default for JwksStore {
+    fn default() -> Self {
+        JwksStore::new(Keys::new())
+    }
+}
+
+impl JwksStore {
+    pub fn new(keys: Keys) -> Self {
+        JwksStore {
+            inner: Arc::new(RwLock::new(Inner {
+                fingerprint: fingerprint(&keys),
+                keys,
+                manual: Keys::new(),
+                revoked: HashSet::new(),
+                thumbprints: HashMap::new(),
+                updated: Instant::now(),
+                updated_at: SystemTime::now(),
+                last_error: None,
+                failures: 0,
+                failing_since: None,
+            })),
+            max_staleness: None,
+        }
+    }
+
+    /// Stop serving keys once refreshes have been failing for `max_staleness`
+    pub fn max_staleness(mut self, max_staleness: Duration) -> Self {
+        self.max_staleness = Some(max_staleness);
+        self
+    }
+
+    /// Identifies the current keys, e.g. to tell apart what claims were verified with
+    pub(crate) fn fingerprint(&self) -> String {
+        self.inner.read().unwrap().fingerprint.clone()
+    }
+
+    /// Whether any key is published under `kid`
+    pub fn contains(&self, kid: &str) -> bool {
+        self.inner.read().unwrap().keys.contains_key(kid)
+    }
+
+    /// Every key published under `kid`, to be tried in turn
+    pub fn get(&self, kid: &str) -> Option<Vec<DecodingKey<'static>>> {
+        self.inner.read().unwrap().keys.get(kid).cloned()
+    }
+
+    /// The kid of the key with this `x5t` or `x5t#S256` certificate thumbprint
+    pub fn kid_for_thumbprint(&self, thumbprint: &str) -> Option<String> {
+        self.inner
+            .read()
+            .unwrap()
+            .thumbprints
+            .get(thumbprint)
+            .cloned()
+    }
+
+    /// Replace the thumbprint to kid mapping, refreshed along with the keys
+    pub fn replace_thumbprints(&self, thumbprints: HashMap<String, String>) {
+        self.inner.write().unwrap().thumbprints = thumbprints;
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Time since the keys were last replaced
+    pub fn staleness(&self) -> Duration {
+        self.inner.read().unwrap().updated.elapsed()
+    }
+
+    /// Time since refreshes started failing, while they still do, the keys being
+    /// served stale meanwhile
+    pub fn failing_for(&self) -> Option<Duration> {
+        let inner = self.inner.read().unwrap();
+        inner.failing_since.map(|since| since.elapsed())
+    }
+
+    /// Whether refreshes have been failing for longer than the maximum staleness
+    pub fn is_expired(&self) -> bool {
+        let failing = self.failing_for();
+        self.max_staleness
+            .is_some_and(|max| failing.is_some_and(|failing| failing > max))
+    }
+
+    /// Ready once keys are loaded, as long as they are not expired
+    pub fn health(&self) -> Health {
+        let inner = self.inner.read().unwrap();
+        let stale = inner.updated.elapsed();
+        let failing = inner.failing_since.map(|since| since.elapsed());
+        let expired = self
+            .max_staleness
+            .is_some_and(|max| failing.is_some_and(|failing| failing > max));
+        Health {
+            ready: !inner.keys.is_empty() && !expired,
+            keys: inner.keys.len(),
+            last_refresh: inner
+                .updated_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            stale_secs: stale.as_secs(),
+            last_error: inner.last_error.clone(),
+            consecutive_failures: inner.failures,
+        }
+    }
+
+    /// A handler answering the health as JSON, with 503 until the store is ready,
+    /// e.g. `.route("/ready", web::get().to(store.readiness()))`
+    pub fn readiness(&self) -> impl Fn() -> Ready<HttpResponse> + Clone + 'static {
+        let store = self.clone();
+        move || {
+            let health = store.health();
+            ready(if health.ready {
+                HttpResponse::Ok().json(health)
+            } else {
+                HttpResponse::ServiceUnavailable().json(health)
+            })
+        }
+    }
+
+    /// Add or overwrite a key, kept until removed even when refreshes no longer publish it
+    pub fn insert(&self, kid: impl Into<String>, key: DecodingKey<'static>) {
+        let kid = kid.into();
+        let mut inner = self.inner.write().unwrap();
+        inner.revoked.remove(&kid);
+        inner.manual.insert(kid.clone(), vec![key.clone()]);
+        let mut keys = std::mem::take(&mut inner.keys);
+        keys.insert(kid, vec![key]);
+        inner.set_keys(keys);
+    }
+
+    /// Evict the keys of a kid until the next refresh publishing it
+    pub fn remove(&self, kid: &str) -> Option<Vec<DecodingKey<'static>>> {
+        let mut inner = self.inner.write().unwrap();
+        inner.manual.remove(kid);
+        let mut keys = std::mem::take(&mut inner.keys);
+        let removed = keys.remove(kid);
+        inner.set_keys(keys);
+        removed
+    }
+
+    /// Evict a key and ignore it in every later refresh, e.g. when it is compromised
+    pub fn revoke(&self, kid: &str) -> Option<Vec<DecodingKey<'static>>> {
+        let key = self.remove(kid);
+        self.inner.write().unwrap().revoked.insert(kid.into());
+        key
+    }
+
+    /// Replace every key, including inserted ones
+    pub fn replace_all(&self, keys: Keys) {
+        let mut inner = self.inner.write().unwrap();
+        inner.manual.clear();
+        inner.set_keys(keys);
+        inner.updated = Instant::now();
+        inner.updated_at = SystemTime::now();
+        inner.failing_since = None;
+    }
+
+    /// A copy of the current keys
+    pub fn snapshot(&self) -> Keys {
+        self.inner.read().unwrap().keys.clone()
+    }
+
+    /// Store freshly fetched keys, keeping inserted keys and dropping revoked ones
+    pub(crate) fn refreshed(&self, mut keys: Keys) {
+        let mut inner = self.inner.write().unwrap();
+        keys.retain(|kid, _| !inner.revoked.contains(kid));
+        keys.extend(inner.manual.clone());
+        inner.set_keys(keys);
+        inner.updated = Instant::now();
+        inner.updated_at = SystemTime::now();
+        inner.last_error = None;
+        inner.failures = 0;
+        inner.failing_since = None;
+    }
+
+    pub(crate) fn record_failure(&self, error: &anyhow::Error) {
+        let mut inner = self.inner.write().unwrap();
+        inner.last_error = Some(error.to_string());
+        inner.failures += 1;
+        inner.failing_since.get_or_insert_with(Instant::now);
+    }
+}
+
+impl From<Keys> for JwksStore {
+    fn from(keys: Keys) -> Self {
+        JwksStore::new(keys)
+    }
+}
+
+/// Periodically refetches the JWK Set into a `JwksStore`. While the endpoint
+/// keeps failing the circuit breaker stops requests and the previous keys stay in use.
+///
+/// Tokens with an unknown kid trigger an early refetch, but a kid still missing
+/// afterwards is not looked up again for `kid_cooldown`, and such refetches
+/// happen at most once per `min_refetch_interval`. A lookup throttled while a
+/// refetch is running waits for it and sees its keys.
+///
+/// Background refreshes are spread out by a random jitter, so that instances started
+/// together do not all hit the endpoint at once, and concurrent refreshes within an
+/// instance share a single fetch.
+#[derive(Clone)]
+pub struct Refresher {
+    jwks_uri: String,
+    store: JwksStore,
+    client: Arc<dyn HttpFetch>,
+    interval: Duration,
+    jitter: f64,
+    kid_cooldown: Duration,
+    min_refetch_interval: Duration,
+    limits: Limits,
+    breaker: Arc<CircuitBreaker>,
+    unknown_kids: Arc<Mutex<UnknownKids>>,
+    /// Held while fetching, with the outcome of the last fetch
+    in_flight: Arc<futures::lock::Mutex<Result<(), String>>>,
+    /// Fetches completed so far
+    fetches: Arc<AtomicU64>,
+    trigger: UnboundedSender<()>,
+    requests: Arc<Mutex<Option<UnboundedReceiver<()>>>>,
+    /// Where to persist fetched keys, and the issuer stored alongside them
+    persist: Option<(String, String)>,
+    shutdown: Shutdown,
+}
+
+/// Cloneable trigger for an out of band refresh, e.g. from an admin route or a signal handler
+#[derive(Clone, Debug)]
+pub struct RefreshHandle(UnboundedSender<()>);
+
+impl RefreshHandle {
+    /// Ask for a refresh as soon as possible. Returns false if the refresh task is gone.
+    pub fn refresh_now(&self) -> bool {
+        self.0.unbounded_send(()).is_ok()
+    }
+}
+
+#[derive(Default)]
+struct UnknownKids {
+    kids: HashMap<String, Instant>,
+    last_refetch: Option<Instant>,
+}
+
+impl Refresher {
+    pub fn new(jwks_uri: impl Into<String>, store: JwksStore) -> Self {
+        let (trigger, requests) = mpsc::unbounded();
+        Refresher {
+            jwks_uri: jwks_uri.into(),
+            store,
+            client: default_fetcher(),
+            interval: Duration::from_secs(3600),
+            jitter: 0.1,
+            kid_cooldown: Duration::from_secs(300),
+            min_refetch_interval: Duration::from_secs(10),
+            limits: Limits::default(),
+            breaker: Arc::new(CircuitBreaker::default()),
+            unknown_kids: Arc::default(),
+            in_flight: Arc::new(futures::lock::Mutex::new(Ok(()))),
+            fetches: Arc::default(),
+            trigger,
+            requests: Arc::new(Mutex::new(Some(requests))),
+            persist: None,
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    pub fn client(mut self, client: Arc<dyn HttpFetch>) -> Self {
+        self.client = client;
+        self
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Shorten each background interval by a random fraction of up to `jitter`, 0.1
+    /// by default
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn kid_cooldown(mut self, kid_cooldown: Duration) -> Self {
+        self.kid_cooldown = kid_cooldown;
+        self
+    }
+
+    pub fn min_refetch_interval(mut self, min_refetch_interval: Duration) -> Self {
+        self.min_refetch_interval = min_refetch_interval;
+        self
+    }
+
+    /// Reject JWK Sets beyond these bounds, keeping the previous keys
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Save every successfully fetched JWK Set to `path` as a `WarmStart`
+    pub fn persist_to(mut self, path: impl Into<String>, issuer: impl Into<String>) -> Self {
+        self.persist = Some((path.into(), issuer.into()));
+        self
+    }
+
+    pub fn breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Arc::new(breaker);
+        self
+    }
+
+    /// Fetch the keys once, unless the circuit is open. Calls while a fetch is under
+    /// way wait for it and share its outcome.
+    pub async fn refresh(&self) -> anyhow::Result<()> {
+        let seen = self.fetches.load(Ordering::SeqCst);
+        let mut last = self.in_flight.lock().await;
+        if self.fetches.load(Ordering::SeqCst) != seen {
+            return last.clone().map_err(anyhow::Error::msg);
+        }
+        let result = self.fetch().await;
+        *last = result.as_ref().map(|_| ()).map_err(|e| format!("{:#}", e));
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+        result
+    }
+
+    async fn fetch(&self) -> anyhow::Result<()> {
+        if !self.breaker.allow() {
+            anyhow::bail!("circuit open for {}", self.jwks_uri);
+        }
+        let retry = Retry {
+            attempts: 1,
+            ..Retry::default()
+        };
+        let fetched =
+            openid::get_jwks_document(self.client.as_ref(), &self.jwks_uri, &retry, &self.limits)
+                .await
+                .and_then(|document| Ok((JwkSet::deserialize(&document)?, document)));
+        match fetched {
+            Ok((set, document)) => {
+                self.breaker.record_success();
+                let keys = set.decoding_keys_with(&self.limits.key_strength);
+                info!("refreshed {} keys from {}", keys.len(), self.jwks_uri);
+                self.store.refreshed(keys);
+                self.store.replace_thumbprints(set.thumbprints());
+                if let Some((path, issuer)) = &self.persist {
+                    let warm = WarmStart {
+                        issuer: issuer.clone(),
+                        jwks_uri: self.jwks_uri.clone(),
+                        jwks: document,
+                    };
+                    if let Err(e) = warm.save(path) {
+                        warn!("saving keys to {} failed: {}", path, e);
+                        report(Component::Persistence, &e);
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.breaker.record_failure();
+                report(Component::Jwks, &e);
+                self.store.record_failure(&e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Refetch the keys looking for `kid`, unless it was recently missing.
+    /// Returns whether the store has the kid afterwards.
+    pub async fn refresh_for_kid(&self, kid: &str) -> bool {
+        if self.store.get(kid).is_some() {
+            return true;
+        }
+        let throttled = {
+            let now = Instant::now();
+            let mut unknown = self.unknown_kids.lock().unwrap();
+            let cooldown = self.kid_cooldown;
+            unknown
+                .kids
+                .retain(|_, since| now.duration_since(*since) < cooldown);
+            let throttled = unknown
+                .last_refetch
+                .is_some_and(|last| now.duration_since(last) < self.min_refetch_interval);
+            if unknown.kids.contains_key(kid) {
+                return false;
+            }
+            if !throttled {
+                unknown.last_refetch = Some(now);
+            }
+            throttled
+        };
+        if throttled {
+            drop(self.in_flight.lock().await);
+            return self.store.get(kid).is_some();
+        }
+        if let Err(e) = self.refresh().await {
+            warn!("key refresh for kid {} failed: {}", kid, e);
+        }
+        if self.store.get(kid).is_some() {
+            return true;
+        }
+        self.unknown_kids
+            .lock()
+            .unwrap()
+            .kids
+            .insert(kid.into(), Instant::now());
+        false
+    }
+
+    /// Stop the spawned background task with `shutdown`, e.g. one shared with other
+    /// tasks, rather than a shutdown of its own
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// What stops the spawned background task
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// A handle forcing an immediate refresh by the spawned background task
+    pub fn handle(&self) -> RefreshHandle {
+        RefreshHandle(self.trigger.clone())
+    }
+
+    /// The interval less a random jitter
+    fn next_delay(&self) -> Duration {
+        let jitter = rand::thread_rng().gen_range(0.0, 1.0) * self.jitter;
+        self.interval.mul_f64(1.0 - jitter)
+    }
+
+    /// Refresh on every interval in the background, and whenever a `RefreshHandle` asks,
+    /// until the `Shutdown` stops it
+    pub fn spawn(&self) {
+        let refresher = self.clone();
+        let mut requests = self.requests.lock().unwrap().take();
+        self.shutdown.spawn(async move {
+            loop {
+                let delay = actix_rt::time::delay_for(refresher.next_delay()).boxed_local();
+                match requests.as_mut() {
+                    Some(rx) => match select(delay, rx.next()).await {
+                        Either::Left(_) => {}
+                        Either::Right((Some(()), _)) => {
+                            // Coalesce requests queued up meanwhile
+                            while rx.try_recv().is_ok() {}
+                            info!("refresh of {} requested", refresher.jwks_uri);
+                        }
+                        Either::Right((None, _)) => {
+                            requests = None;
+                            continue;
+                        }
+                    },
+                    None => delay.await,
+                }
+                if let Err(e) = refresher.refresh().await {
+                    warn!(
+                        "key refresh failed, serving keys {}s stale: {}",
+                        refresher.store.staleness().as_secs(),
+                        e
+                    );
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_breaker_keeps_stale_keys() {
+        let path = "/common/discovery/keys";
+        let mock = mockito::mock("GET", path)
+            .with_status(503)
+            .expect(2)
+            .create();
+
+        let mut keys = Keys::new();
+        keys.insert("old".into(), vec![DecodingKey::from_secret(b"secret")]);
+        let store = JwksStore::new(keys);
+        let refresher = Refresher::new(mockito::server_url() + path, store.clone())
+            .breaker(CircuitBreaker::new(2, Duration::from_secs(60)));
+
+        for _ in 0..3 {
+            assert!(refresher.refresh().await.is_err());
+        }
+        assert!(store.get("old").is_some());
+        mock.assert();
+    }
+
+    #[test]
+    fn test_max_staleness() {
+        let store = JwksStore::new(Keys::new()).max_staleness(Duration::from_millis(10));
+        // Old keys, yet the last refresh did not fail
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(!store.is_expired());
+        assert_eq!(store.failing_for(), None);
+
+        store.record_failure(&anyhow::anyhow!("down"));
+        assert!(!store.is_expired());
+        std::thread::sleep(Duration::from_millis(10));
+        // Measured from the first failure in a row
+        store.record_failure(&anyhow::anyhow!("down"));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(store.is_expired());
+        assert!(store.failing_for().unwrap() >= Duration::from_millis(15));
+        store.refreshed(Keys::new());
+        assert!(!store.is_expired());
+        assert_eq!(store.failing_for(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_kid_cooldown() {
+        let path = "/keys/cooldown";
+        let mock = mockito::mock("GET", path)
+            .with_body(r#"{"keys": []}"#)
+            .expect(2)
+            .create();
+
+        let refresher = Refresher::new(mockito::server_url() + path, JwksStore::default())
+            .min_refetch_interval(Duration::from_secs(0));
+        assert!(!refresher.refresh_for_kid("a").await);
+        assert!(!refresher.refresh_for_kid("a").await);
+        assert!(!refresher.refresh_for_kid("b").await);
+        mock.assert();
+
+        let refresher = refresher.min_refetch_interval(Duration::from_secs(60));
+        assert!(!refresher.refresh_for_kid("c").await);
+        mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_concurrent_new_kid() {
+        let path = "/keys/rotated";
+        let n = base64::encode_config([0xc5; 256], base64::URL_SAFE_NO_PAD);
+        let mock = mockito::mock("GET", path)
+            .with_body(
+                serde_json::json!({"keys": [{"kty": "RSA", "kid": "new", "n": n, "e": "AQAB"}]})
+                    .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let refresher = Refresher::new(mockito::server_url() + path, JwksStore::default());
+        let lookups = futures::future::join_all((0..4).map(|_| refresher.refresh_for_kid("new")));
+        assert_eq!(lookups.await, vec![true; 4]);
+        mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_health() {
+        use actix_web::{http::StatusCode, test, web, App};
+
+        let store = JwksStore::default();
+        let mut app =
+            test::init_service(App::new().route("/ready", web::get().to(store.readiness()))).await;
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        store.record_failure(&anyhow::anyhow!("down"));
+        let health = store.health();
+        assert_eq!(health.last_error.as_deref(), Some("down"));
+        assert_eq!(health.consecutive_failures, 1);
+
+        let mut keys = Keys::new();
+        keys.insert("0".into(), vec![DecodingKey::from_secret(b"secret")]);
+        store.refreshed(keys);
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let health: serde_json::Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(health["ready"], true);
+        assert_eq!(health["keys"], 1);
+        assert_eq!(health["consecutive_failures"], 0);
+    }
+
+    #[test]
+    fn test_runtime_management() {
+        let key = || vec![DecodingKey::from_secret(b"secret")];
+        let mut keys = Keys::new();
+        keys.insert("a".into(), key());
+        keys.insert("b".into(), key());
+        let store = JwksStore::new(keys.clone());
+
+        store.insert("emergency", DecodingKey::from_secret(b"secret"));
+        store.revoke("b");
+        assert!(store.remove("a").is_some());
+        assert!(store.get("a").is_none());
+
+        store.refreshed(keys);
+        let mut kids: Vec<_> = store.snapshot().into_keys().collect();
+        kids.sort();
+        assert_eq!(kids, vec!["a", "emergency"]);
+
+        store.replace_all(Keys::new());
+        assert!(store.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_handle() {
+        let path = "/keys/handle";
+        let mock = mockito::mock("GET", path)
+            .with_body(r#"{"keys": []}"#)
+            .expect(1)
+            .create();
+
+        let refresher = Refresher::new(mockito::server_url() + path, JwksStore::default());
+        let handle = refresher.handle();
+        refresher.spawn();
+        assert!(handle.clone().refresh_now());
+        assert!(handle.refresh_now());
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+        mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_refresh_coalescing() {
+        let path = "/keys/coalesce";
+        let mock = mockito::mock("GET", path)
+            .with_body(r#"{"keys": []}"#)
+            .expect(2)
+            .create();
+
+        let refresher = Refresher::new(mockito::server_url() + path, JwksStore::default())
+            .interval(Duration::from_secs(100))
+            .jitter(0.5);
+        let (a, b) = futures::join!(refresher.refresh(), refresher.refresh());
+        assert!(a.is_ok() && b.is_ok());
+        refresher.refresh().await.unwrap();
+        mock.assert();
+
+        for _ in 0..20 {
+            let delay = refresher.next_delay();
+            assert!(delay > Duration::from_secs(50) && delay <= Duration::from_secs(100));
+        }
+    }
+}
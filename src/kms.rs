@@ -0,0 +1,390 @@
+//! Keys in AWS KMS, signing for the `issuer` module and verifying HMAC tokens whose
+//! secret never leaves KMS
+use crate::aws::{AwsClient, AwsError};
+use crate::jwk::KeyParams;
+use crate::remote::{RemoteFuture, RemoteKey};
+use anyhow::{bail, ensure, Context};
+use jsonwebtoken::Algorithm;
+use ring::digest;
+use serde_json::{json, Value};
+
+/// How KMS computes the signatures of an algorithm
+enum Scheme {
+    /// `GenerateMac` and `VerifyMac` of the message
+    Mac(&'static str),
+    /// `Sign` and `Verify` of the digest of the message, DER encoded for ECDSA with
+    /// coordinates of `ecdsa_len` bytes
+    Sign {
+        algorithm: &'static str,
+        digest: &'static digest::Algorithm,
+        ecdsa_len: Option<usize>,
+    },
+}
+
+fn scheme(alg: Algorithm) -> Scheme {
+    let sign = |algorithm, digest, ecdsa_len| Scheme::Sign {
+        algorithm,
+        digest,
+        ecdsa_len,
+    };
+    match alg {
+        Algorithm::HS256 => Scheme::Mac("HMAC_SHA_256"),
+        Algorithm::HS384 => Scheme::Mac("HMAC_SHA_384"),
+        Algorithm::HS512 => Scheme::Mac("HMAC_SHA_512"),
+        Algorithm::RS256 => sign("RSASSA_PKCS1_V1_5_SHA_256", &digest::SHA256, None),
+        Algorithm::RS384 => sign("RSASSA_PKCS1_V1_5_SHA_384", &digest::SHA384, None),
+        Algorithm::RS512 => sign("RSASSA_PKCS1_V1_5_SHA_512", &digest::SHA512, None),
+        Algorithm::PS256 => sign("RSASSA_PSS_SHA_256", &digest::SHA256, None),
+        Algorithm::PS384 => sign("RSASSA_PSS_SHA_384", &digest::SHA384, None),
+        Algorithm::PS512 => sign("RSASSA_PSS_SHA_512", &digest::SHA512, None),
+        Algorithm::ES256 => sign("ECDSA_SHA_256", &digest::SHA256, Some(32)),
+        Algorithm::ES384 => sign("ECDSA_SHA_384", &digest::SHA384, Some(48)),
+    }
+}
+
+/// A KMS key, by ARN, id or alias, used with one algorithm: HMAC keys for HS*, RSA
+/// keys for RS* and PS*, and ECC NIST keys for ES256 and ES384
+pub struct KmsKey {
+    key_id: String,
+    alg: Algorithm,
+    aws: AwsClient,
+}
+
+impl KmsKey {
+    pub fn new(
+        key_id: impl Into<String>,
+        alg: Algorithm,
+        region: impl Into<String>,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Self {
+        KmsKey {
+            key_id: key_id.into(),
+            alg,
+            aws: AwsClient::new(
+                "kms",
+                region.into(),
+                access_key_id.into(),
+                secret_access_key.into(),
+            ),
+        }
+    }
+
+    /// Credentials from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN`, in the region of a key ARN or else `AWS_REGION`
+    pub fn from_env(key_id: impl Into<String>, alg: Algorithm) -> anyhow::Result<Self> {
+        let key_id = key_id.into();
+        // arn:aws:kms:<region>:<account>:key/<id>
+        let region = Some(&key_id)
+            .filter(|id| id.starts_with("arn:"))
+            .and_then(|arn| arn.split(':').nth(3));
+        Ok(KmsKey {
+            aws: AwsClient::from_env("kms", region)?,
+            key_id,
+            alg,
+        })
+    }
+
+    /// Temporary credentials come with a session token
+    pub fn session_token(mut self, token: impl Into<String>) -> Self {
+        self.aws.session_token = Some(token.into());
+        self
+    }
+
+    /// Where requests go instead of the regional endpoint, e.g. a VPC endpoint
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.aws.endpoint = endpoint.into().trim_end_matches('/').into();
+        self
+    }
+
+    /// The base64 `field` of a response
+    fn decoded(resp: &Value, field: &str) -> anyhow::Result<Vec<u8>> {
+        let value = resp[field]
+            .as_str()
+            .with_context(|| format!("KMS answered no {}", field))?;
+        Ok(base64::decode(value)?)
+    }
+}
+
+impl RemoteKey for KmsKey {
+    fn alg(&self) -> Algorithm {
+        self.alg
+    }
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> RemoteFuture<'a, Vec<u8>> {
+        Box::pin(async move {
+            match scheme(self.alg) {
+                Scheme::Mac(algorithm) => {
+                    let body = json!({
+                        "KeyId": self.key_id,
+                        "Message": base64::encode(message),
+                        "MacAlgorithm": algorithm,
+                    });
+                    let resp = self.aws.call("TrentService.GenerateMac", &body).await?;
+                    KmsKey::decoded(&resp, "Mac")
+                }
+                Scheme::Sign {
+                    algorithm,
+                    digest,
+                    ecdsa_len,
+                } => {
+                    let body = json!({
+                        "KeyId": self.key_id,
+                        "Message": base64::encode(digest::digest(digest, message)),
+                        "MessageType": "DIGEST",
+                        "SigningAlgorithm": algorithm,
+                    });
+                    let resp = self.aws.call("TrentService.Sign", &body).await?;
+                    let signature = KmsKey::decoded(&resp, "Signature")?;
+                    match ecdsa_len {
+                        Some(len) => ecdsa_fixed(&signature, len),
+                        None => Ok(signature),
+                    }
+                }
+            }
+        })
+    }
+
+    fn verify<'a>(&'a self, message: &'a [u8], signature: &'a [u8]) -> RemoteFuture<'a, bool> {
+        Box::pin(async move {
+            let (target, body, field) = match scheme(self.alg) {
+                Scheme::Mac(algorithm) => (
+                    "TrentService.VerifyMac",
+                    json!({
+                        "KeyId": self.key_id,
+                        "Message": base64::encode(message),
+                        "Mac": base64::encode(signature),
+                        "MacAlgorithm": algorithm,
+                    }),
+                    "MacValid",
+                ),
+                Scheme::Sign {
+                    algorithm,
+                    digest,
+                    ecdsa_len,
+                } => {
+                    let signature = match ecdsa_len {
+                        Some(len) if signature.len() != 2 * len => return Ok(false),
+                        Some(len) => ecdsa_der(signature, len),
+                        None => signature.to_vec(),
+                    };
+                    (
+                        "TrentService.Verify",
+                        json!({
+                            "KeyId": self.key_id,
+                            "Message": base64::encode(digest::digest(digest, message)),
+                            "MessageType": "DIGEST",
+                            "Signature": base64::encode(signature),
+                            "SigningAlgorithm": algorithm,
+                        }),
+                        "SignatureValid",
+                    )
+                }
+            };
+            match self.aws.call(target, &body).await {
+                Ok(resp) => Ok(resp[field] == true),
+                // KMS answers invalid signatures with an error
+                Err(e)
+                    if e.downcast_ref::<AwsError>().is_some_and(|e| {
+                        e.kind == "KMSInvalidSignatureException"
+                            || e.kind == "KMSInvalidMacException"
+                    }) =>
+                {
+                    Ok(false)
+                }
+                Err(e) => Err(e),
+            }
+        })
+    }
+
+    fn public_key(&self) -> RemoteFuture<'_, Option<KeyParams>> {
+        Box::pin(async move {
+            let ecdsa_len = match scheme(self.alg) {
+                Scheme::Mac(_) => return Ok(None),
+                Scheme::Sign { ecdsa_len, .. } => ecdsa_len,
+            };
+            let body = json!({ "KeyId": self.key_id });
+            let resp = self.aws.call("TrentService.GetPublicKey", &body).await?;
+            spki_params(&KmsKey::decoded(&resp, "PublicKey")?, ecdsa_len).map(Some)
+        })
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The contents of the DER element `tag` at the start of `der`, and what follows it
+fn der_element(der: &[u8], tag: u8) -> anyhow::Result<(&[u8], &[u8])> {
+    let rest = match der {
+        [first, rest @ ..] if *first == tag => rest,
+        _ => bail!("expected DER tag {:#04x}", tag),
+    };
+    let (len, rest) = match rest {
+        [len, rest @ ..] if *len < 0x80 => (*len as usize, rest),
+        [0x81, len, rest @ ..] => (*len as usize, rest),
+        [0x82, high, low, rest @ ..] => ((*high as usize) << 8 | *low as usize, rest),
+        _ => bail!("unsupported DER length"),
+    };
+    ensure!(rest.len() >= len, "truncated DER");
+    Ok(rest.split_at(len))
+}
+
+/// The JWK parameters of a DER SubjectPublicKeyInfo, of a P-256 or P-384 key for
+/// ECDSA and otherwise of an RSA key
+fn spki_params(der: &[u8], ecdsa_len: Option<usize>) -> anyhow::Result<KeyParams> {
+    let (spki, _) = der_element(der, 0x30)?;
+    let (_, rest) = der_element(spki, 0x30)?;
+    let (bits, _) = der_element(rest, 0x03)?;
+    let key = bits.strip_prefix(&[0]).context("unexpected unused bits")?;
+    match ecdsa_len {
+        Some(len) => {
+            // Uncompressed point: 0x04, x, y
+            ensure!(
+                key.len() == 1 + 2 * len && key[0] == 4,
+                "not an uncompressed point"
+            );
+            Ok(KeyParams::Ec {
+                crv: if len == 32 { "P-256" } else { "P-384" }.into(),
+                x: base64url(&key[1..=len]),
+                y: base64url(&key[1 + len..]),
+            })
+        }
+        None => {
+            let (rsa, _) = der_element(key, 0x30)?;
+            let (n, rest) = der_element(rsa, 0x02)?;
+            let (e, _) = der_element(rest, 0x02)?;
+            let unsigned =
+                |int: &[u8]| base64url(&int[int.iter().take_while(|b| **b == 0).count()..]);
+            Ok(KeyParams::Rsa {
+                n: unsigned(n),
+                e: unsigned(e),
+            })
+        }
+    }
+}
+
+/// The JWS `r || s` of a DER encoded ECDSA signature
+fn ecdsa_fixed(der: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    let (seq, _) = der_element(der, 0x30)?;
+    let (r, rest) = der_element(seq, 0x02)?;
+    let (s, _) = der_element(rest, 0x02)?;
+    let mut fixed = vec![0; 2 * len];
+    for (int, half) in [r, s].iter().zip(fixed.chunks_mut(len)) {
+        let int = &int[int.iter().take_while(|b| **b == 0).count()..];
+        ensure!(int.len() <= len, "ECDSA signature too long");
+        half[len - int.len()..].copy_from_slice(int);
+    }
+    Ok(fixed)
+}
+
+/// The DER encoding of a JWS `r || s` ECDSA signature
+fn ecdsa_der(fixed: &[u8], len: usize) -> Vec<u8> {
+    let mut seq = Vec::new();
+    for int in fixed.chunks(len) {
+        let zeros = int.iter().take_while(|b| **b == 0).count().min(len - 1);
+        let int = &int[zeros..];
+        // A leading zero keeps the integer positive
+        let pad = int[0] >= 0x80;
+        seq.push(0x02);
+        seq.push((int.len() + pad as usize) as u8);
+        if pad {
+            seq.push(0);
+        }
+        seq.extend_from_slice(int);
+    }
+    let mut der = vec![0x30, seq.len() as u8];
+    der.extend(seq);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::{mock, Matcher};
+    use ring::rand::SystemRandom;
+    use ring::signature::{self, EcdsaKeyPair, KeyPair};
+
+    #[test]
+    fn test_der() {
+        let rng = SystemRandom::new();
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let pair =
+            EcdsaKeyPair::from_pkcs8(&signature::ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+                .unwrap();
+        let public = signature::UnparsedPublicKey::new(
+            &signature::ECDSA_P256_SHA256_FIXED,
+            pair.public_key().as_ref(),
+        );
+        for _ in 0..20 {
+            let der = pair.sign(&rng, b"message").unwrap();
+            let fixed = ecdsa_fixed(der.as_ref(), 32).unwrap();
+            public.verify(b"message", &fixed).unwrap();
+            assert_eq!(ecdsa_der(&fixed, 32), der.as_ref());
+        }
+
+        // SubjectPublicKeyInfo of a P-256 key: algorithm, then the point as bit string
+        let point = pair.public_key().as_ref();
+        let mut spki = vec![0x30, 0x59, 0x30, 0x13];
+        spki.extend_from_slice(&[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]);
+        spki.extend_from_slice(&[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07]);
+        spki.extend_from_slice(&[0x03, 0x42, 0x00]);
+        spki.extend_from_slice(point);
+        match spki_params(&spki, Some(32)).unwrap() {
+            KeyParams::Ec { crv, x, .. } => {
+                assert_eq!(crv, "P-256");
+                assert_eq!(x, base64url(&point[1..33]));
+            }
+            params => panic!("unexpected {:?}", params),
+        }
+        assert!(spki_params(&spki[..40], Some(32)).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_kms() {
+        let key = KmsKey::new(
+            "alias/rapi-hmac",
+            Algorithm::HS256,
+            "eu-west-1",
+            "AKID",
+            "secret",
+        )
+        .endpoint(mockito::server_url());
+        let target = |target: &str| {
+            mock("POST", "/")
+                .match_header("x-amz-target", target)
+                .match_header("authorization", Matcher::Regex("^AWS4-HMAC-SHA256 ".into()))
+                .match_body(Matcher::PartialJson(json!({"KeyId": "alias/rapi-hmac"})))
+        };
+        let generate = target("TrentService.GenerateMac")
+            .with_body(json!({ "Mac": base64::encode(b"mac") }).to_string())
+            .create();
+        assert_eq!(key.sign(b"message").await.unwrap(), b"mac");
+        generate.assert();
+
+        let valid = target("TrentService.VerifyMac")
+            .match_body(Matcher::PartialJson(
+                json!({"KeyId": "alias/rapi-hmac", "Mac": base64::encode(b"mac")}),
+            ))
+            .with_body(r#"{"MacValid": true}"#)
+            .create();
+        let invalid = target("TrentService.VerifyMac")
+            .with_status(400)
+            .with_body(r#"{"__type": "KMSInvalidMacException", "message": ""}"#)
+            .create();
+        assert!(key.verify(b"message", b"mac").await.unwrap());
+        assert!(!key.verify(b"message", b"forged").await.unwrap());
+        valid.assert();
+        invalid.assert();
+        assert!(key.public_key().await.unwrap().is_none());
+
+        let unavailable = target("TrentService.VerifyMac")
+            .with_status(500)
+            .with_body(r#"{"__type": "KMSInternalException"}"#)
+            .create();
+        drop((valid, invalid));
+        assert!(key.verify(b"message", b"mac").await.is_err());
+        unavailable.assert();
+    }
+}
@@ -0,0 +1,421 @@
+//! Group memberships from an LDAP directory, e.g. OpenLDAP or Active Directory, for
+//! `groups::Groups`
+use crate::claims::UserId;
+use crate::groups::{GroupResolver, GroupsFuture};
+use actix_web::error::BlockingError;
+use actix_web::web;
+use anyhow::{bail, Context};
+use serde_json::{Map, Value};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+/// Largest LDAP message read, against directories answering without end
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// The groups of users from the `memberOf` attribute of their entry, found under a
+/// base DN by the user id in an attribute such as `uid` or `sAMAccountName`. Users
+/// without an entry have no groups, while an id matching several entries is an
+/// error. `ldaps://` URLs connect over TLS; binds are simple binds of a service
+/// account, or anonymous.
+#[derive(Clone)]
+pub struct LdapGroups {
+    host: String,
+    port: u16,
+    tls: bool,
+    base_dn: String,
+    bind: Option<(String, String)>,
+    user_attribute: String,
+    group_attribute: String,
+    user_claim: String,
+    timeout: Duration,
+}
+
+impl LdapGroups {
+    /// Search under `base_dn` of the directory at `url`, e.g. `ldaps://ldap.example.com`
+    pub fn new(url: &str, base_dn: impl Into<String>) -> anyhow::Result<Self> {
+        let parsed = Url::parse(url).with_context(|| format!("invalid LDAP URL {:?}", url))?;
+        let tls = match parsed.scheme() {
+            "ldap" => false,
+            "ldaps" => true,
+            scheme => bail!("LDAP URL {:?} is not ldap or ldaps but {}", url, scheme),
+        };
+        Ok(LdapGroups {
+            host: parsed
+                .host_str()
+                .with_context(|| format!("LDAP URL {:?} has no host", url))?
+                .into(),
+            port: parsed.port().unwrap_or(if tls { 636 } else { 389 }),
+            tls,
+            base_dn: base_dn.into(),
+            bind: None,
+            user_attribute: "uid".into(),
+            group_attribute: "memberOf".into(),
+            user_claim: "sub".into(),
+            timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// Bind as `dn` with `password` before searching, instead of anonymously
+    pub fn bind(mut self, dn: impl Into<String>, password: impl Into<String>) -> Self {
+        self.bind = Some((dn.into(), password.into()));
+        self
+    }
+
+    /// The attribute of user entries holding the user id, `uid` by default
+    pub fn user_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.user_attribute = attribute.into();
+        self
+    }
+
+    /// The attribute of user entries listing their groups, `memberOf` by default
+    pub fn group_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.group_attribute = attribute.into();
+        self
+    }
+
+    /// The claim with the user id, `sub` by default
+    pub fn user_claim(mut self, claim: impl Into<String>) -> Self {
+        self.user_claim = claim.into();
+        self
+    }
+
+    /// For connecting and for each answer, 5 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The groups of `user`, blocking
+    fn lookup(&self, user: &str) -> anyhow::Result<Vec<String>> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .with_context(|| format!("{} does not resolve", self.host))?;
+        let tcp = TcpStream::connect_timeout(&addr, self.timeout)?;
+        tcp.set_read_timeout(Some(self.timeout))?;
+        tcp.set_write_timeout(Some(self.timeout))?;
+        if !self.tls {
+            return self.search(tcp, user);
+        }
+        let connector = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls())?;
+        let tls = connector
+            .build()
+            .connect(&self.host, tcp)
+            .map_err(|e| anyhow::anyhow!("TLS to {} failed: {}", self.host, e))?;
+        self.search(tls, user)
+    }
+
+    /// Bind if configured, then search for the entry of `user`
+    fn search(&self, mut stream: impl Read + Write, user: &str) -> anyhow::Result<Vec<String>> {
+        if let Some((dn, password)) = &self.bind {
+            let request = [
+                integer(3),
+                tlv(0x04, dn.as_bytes()),
+                tlv(0x80, password.as_bytes()),
+            ];
+            send(&mut stream, 1, tlv(0x60, &request.concat()))?;
+            match receive(&mut stream, 1)? {
+                (0x61, result) => check_result(&result).context("LDAP bind failed")?,
+                (op, _) => bail!("LDAP bind answered with operation {:#x}", op),
+            }
+        }
+
+        // The user id as an assertion value needs no escaping, unlike in a filter string
+        let filter = [
+            tlv(0x04, self.user_attribute.as_bytes()),
+            tlv(0x04, user.as_bytes()),
+        ];
+        let request = [
+            tlv(0x04, self.base_dn.as_bytes()),
+            // The whole subtree, never dereferencing aliases
+            tlv(0x0a, &[2]),
+            tlv(0x0a, &[0]),
+            // A second entry exceeds the size limit, failing the search
+            integer(1),
+            integer(self.timeout.as_secs() as u32),
+            tlv(0x01, &[0]),
+            tlv(0xa3, &filter.concat()),
+            tlv(0x30, &tlv(0x04, self.group_attribute.as_bytes())),
+        ];
+        send(&mut stream, 2, tlv(0x63, &request.concat()))?;
+        let mut groups = Vec::new();
+        loop {
+            match receive(&mut stream, 2)? {
+                (0x64, entry) => groups.extend(values(&entry, &self.group_attribute)?),
+                (0x65, result) => {
+                    check_result(&result).context("LDAP search failed")?;
+                    break;
+                }
+                // References to other directories are not followed
+                (0x73, _) => {}
+                (op, _) => bail!("LDAP search answered with operation {:#x}", op),
+            }
+        }
+        // Unbinding is a courtesy, the connection closes anyway
+        let _ = send(&mut stream, 3, vec![0x42, 0x00]);
+        Ok(groups)
+    }
+}
+
+impl GroupResolver for LdapGroups {
+    fn groups<'a>(&'a self, claims: &'a Map<String, Value>) -> GroupsFuture<'a> {
+        Box::pin(async move {
+            let user = UserId::from_claims(claims, &self.user_claim)
+                .ok_or_else(|| anyhow::anyhow!("no {} to look up groups for", self.user_claim))?;
+            let user = user.as_str().to_string();
+            let ldap = self.clone();
+            web::block(move || ldap.lookup(&user))
+                .await
+                .map_err(|e| match e {
+                    BlockingError::Error(e) => e,
+                    BlockingError::Canceled => anyhow::anyhow!("LDAP lookup canceled"),
+                })
+        })
+    }
+}
+
+/// A BER element of `tag`, in the definite length form LDAP requires
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    if content.len() < 0x80 {
+        element.push(content.len() as u8);
+    } else {
+        let len = (content.len() as u32).to_be_bytes();
+        let skip = len.iter().take_while(|byte| **byte == 0).count();
+        element.push(0x80 | (len.len() - skip) as u8);
+        element.extend_from_slice(&len[skip..]);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+fn integer(n: u32) -> Vec<u8> {
+    let bytes = n.to_be_bytes();
+    let skip = bytes.iter().take_while(|byte| **byte == 0).count().min(3);
+    let mut content = bytes[skip..].to_vec();
+    // A leading set bit would read as negative
+    if content[0] & 0x80 != 0 {
+        content.insert(0, 0);
+    }
+    tlv(0x02, &content)
+}
+
+fn send(stream: &mut impl Write, id: u32, op: Vec<u8>) -> std::io::Result<()> {
+    stream.write_all(&tlv(0x30, &[integer(id), op].concat()))?;
+    stream.flush()
+}
+
+/// The tag and content of one BER element read from `stream`
+fn read_element(stream: &mut impl Read) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let len = match head[1] {
+        len if len < 0x80 => len as usize,
+        len if (0x81..=0x84).contains(&len) => {
+            let mut bytes = [0; 4];
+            stream.read_exact(&mut bytes[4 - (len & 0x7f) as usize..])?;
+            u32::from_be_bytes(bytes) as usize
+        }
+        _ => bail!("LDAP message of unsupported length"),
+    };
+    if len > MAX_MESSAGE_LEN {
+        bail!("LDAP message of {} bytes is too large", len);
+    }
+    let mut content = vec![0; len];
+    stream.read_exact(&mut content)?;
+    Ok((head[0], content))
+}
+
+/// The protocol operation of the next message, which must answer request `id`
+fn receive(stream: &mut impl Read, id: u32) -> anyhow::Result<(u8, Vec<u8>)> {
+    let (tag, message) = read_element(stream)?;
+    if tag != 0x30 {
+        bail!("malformed LDAP message");
+    }
+    let mut message = Elements(&message);
+    match message.next()? {
+        (0x02, answered) if uint(answered) == id => {}
+        _ => bail!("LDAP answer to another request"),
+    }
+    let (op, content) = message.next()?;
+    Ok((op, content.to_vec()))
+}
+
+/// Fails with the diagnostic message of an `LDAPResult` other than success
+fn check_result(result: &[u8]) -> anyhow::Result<()> {
+    let mut result = Elements(result);
+    let (_, code) = result.next()?;
+    let _matched = result.next()?;
+    let (_, message) = result.next()?;
+    match uint(code) {
+        0 => Ok(()),
+        code => bail!("result code {}: {}", code, String::from_utf8_lossy(message)),
+    }
+}
+
+/// The values of `attribute` in a `SearchResultEntry`
+fn values(entry: &[u8], attribute: &str) -> anyhow::Result<Vec<String>> {
+    let mut entry = Elements(entry);
+    let _dn = entry.next()?;
+    let (_, attributes) = entry.next()?;
+    let mut attributes = Elements(attributes);
+    let mut values = Vec::new();
+    while !attributes.0.is_empty() {
+        let (_, partial) = attributes.next()?;
+        let mut partial = Elements(partial);
+        let (_, name) = partial.next()?;
+        let (_, vals) = partial.next()?;
+        // Attribute names are case insensitive
+        if !name.eq_ignore_ascii_case(attribute.as_bytes()) {
+            continue;
+        }
+        let mut vals = Elements(vals);
+        while !vals.0.is_empty() {
+            let (_, value) = vals.next()?;
+            values.push(String::from_utf8(value.to_vec()).context("group is not UTF-8")?);
+        }
+    }
+    Ok(values)
+}
+
+fn uint(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |n, byte| n << 8 | u32::from(*byte))
+}
+
+/// The BER elements of a constructed element's content, one by one
+struct Elements<'a>(&'a [u8]);
+
+impl<'a> Elements<'a> {
+    fn next(&mut self) -> anyhow::Result<(u8, &'a [u8])> {
+        let truncated = || anyhow::anyhow!("truncated LDAP message");
+        let (&tag, rest) = self.0.split_first().ok_or_else(truncated)?;
+        let (&len, rest) = rest.split_first().ok_or_else(truncated)?;
+        let (len, rest) = match len {
+            len if len < 0x80 => (len as usize, rest),
+            len if (0x81..=0x84).contains(&len) && rest.len() >= (len & 0x7f) as usize => {
+                let (len, rest) = rest.split_at((len & 0x7f) as usize);
+                (uint(len) as usize, rest)
+            }
+            _ => return Err(truncated()),
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        let (content, rest) = rest.split_at(len);
+        self.0 = rest;
+        Ok((tag, content))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::groups::Groups;
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    fn result(code: u8, message: &str) -> Vec<u8> {
+        [
+            tlv(0x0a, &[code]),
+            tlv(0x04, b""),
+            tlv(0x04, message.as_bytes()),
+        ]
+        .concat()
+    }
+
+    /// Answer one connection like a directory knowing alice only, binding
+    /// `cn=rapi` with `secret`
+    fn serve(mut stream: TcpStream) -> anyhow::Result<()> {
+        loop {
+            let (_, message) = read_element(&mut stream)?;
+            let mut message = Elements(&message);
+            let id = uint(message.next()?.1);
+            let (op, content) = message.next()?;
+            let mut content = Elements(content);
+            match op {
+                0x60 => {
+                    let _version = content.next()?;
+                    let (_, dn) = content.next()?;
+                    let (_, password) = content.next()?;
+                    let answer = match (dn, password) {
+                        (b"cn=rapi,dc=example,dc=com", b"secret") => result(0, ""),
+                        _ => result(49, "invalid credentials"),
+                    };
+                    send(&mut stream, id, tlv(0x61, &answer))?;
+                }
+                0x63 => {
+                    for _ in 0..6 {
+                        content.next()?;
+                    }
+                    let (_, filter) = content.next()?;
+                    let mut filter = Elements(filter);
+                    let (_, attribute) = filter.next()?;
+                    let (_, user) = filter.next()?;
+                    if (attribute, user) == (b"uid", b"alice") {
+                        let groups = [
+                            tlv(0x04, b"cn=eng,ou=groups,dc=example,dc=com"),
+                            tlv(0x04, b"cn=ops,ou=groups,dc=example,dc=com"),
+                        ];
+                        let attributes = [
+                            tlv(
+                                0x30,
+                                &[tlv(0x04, b"cn"), tlv(0x31, &tlv(0x04, b"alice"))].concat(),
+                            ),
+                            tlv(
+                                0x30,
+                                &[tlv(0x04, b"memberof"), tlv(0x31, &groups.concat())].concat(),
+                            ),
+                        ];
+                        let entry = [
+                            tlv(0x04, b"uid=alice,dc=example,dc=com"),
+                            tlv(0x30, &attributes.concat()),
+                        ];
+                        send(&mut stream, id, tlv(0x64, &entry.concat()))?;
+                    }
+                    send(&mut stream, id, tlv(0x65, &result(0, "")))?;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_ldap_groups() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = serve(stream.unwrap());
+            }
+        });
+        let ldap = LdapGroups::new(&url, "dc=example,dc=com")
+            .unwrap()
+            .bind("cn=rapi,dc=example,dc=com", "secret");
+        let claims = |user: &str| json!({ "sub": user }).as_object().unwrap().clone();
+
+        assert_eq!(
+            ldap.groups(&claims("alice")).await.unwrap(),
+            vec![
+                "cn=eng,ou=groups,dc=example,dc=com",
+                "cn=ops,ou=groups,dc=example,dc=com"
+            ]
+        );
+        assert!(ldap.groups(&claims("bob")).await.unwrap().is_empty());
+
+        let wrong = ldap.clone().bind("cn=rapi,dc=example,dc=com", "guessed");
+        let err = wrong.groups(&claims("alice")).await.unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "LDAP bind failed: result code 49: invalid credentials"
+        );
+        assert!(ldap.groups(&Map::new()).await.is_err());
+        assert!(LdapGroups::new("https://ldap.example.com", "dc=example").is_err());
+
+        let groups = Groups::new(ldap).when_absent();
+        let mut claims = claims("alice");
+        groups.fill(&mut claims, Some("alice")).await.unwrap();
+        assert_eq!(claims["groups"].as_array().unwrap().len(), 2);
+    }
+}
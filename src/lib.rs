@@ -0,0 +1,78 @@
+#[macro_use]
+extern crate lazy_static;
+// For the paths of derived code in the crate's own tests
+extern crate self as rapi;
+
+pub mod apikey;
+pub mod audience;
+pub mod auth;
+#[cfg(any(feature = "aws-secrets", feature = "aws-kms"))]
+mod aws;
+#[cfg(feature = "azure-keyvault")]
+pub mod azure;
+#[cfg(feature = "reqwest")]
+pub mod basic;
+pub mod breaker;
+pub mod cache;
+pub mod chain;
+pub mod claims;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod config;
+pub mod cookie;
+pub mod csrf;
+pub mod debug;
+#[cfg(feature = "dangerous-dev-mode")]
+pub mod devmode;
+pub mod error;
+pub mod events;
+pub mod extract;
+pub mod groups;
+pub mod guard;
+pub mod headers;
+pub mod http;
+mod init;
+#[cfg(feature = "issuer")]
+pub mod issuer;
+pub mod jwk;
+pub mod keystore;
+#[cfg(feature = "aws-kms")]
+pub mod kms;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+#[cfg(feature = "login")]
+pub mod login;
+pub mod metrics;
+pub mod network;
+pub mod openid;
+#[cfg(feature = "reqwest")]
+pub mod outbound;
+pub mod persist;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod policy;
+pub mod proxy;
+pub mod public;
+pub mod ratelimit;
+pub mod redact;
+pub mod relay;
+#[cfg(all(unix, feature = "unix"))]
+pub mod reload;
+pub mod remote;
+pub mod renewal;
+pub mod report;
+pub mod resolver;
+pub mod revocation;
+pub mod rules;
+pub mod schema;
+pub mod scopes;
+pub mod secrets;
+pub mod sessions;
+pub mod shutdown;
+#[cfg(feature = "issuer")]
+pub mod sliding;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "derive")]
+pub use rapi_derive::ValidateClaims;
@@ -0,0 +1,1025 @@
+//! Browser login through the OpenID Connect authorization code flow with PKCE
+use crate::auth::JwtAuth;
+use crate::config::Config;
+use crate::csrf::{CsrfProtection, CSRF_COOKIE};
+use crate::error::ErrorPages;
+use crate::openid::OidConf;
+use crate::revocation::Revocations;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{error, http::header, web, Error, HttpMessage, HttpRequest, HttpResponse};
+use anyhow::Context;
+use log::warn;
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Cookie carrying the PKCE verifier, state and nonce from `/login` to `/callback`
+const LOGIN_COOKIE: &str = "login";
+
+/// Seconds a login may take before its cookie is refused
+const LOGIN_TTL: u64 = 600;
+
+/// Cookie keeping the refresh token, sent to `/token/refresh` only
+const REFRESH_COOKIE: &str = "refresh_token";
+const REFRESH_PATH: &str = "/token/refresh";
+
+/// Member of the `events` claim identifying logout tokens
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Serves `/login` and `/callback`, leaving the verified ID token in a session cookie
+#[derive(Clone)]
+pub struct Login {
+    client_id: String,
+    client_secret: Option<String>,
+    authorization_endpoint: Url,
+    token_endpoint: String,
+    end_session_endpoint: Option<Url>,
+    redirect_uri: Url,
+    post_logout_redirect_uri: Option<String>,
+    scope: String,
+    session_cookie: String,
+    cookie_key: hmac::Key,
+    revocations: Option<Revocations>,
+    token_refresh: bool,
+    auth: JwtAuth,
+    client: reqwest::Client,
+}
+
+/// What `/login` hands over to `/callback`
+#[derive(Serialize, Deserialize)]
+struct LoginState {
+    verifier: String,
+    /// Echoed by the authserver, tying the callback to this browser against CSRF
+    state: String,
+    /// Echoed in the ID token, against replaying tokens of other logins
+    nonce: String,
+    return_to: String,
+    issued_at: u64,
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    redirect_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LogoutForm {
+    logout_token: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+/// What `/token/refresh` answers, keeping the refresh token in its cookie
+#[derive(Serialize)]
+struct RefreshResponse {
+    access_token: String,
+    token_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in: Option<u64>,
+}
+
+impl Login {
+    /// Log in `client_id` at the endpoints of `conf`, returning to `redirect_uri`,
+    /// the absolute URL of `/callback`. ID tokens are verified with the keys and
+    /// registered claims of `auth`, but not as access tokens or for its scopes and
+    /// claim rules.
+    pub fn new(
+        auth: &JwtAuth,
+        conf: &OidConf,
+        client_id: impl Into<String>,
+        redirect_uri: &str,
+    ) -> anyhow::Result<Self> {
+        let client_id = client_id.into();
+        let endpoint = |endpoint: &Option<String>, name| {
+            endpoint
+                .clone()
+                .with_context(|| format!("no {} discovered", name))
+        };
+        Ok(Login {
+            authorization_endpoint: Url::parse(&endpoint(
+                &conf.authorization_endpoint,
+                "authorization_endpoint",
+            )?)?,
+            token_endpoint: endpoint(&conf.token_endpoint, "token_endpoint")?,
+            end_session_endpoint: conf
+                .end_session_endpoint
+                .as_deref()
+                .map(Url::parse)
+                .transpose()?,
+            redirect_uri: Url::parse(redirect_uri)
+                .with_context(|| format!("invalid redirect uri {:?}", redirect_uri))?,
+            post_logout_redirect_uri: None,
+            auth: auth.clone().for_id_tokens().audience(&client_id),
+            client_id,
+            client_secret: None,
+            scope: "openid".into(),
+            session_cookie: "session".into(),
+            revocations: None,
+            token_refresh: false,
+            cookie_key: hmac::Key::new(hmac::HMAC_SHA256, &rand::thread_rng().gen::<[u8; 32]>()),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// The login configured by `LOGIN_*` settings, if any
+    pub fn from_config(
+        config: &Config,
+        auth: &JwtAuth,
+        conf: &OidConf,
+    ) -> anyhow::Result<Option<Self>> {
+        let client_id = match &config.login_client_id {
+            Some(client_id) => client_id,
+            None => return Ok(None),
+        };
+        let redirect_uri = config
+            .login_redirect_uri
+            .as_ref()
+            .context("LOGIN_CLIENT_ID requires LOGIN_REDIRECT_URI")?;
+        let mut login = Login::new(auth, conf, client_id, redirect_uri)?;
+        if let Some(secret) = &config.login_client_secret {
+            login = login.client_secret(secret);
+        }
+        if let Some(scope) = &config.login_scope {
+            login = login.scope(scope);
+        }
+        if let Some(uri) = &config.login_post_logout_redirect_uri {
+            login = login.post_logout_redirect_uri(uri);
+        }
+        if let Some(key) = &config.login_cookie_key {
+            login = login.cookie_key(key);
+        }
+        if config.login_token_refresh == Some(true) {
+            login = login.token_refresh();
+        }
+        if config.login_backchannel_logout == Some(true) {
+            login = login.revocations(Revocations::default());
+        }
+        Ok(Some(login))
+    }
+
+    /// Authenticate at the token endpoint as a confidential client
+    pub fn client_secret(mut self, secret: impl Into<String>) -> Self {
+        self.client_secret = Some(secret.into());
+        self
+    }
+
+    /// Space separated scopes requested, `openid` by default
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = scope.into();
+        self
+    }
+
+    /// Name of the cookie keeping the ID token, `session` by default
+    pub fn session_cookie(mut self, name: impl Into<String>) -> Self {
+        self.session_cookie = name.into();
+        self
+    }
+
+    /// Where the authserver sends browsers after `/logout`, registered there too
+    pub fn post_logout_redirect_uri(mut self, uri: impl Into<String>) -> Self {
+        self.post_logout_redirect_uri = Some(uri.into());
+        self
+    }
+
+    /// Key signing the login cookie, random by default. Instances behind a load
+    /// balancer need to share it, as the callback may reach any of them.
+    pub fn cookie_key(mut self, key: impl AsRef<[u8]>) -> Self {
+        self.cookie_key = hmac::Key::new(hmac::HMAC_SHA256, key.as_ref());
+        self
+    }
+
+    /// Keep refresh tokens in an HttpOnly cookie and serve `/token/refresh`, trading
+    /// it for a new access token, so scripts never see refresh tokens. Authservers
+    /// often issue them only for the `offline_access` scope.
+    pub fn token_refresh(mut self) -> Self {
+        self.token_refresh = true;
+        self
+    }
+
+    /// Accept back-channel logouts at `/backchannel-logout`, revoking the sessions
+    /// named in `revocations`. Share the store between instances to log out everywhere.
+    pub fn revocations(mut self, revocations: Revocations) -> Self {
+        self.revocations = Some(revocations);
+        self
+    }
+
+    /// Validates the session cookie, sending browsers without one to `/login`.
+    /// State-changing requests need the `csrf_token` cookie set at login repeated
+    /// in `X-CSRF-Token`.
+    pub fn session(&self) -> JwtAuth {
+        let mut auth = self
+            .auth
+            .clone()
+            .session_cookie(&self.session_cookie)
+            .csrf(CsrfProtection::default())
+            .error_pages(ErrorPages::default().login_redirect("/login"));
+        if let Some(revocations) = &self.revocations {
+            auth = auth.revocations(revocations.clone());
+        }
+        auth
+    }
+
+    /// Register `/login`, `/callback` and `/logout`, and `/backchannel-logout` with
+    /// `revocations`
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.data(self.clone())
+            .route("/login", web::get().to(login))
+            .route("/callback", web::get().to(callback))
+            .route("/logout", web::get().to(logout));
+        if self.revocations.is_some() {
+            cfg.route("/backchannel-logout", web::post().to(backchannel_logout));
+        }
+        if self.token_refresh {
+            cfg.route(REFRESH_PATH, web::post().to(refresh));
+        }
+    }
+
+    /// Call the token endpoint with `form`, authenticating as the client
+    async fn tokens(&self, mut form: Vec<(&str, &str)>) -> reqwest::Result<TokenResponse> {
+        form.push(("client_id", &self.client_id));
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        self.client
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    fn refresh_cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = self.cookie(REFRESH_COOKIE, value);
+        cookie.set_path(REFRESH_PATH);
+        cookie
+    }
+
+    /// The double submit cookie, for scripts to repeat in the CSRF header
+    fn csrf_cookie(&self, value: String) -> Cookie<'static> {
+        let mut cookie = self.cookie(CSRF_COOKIE, value);
+        cookie.set_http_only(false);
+        cookie
+    }
+
+    fn cookie<'c>(&self, name: &'c str, value: String) -> Cookie<'c> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .secure(self.redirect_uri.scheme() == "https")
+            .same_site(SameSite::Lax)
+            .finish()
+    }
+
+    /// The login cookie value, signed so browsers cannot alter it
+    fn seal(&self, state: &LoginState) -> String {
+        let payload = base64url(&serde_json::to_vec(state).unwrap_or_default());
+        let tag = hmac::sign(&self.cookie_key, payload.as_bytes());
+        format!("{}.{}", payload, base64url(tag.as_ref()))
+    }
+
+    /// The state of a login cookie with a valid signature, unless it expired
+    fn open(&self, value: &str) -> Option<LoginState> {
+        let (payload, tag) = value.split_once('.')?;
+        let tag = base64::decode_config(tag, base64::URL_SAFE_NO_PAD).ok()?;
+        hmac::verify(&self.cookie_key, payload.as_bytes(), &tag).ok()?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+        let state: LoginState = serde_json::from_slice(&payload).ok()?;
+        if now().saturating_sub(state.issued_at) > LOGIN_TTL {
+            warn!("login cookie expired");
+            return None;
+        }
+        Some(state)
+    }
+
+    /// Where to go after logging in, only ever on the site of the callback.
+    /// Browsers skip some characters in URLs, `"/\t/evil.com"` being `"//evil.com"`
+    /// to them, so anything with whitespace, control characters or backslashes,
+    /// also percent-encoded, is refused too.
+    fn return_to(&self, requested: Option<&str>) -> String {
+        let requested = match requested {
+            Some(requested) => requested,
+            None => return "/".into(),
+        };
+        let suspicious = |b: u8| b <= b' ' || b == 0x7f || b == b'\\';
+        let encoded = requested.split('%').skip(1).filter_map(|rest| {
+            rest.get(..2)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        });
+        let clean = !requested.bytes().chain(encoded).any(suspicious);
+        let same_origin = self
+            .redirect_uri
+            .join(requested)
+            .is_ok_and(|url| url.origin() == self.redirect_uri.origin());
+        if clean && same_origin {
+            requested.into()
+        } else {
+            warn!("not returning to {:?} after login", requested);
+            "/".into()
+        }
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn random() -> String {
+    base64url(&rand::thread_rng().gen::<[u8; 32]>())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+async fn login(login: web::Data<Login>, query: web::Query<LoginQuery>) -> HttpResponse {
+    let state = LoginState {
+        verifier: random(),
+        state: random(),
+        nonce: random(),
+        return_to: login.return_to(query.redirect_uri.as_deref()),
+        issued_at: now(),
+    };
+    let mut location = login.authorization_endpoint.clone();
+    location
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &login.client_id)
+        .append_pair("redirect_uri", login.redirect_uri.as_str())
+        .append_pair("scope", &login.scope)
+        .append_pair(
+            "code_challenge",
+            &base64url(digest(&SHA256, state.verifier.as_bytes()).as_ref()),
+        )
+        .append_pair("code_challenge_method", "S256")
+        .append_pair("state", &state.state)
+        .append_pair("nonce", &state.nonce);
+    HttpResponse::Found()
+        .header(header::LOCATION, location.as_str())
+        .cookie(login.cookie(LOGIN_COOKIE, login.seal(&state)))
+        .finish()
+}
+
+async fn callback(
+    req: HttpRequest,
+    login: web::Data<Login>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse, Error> {
+    if let Some(e) = &query.error {
+        warn!("login failed: {}", e);
+        return Err(error::ErrorUnauthorized("login failed"));
+    }
+    let code = query
+        .code
+        .as_ref()
+        .ok_or_else(|| error::ErrorBadRequest("missing code"))?;
+    let state: LoginState = req
+        .cookie(LOGIN_COOKIE)
+        .and_then(|cookie| login.open(cookie.value()))
+        .ok_or_else(|| error::ErrorBadRequest("no login in progress"))?;
+    if query.state.as_deref() != Some(&state.state) {
+        warn!("login callback with a foreign state");
+        return Err(error::ErrorBadRequest("state mismatch"));
+    }
+
+    let form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", login.redirect_uri.as_str()),
+        ("code_verifier", &state.verifier),
+    ];
+    let tokens = login.tokens(form).await.map_err(|e| {
+        warn!("exchanging the authorization code failed: {}", e);
+        error::ErrorBadGateway("token endpoint failed")
+    })?;
+    let id_token = tokens
+        .id_token
+        .ok_or_else(|| error::ErrorBadGateway("no ID token issued"))?;
+    let claims = login.auth.verify(&id_token).await.map_err(|e| {
+        warn!("rejected ID token: {}", e);
+        error::ErrorUnauthorized("invalid ID token")
+    })?;
+    if claims.get("nonce").and_then(|nonce| nonce.as_str()) != Some(&state.nonce) {
+        warn!("ID token with a foreign nonce");
+        return Err(error::ErrorUnauthorized("invalid ID token"));
+    }
+
+    let mut resp = HttpResponse::Found();
+    resp.header(header::LOCATION, state.return_to)
+        .cookie(login.cookie(&login.session_cookie, id_token))
+        .cookie(login.csrf_cookie(CsrfProtection::token()))
+        .del_cookie(&login.cookie(LOGIN_COOKIE, String::new()));
+    if let (true, Some(refresh_token)) = (login.token_refresh, tokens.refresh_token) {
+        resp.cookie(login.refresh_cookie(refresh_token));
+    }
+    Ok(resp.finish())
+}
+
+/// Trade the refresh token cookie for a new access token, rotating the cookie
+/// when the authserver rotates refresh tokens
+async fn refresh(req: HttpRequest, login: web::Data<Login>) -> HttpResponse {
+    let refresh_token = match req.cookie(REFRESH_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return HttpResponse::Unauthorized().body("no refresh token"),
+    };
+    let form = vec![
+        ("grant_type", "refresh_token"),
+        ("refresh_token", &refresh_token),
+    ];
+    let tokens = match login.tokens(form).await {
+        Ok(tokens) => tokens,
+        Err(e) if e.status().is_some_and(|status| status.is_client_error()) => {
+            warn!("refresh token refused: {}", e);
+            return HttpResponse::Unauthorized()
+                .del_cookie(&login.refresh_cookie(String::new()))
+                .body("refresh token refused");
+        }
+        Err(e) => {
+            warn!("refreshing failed: {}", e);
+            return HttpResponse::BadGateway().body("token endpoint failed");
+        }
+    };
+    let access_token = match tokens.access_token {
+        Some(access_token) => access_token,
+        None => return HttpResponse::BadGateway().body("no access token issued"),
+    };
+    let mut resp = HttpResponse::Ok();
+    resp.header(header::CACHE_CONTROL, "no-store");
+    if let Some(rotated) = tokens.refresh_token {
+        resp.cookie(login.refresh_cookie(rotated));
+    }
+    resp.json(RefreshResponse {
+        access_token,
+        token_type: "Bearer",
+        expires_in: tokens.expires_in,
+    })
+}
+
+/// Drop the session cookie and end the session at the authserver too, when it
+/// supports RP-initiated logout
+async fn logout(req: HttpRequest, login: web::Data<Login>) -> HttpResponse {
+    let location = match &login.end_session_endpoint {
+        Some(endpoint) => {
+            let mut location = endpoint.clone();
+            {
+                let mut query = location.query_pairs_mut();
+                query.append_pair("client_id", &login.client_id);
+                if let Some(session) = req.cookie(&login.session_cookie) {
+                    query.append_pair("id_token_hint", session.value());
+                }
+                if let Some(uri) = &login.post_logout_redirect_uri {
+                    query.append_pair("post_logout_redirect_uri", uri);
+                }
+            }
+            location.into()
+        }
+        None => login
+            .post_logout_redirect_uri
+            .clone()
+            .unwrap_or_else(|| "/".into()),
+    };
+    let mut resp = HttpResponse::Found();
+    resp.header(header::LOCATION, location)
+        .del_cookie(&login.cookie(&login.session_cookie, String::new()))
+        .del_cookie(&login.csrf_cookie(String::new()));
+    if login.token_refresh {
+        resp.del_cookie(&login.refresh_cookie(String::new()));
+    }
+    resp.finish()
+}
+
+/// Revoke the session or subject of a logout token sent by the authserver
+async fn backchannel_logout(login: web::Data<Login>, form: web::Form<LogoutForm>) -> HttpResponse {
+    let revocations = match &login.revocations {
+        Some(revocations) => revocations,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let claims = match login.auth.verify(&form.logout_token).await {
+        Ok(claims) => claims,
+        Err(e) => {
+            warn!("rejected logout token: {}", e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    let is_logout = claims
+        .get("events")
+        .and_then(|events| events.get(BACKCHANNEL_LOGOUT_EVENT))
+        .is_some_and(Value::is_object);
+    // A nonce would make it an ID token
+    if !is_logout || claims.contains_key("nonce") || !claims.contains_key("iat") {
+        warn!("logout token with unexpected claims");
+        return HttpResponse::BadRequest().finish();
+    }
+    let revoked = match (
+        claims.get("sid").and_then(Value::as_str),
+        claims.get("sub").and_then(Value::as_str),
+    ) {
+        (Some(sid), _) => revocations.revoke_session(sid).await,
+        (None, Some(sub)) => revocations.revoke_subject(sub).await,
+        (None, None) => {
+            warn!("logout token without sid or sub");
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+    match revoked {
+        Ok(()) => HttpResponse::Ok()
+            .header(header::CACHE_CONTROL, "no-store")
+            .finish(),
+        Err(e) => {
+            warn!("revoking failed: {}", e);
+            HttpResponse::ServiceUnavailable().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestKey;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+    use jsonwebtoken::{encode, Algorithm, Header, Validation};
+    use std::collections::HashMap;
+
+    lazy_static! {
+        static ref KEY: TestKey = TestKey::rsa(2048).unwrap();
+    }
+
+    fn id_token(claims: Value) -> String {
+        let header = Header {
+            kid: Some("0".into()),
+            ..Header::new(Algorithm::RS256)
+        };
+        encode(&header, &claims, &KEY.encoding_key).unwrap()
+    }
+
+    fn auth() -> JwtAuth {
+        let mut keys = HashMap::new();
+        keys.insert("0".to_string(), vec![KEY.decoding_key()]);
+        let validation = Validation {
+            iss: Some("me".into()),
+            ..Validation::new(Algorithm::RS256)
+        };
+        JwtAuth::new(validation, keys)
+    }
+
+    fn login() -> Login {
+        login_for(&auth())
+    }
+
+    fn login_for(auth: &JwtAuth) -> Login {
+        let conf = OidConf {
+            jwks: HashMap::new(),
+            issuer: "me".into(),
+            jwks_uri: "".into(),
+            jwks_document: serde_json::Value::Null,
+            authorization_endpoint: Some("https://example.com/authorize".into()),
+            token_endpoint: Some(mockito::server_url() + "/login/token"),
+            end_session_endpoint: Some("https://example.com/logout".into()),
+            introspection_endpoint: None,
+            revocation_endpoint: None,
+        };
+        Login::new(auth, &conf, "app", "https://app.example.com/callback").unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_access_tokens_only() {
+        let auth = auth()
+            .access_tokens_only()
+            .require(crate::rules::ClaimRule::present("scope"));
+        let mut login = login_for(&auth);
+        login.token_endpoint = mockito::server_url() + "/login/id-token";
+        let state = LoginState {
+            verifier: "v".into(),
+            state: "s".into(),
+            nonce: "n".into(),
+            return_to: "/".into(),
+            issued_at: now(),
+        };
+        let cookie = login.cookie(LOGIN_COOKIE, login.seal(&state));
+        let id_token = id_token(
+            serde_json::json!({"iss": "me", "aud": "app", "sub": "alice",
+                "exp": 4102444800u64, "nonce": "n"}),
+        );
+        let _token_mock = mockito::mock("POST", "/login/id-token")
+            .with_body(serde_json::json!({ "id_token": id_token }).to_string())
+            .create();
+
+        // Not for the API
+        let rejected = auth.verify(&id_token).await;
+        assert!(matches!(
+            rejected,
+            Err(crate::error::AuthError::NotAccessToken)
+        ));
+
+        let mut app = test::init_service(App::new().configure(|cfg| login.configure(cfg))).await;
+        let req = test::TestRequest::get()
+            .uri("/callback?code=abc&state=s")
+            .cookie(cookie)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+    }
+
+    #[test]
+    fn test_return_to() {
+        let login = login();
+        for allowed in &["/page?a=1", "https://app.example.com/page", "/%41"] {
+            assert_eq!(login.return_to(Some(allowed)), *allowed);
+        }
+        for refused in &[
+            "//evil.com",
+            "/\\evil.com",
+            "/\t/evil.com",
+            "/\n/evil.com",
+            "/%09/evil.com",
+            "/%5C/evil.com",
+            "https://evil.com/",
+            "http://app.example.com/",
+        ] {
+            assert_eq!(login.return_to(Some(refused)), "/", "{:?}", refused);
+        }
+        assert_eq!(login.return_to(None), "/");
+    }
+
+    #[actix_rt::test]
+    async fn test_login() {
+        let login = login();
+        let mut app = test::init_service(
+            App::new().configure(|cfg| login.configure(cfg)).service(
+                web::scope("/page")
+                    .wrap(login.session().middleware())
+                    .route("", web::get().to(|| async { "page" }))
+                    .route("", web::post().to(|| async { "posted" })),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/login?redirect_uri=https%3A%2F%2Fevil.example.com%2F")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        let state = resp.response().cookies().next().unwrap().into_owned();
+        let state = login.open(state.value()).unwrap();
+        assert_eq!(state.return_to, "/");
+
+        let req = test::TestRequest::get()
+            .uri("/login?redirect_uri=%2Fpage")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(location.starts_with("https://example.com/authorize?response_type=code"));
+        assert!(location.contains("code_challenge_method=S256"));
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        let state = login.open(cookie.value()).unwrap();
+        assert!(location.contains(&format!("state={}", state.state)));
+        let mut forged = cookie.clone();
+        forged.set_value(login.seal(&state).replace('.', "x."));
+        assert!(login.open(forged.value()).is_none());
+        let expired = LoginState {
+            issued_at: now() - LOGIN_TTL - 1,
+            ..login.open(cookie.value()).unwrap()
+        };
+        assert!(login.open(&login.seal(&expired)).is_none());
+
+        let id_token = id_token(
+            serde_json::json!({"iss": "me", "aud": "app", "sub": "alice",
+                "exp": 4102444800u64, "nonce": state.nonce}),
+        );
+        let token_mock = mockito::mock("POST", "/login/token")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("code".into(), "abc".into()),
+                mockito::Matcher::UrlEncoded("client_id".into(), "app".into()),
+            ]))
+            .with_body(serde_json::json!({ "id_token": id_token }).to_string())
+            .create();
+        let req = test::TestRequest::get()
+            .uri("/callback?code=abc&state=other")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::get()
+            .uri(&format!("/callback?code=abc&state={}", state.state))
+            .cookie(cookie)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(resp.headers().get(header::LOCATION).unwrap(), "/page");
+        let session = resp
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == "session")
+            .unwrap()
+            .into_owned();
+        let csrf = resp
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == CSRF_COOKIE)
+            .unwrap()
+            .into_owned();
+        // Readable by scripts
+        assert_ne!(csrf.http_only(), Some(true));
+        token_mock.assert();
+
+        let req = test::TestRequest::get()
+            .uri("/page")
+            .cookie(session.clone())
+            .to_request();
+        assert!(app.call(req).await.is_ok());
+
+        // State-changing requests repeat the CSRF cookie
+        let req = test::TestRequest::post()
+            .uri("/page")
+            .cookie(session.clone())
+            .to_request();
+        let err = app.call(req).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::post()
+            .uri("/page")
+            .cookie(session.clone())
+            .cookie(csrf.clone())
+            .header("x-csrf-token", csrf.value())
+            .to_request();
+        assert!(app.call(req).await.is_ok());
+
+        let req = test::TestRequest::get()
+            .uri("/logout")
+            .cookie(session)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(location.starts_with("https://example.com/logout?client_id=app&id_token_hint="));
+        let mut removals: Vec<_> = resp
+            .response()
+            .cookies()
+            .map(|cookie| (cookie.name().to_string(), cookie.value().to_string()))
+            .collect();
+        removals.sort();
+        assert_eq!(
+            removals,
+            vec![
+                (CSRF_COOKIE.into(), "".into()),
+                ("session".into(), "".into())
+            ]
+        );
+        let req = test::TestRequest::get()
+            .uri("/callback?code=abc")
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_login_state() {
+        let mut login = login().cookie_key(b"shared");
+        login.token_endpoint = mockito::server_url() + "/login/nonce";
+        let mut app = test::init_service(App::new().configure(|cfg| login.configure(cfg))).await;
+        let req = test::TestRequest::get().uri("/login").to_request();
+        let resp = app.call(req).await.unwrap();
+        let location = resp
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let cookie = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(cookie.http_only(), Some(true));
+        let state = login.open(cookie.value()).unwrap();
+        assert!(location.contains(&format!("nonce={}", state.nonce)));
+        let callback = format!("/callback?code=abc&state={}", state.state);
+
+        // An ID token issued to another login
+        let replayed = id_token(
+            serde_json::json!({"iss": "me", "aud": "app", "sub": "alice",
+                "exp": 4102444800u64, "nonce": "other"}),
+        );
+        let token_mock = mockito::mock("POST", "/login/nonce")
+            .with_body(serde_json::json!({ "id_token": replayed }).to_string())
+            .create();
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        token_mock.assert();
+
+        // A cookie another instance signed with its own key
+        let other = login_for(&auth()).cookie_key(b"other");
+        let foreign = login.cookie(LOGIN_COOKIE, other.seal(&state));
+        let req = test::TestRequest::get()
+            .uri(&callback)
+            .cookie(foreign)
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        // Instances sharing the key open each other's cookies
+        let shared = login_for(&auth()).cookie_key(b"shared");
+        assert!(shared.open(cookie.value()).is_some());
+    }
+
+    #[actix_rt::test]
+    async fn test_logout() {
+        let session = Cookie::new("session", "id-token");
+        let logout = |login: Login| {
+            let session = session.clone();
+            async move {
+                let mut app =
+                    test::init_service(App::new().configure(|cfg| login.configure(cfg))).await;
+                let req = test::TestRequest::get()
+                    .uri("/logout")
+                    .cookie(session)
+                    .to_request();
+                let resp = app.call(req).await.unwrap();
+                assert_eq!(resp.status(), StatusCode::FOUND);
+                let mut removed: Vec<_> = resp
+                    .response()
+                    .cookies()
+                    .map(|cookie| cookie.name().to_string())
+                    .collect();
+                removed.sort();
+                let location = resp.headers().get(header::LOCATION).unwrap();
+                (location.to_str().unwrap().to_string(), removed)
+            }
+        };
+
+        let (location, removed) =
+            logout(login().post_logout_redirect_uri("https://app.example.com/bye")).await;
+        let location = Url::parse(&location).unwrap();
+        assert_eq!(location.path(), "/logout");
+        let query: HashMap<_, _> = location.query_pairs().into_owned().collect();
+        assert_eq!(query["client_id"], "app");
+        assert_eq!(query["id_token_hint"], "id-token");
+        assert_eq!(
+            query["post_logout_redirect_uri"],
+            "https://app.example.com/bye"
+        );
+        assert_eq!(removed, [CSRF_COOKIE, "session"]);
+
+        // Without RP-initiated logout at the authserver
+        let mut local = login().post_logout_redirect_uri("/bye").token_refresh();
+        local.end_session_endpoint = None;
+        let (location, removed) = logout(local).await;
+        assert_eq!(location, "/bye");
+        assert_eq!(removed, [CSRF_COOKIE, REFRESH_COOKIE, "session"]);
+        let mut local = login();
+        local.end_session_endpoint = None;
+        assert_eq!(logout(local).await.0, "/");
+    }
+
+    #[actix_rt::test]
+    async fn test_backchannel_logout() {
+        let login = login().revocations(Revocations::default());
+        let mut app = test::init_service(
+            App::new().configure(|cfg| login.configure(cfg)).service(
+                web::scope("/page")
+                    .wrap(login.session().middleware())
+                    .route("", web::get().to(|| async { "page" })),
+            ),
+        )
+        .await;
+        let claims = |extra: Value| {
+            let mut claims = serde_json::json!({"iss": "me", "aud": "app", "sub": "alice",
+                "iat": 1, "exp": 4102444800u64, "sid": "s1"});
+            claims
+                .as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            claims
+        };
+        let session = Cookie::new("session", id_token(claims(serde_json::json!({}))));
+        let req = test::TestRequest::get()
+            .uri("/page")
+            .cookie(session.clone())
+            .to_request();
+        assert!(app.call(req).await.is_ok());
+
+        let logout = |claims: Value| {
+            test::TestRequest::post()
+                .uri("/backchannel-logout")
+                .set_form(&serde_json::json!({ "logout_token": id_token(claims) }))
+                .to_request()
+        };
+        let resp = app
+            .call(logout(claims(serde_json::json!({}))))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let events = serde_json::json!({"events": {BACKCHANNEL_LOGOUT_EVENT: {}}});
+        let resp = app.call(logout(claims(events.clone()))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/page")
+            .cookie(session)
+            .to_request();
+        let err = app.call(req).await.err().unwrap();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        // ID tokens, and logout tokens without iat or naming no one, revoke nothing
+        let without = |names: &[&str]| {
+            let mut claims = claims(events.clone());
+            for name in names {
+                claims.as_object_mut().unwrap().remove(*name);
+            }
+            claims
+        };
+        let mut with_nonce = without(&[]);
+        with_nonce["nonce"] = "n".into();
+        for claims in [with_nonce, without(&["iat"]), without(&["sid", "sub"])] {
+            let resp = app.call(logout(claims)).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // Without a sid, every session of the subject ends
+        let bob = |extra: Value| {
+            let mut claims = claims(extra);
+            claims["sub"] = "bob".into();
+            claims.as_object_mut().unwrap().remove("sid");
+            claims
+        };
+        let session = Cookie::new("session", id_token(bob(serde_json::json!({}))));
+        let page = || {
+            test::TestRequest::get()
+                .uri("/page")
+                .cookie(session.clone())
+                .to_request()
+        };
+        assert!(app.call(page()).await.is_ok());
+        let resp = app.call(logout(bob(events))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(app.call(page()).await.is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_token_refresh() {
+        let login = login().token_refresh();
+        let mut app = test::init_service(App::new().configure(|cfg| login.configure(cfg))).await;
+
+        let token_mock = mockito::mock("POST", "/login/token")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "refresh_token".into()),
+                mockito::Matcher::UrlEncoded("refresh_token".into(), "r1".into()),
+            ]))
+            .with_body(r#"{"access_token": "a2", "refresh_token": "r2", "expires_in": 300}"#)
+            .create();
+        let req = test::TestRequest::post()
+            .uri("/token/refresh")
+            .cookie(Cookie::new(REFRESH_COOKIE, "r1"))
+            .to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let rotated = resp.response().cookies().next().unwrap().into_owned();
+        assert_eq!(
+            (rotated.value(), rotated.path()),
+            ("r2", Some(REFRESH_PATH))
+        );
+        assert!(rotated.http_only().unwrap());
+        let body = test::read_body(resp).await;
+        assert_eq!(
+            serde_json::from_slice::<Value>(&body).unwrap(),
+            serde_json::json!({"access_token": "a2", "token_type": "Bearer", "expires_in": 300})
+        );
+        token_mock.assert();
+
+        let req = test::TestRequest::post().uri("/token/refresh").to_request();
+        let resp = app.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+}
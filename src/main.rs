@@ -1,15 +1,11 @@
-#[macro_use]
-extern crate lazy_static;
-use crate::config::CONFIG;
-use actix_web::middleware::Logger;
+use actix_web::middleware::{Condition, Logger};
 use actix_web::{web, App, HttpServer};
 use actix_web::{HttpResponse, Responder};
-use actix_web_httpauth::middleware::HttpAuthentication;
-use jsonwebtoken::{Algorithm, Validation};
-
-mod auth;
-mod config;
-mod openid;
+use rapi::auth::JwtAuth;
+use rapi::config::ConfigHandle;
+#[cfg(feature = "cli")]
+use rapi::config::ConfigLoader;
+use rapi::renewal::RenewalHint;
 
 async fn index() -> impl Responder {
     HttpResponse::Ok().body("hello world")
@@ -21,28 +17,83 @@ async fn main() -> anyhow::Result<()> {
 
     env_logger::init_from_env(env_logger::Env::default().filter_or("LOG_LEVEL", ""));
 
-    let mut aud = std::collections::HashSet::new();
-    aud.insert(CONFIG.audience.clone());
+    #[cfg(feature = "cli")]
+    let flags = {
+        if std::env::args().any(|arg| arg == "--help") {
+            println!("{}", rapi::cli::USAGE);
+            return Ok(());
+        }
+        rapi::cli::flags(std::env::args().skip(1))?
+    };
+    #[cfg(feature = "cli")]
+    let mut config = ConfigLoader::from_env()?.vars(flags.clone()).load()?;
+    #[cfg(not(feature = "cli"))]
+    let mut config = rapi::config::CONFIG.clone();
 
-    let oidc = openid::get_config(CONFIG.authserver.as_ref()).await?;
+    #[cfg(feature = "sentry")]
+    if let Some(dsn) = &config.sentry_dsn {
+        let mut sentry = rapi::report::SentryReporter::new(dsn)?;
+        if let Some(environment) = &config.sentry_environment {
+            sentry = sentry.environment(environment);
+        }
+        rapi::report::set_reporter(sentry);
+    }
+    #[cfg(not(feature = "sentry"))]
+    if config.sentry_dsn.is_some() {
+        anyhow::bail!("SENTRY_DSN requires the sentry feature");
+    }
 
-    let mut validation = Validation::default();
-    validation.algorithms = vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512];
-    validation.iss = Some(oidc.issuer.clone());
-    validation.aud = Some(aud);
+    if let Some(source) = rapi::secrets::from_config(&config)? {
+        config.resolve_secrets(source.as_ref()).await?;
+    }
+    let live = ConfigHandle::new(config.clone());
+    #[cfg(feature = "cli")]
+    let live = live.loader(move || ConfigLoader::from_env()?.vars(flags.clone()).load());
+    let (auth, _oidc) = JwtAuth::discover(&config).await?;
+    let auth = auth.live_config(live.clone());
+    let shutdown = auth.shutdown_handle().unwrap_or_default();
+    #[cfg(all(unix, feature = "unix"))]
+    {
+        let mut reload = rapi::reload::Reload::new().on_config(move |_| match live.reload() {
+            Ok(_) => log::info!(
+                "leeway, required claims and scopes reloaded, other settings apply on restart"
+            ),
+            Err(e) => log::warn!("reloading the configuration failed: {:#}", e),
+        });
+        if let Some(handle) = auth.refresh_handle() {
+            reload = reload.refresh(handle);
+        }
+        reload.spawn(&shutdown)?;
+    }
+    let renewal = RenewalHint::from_config(&config)?;
+    #[cfg(feature = "login")]
+    let login = rapi::login::Login::from_config(&config, &auth, &_oidc)?;
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
-            .wrap(HttpAuthentication::bearer(auth::validator(
-                validation.clone(),
-                oidc.jwks.clone(),
-            )))
-            .service(web::scope("/").route("", web::get().to(index)))
+            .route("/ready", web::get().to(auth.key_store().readiness()))
+            .configure(|_cfg| {
+                #[cfg(feature = "login")]
+                if let Some(login) = &login {
+                    login.configure(_cfg);
+                }
+            })
+            .service(
+                web::scope("/")
+                    .wrap(auth.clone().middleware())
+                    .wrap(Condition::new(
+                        renewal.is_some(),
+                        renewal
+                            .clone()
+                            .unwrap_or_else(|| RenewalHint::new(Default::default())),
+                    ))
+                    .route("", web::get().to(index)),
+            )
     })
     .bind("127.0.0.1:8080")?
-    .run()
-    .await?;
+    .run();
+    shutdown.run(server).await?;
 
     Ok(())
 }
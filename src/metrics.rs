@@ -0,0 +1,413 @@
+//! Counters of authentication outcomes, in the Prometheus text format
+use crate::error::AuthError;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Label value of kids and issuers beyond the cap
+pub const OTHER: &str = "other";
+
+/// Counts of requests by outcome, key id and issuer, to tell e.g. a rotated key or
+/// one misbehaving identity provider from failures overall. `JwtAuth` labels rejected
+/// tokens with kids and issuers it does not know as `other`, and beyond that only the
+/// first few distinct kids and issuers get their own label, later ones counting as
+/// `other` too.
+#[derive(Clone)]
+pub struct AuthMetrics {
+    inner: Arc<Mutex<Inner>>,
+    near_expiry: Option<Duration>,
+}
+
+struct Inner {
+    max_values: usize,
+    kids: HashSet<String>,
+    issuers: HashSet<String>,
+    clients: HashSet<String>,
+    counts: BTreeMap<(&'static str, String, String), u64>,
+    near_expiry_counts: BTreeMap<String, u64>,
+    stale_key_requests: u64,
+}
+
+impl Default for AuthMetrics {
+    fn default() -> Self {
+        AuthMetrics::new(50)
+    }
+}
+
+impl AuthMetrics {
+    /// Label at most `max_values` distinct kids, and as many issuers
+    pub fn new(max_values: usize) -> Self {
+        AuthMetrics {
+            inner: Arc::new(Mutex::new(Inner {
+                max_values,
+                kids: HashSet::new(),
+                issuers: HashSet::new(),
+                clients: HashSet::new(),
+                counts: BTreeMap::new(),
+                near_expiry_counts: BTreeMap::new(),
+                stale_key_requests: 0,
+            })),
+            near_expiry: None,
+        }
+    }
+
+    /// Also count accepted tokens expiring within `window`, by the client they were
+    /// issued to, `azp` or `client_id`, to spot clients that fail to refresh them
+    /// before the rejections start
+    pub fn near_expiry(mut self, window: Duration) -> Self {
+        self.near_expiry = Some(window);
+        self
+    }
+
+    /// Count the accepted token with `claims` if it is near its expiry
+    pub fn record_expiry(&self, claims: &Map<String, Value>) {
+        let window = match self.near_expiry {
+            Some(window) => window,
+            None => return,
+        };
+        let exp = match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) => exp,
+            None => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if exp > now + window.as_secs() {
+            return;
+        }
+        let client = ["azp", "client_id"]
+            .iter()
+            .find_map(|claim| claims.get(*claim)?.as_str());
+        let mut inner = self.inner.lock().unwrap();
+        let max_values = inner.max_values;
+        let client = capped(&mut inner.clients, client, max_values);
+        *inner.near_expiry_counts.entry(client).or_insert(0) += 1;
+    }
+
+    /// The count of tokens near their expiry of `client`, empty for absent
+    pub fn near_expiry_count(&self, client: &str) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.near_expiry_counts.get(client).copied().unwrap_or(0)
+    }
+
+    /// Count a token verified with stale keys, while refreshes fail yet before the
+    /// keys expire, to alert ahead of the rejections
+    pub fn record_stale_keys(&self) {
+        self.inner.lock().unwrap().stale_key_requests += 1;
+    }
+
+    /// The count of tokens verified with stale keys
+    pub fn stale_key_requests(&self) -> u64 {
+        self.inner.lock().unwrap().stale_key_requests
+    }
+
+    /// Count a request with a token of `kid` from `iss`, either of which may be absent
+    pub fn record(&self, result: Result<(), &AuthError>, kid: Option<&str>, iss: Option<&str>) {
+        let outcome = match result {
+            Ok(()) => "accepted",
+            Err(e) => e.kind(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let max_values = inner.max_values;
+        let kid = capped(&mut inner.kids, kid, max_values);
+        let iss = capped(&mut inner.issuers, iss, max_values);
+        *inner.counts.entry((outcome, kid, iss)).or_insert(0) += 1;
+    }
+
+    /// The count of requests with `outcome`, `kid` and `iss` labels, empty for absent
+    pub fn count(&self, outcome: &str, kid: &str, iss: &str) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .counts
+            .iter()
+            .find(|((o, k, i), _)| *o == outcome && k == kid && i == iss)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    /// The counters in the Prometheus text exposition format, e.g. to serve at
+    /// `/metrics`
+    pub fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut out = String::from(
+            "# HELP auth_requests_total Authentication outcomes by key id and issuer\n\
+             # TYPE auth_requests_total counter\n",
+        );
+        for ((outcome, kid, iss), count) in &inner.counts {
+            let _ = writeln!(
+                out,
+                "auth_requests_total{{outcome=\"{}\",kid=\"{}\",iss=\"{}\"}} {}",
+                outcome,
+                escape(kid),
+                escape(iss),
+                count
+            );
+        }
+        let _ = write!(
+            out,
+            "# HELP auth_stale_keys_requests_total Tokens verified with keys whose refreshes fail\n\
+             # TYPE auth_stale_keys_requests_total counter\n\
+             auth_stale_keys_requests_total {}\n",
+            inner.stale_key_requests
+        );
+        if self.near_expiry.is_some() {
+            out.push_str(
+                "# HELP auth_near_expiry_tokens_total Accepted tokens close to their expiry by client\n\
+                 # TYPE auth_near_expiry_tokens_total counter\n",
+            );
+            for (client, count) in &inner.near_expiry_counts {
+                let _ = writeln!(
+                    out,
+                    "auth_near_expiry_tokens_total{{client=\"{}\"}} {}",
+                    escape(client),
+                    count
+                );
+            }
+        }
+        out
+    }
+}
+
+/// `value` as a label, unless `seen` already holds `max_values` others
+fn capped(seen: &mut HashSet<String>, value: Option<&str>, max_values: usize) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return String::new(),
+    };
+    if !seen.contains(value) {
+        if seen.len() >= max_values {
+            return OTHER.into();
+        }
+        seen.insert(value.into());
+    }
+    value.into()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = AuthMetrics::new(2);
+        let idp = Some("https://idp");
+        metrics.record(Ok(()), Some("k1"), idp);
+        metrics.record(Ok(()), Some("k1"), idp);
+        metrics.record(Err(&AuthError::InvalidToken), Some("k2"), idp);
+        metrics.record(Err(&AuthError::UnknownKid), Some("k3"), Some("evil\""));
+        metrics.record(Err(&AuthError::MissingToken), None, None);
+
+        assert_eq!(metrics.count("accepted", "k1", "https://idp"), 2);
+        assert_eq!(metrics.count("invalid_token", "k2", "https://idp"), 1);
+        assert_eq!(metrics.count("unknown_kid", OTHER, "evil\""), 1);
+        assert_eq!(metrics.count("missing_token", "", ""), 1);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE auth_requests_total counter\n"));
+        assert!(text.contains(
+            "auth_requests_total{outcome=\"accepted\",kid=\"k1\",iss=\"https://idp\"} 2\n"
+        ));
+        assert!(text.contains("iss=\"evil\\\"\"} 1\n"));
+        assert!(!text.contains("auth_near_expiry_tokens_total"));
+
+        let metrics = AuthMetrics::default().near_expiry(Duration::from_secs(60));
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        for claims in [
+            serde_json::json!({"exp": now + 30, "azp": "spa"}),
+            serde_json::json!({"exp": now + 10, "client_id": "spa"}),
+            serde_json::json!({"exp": now + 3600, "azp": "spa"}),
+            serde_json::json!({"exp": now + 5}),
+        ] {
+            metrics.record_expiry(claims.as_object().unwrap());
+        }
+        assert_eq!(metrics.near_expiry_count("spa"), 2);
+        assert_eq!(metrics.near_expiry_count(""), 1);
+        assert!(metrics
+            .render()
+            .contains("auth_near_expiry_tokens_total{client=\"spa\"} 2\n"));
+    }
+
+    #[actix_rt::test]
+    async fn test_near_expiry_requests() {
+        use crate::auth::JwtAuth;
+        use crate::keystore::Keys;
+        use actix_web::dev::Service;
+        use actix_web::{test, web, App};
+        use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let metrics = AuthMetrics::default().near_expiry(Duration::from_secs(60));
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys).metrics(metrics.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.middleware())
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let header = Header {
+            kid: Some("0".into()),
+            ..Header::new(Algorithm::HS256)
+        };
+        for (claims, secret) in [
+            (serde_json::json!({"exp": now + 20, "azp": "spa"}), "secret"),
+            (serde_json::json!({"exp": now + 40, "azp": "spa"}), "secret"),
+            (serde_json::json!({"exp": now + 20, "azp": "cli"}), "secret"),
+            (
+                serde_json::json!({"exp": now + 3600, "azp": "cli"}),
+                "secret",
+            ),
+            // Rejected, so not counted
+            (
+                serde_json::json!({"exp": now + 20, "azp": "cli"}),
+                "guessed",
+            ),
+        ] {
+            let key = EncodingKey::from_secret(secret.as_bytes());
+            let req = test::TestRequest::get()
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", encode(&header, &claims, &key).unwrap()),
+                )
+                .to_request();
+            let _ = app.call(req).await;
+        }
+        assert_eq!(metrics.near_expiry_count("spa"), 2);
+        assert_eq!(metrics.near_expiry_count("cli"), 1);
+        assert_eq!(metrics.count("accepted", "0", ""), 4);
+        assert_eq!(metrics.count("invalid_token", "0", ""), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_unknown_labels() {
+        use crate::auth::JwtAuth;
+        use crate::keystore::Keys;
+        use actix_web::dev::Service;
+        use actix_web::{test, web, App};
+        use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let validation = Validation {
+            iss: Some("me".into()),
+            ..Validation::new(Algorithm::HS256)
+        };
+        let metrics = AuthMetrics::new(2);
+        let auth = JwtAuth::new(validation, keys).metrics(metrics.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.middleware())
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let key = EncodingKey::from_secret(b"secret");
+        let mut tokens: Vec<_> = (0..5)
+            .map(|i| {
+                let header = Header {
+                    kid: Some(format!("junk{}", i)),
+                    ..Header::new(Algorithm::HS256)
+                };
+                let claims =
+                    serde_json::json!({"exp": 4_000_000_000u64, "iss": format!("junk{}", i)});
+                encode(&header, &claims, &key).unwrap()
+            })
+            .collect();
+        let header = Header {
+            kid: Some("0".into()),
+            ..Header::new(Algorithm::HS256)
+        };
+        for iss in &["me", "junk"] {
+            let claims = serde_json::json!({"exp": 4_000_000_000u64, "iss": iss});
+            tokens.push(encode(&header, &claims, &key).unwrap());
+        }
+        for token in tokens {
+            let req = test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request();
+            let _ = app.call(req).await;
+        }
+
+        assert_eq!(metrics.count("unknown_kid", OTHER, OTHER), 5);
+        assert_eq!(metrics.count("accepted", "0", "me"), 1);
+        assert_eq!(metrics.count("invalid_token", "0", OTHER), 1);
+        assert!(!metrics.render().contains("junk"));
+    }
+
+    #[actix_rt::test]
+    async fn test_stale_keys() {
+        use crate::auth::JwtAuth;
+        use crate::keystore::{JwksStore, Keys};
+        use actix_web::{test, web, App};
+        use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let store = JwksStore::new(keys.clone()).max_staleness(Duration::from_secs(60));
+        let metrics = AuthMetrics::default();
+        let auth =
+            JwtAuth::new(Validation::new(Algorithm::HS256), store.clone()).metrics(metrics.clone());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.middleware())
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let header = Header {
+            kid: Some("0".into()),
+            ..Header::new(Algorithm::HS256)
+        };
+        let claims = serde_json::json!({"exp": 4_000_000_000u64});
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let get = || {
+            test::TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request()
+        };
+
+        assert!(test::call_service(&mut app, get())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(metrics.stale_key_requests(), 0);
+        // Still served while refreshes fail, but counted
+        store.record_failure(&anyhow::anyhow!("down"));
+        assert!(test::call_service(&mut app, get())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(metrics.stale_key_requests(), 1);
+        assert!(metrics
+            .render()
+            .contains("auth_stale_keys_requests_total 1\n"));
+        store.refreshed(keys);
+        assert!(test::call_service(&mut app, get())
+            .await
+            .status()
+            .is_success());
+        assert_eq!(metrics.stale_key_requests(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! Constraints on the networks tokens may be used from
+use anyhow::Context;
+use ipnet::IpNet;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// A network in CIDR notation, or a single address
+pub fn parse_net(s: &str) -> Result<IpNet, std::net::AddrParseError> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+}
+
+/// Restricts tokens to the networks their `cnf.ip` claim names, and principals to the
+/// networks an allow-list maps them to. Either applies only where present, and a
+/// token both name must satisfy both. The client address is that of the
+/// `TrustedProxies` of the `JwtAuth`.
+#[derive(Clone, Debug)]
+pub struct IpConstraint {
+    claim: Option<Vec<String>>,
+    key_claim: String,
+    allowed: HashMap<String, Vec<IpNet>>,
+}
+
+impl Default for IpConstraint {
+    fn default() -> Self {
+        IpConstraint::new()
+    }
+}
+
+impl IpConstraint {
+    pub fn new() -> Self {
+        IpConstraint {
+            claim: Some(vec!["cnf".into(), "ip".into()]),
+            key_claim: "sub".into(),
+            allowed: HashMap::new(),
+        }
+    }
+
+    /// The claim holding the networks of each token, a string or an array of them,
+    /// with dots separating nested claims; `cnf.ip` by default
+    pub fn claim(mut self, claim: &str) -> Self {
+        self.claim = Some(claim.split('.').map(String::from).collect());
+        self
+    }
+
+    /// Ignore networks in tokens, only checking the allow-list
+    pub fn without_claim(mut self) -> Self {
+        self.claim = None;
+        self
+    }
+
+    /// The claim keying the allow-list, `sub` by default, e.g. `azp` for clients
+    pub fn key_claim(mut self, claim: impl Into<String>) -> Self {
+        self.key_claim = claim.into();
+        self
+    }
+
+    /// Accept the principal `key` only from `networks`
+    pub fn allow(mut self, key: impl Into<String>, networks: Vec<IpNet>) -> Self {
+        self.allowed.entry(key.into()).or_default().extend(networks);
+        self
+    }
+
+    /// An allow-list from a JSON object mapping each principal to its networks
+    pub fn allow_file(mut self, path: &str) -> anyhow::Result<Self> {
+        let file = std::fs::read(path).with_context(|| format!("reading {}", path))?;
+        let allowed: HashMap<String, Vec<String>> = serde_json::from_slice(&file)
+            .with_context(|| format!("invalid allow-list in {}", path))?;
+        for (key, networks) in allowed {
+            let networks = networks
+                .iter()
+                .map(|s| parse_net(s).with_context(|| format!("invalid network {:?}", s)))
+                .collect::<anyhow::Result<_>>()?;
+            self = self.allow(key, networks);
+        }
+        Ok(self)
+    }
+
+    /// Whether the token with `claims` may be used from `ip`, unknown when `None`
+    pub fn check(&self, claims: &Map<String, Value>, ip: Option<IpAddr>) -> bool {
+        let claimed = self.claim.as_ref().and_then(|path| {
+            let (first, rest) = path.split_first()?;
+            rest.iter()
+                .try_fold(claims.get(first)?, |value, claim| value.get(claim))
+        });
+        let claimed: Option<Vec<IpNet>> = claimed.map(|value| match value {
+            Value::String(s) => parse_net(s).into_iter().collect(),
+            Value::Array(items) => items
+                .iter()
+                .filter_map(|item| parse_net(item.as_str()?).ok())
+                .collect(),
+            _ => Vec::new(),
+        });
+        let allowed = claims
+            .get(&self.key_claim)
+            .and_then(Value::as_str)
+            .and_then(|key| self.allowed.get(key));
+        claimed.is_none_or(|claimed| within(&claimed, ip))
+            && allowed.is_none_or(|allowed| within(allowed, ip))
+    }
+}
+
+fn within(networks: &[IpNet], ip: Option<IpAddr>) -> bool {
+    ip.is_some_and(|ip| networks.iter().any(|net| net.contains(&ip)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ip_constraint() {
+        let net = |s| parse_net(s).unwrap();
+        let constraint = IpConstraint::new().allow("alice", vec![net("10.0.0.0/8")]);
+        let ip = |s: &str| Some(s.parse().unwrap());
+
+        let alice = json!({"sub": "alice"});
+        let alice = alice.as_object().unwrap();
+        assert!(constraint.check(alice, ip("10.1.2.3")));
+        assert!(!constraint.check(alice, ip("11.1.2.3")));
+        assert!(!constraint.check(alice, None));
+
+        let bound = json!({"sub": "bob", "cnf": {"ip": ["2001:db8::/32", "203.0.113.7"]}});
+        let bound = bound.as_object().unwrap();
+        assert!(constraint.check(bound, ip("2001:db8::1")));
+        assert!(constraint.check(bound, ip("203.0.113.7")));
+        assert!(!constraint.check(bound, ip("203.0.113.8")));
+
+        // Both apply to a bound token of alice
+        let both = json!({"sub": "alice", "cnf": {"ip": "10.0.0.0/16"}});
+        let both = both.as_object().unwrap();
+        assert!(constraint.check(both, ip("10.0.1.1")));
+        assert!(!constraint.check(both, ip("10.1.1.1")));
+
+        let unconstrained = json!({"sub": "carol"});
+        assert!(constraint.check(unconstrained.as_object().unwrap(), None));
+    }
+}
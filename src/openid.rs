@@ -1,59 +1,285 @@
-use jsonwebtoken::DecodingKey;
+use crate::http::{default_fetcher, HttpFetch};
+use crate::jwk::{JwkSet, KeyStrength};
+use crate::keystore::Keys;
+use log::warn;
+use rand::Rng;
 use serde::Deserialize;
-use std::collections::{hash_map, HashMap};
+use serde_json::Value;
+use std::fmt;
+use std::time::Duration;
 
 #[derive(Clone)]
-pub struct OidConf<'a> {
-    pub jwks: HashMap<String, DecodingKey<'a>, hash_map::RandomState>,
+pub struct OidConf {
+    pub jwks: Keys,
     pub issuer: String,
+    pub jwks_uri: String,
+    /// The JWK Set as fetched, e.g. for persisting it
+    pub jwks_document: Value,
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub end_session_endpoint: Option<String>,
+    pub introspection_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
 }
 
-pub async fn get_config<'a>(uri: &str) -> anyhow::Result<OidConf<'a>> {
-    let oidc =
-        get_json::<Oid>(&(uri.to_string() + "/v2.0/.well-known/openid-configuration")).await?;
-    let jwks = get_json::<JWKS>(&oidc.jwks_uri)
-        .await?
-        .keys
-        .iter()
-        .filter(|jwk| jwk.kty == Some("RSA".into()))
-        .filter_map(|jwk| Some((jwk.kid.as_ref()?, jwk.n.as_ref()?, jwk.e.as_ref()?)))
-        .fold(HashMap::new(), |mut dec_keys, v| {
-            dec_keys.insert(
-                v.0.into(),
-                DecodingKey::from_rsa_components(&v.1, &v.2).into_static(),
-            );
-            dec_keys
-        });
+/// Bounds on fetched documents, so a misbehaving endpoint cannot exhaust memory,
+/// and on the keys they contain
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Largest discovery or JWKS document, in bytes
+    pub max_document_len: usize,
+    /// Most keys accepted in a JWK Set
+    pub max_keys: usize,
+    /// Weaker keys are skipped
+    pub key_strength: KeyStrength,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_document_len: 1 << 20,
+            max_keys: 100,
+            key_strength: KeyStrength::default(),
+        }
+    }
+}
+
+/// How often and how patiently failed fetches are retried
+#[derive(Clone, Debug)]
+pub struct Retry {
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 5,
+            initial_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Retry {
+    /// Full jitter: a random delay up to the exponentially growing cap
+    fn delay(&self, attempt: u32) -> Duration {
+        let cap = self
+            .initial_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(self.max_delay, |d| d.min(self.max_delay));
+        cap.mul_f64(rand::thread_rng().gen_range(0.0, 1.0))
+    }
+}
+
+/// A fetch that kept failing after every retry
+#[derive(Debug)]
+pub struct FetchError {
+    pub uri: String,
+    pub attempts: u32,
+    pub last_error: anyhow::Error,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "fetching {} failed after {} attempts: {}",
+            self.uri, self.attempts, self.last_error
+        )
+    }
+}
+
+impl std::error::Error for FetchError {}
+
+pub async fn get_config(uri: &str) -> anyhow::Result<OidConf> {
+    get_config_with_retry(
+        uri,
+        default_fetcher().as_ref(),
+        &Retry::default(),
+        &Limits::default(),
+    )
+    .await
+}
+
+pub async fn get_config_with_retry(
+    uri: &str,
+    client: &dyn HttpFetch,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<OidConf> {
+    get_config_at_with_retry(uri, DISCOVERY_PATH, client, retry, limits).await
+}
+
+/// Path of the OpenID Connect discovery document under the issuer, as Azure AD
+/// serves it
+pub const DISCOVERY_PATH: &str = "/v2.0/.well-known/openid-configuration";
+
+/// Discover the keys from the document at `path` under `uri`, e.g.
+/// `/.well-known/openid-configuration` for most providers besides Azure AD
+pub async fn get_config_at_with_retry(
+    uri: &str,
+    path: &str,
+    client: &dyn HttpFetch,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<OidConf> {
+    discover(
+        &(uri.trim_end_matches('/').to_string() + path),
+        client,
+        retry,
+        limits,
+    )
+    .await
+}
+
+/// Discover the keys through the OAuth 2.0 Authorization Server Metadata (RFC 8414)
+/// of the issuer `uri`, for servers without OpenID Connect discovery
+pub async fn get_oauth_config_with_retry(
+    uri: &str,
+    client: &dyn HttpFetch,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<OidConf> {
+    discover(&oauth_metadata_uri(uri)?, client, retry, limits).await
+}
+
+/// Skip discovery, taking the keys of `issuer` from the JWK Set at `jwks_uri`
+pub async fn get_jwks_config_with_retry(
+    issuer: &str,
+    jwks_uri: &str,
+    client: &dyn HttpFetch,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<OidConf> {
+    let jwks_document = get_jwks_document(client, jwks_uri, retry, limits).await?;
     Ok(OidConf {
-        jwks,
+        jwks: decoding_keys(&jwks_document, &limits.key_strength)?,
+        jwks_document,
+        issuer: issuer.into(),
+        jwks_uri: jwks_uri.into(),
+        authorization_endpoint: None,
+        token_endpoint: None,
+        end_session_endpoint: None,
+        introspection_endpoint: None,
+        revocation_endpoint: None,
+    })
+}
+
+/// The well-known URI goes between the host and the path of the issuer
+fn oauth_metadata_uri(issuer: &str) -> anyhow::Result<String> {
+    let mut url = url::Url::parse(issuer)?;
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&format!("/.well-known/oauth-authorization-server{}", path));
+    Ok(url.into())
+}
+
+async fn discover(
+    metadata_uri: &str,
+    client: &dyn HttpFetch,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<OidConf> {
+    let oidc = get_json_with_retry::<Oid>(client, metadata_uri, retry, limits).await?;
+    let jwks_document = get_jwks_document(client, &oidc.jwks_uri, retry, limits).await?;
+    Ok(OidConf {
+        jwks: decoding_keys(&jwks_document, &limits.key_strength)?,
+        jwks_document,
         issuer: oidc.issuer,
+        jwks_uri: oidc.jwks_uri,
+        authorization_endpoint: oidc.authorization_endpoint,
+        token_endpoint: oidc.token_endpoint,
+        end_session_endpoint: oidc.end_session_endpoint,
+        introspection_endpoint: oidc.introspection_endpoint,
+        revocation_endpoint: oidc.revocation_endpoint,
     })
 }
 
-async fn get_json<'a, T>(uri: &str) -> anyhow::Result<T>
+/// Fetch the signing keys of the JWK Set at `uri`, by kid
+pub async fn get_jwks(
+    client: &dyn HttpFetch,
+    uri: &str,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<Keys> {
+    decoding_keys(
+        &get_jwks_document(client, uri, retry, limits).await?,
+        &limits.key_strength,
+    )
+}
+
+/// Fetch the JWK Set at `uri` without interpreting it, beyond counting its keys
+pub async fn get_jwks_document(
+    client: &dyn HttpFetch,
+    uri: &str,
+    retry: &Retry,
+    limits: &Limits,
+) -> anyhow::Result<Value> {
+    let document = get_json_with_retry::<Value>(client, uri, retry, limits).await?;
+    let count = document["keys"].as_array().map_or(0, Vec::len);
+    if count > limits.max_keys {
+        anyhow::bail!(
+            "{} has {} keys, more than the {} allowed",
+            uri,
+            count,
+            limits.max_keys
+        );
+    }
+    Ok(document)
+}
+
+/// The signing keys of a JWK Set document at least as strong as `strength`, by kid
+pub fn decoding_keys(document: &Value, strength: &KeyStrength) -> anyhow::Result<Keys> {
+    Ok(JwkSet::deserialize(document)?.decoding_keys_with(strength))
+}
+
+async fn get_json<'a, T>(client: &dyn HttpFetch, uri: &str, max_len: usize) -> anyhow::Result<T>
+where
+    for<'de> T: Deserialize<'de> + 'a,
+{
+    Ok(serde_json::from_slice(&client.fetch(uri, max_len).await?)?)
+}
+
+async fn get_json_with_retry<'a, T>(
+    client: &dyn HttpFetch,
+    uri: &str,
+    retry: &Retry,
+    limits: &Limits,
+) -> Result<T, FetchError>
 where
     for<'de> T: Deserialize<'de> + 'a,
 {
-    Ok(reqwest::get(uri).await?.json::<T>().await?)
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match get_json::<T>(client, uri, limits.max_document_len).await {
+            Ok(t) => return Ok(t),
+            Err(e) if attempt >= retry.attempts => {
+                return Err(FetchError {
+                    uri: uri.into(),
+                    attempts: attempt,
+                    last_error: e,
+                })
+            }
+            Err(e) => {
+                let delay = retry.delay(attempt - 1);
+                warn!("fetching {} failed, retrying in {:?}: {}", uri, delay, e);
+                actix_rt::time::delay_for(delay).await;
+            }
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct Oid {
     jwks_uri: String,
     issuer: String,
-}
-
-#[derive(Clone, Debug, Deserialize)]
-struct JWK {
-    kty: Option<String>,
-    kid: Option<String>,
-    n: Option<String>,
-    e: Option<String>,
-}
-
-#[derive(Clone, Debug, Deserialize)]
-struct JWKS {
-    keys: Vec<JWK>,
+    authorization_endpoint: Option<String>,
+    token_endpoint: Option<String>,
+    end_session_endpoint: Option<String>,
+    introspection_endpoint: Option<String>,
+    revocation_endpoint: Option<String>,
 }
 
 #[cfg(test)]
@@ -71,14 +297,18 @@ mod tests {
         let jwk_body = r#" { "keys": [ {
                         "kty": "RSA",
                         "e": "AQAB",
-                        "n": "actually a big int base 64 encoded as a string",
+                        "n": "MODULUS",
                         "kid": "N" },
                         {"kty":"oct",
                          "use":"sig",
                          "kid":"hmac",
-                         "k":"SECRET_2gtzk"}] } "#;
+                         "k":"SECRET_2gtzk"}] } "#
+            .replace(
+                "MODULUS",
+                &base64::encode_config([0xc5; 256], base64::URL_SAFE_NO_PAD),
+            );
 
-        serde_json::from_str::<JWKS>(&jwk_body).unwrap();
+        serde_json::from_str::<JwkSet>(&jwk_body).unwrap();
 
         let disc_mock = mockito::mock("GET", disc)
             .with_header("content-type", "application/json")
@@ -98,4 +328,107 @@ mod tests {
         jwk_mock.assert();
         disc_mock.assert();
     }
+
+    #[actix_rt::test]
+    async fn test_retry() {
+        let disc = "/v2.0/.well-known/openid-configuration";
+        let disc_mock = mockito::mock("GET", disc)
+            .with_status(500)
+            .expect(3)
+            .create();
+
+        let retry = Retry {
+            attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        };
+        let err = get_config_with_retry(
+            &mockito::server_url(),
+            &reqwest::Client::new(),
+            &retry,
+            &Limits::default(),
+        )
+        .await
+        .err()
+        .unwrap();
+        let err = err.downcast_ref::<FetchError>().unwrap();
+        assert_eq!(err.attempts, 3);
+        assert!(err.uri.ends_with(disc));
+
+        disc_mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_oauth_metadata() {
+        assert_eq!(
+            oauth_metadata_uri("https://example.com/tenant/").unwrap(),
+            "https://example.com/.well-known/oauth-authorization-server/tenant"
+        );
+
+        let disc = "/.well-known/oauth-authorization-server/oauth";
+        let keys = "/oauth/keys";
+        let disc_mock = mockito::mock("GET", disc)
+            .with_body(format!(
+                r#"{{"issuer": "me", "jwks_uri": "{}{}",
+                    "introspection_endpoint": "https://example.com/introspect"}}"#,
+                mockito::server_url(),
+                keys
+            ))
+            .create();
+        let keys_mock = mockito::mock("GET", keys)
+            .with_body(r#"{"keys": []}"#)
+            .create();
+
+        let conf = get_oauth_config_with_retry(
+            &(mockito::server_url() + "/oauth"),
+            default_fetcher().as_ref(),
+            &Retry::default(),
+            &Limits::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(conf.issuer, "me");
+        assert_eq!(
+            conf.introspection_endpoint.as_deref(),
+            Some("https://example.com/introspect")
+        );
+        assert_eq!(conf.revocation_endpoint, None);
+        disc_mock.assert();
+        keys_mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_limits() {
+        let path = "/limits/keys";
+        let mock = mockito::mock("GET", path)
+            .with_body(r#"{"keys": [{}, {}, {}]}"#)
+            .expect(3)
+            .create();
+        let uri = mockito::server_url() + path;
+        let client = reqwest::Client::new();
+        let retry = Retry {
+            attempts: 1,
+            ..Retry::default()
+        };
+
+        let limits = Limits::default();
+        assert!(get_jwks_document(&client, &uri, &retry, &limits)
+            .await
+            .is_ok());
+        let limits = Limits {
+            max_keys: 2,
+            ..Limits::default()
+        };
+        assert!(get_jwks_document(&client, &uri, &retry, &limits)
+            .await
+            .is_err());
+        let limits = Limits {
+            max_document_len: 8,
+            ..Limits::default()
+        };
+        assert!(get_jwks_document(&client, &uri, &retry, &limits)
+            .await
+            .is_err());
+        mock.assert();
+    }
 }
@@ -0,0 +1,318 @@
+//! Tokens for calls this service makes to other APIs
+use crate::config::Config;
+use crate::openid::OidConf;
+use anyhow::Context;
+use futures::lock::Mutex;
+use log::debug;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize)]
+pub(crate) struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: Option<u64>,
+}
+
+pub(crate) struct CachedToken {
+    pub access_token: String,
+    pub renew_at: Instant,
+}
+
+/// Obtains access tokens for this service itself with the client credentials grant,
+/// keeping each until shortly before it expires
+#[derive(Clone)]
+pub struct ServiceTokenProvider {
+    token_endpoint: String,
+    client_id: String,
+    client_secret: String,
+    scope: Option<String>,
+    audience: Option<String>,
+    early_refresh: Duration,
+    client: reqwest::Client,
+    cached: Arc<Mutex<Option<CachedToken>>>,
+}
+
+impl ServiceTokenProvider {
+    pub fn new(
+        token_endpoint: impl Into<String>,
+        client_id: impl Into<String>,
+        client_secret: impl Into<String>,
+    ) -> Self {
+        ServiceTokenProvider {
+            token_endpoint: token_endpoint.into(),
+            client_id: client_id.into(),
+            client_secret: client_secret.into(),
+            scope: None,
+            audience: None,
+            early_refresh: Duration::from_secs(60),
+            client: reqwest::Client::new(),
+            cached: Arc::default(),
+        }
+    }
+
+    /// The provider configured by `SERVICE_*` settings, if any, at the token
+    /// endpoint of `conf`
+    pub fn from_config(config: &Config, conf: &OidConf) -> anyhow::Result<Option<Self>> {
+        let client_id = match &config.service_client_id {
+            Some(client_id) => client_id,
+            None => return Ok(None),
+        };
+        let secret = config
+            .service_client_secret
+            .as_ref()
+            .context("SERVICE_CLIENT_ID requires SERVICE_CLIENT_SECRET")?;
+        let token_endpoint = conf
+            .token_endpoint
+            .as_ref()
+            .context("no token_endpoint discovered")?;
+        let mut provider = ServiceTokenProvider::new(token_endpoint, client_id, secret);
+        if let Some(scope) = &config.service_scope {
+            provider = provider.scope(scope);
+        }
+        if let Some(audience) = &config.service_audience {
+            provider = provider.audience(audience);
+        }
+        Ok(Some(provider))
+    }
+
+    /// Space separated scopes requested
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scope = Some(scope.into());
+        self
+    }
+
+    /// Audience requested, for authservers supporting the `audience` parameter
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Fetch a new token this long before the cached one expires, 60s by default
+    pub fn early_refresh(mut self, early_refresh: Duration) -> Self {
+        self.early_refresh = early_refresh;
+        self
+    }
+
+    /// A current access token, fetched only when the cached one is due for renewal.
+    /// Concurrent callers wait for a single fetch.
+    pub async fn token(&self) -> anyhow::Result<String> {
+        let mut cached = self.cached.lock().await;
+        if let Some(token) = cached.as_ref().filter(|t| t.renew_at > Instant::now()) {
+            return Ok(token.access_token.clone());
+        }
+        let token = self.fetch().await?;
+        debug!("fetched service token from {}", self.token_endpoint);
+        let access_token = token.access_token.clone();
+        // Tokens of unknown lifetime are not kept
+        *cached = token.expires_in.map(|expires_in| CachedToken {
+            access_token: token.access_token,
+            renew_at: Instant::now()
+                + Duration::from_secs(expires_in).saturating_sub(self.early_refresh),
+        });
+        Ok(access_token)
+    }
+
+    async fn fetch(&self) -> anyhow::Result<TokenResponse> {
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", &self.client_id),
+            ("client_secret", &self.client_secret),
+        ];
+        if let Some(scope) = &self.scope {
+            form.push(("scope", scope));
+        }
+        if let Some(audience) = &self.audience {
+            form.push(("audience", audience));
+        }
+        Ok(self
+            .client
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("client credentials grant at {}", self.token_endpoint))?
+            .json()
+            .await?)
+    }
+}
+
+/// Trades incoming access tokens for tokens restricted to a downstream audience with
+/// OAuth 2.0 Token Exchange (RFC 8693), keeping each by subject and audience until
+/// shortly before it expires
+#[derive(Clone)]
+pub struct TokenExchange {
+    token_endpoint: String,
+    client_id: String,
+    client_secret: Option<String>,
+    early_refresh: Duration,
+    client: reqwest::Client,
+    cached: Arc<Mutex<HashMap<(String, String), CachedToken>>>,
+}
+
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+impl TokenExchange {
+    pub fn new(token_endpoint: impl Into<String>, client_id: impl Into<String>) -> Self {
+        TokenExchange {
+            token_endpoint: token_endpoint.into(),
+            client_id: client_id.into(),
+            client_secret: None,
+            early_refresh: Duration::from_secs(60),
+            client: reqwest::Client::new(),
+            cached: Arc::default(),
+        }
+    }
+
+    /// Exchanging as the client of `SERVICE_*` settings, if any, at the token endpoint
+    /// of `conf`
+    pub fn from_config(config: &Config, conf: &OidConf) -> anyhow::Result<Option<Self>> {
+        let client_id = match &config.service_client_id {
+            Some(client_id) => client_id,
+            None => return Ok(None),
+        };
+        let token_endpoint = conf
+            .token_endpoint
+            .as_ref()
+            .context("no token_endpoint discovered")?;
+        let mut exchange = TokenExchange::new(token_endpoint, client_id);
+        if let Some(secret) = &config.service_client_secret {
+            exchange = exchange.client_secret(secret);
+        }
+        Ok(Some(exchange))
+    }
+
+    pub fn client_secret(mut self, secret: impl Into<String>) -> Self {
+        self.client_secret = Some(secret.into());
+        self
+    }
+
+    /// Exchange again this long before a cached token expires, 60s by default
+    pub fn early_refresh(mut self, early_refresh: Duration) -> Self {
+        self.early_refresh = early_refresh;
+        self
+    }
+
+    /// A token for `audience` on behalf of `subject_token`, the incoming access token.
+    /// `subject` keys the cache, e.g. the `sub` claim or the `TokenHash`.
+    pub async fn exchange(
+        &self,
+        subject: &str,
+        subject_token: &str,
+        audience: &str,
+    ) -> anyhow::Result<String> {
+        let key = (subject.to_string(), audience.to_string());
+        {
+            let mut cached = self.cached.lock().await;
+            let now = Instant::now();
+            cached.retain(|_, token| token.renew_at > now);
+            if let Some(token) = cached.get(&key) {
+                return Ok(token.access_token.clone());
+            }
+        }
+        let mut form = vec![
+            (
+                "grant_type",
+                "urn:ietf:params:oauth:grant-type:token-exchange",
+            ),
+            ("subject_token", subject_token),
+            ("subject_token_type", ACCESS_TOKEN_TYPE),
+            ("requested_token_type", ACCESS_TOKEN_TYPE),
+            ("audience", audience),
+            ("client_id", &self.client_id),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let token: TokenResponse = self
+            .client
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("token exchange at {}", self.token_endpoint))?
+            .json()
+            .await?;
+        debug!("exchanged a token for audience {}", audience);
+        if let Some(expires_in) = token.expires_in {
+            self.cached.lock().await.insert(
+                key,
+                CachedToken {
+                    access_token: token.access_token.clone(),
+                    renew_at: Instant::now()
+                        + Duration::from_secs(expires_in).saturating_sub(self.early_refresh),
+                },
+            );
+        }
+        Ok(token.access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_rt::test]
+    async fn test_service_token() {
+        let path = "/outbound/token";
+        let mock = mockito::mock("POST", path)
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::UrlEncoded("grant_type".into(), "client_credentials".into()),
+                mockito::Matcher::UrlEncoded("client_id".into(), "svc".into()),
+                mockito::Matcher::UrlEncoded("scope".into(), "read".into()),
+            ]))
+            .with_body(r#"{"access_token": "t1", "token_type": "Bearer", "expires_in": 300}"#)
+            .expect(3)
+            .create();
+        let provider = || {
+            ServiceTokenProvider::new(mockito::server_url() + path, "svc", "secret").scope("read")
+        };
+        let cached = provider();
+        assert_eq!(cached.token().await.unwrap(), "t1");
+        // Clones share the cache
+        assert_eq!(cached.clone().token().await.unwrap(), "t1");
+
+        // Always due for renewal
+        let provider = provider().early_refresh(Duration::from_secs(300));
+        provider.token().await.unwrap();
+        provider.token().await.unwrap();
+        mock.assert();
+    }
+
+    #[actix_rt::test]
+    async fn test_token_exchange() {
+        let path = "/outbound/exchange";
+        let mock = |audience: &str, token: &str| {
+            mockito::mock("POST", path)
+                .match_body(mockito::Matcher::AllOf(vec![
+                    mockito::Matcher::UrlEncoded("subject_token".into(), "incoming".into()),
+                    mockito::Matcher::UrlEncoded(
+                        "subject_token_type".into(),
+                        ACCESS_TOKEN_TYPE.into(),
+                    ),
+                    mockito::Matcher::UrlEncoded("audience".into(), audience.into()),
+                ]))
+                .with_body(format!(
+                    r#"{{"access_token": "{}", "expires_in": 300}}"#,
+                    token
+                ))
+                .expect(1)
+                .create()
+        };
+        let orders = mock("orders", "t-orders");
+        let billing = mock("billing", "t-billing");
+        let exchange = TokenExchange::new(mockito::server_url() + path, "svc");
+
+        for _ in 0..2 {
+            let token = exchange.exchange("alice", "incoming", "orders").await;
+            assert_eq!(token.unwrap(), "t-orders");
+        }
+        let token = exchange.exchange("alice", "incoming", "billing").await;
+        assert_eq!(token.unwrap(), "t-billing");
+        orders.assert();
+        billing.assert();
+    }
+}
@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+/// The last known good discovery result and JWK Set, kept on disk so a restart
+/// during an authserver outage can still validate tokens signed with recent keys
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct WarmStart {
+    pub issuer: String,
+    pub jwks_uri: String,
+    pub jwks: Value,
+}
+
+impl WarmStart {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Write through a temporary file, so a crash never leaves a truncated cache
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_load() {
+        let path = std::env::temp_dir().join(format!("warm-start-{}.json", std::process::id()));
+        let warm = WarmStart {
+            issuer: "me".into(),
+            jwks_uri: "https://example.com/keys".into(),
+            jwks: serde_json::json!({"keys": []}),
+        };
+        warm.save(&path).unwrap();
+        let loaded = WarmStart::load(&path).unwrap();
+        assert_eq!(loaded.issuer, "me");
+        assert_eq!(loaded.jwks, warm.jwks);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(WarmStart::load("/nonexistent/warm-start.json").is_err());
+    }
+}
@@ -0,0 +1,566 @@
+//! Keys in a PKCS#11 token, e.g. an HSM, signing for the `issuer` module and
+//! verifying tokens without the key material ever leaving the hardware
+use crate::jwk::KeyParams;
+use crate::remote::{RemoteFuture, RemoteKey};
+use actix_web::error::BlockingError;
+use actix_web::web;
+use anyhow::{bail, Context};
+use jsonwebtoken::Algorithm;
+use libc::{c_ulong, c_void};
+use ring::digest;
+use std::ffi::CString;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+type Ulong = c_ulong;
+type Rv = Ulong;
+type Unused = Option<unsafe extern "C" fn()>;
+
+const CKR_OK: Rv = 0;
+const CKR_SIGNATURE_INVALID: Rv = 0xc0;
+const CKR_SIGNATURE_LEN_RANGE: Rv = 0xc1;
+const CKR_USER_ALREADY_LOGGED_IN: Rv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: Rv = 0x191;
+const CKF_OS_LOCKING_OK: Ulong = 0x2;
+const CKF_SERIAL_SESSION: Ulong = 0x4;
+const CKU_USER: Ulong = 1;
+const CKA_CLASS: Ulong = 0x0;
+const CKA_LABEL: Ulong = 0x3;
+const CKA_MODULUS: Ulong = 0x120;
+const CKA_PUBLIC_EXPONENT: Ulong = 0x122;
+const CKA_EC_POINT: Ulong = 0x181;
+const CKO_PUBLIC_KEY: Ulong = 2;
+const CKO_PRIVATE_KEY: Ulong = 3;
+const CKO_SECRET_KEY: Ulong = 4;
+const CKM_SHA256_RSA_PKCS: Ulong = 0x40;
+const CKM_SHA384_RSA_PKCS: Ulong = 0x41;
+const CKM_SHA512_RSA_PKCS: Ulong = 0x42;
+const CKM_SHA256_RSA_PKCS_PSS: Ulong = 0x43;
+const CKM_SHA384_RSA_PKCS_PSS: Ulong = 0x44;
+const CKM_SHA512_RSA_PKCS_PSS: Ulong = 0x45;
+const CKM_SHA256: Ulong = 0x250;
+const CKM_SHA256_HMAC: Ulong = 0x251;
+const CKM_SHA384: Ulong = 0x260;
+const CKM_SHA384_HMAC: Ulong = 0x261;
+const CKM_SHA512: Ulong = 0x270;
+const CKM_SHA512_HMAC: Ulong = 0x271;
+const CKM_ECDSA: Ulong = 0x1041;
+const CKG_MGF1_SHA256: Ulong = 0x2;
+const CKG_MGF1_SHA384: Ulong = 0x3;
+const CKG_MGF1_SHA512: Ulong = 0x4;
+
+#[repr(C)]
+struct Attribute {
+    kind: Ulong,
+    value: *mut c_void,
+    len: Ulong,
+}
+
+#[repr(C)]
+struct Mechanism {
+    mechanism: Ulong,
+    parameter: *mut c_void,
+    len: Ulong,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct PssParams {
+    hash: Ulong,
+    mgf: Ulong,
+    salt_len: Ulong,
+}
+
+#[repr(C)]
+struct InitializeArgs {
+    mutex_callbacks: [*mut c_void; 4],
+    flags: Ulong,
+    reserved: *mut c_void,
+}
+
+#[repr(C)]
+struct TokenInfo {
+    label: [u8; 32],
+    manufacturer: [u8; 32],
+    model: [u8; 16],
+    serial_number: [u8; 16],
+    flags: Ulong,
+    /// Session, PIN length and memory limits
+    limits: [Ulong; 10],
+    versions: [u8; 4],
+    utc_time: [u8; 16],
+}
+
+/// The `CK_FUNCTION_LIST` of a module, up to `C_Verify`
+#[repr(C)]
+struct FunctionList {
+    version: [u8; 2],
+    initialize: unsafe extern "C" fn(*mut InitializeArgs) -> Rv,
+    _info: [Unused; 3],
+    get_slot_list: unsafe extern "C" fn(u8, *mut Ulong, *mut Ulong) -> Rv,
+    _slot_info: Unused,
+    get_token_info: unsafe extern "C" fn(Ulong, *mut TokenInfo) -> Rv,
+    _token_admin: [Unused; 5],
+    open_session: unsafe extern "C" fn(Ulong, Ulong, *mut c_void, *mut c_void, *mut Ulong) -> Rv,
+    close_session: unsafe extern "C" fn(Ulong) -> Rv,
+    _session_admin: [Unused; 4],
+    login: unsafe extern "C" fn(Ulong, Ulong, *const u8, Ulong) -> Rv,
+    _objects_admin: [Unused; 5],
+    get_attribute_value: unsafe extern "C" fn(Ulong, Ulong, *mut Attribute, Ulong) -> Rv,
+    _set_attribute_value: Unused,
+    find_objects_init: unsafe extern "C" fn(Ulong, *mut Attribute, Ulong) -> Rv,
+    find_objects: unsafe extern "C" fn(Ulong, *mut Ulong, Ulong, *mut Ulong) -> Rv,
+    find_objects_final: unsafe extern "C" fn(Ulong) -> Rv,
+    _crypt_digest: [Unused; 13],
+    sign_init: unsafe extern "C" fn(Ulong, *mut Mechanism, Ulong) -> Rv,
+    sign: unsafe extern "C" fn(Ulong, *const u8, Ulong, *mut u8, *mut Ulong) -> Rv,
+    _sign_parts: [Unused; 4],
+    verify_init: unsafe extern "C" fn(Ulong, *mut Mechanism, Ulong) -> Rv,
+    verify: unsafe extern "C" fn(Ulong, *const u8, Ulong, *const u8, Ulong) -> Rv,
+}
+
+fn check(rv: Rv, call: &str) -> anyhow::Result<()> {
+    if rv != CKR_OK {
+        bail!("{} failed with CKR {:#x}", call, rv);
+    }
+    Ok(())
+}
+
+/// How the token computes the signatures of an algorithm
+#[derive(Clone, Copy)]
+struct Scheme {
+    mechanism: Ulong,
+    pss: Option<PssParams>,
+    /// The digest signed by `CKM_ECDSA`, computed here
+    prehash: Option<&'static digest::Algorithm>,
+}
+
+fn scheme(alg: Algorithm) -> Scheme {
+    let plain = |mechanism| Scheme {
+        mechanism,
+        pss: None,
+        prehash: None,
+    };
+    let pss = |mechanism, hash, mgf, salt_len| Scheme {
+        pss: Some(PssParams {
+            hash,
+            mgf,
+            salt_len,
+        }),
+        ..plain(mechanism)
+    };
+    let ecdsa = |digest| Scheme {
+        prehash: Some(digest),
+        ..plain(CKM_ECDSA)
+    };
+    match alg {
+        Algorithm::HS256 => plain(CKM_SHA256_HMAC),
+        Algorithm::HS384 => plain(CKM_SHA384_HMAC),
+        Algorithm::HS512 => plain(CKM_SHA512_HMAC),
+        Algorithm::RS256 => plain(CKM_SHA256_RSA_PKCS),
+        Algorithm::RS384 => plain(CKM_SHA384_RSA_PKCS),
+        Algorithm::RS512 => plain(CKM_SHA512_RSA_PKCS),
+        Algorithm::PS256 => pss(CKM_SHA256_RSA_PKCS_PSS, CKM_SHA256, CKG_MGF1_SHA256, 32),
+        Algorithm::PS384 => pss(CKM_SHA384_RSA_PKCS_PSS, CKM_SHA384, CKG_MGF1_SHA384, 48),
+        Algorithm::PS512 => pss(CKM_SHA512_RSA_PKCS_PSS, CKM_SHA512, CKG_MGF1_SHA512, 64),
+        Algorithm::ES256 => ecdsa(&digest::SHA256),
+        Algorithm::ES384 => ecdsa(&digest::SHA384),
+    }
+}
+
+/// A logged in session, with the handles of the key
+struct Session {
+    functions: &'static FunctionList,
+    handle: Ulong,
+    /// The secret key of HMAC, or the private key
+    signing: Option<Ulong>,
+    /// The secret key of HMAC, or the public key
+    verifying: Option<Ulong>,
+}
+
+impl Session {
+    fn find(&self, class: Ulong, label: &str) -> anyhow::Result<Option<Ulong>> {
+        let mut class = class;
+        let mut template = [
+            Attribute {
+                kind: CKA_CLASS,
+                value: &mut class as *mut Ulong as *mut c_void,
+                len: std::mem::size_of::<Ulong>() as Ulong,
+            },
+            Attribute {
+                kind: CKA_LABEL,
+                value: label.as_ptr() as *mut c_void,
+                len: label.len() as Ulong,
+            },
+        ];
+        let mut object = 0;
+        let mut found = 0;
+        // Safety: the template outlives the search, finished before returning
+        unsafe {
+            let f = self.functions;
+            check(
+                (f.find_objects_init)(self.handle, template.as_mut_ptr(), 2),
+                "C_FindObjectsInit",
+            )?;
+            let rv = (f.find_objects)(self.handle, &mut object, 1, &mut found);
+            check((f.find_objects_final)(self.handle), "C_FindObjectsFinal")?;
+            check(rv, "C_FindObjects")?;
+        }
+        Ok(Some(object).filter(|_| found == 1))
+    }
+
+    fn attribute(&self, object: Ulong, kind: Ulong) -> anyhow::Result<Vec<u8>> {
+        let mut attribute = Attribute {
+            kind,
+            value: ptr::null_mut(),
+            len: 0,
+        };
+        // Safety: the first call only sets the length, the second fills a buffer of it
+        unsafe {
+            let f = self.functions;
+            check(
+                (f.get_attribute_value)(self.handle, object, &mut attribute, 1),
+                "C_GetAttributeValue",
+            )?;
+            let mut value = vec![0; attribute.len as usize];
+            attribute.value = value.as_mut_ptr() as *mut c_void;
+            check(
+                (f.get_attribute_value)(self.handle, object, &mut attribute, 1),
+                "C_GetAttributeValue",
+            )?;
+            value.truncate(attribute.len as usize);
+            Ok(value)
+        }
+    }
+
+    fn sign(&self, scheme: Scheme, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let key = self
+            .signing
+            .context("no private or secret key to sign with")?;
+        let mut pss = scheme.pss;
+        let mut mechanism = mechanism(scheme, &mut pss);
+        // Big enough for RSA keys of 8192 bits
+        let mut signature = vec![0; 1024];
+        let mut len = signature.len() as Ulong;
+        // Safety: the mechanism and its parameters outlive the operation
+        unsafe {
+            let f = self.functions;
+            check(
+                (f.sign_init)(self.handle, &mut mechanism, key),
+                "C_SignInit",
+            )?;
+            check(
+                (f.sign)(
+                    self.handle,
+                    data.as_ptr(),
+                    data.len() as Ulong,
+                    signature.as_mut_ptr(),
+                    &mut len,
+                ),
+                "C_Sign",
+            )?;
+        }
+        signature.truncate(len as usize);
+        Ok(signature)
+    }
+
+    fn verify(&self, scheme: Scheme, data: &[u8], signature: &[u8]) -> anyhow::Result<bool> {
+        let key = self
+            .verifying
+            .context("no public or secret key to verify with")?;
+        let mut pss = scheme.pss;
+        let mut mechanism = mechanism(scheme, &mut pss);
+        // Safety: the mechanism and its parameters outlive the operation
+        unsafe {
+            let f = self.functions;
+            check(
+                (f.verify_init)(self.handle, &mut mechanism, key),
+                "C_VerifyInit",
+            )?;
+            match (f.verify)(
+                self.handle,
+                data.as_ptr(),
+                data.len() as Ulong,
+                signature.as_ptr(),
+                signature.len() as Ulong,
+            ) {
+                CKR_OK => Ok(true),
+                CKR_SIGNATURE_INVALID | CKR_SIGNATURE_LEN_RANGE => Ok(false),
+                rv => check(rv, "C_Verify").map(|_| false),
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Safety: the session is not used after this
+        unsafe {
+            (self.functions.close_session)(self.handle);
+        }
+    }
+}
+
+/// The `CK_MECHANISM` of `scheme`, pointing into `pss`
+fn mechanism(scheme: Scheme, pss: &mut Option<PssParams>) -> Mechanism {
+    match pss {
+        Some(params) => Mechanism {
+            mechanism: scheme.mechanism,
+            parameter: params as *mut PssParams as *mut c_void,
+            len: std::mem::size_of::<PssParams>() as Ulong,
+        },
+        None => Mechanism {
+            mechanism: scheme.mechanism,
+            parameter: ptr::null_mut(),
+            len: 0,
+        },
+    }
+}
+
+/// The function list of the module at `path`, which stays loaded
+fn load(path: &str) -> anyhow::Result<&'static FunctionList> {
+    let c_path = CString::new(path)?;
+    let symbol = CString::new("C_GetFunctionList")?;
+    // Safety: C_GetFunctionList has this signature in every PKCS#11 module, and the
+    // list it returns lives as long as the module, which is never unloaded
+    unsafe {
+        let module = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+        if module.is_null() {
+            bail!("cannot load PKCS#11 module {}", path);
+        }
+        let get_function_list = libc::dlsym(module, symbol.as_ptr());
+        if get_function_list.is_null() {
+            bail!("{} is not a PKCS#11 module", path);
+        }
+        let get_function_list: unsafe extern "C" fn(*mut *const FunctionList) -> Rv =
+            std::mem::transmute(get_function_list);
+        let mut functions = ptr::null();
+        check(get_function_list(&mut functions), "C_GetFunctionList")?;
+        let functions = functions
+            .as_ref()
+            .context("C_GetFunctionList returned no functions")?;
+        let mut args = InitializeArgs {
+            mutex_callbacks: [ptr::null_mut(); 4],
+            flags: CKF_OS_LOCKING_OK,
+            reserved: ptr::null_mut(),
+        };
+        match (functions.initialize)(&mut args) {
+            CKR_OK | CKR_CRYPTOKI_ALREADY_INITIALIZED => Ok(functions),
+            rv => check(rv, "C_Initialize").map(|_| functions),
+        }
+    }
+}
+
+/// The slot of the token labelled `label`
+fn slot(functions: &FunctionList, label: &str) -> anyhow::Result<Ulong> {
+    let mut count = 0;
+    // Safety: the slot list is sized by the first call
+    unsafe {
+        check(
+            (functions.get_slot_list)(1, ptr::null_mut(), &mut count),
+            "C_GetSlotList",
+        )?;
+        let mut slots = vec![0; count as usize];
+        check(
+            (functions.get_slot_list)(1, slots.as_mut_ptr(), &mut count),
+            "C_GetSlotList",
+        )?;
+        for slot in slots.into_iter().take(count as usize) {
+            let mut info: TokenInfo = std::mem::zeroed();
+            check(
+                (functions.get_token_info)(slot, &mut info),
+                "C_GetTokenInfo",
+            )?;
+            // Labels are padded with spaces
+            if String::from_utf8_lossy(&info.label).trim_end() == label {
+                return Ok(slot);
+            }
+        }
+    }
+    bail!("no PKCS#11 token labelled {:?}", label)
+}
+
+/// A key of a PKCS#11 token, found by its label. Signing needs the private key of
+/// RS*, PS* and ES*, and verifying the public key, so either may be left out of the
+/// token where it is not used; HMAC needs the secret key.
+pub struct Pkcs11Key {
+    alg: Algorithm,
+    session: Arc<Mutex<Session>>,
+}
+
+impl Pkcs11Key {
+    /// The key labelled `key_label` of the token labelled `token_label`, through the
+    /// `module` of its vendor, e.g. `/usr/lib/softhsm/libsofthsm2.so`, logging in
+    /// with the user `pin`
+    pub fn open(
+        module: &str,
+        token_label: &str,
+        pin: &str,
+        key_label: &str,
+        alg: Algorithm,
+    ) -> anyhow::Result<Self> {
+        let functions = load(module)?;
+        let slot = slot(functions, token_label)?;
+        let mut handle = 0;
+        // Safety: the session handle is written by the module
+        unsafe {
+            check(
+                (functions.open_session)(
+                    slot,
+                    CKF_SERIAL_SESSION,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut handle,
+                ),
+                "C_OpenSession",
+            )?;
+        }
+        let mut session = Session {
+            functions,
+            handle,
+            signing: None,
+            verifying: None,
+        };
+        // Safety: the PIN is only read during the call
+        match unsafe { (functions.login)(handle, CKU_USER, pin.as_ptr(), pin.len() as Ulong) } {
+            CKR_OK | CKR_USER_ALREADY_LOGGED_IN => {}
+            rv => check(rv, "C_Login")?,
+        }
+        match alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                session.signing = session.find(CKO_SECRET_KEY, key_label)?;
+                session.verifying = session.signing;
+            }
+            _ => {
+                session.signing = session.find(CKO_PRIVATE_KEY, key_label)?;
+                session.verifying = session.find(CKO_PUBLIC_KEY, key_label)?;
+            }
+        }
+        if session.signing.is_none() && session.verifying.is_none() {
+            bail!("no key labelled {:?} in token {:?}", key_label, token_label);
+        }
+        Ok(Pkcs11Key {
+            alg,
+            session: Arc::new(Mutex::new(session)),
+        })
+    }
+
+    /// Run `f` on the session in the blocking thread pool, calls into the module
+    /// taking as long as the hardware takes
+    fn run<T, F>(&self, f: F) -> RemoteFuture<'static, T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Session) -> anyhow::Result<T> + Send + 'static,
+    {
+        let session = self.session.clone();
+        Box::pin(async move {
+            web::block(move || f(&session.lock().unwrap()))
+                .await
+                .map_err(|e| match e {
+                    BlockingError::Error(e) => e,
+                    BlockingError::Canceled => anyhow::anyhow!("PKCS#11 call canceled"),
+                })
+        })
+    }
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The JWK parameters of the `CKA_EC_POINT` of a P-256 or P-384 key, DER encoded as
+/// the standard has it or raw as some modules answer
+fn ec_params(point: &[u8], len: usize) -> anyhow::Result<KeyParams> {
+    let point = match point {
+        [0x04, outer, rest @ ..] if rest.len() == 1 + 2 * len && *outer as usize == rest.len() => {
+            rest
+        }
+        point => point,
+    };
+    if point.len() != 1 + 2 * len || point[0] != 4 {
+        bail!("not an uncompressed point");
+    }
+    Ok(KeyParams::Ec {
+        crv: if len == 32 { "P-256" } else { "P-384" }.into(),
+        x: base64url(&point[1..=len]),
+        y: base64url(&point[1 + len..]),
+    })
+}
+
+impl RemoteKey for Pkcs11Key {
+    fn alg(&self) -> Algorithm {
+        self.alg
+    }
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> RemoteFuture<'a, Vec<u8>> {
+        let scheme = scheme(self.alg);
+        let data = match scheme.prehash {
+            Some(digest) => digest::digest(digest, message).as_ref().to_vec(),
+            None => message.to_vec(),
+        };
+        self.run(move |session| session.sign(scheme, &data))
+    }
+
+    fn verify<'a>(&'a self, message: &'a [u8], signature: &'a [u8]) -> RemoteFuture<'a, bool> {
+        let scheme = scheme(self.alg);
+        let data = match scheme.prehash {
+            Some(digest) => digest::digest(digest, message).as_ref().to_vec(),
+            None => message.to_vec(),
+        };
+        let signature = signature.to_vec();
+        self.run(move |session| session.verify(scheme, &data, &signature))
+    }
+
+    fn public_key(&self) -> RemoteFuture<'_, Option<KeyParams>> {
+        let alg = self.alg;
+        self.run(move |session| {
+            let public = match alg {
+                Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => return Ok(None),
+                _ => session.verifying.context("no public key in the token")?,
+            };
+            let unsigned =
+                |int: Vec<u8>| base64url(&int[int.iter().take_while(|b| **b == 0).count()..]);
+            let params = match alg {
+                Algorithm::ES256 => ec_params(&session.attribute(public, CKA_EC_POINT)?, 32)?,
+                Algorithm::ES384 => ec_params(&session.attribute(public, CKA_EC_POINT)?, 48)?,
+                _ => KeyParams::Rsa {
+                    n: unsigned(session.attribute(public, CKA_MODULUS)?),
+                    e: unsigned(session.attribute(public, CKA_PUBLIC_EXPONENT)?),
+                },
+            };
+            Ok(Some(params))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkcs11() {
+        // The list must match the layout of CK_FUNCTION_LIST up to C_Verify
+        assert_eq!(
+            std::mem::size_of::<FunctionList>(),
+            std::mem::size_of::<usize>() * 51
+        );
+        assert_eq!(scheme(Algorithm::PS384).pss.unwrap().salt_len, 48);
+        assert!(scheme(Algorithm::ES256).prehash.is_some());
+
+        let mut point = vec![0x04];
+        point.extend((1..=64).collect::<Vec<u8>>());
+        let raw = ec_params(&point, 32).unwrap();
+        let mut der = vec![0x04, 65];
+        der.extend(&point);
+        assert_eq!(
+            format!("{:?}", ec_params(&der, 32).unwrap()),
+            format!("{:?}", raw)
+        );
+        assert!(ec_params(&point[..40], 32).is_err());
+
+        let err = Pkcs11Key::open("/nonexistent.so", "t", "1234", "k", Algorithm::RS256)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("cannot load"));
+    }
+}
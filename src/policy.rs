@@ -0,0 +1,94 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::pin::Pin;
+
+/// What is sent to the policy engine for a request carrying a valid token
+#[derive(Debug, Serialize)]
+pub struct PolicyInput<'a> {
+    pub claims: &'a Map<String, Value>,
+    pub method: &'a str,
+    pub path: &'a str,
+}
+
+/// An external authorization decision point consulted after token validation
+pub trait PolicyEvaluator: Send + Sync {
+    /// Resolve to `true` if the request is allowed
+    fn evaluate<'a>(
+        &'a self,
+        input: &'a PolicyInput<'a>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>>;
+}
+
+/// Asks an Open Policy Agent server, e.g. `http://localhost:8181/v1/data/http/authz/allow`
+#[cfg(feature = "opa")]
+pub struct OpaEvaluator {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "opa")]
+impl OpaEvaluator {
+    pub fn new(url: impl Into<String>) -> Self {
+        OpaEvaluator {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[cfg(feature = "opa")]
+#[derive(serde::Deserialize)]
+struct OpaResponse {
+    result: Option<bool>,
+}
+
+#[cfg(feature = "opa")]
+impl PolicyEvaluator for OpaEvaluator {
+    fn evaluate<'a>(
+        &'a self,
+        input: &'a PolicyInput<'a>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>> {
+        Box::pin(async move {
+            let resp = self
+                .client
+                .post(&self.url)
+                .json(&serde_json::json!({ "input": input }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<OpaResponse>()
+                .await?;
+            // An undefined decision means the policy did not allow the request
+            Ok(resp.result.unwrap_or(false))
+        })
+    }
+}
+
+#[cfg(all(test, feature = "opa"))]
+mod tests {
+    use super::*;
+    use mockito::Matcher;
+
+    #[actix_rt::test]
+    async fn test_opa() {
+        let path = "/v1/data/http/authz/allow";
+        let mock = mockito::mock("POST", path)
+            .match_body(Matcher::PartialJson(
+                serde_json::json!({"input": {"method": "GET", "path": "/"}}),
+            ))
+            .with_body(r#"{"result": true}"#)
+            .expect(1)
+            .create();
+
+        let opa = OpaEvaluator::new(mockito::server_url() + path);
+        let claims = Map::new();
+        let input = PolicyInput {
+            claims: &claims,
+            method: "GET",
+            path: "/",
+        };
+        assert!(opa.evaluate(&input).await.unwrap());
+        mock.assert();
+    }
+}
@@ -0,0 +1,161 @@
+//! The address of clients behind reverse proxies
+use actix_web::dev::ServiceRequest;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// The header trusted proxies name the client in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientIpHeader {
+    /// `Forwarded: for=...` of RFC 7239
+    Forwarded,
+    /// `X-Forwarded-For`, each proxy appending the address it saw
+    XForwardedFor,
+    /// `X-Real-IP`, a single address set by the proxy next to this service
+    XRealIp,
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forwarded" => Ok(ClientIpHeader::Forwarded),
+            "x-forwarded-for" => Ok(ClientIpHeader::XForwardedFor),
+            "x-real-ip" => Ok(ClientIpHeader::XRealIp),
+            _ => anyhow::bail!("unknown client ip header {:?}", s),
+        }
+    }
+}
+
+/// The proxies in front of this service. Headers naming the client are believed only
+/// from them, so with none, the default, the client is always the peer. Behind a
+/// chain of proxies, the client is the last hop that is not one of them.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+    header: ClientIpHeader,
+}
+
+impl Default for TrustedProxies {
+    fn default() -> Self {
+        TrustedProxies::new(Vec::new())
+    }
+}
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNet>) -> Self {
+        TrustedProxies {
+            networks,
+            header: ClientIpHeader::XForwardedFor,
+        }
+    }
+
+    /// The header naming the client, `X-Forwarded-For` by default
+    pub fn header(mut self, header: ClientIpHeader) -> Self {
+        self.header = header;
+        self
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of the client of `req`. Proxies that mangle the header fall back
+    /// to themselves as the client.
+    pub fn client_ip(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        let values = |name| {
+            req.headers()
+                .get_all(name)
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+        };
+        let hops: Option<Vec<IpAddr>> = match self.header {
+            ClientIpHeader::Forwarded => values("forwarded").map(forwarded_for).collect(),
+            ClientIpHeader::XForwardedFor => values("x-forwarded-for").map(parse_hop).collect(),
+            ClientIpHeader::XRealIp => values("x-real-ip").map(parse_hop).collect(),
+        };
+        let hops = hops.unwrap_or_default();
+        hops.iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or_else(|| hops.first())
+            .copied()
+            .or(Some(peer))
+    }
+}
+
+/// An address, possibly with a port or in brackets
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// The `for` parameter of one `Forwarded` element
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("for") {
+            parse_hop(value)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_client_ip() {
+        let ip = |s: &str| Some(s.parse().unwrap());
+        let peer = |addr: &str| TestRequest::default().peer_addr(addr.parse().unwrap());
+        let proxies = TrustedProxies::new(vec!["192.168.0.0/24".parse().unwrap()]);
+
+        let req = peer("192.168.0.1:1234")
+            .header("x-forwarded-for", "1.1.1.1, 10.0.0.5")
+            .to_srv_request();
+        assert_eq!(proxies.client_ip(&req), ip("10.0.0.5"));
+        let req = peer("192.168.0.1:1234")
+            .header("x-forwarded-for", "10.0.0.5, 192.168.0.2")
+            .to_srv_request();
+        assert_eq!(proxies.client_ip(&req), ip("10.0.0.5"));
+        let req = peer("192.168.0.1:1234")
+            .header("x-forwarded-for", "10.0.0.5, junk")
+            .to_srv_request();
+        assert_eq!(proxies.client_ip(&req), ip("192.168.0.1"));
+        // Untrusted peers cannot claim another address
+        let req = peer("11.0.0.1:1234")
+            .header("x-forwarded-for", "10.0.0.5")
+            .to_srv_request();
+        assert_eq!(proxies.client_ip(&req), ip("11.0.0.1"));
+        assert_eq!(
+            TrustedProxies::default().client_ip(&peer("192.168.0.1:1").to_srv_request()),
+            ip("192.168.0.1")
+        );
+
+        let forwarded = proxies.clone().header(ClientIpHeader::Forwarded);
+        let req = peer("192.168.0.1:1234")
+            .header(
+                "forwarded",
+                r#"for=1.1.1.1;proto=https, For="[2001:db8::1]:4711";by=192.168.0.1"#,
+            )
+            .to_srv_request();
+        assert_eq!(forwarded.client_ip(&req), ip("2001:db8::1"));
+
+        let real_ip = proxies.header("X-Real-IP".parse().unwrap());
+        let req = peer("192.168.0.1:1234")
+            .header("x-real-ip", "10.0.0.7")
+            .to_srv_request();
+        assert_eq!(real_ip.client_ip(&req), ip("10.0.0.7"));
+    }
+}
@@ -0,0 +1,67 @@
+//! Resources exempt from the `JwtAuth` middleware wrapping them
+use actix_web::dev::ServiceRequest;
+use actix_web::{web, Resource};
+
+/// Marks the names of public resources
+const NAME_PREFIX: &str = "rapi:public:";
+
+/// A resource the `JwtAuth` middleware wrapping it lets through without
+/// authenticating, e.g. a login callback inside a protected scope:
+/// `.service(public("/callback").route(web::get().to(callback)))`.
+///
+/// The middleware runs before routing, so it finds these resources by their name in
+/// the resource map, which is taken by the mark and cannot be set otherwise.
+pub fn public(path: &str) -> Resource {
+    web::resource(path).name(&format!("{}{}", NAME_PREFIX, path))
+}
+
+/// Inserted into the extensions of requests to public resources, along with
+/// `Principal::Anonymous`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Public;
+
+/// Whether `req` is routed to a public resource
+pub(crate) fn is_public(req: &ServiceRequest) -> bool {
+    req.resource_map()
+        .match_name(req.path())
+        .is_some_and(|name| name.starts_with(NAME_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtAuth;
+    use crate::keystore::Keys;
+    use actix_web::dev::Service;
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App, HttpRequest};
+    use jsonwebtoken::Validation;
+
+    #[actix_rt::test]
+    async fn test_public() {
+        let auth = JwtAuth::new(Validation::default(), Keys::new());
+        let handler =
+            |req: HttpRequest| async move { format!("{:?}", req.extensions().get::<Public>()) };
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope("/api")
+                    .wrap(auth.middleware())
+                    .service(public("/login/{provider}").route(web::get().to(handler)))
+                    .route("/me", web::get().to(handler)),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/login/github")
+            .header("Authorization", "Bearer junk")
+            .to_request();
+        assert_eq!(test::read_response(&mut app, req).await, "Some(Public)");
+        let req = test::TestRequest::get().uri("/api/me").to_request();
+        let status = match app.call(req).await {
+            Ok(resp) => resp.status(),
+            Err(err) => err.as_response_error().error_response().status(),
+        };
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
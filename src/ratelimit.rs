@@ -0,0 +1,112 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Usage of a quota bucket after counting the current request
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Counter {
+    pub count: u64,
+    pub reset_in: Duration,
+}
+
+/// Where request counts are kept, e.g. in process or in a shared cache
+pub trait CounterStore: Send + Sync {
+    /// Count one request for `key` in the current fixed window of length `window`
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        window: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Counter>> + 'a>>;
+}
+
+/// Fixed window counters held in process memory
+#[derive(Default)]
+pub struct MemoryStore {
+    windows: Mutex<HashMap<String, (Instant, u64)>>,
+}
+
+impl CounterStore for MemoryStore {
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        window: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Counter>> + 'a>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut windows = self.windows.lock().unwrap();
+            windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+            let (start, count) = windows.entry(key.into()).or_insert((now, 0));
+            *count += 1;
+            Ok(Counter {
+                count: *count,
+                reset_in: window - now.duration_since(*start),
+            })
+        })
+    }
+}
+
+/// At most `limit` requests per `window` for each distinct value of `claim`
+#[derive(Clone)]
+pub struct RateLimit {
+    claim: String,
+    limit: u64,
+    window: Duration,
+    store: Arc<dyn CounterStore>,
+}
+
+impl RateLimit {
+    pub fn new(claim: impl Into<String>, limit: u64, window: Duration) -> Self {
+        RateLimit {
+            claim: claim.into(),
+            limit,
+            window,
+            store: Arc::new(MemoryStore::default()),
+        }
+    }
+
+    pub fn store(mut self, store: impl CounterStore + 'static) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Count a request; tokens without the claim share one bucket
+    pub async fn check(&self, claims: &Map<String, Value>) -> anyhow::Result<(bool, Counter)> {
+        let key = match claims.get(&self.claim) {
+            Some(Value::String(s)) => s.clone(),
+            Some(v) => v.to_string(),
+            None => String::new(),
+        };
+        let counter = self.store.increment(&key, self.window).await?;
+        Ok((counter.count <= self.limit, counter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_rate_limit() {
+        let limit = RateLimit::new("sub", 2, Duration::from_secs(60));
+        let alice = json!({"sub": "alice"});
+        let bob = json!({"sub": "bob"});
+        let alice = alice.as_object().unwrap();
+        let bob = bob.as_object().unwrap();
+
+        assert!(limit.check(alice).await.unwrap().0);
+        assert!(limit.check(alice).await.unwrap().0);
+        let (allowed, counter) = limit.check(alice).await.unwrap();
+        assert!(!allowed);
+        assert_eq!(counter.count, 3);
+        assert!(counter.reset_in <= Duration::from_secs(60));
+        assert!(limit.check(bob).await.unwrap().0);
+    }
+}
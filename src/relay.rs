@@ -0,0 +1,149 @@
+//! Forwarding tokens on calls to upstream APIs
+use crate::claims::TokenClaims;
+#[cfg(feature = "reqwest")]
+use crate::outbound::ServiceTokenProvider;
+use actix_web::client::ClientRequest;
+use actix_web::HttpRequest;
+use serde_json::Value;
+use std::fmt;
+
+/// The token a request was accepted with, added to the request extensions by the
+/// validator for relaying it
+#[derive(Clone)]
+pub struct BearerToken(pub(crate) String);
+
+impl BearerToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// Never log the token itself
+impl fmt::Debug for BearerToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BearerToken(..)")
+    }
+}
+
+#[derive(Debug)]
+pub enum RelayError {
+    /// The request was not authenticated by the validator
+    NoToken,
+    /// The incoming token is not meant for the upstream API
+    WrongAudience,
+    ServiceToken(anyhow::Error),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::NoToken => write!(f, "no token to relay"),
+            RelayError::WrongAudience => write!(f, "token not issued for the upstream audience"),
+            RelayError::ServiceToken(e) => write!(f, "service token unavailable: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RelayError {}
+
+/// Picks the token sent upstream: the validated incoming token, or a service token
+/// where there is none or it is not meant for the upstream API
+#[derive(Clone, Default)]
+pub struct TokenRelay {
+    audience: Option<String>,
+    #[cfg(feature = "reqwest")]
+    service_token: Option<ServiceTokenProvider>,
+}
+
+impl TokenRelay {
+    /// Relay only tokens whose `aud` includes `audience`, the upstream API
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Fall back to a token of this service itself instead of failing
+    #[cfg(feature = "reqwest")]
+    pub fn service_token(mut self, provider: ServiceTokenProvider) -> Self {
+        self.service_token = Some(provider);
+        self
+    }
+
+    /// The token to send upstream on behalf of `req`
+    pub async fn token(&self, req: &HttpRequest) -> Result<String, RelayError> {
+        let incoming = self.incoming(req);
+        #[cfg(feature = "reqwest")]
+        if let (Err(_), Some(provider)) = (&incoming, &self.service_token) {
+            return provider.token().await.map_err(RelayError::ServiceToken);
+        }
+        incoming
+    }
+
+    /// `outbound` carrying the token to send upstream on behalf of `req`
+    pub async fn apply(
+        &self,
+        req: &HttpRequest,
+        outbound: ClientRequest,
+    ) -> Result<ClientRequest, RelayError> {
+        Ok(outbound.bearer_auth(self.token(req).await?))
+    }
+
+    fn incoming(&self, req: &HttpRequest) -> Result<String, RelayError> {
+        let extensions = req.extensions();
+        let token = extensions.get::<BearerToken>().ok_or(RelayError::NoToken)?;
+        if let Some(audience) = &self.audience {
+            let aud = extensions
+                .get::<TokenClaims>()
+                .and_then(|claims| claims.get("aud"));
+            let allowed = match aud {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds.iter().any(|aud| aud == audience.as_str()),
+                _ => false,
+            };
+            if !allowed {
+                return Err(RelayError::WrongAudience);
+            }
+        }
+        Ok(token.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::client::Client;
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    fn request(aud: Value) -> HttpRequest {
+        let req = TestRequest::default().to_http_request();
+        let claims = serde_json::json!({ "aud": aud });
+        req.extensions_mut()
+            .insert(TokenClaims(claims.as_object().unwrap().clone()));
+        req.extensions_mut().insert(BearerToken("t0".into()));
+        req
+    }
+
+    #[actix_rt::test]
+    async fn test_relay() {
+        let relay = TokenRelay::default().audience("upstream");
+        let req = request(serde_json::json!(["api", "upstream"]));
+        let outbound = relay
+            .apply(&req, Client::new().get("http://upstream.example.com/"))
+            .await
+            .unwrap();
+        assert_eq!(
+            outbound.headers().get(header::AUTHORIZATION).unwrap(),
+            "Bearer t0"
+        );
+
+        let req = request(serde_json::json!("api"));
+        assert!(matches!(
+            relay.token(&req).await,
+            Err(RelayError::WrongAudience)
+        ));
+        assert_eq!(TokenRelay::default().token(&req).await.unwrap(), "t0");
+        let req = TestRequest::default().to_http_request();
+        assert!(matches!(relay.token(&req).await, Err(RelayError::NoToken)));
+    }
+}
@@ -0,0 +1,137 @@
+//! Reloading on SIGHUP, the way ops tooling tells services their credentials were
+//! rotated
+use crate::config::{Config, ConfigLoader};
+use crate::keystore::RefreshHandle;
+use crate::report::{report, Component};
+use crate::shutdown::Shutdown;
+use actix_rt::signal::unix::{signal, SignalKind};
+use log::{info, warn};
+use std::path::PathBuf;
+
+type Callback = Box<dyn Fn(&Config)>;
+
+/// What to do on SIGHUP: read the configuration again, hand it to the callbacks and
+/// refresh the keys. A configuration that no longer reads is logged and skipped,
+/// leaving the keys to refresh anyway.
+#[derive(Default)]
+pub struct Reload {
+    refresh: Option<RefreshHandle>,
+    env_file: Option<PathBuf>,
+    callbacks: Vec<Callback>,
+}
+
+impl Reload {
+    pub fn new() -> Self {
+        Reload::default()
+    }
+
+    /// Refresh the keys through `handle`, e.g. `JwtAuth::refresh_handle`
+    pub fn refresh(mut self, handle: RefreshHandle) -> Self {
+        self.refresh = Some(handle);
+        self
+    }
+
+    /// Read the variables of `path` over those of the process, which hold the ones
+    /// loaded at startup; `.env` by default
+    pub fn env_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.env_file = Some(path.into());
+        self
+    }
+
+    /// Call `callback` with every configuration read, its `secret:` settings not
+    /// yet resolved
+    pub fn on_config(mut self, callback: impl Fn(&Config) + 'static) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Read the configuration and refresh the keys now, as on a signal
+    pub fn reload(&self) -> anyhow::Result<Config> {
+        if let Some(handle) = &self.refresh {
+            if !handle.refresh_now() {
+                warn!("the key refresh task is gone");
+            }
+        }
+        // Deprecated, yet the only way to read a file without changing the environment
+        #[allow(deprecated)]
+        let file = match &self.env_file {
+            Some(path) => dotenv::from_path_iter(path),
+            None => dotenv::dotenv_iter(),
+        };
+        let mut vars: Vec<(String, String)> = std::env::vars().collect();
+        // Without a file, the process variables are all there is
+        if let Ok(file) = file {
+            vars.extend(file.collect::<Result<Vec<_>, _>>()?);
+        }
+        // The file of `CONFIG_FILE` lies beneath the variables, as at startup
+        let mut loader = ConfigLoader::new();
+        if let Some((_, path)) = vars.iter().rev().find(|(name, _)| name == "CONFIG_FILE") {
+            loader = loader.file(path)?;
+        }
+        let config = loader.vars(vars).load()?;
+        for callback in &self.callbacks {
+            callback(&config);
+        }
+        Ok(config)
+    }
+
+    /// Reload on every SIGHUP until `shutdown` stops
+    pub fn spawn(self, shutdown: &Shutdown) -> std::io::Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        shutdown.spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("SIGHUP, reloading");
+                if let Err(e) = self.reload() {
+                    warn!("reloading the configuration failed: {:#}", e);
+                    report(Component::Config, &e);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::{JwksStore, Refresher};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_sighup() {
+        let path = std::env::temp_dir().join(format!("reload-{}.env", std::process::id()));
+        std::fs::write(&path, "AUTHSERVER=https://rotated\nAUDIENCE=api\n").unwrap();
+        let keys = mockito::mock("GET", "/reload/keys")
+            .with_body(r#"{"keys": []}"#)
+            .expect(1)
+            .create();
+        let refresher =
+            Refresher::new(mockito::server_url() + "/reload/keys", JwksStore::default())
+                .interval(Duration::from_secs(3600));
+        let handle = refresher.handle();
+        refresher.spawn();
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let authservers = seen.clone();
+        let shutdown = Shutdown::new();
+        Reload::new()
+            .refresh(handle)
+            .env_file(&path)
+            .on_config(move |config| authservers.borrow_mut().push(config.authserver.clone()))
+            .spawn(&shutdown)
+            .unwrap();
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+
+        assert_eq!(*seen.borrow(), ["https://rotated"]);
+        keys.assert();
+        shutdown.stop().await;
+        std::fs::remove_file(path).unwrap();
+    }
+}
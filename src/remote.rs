@@ -0,0 +1,24 @@
+//! Keys held by a key management service, signing and verifying without their key
+//! material ever reaching this process
+use crate::jwk::KeyParams;
+use jsonwebtoken::Algorithm;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type RemoteFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + 'a>>;
+
+/// One key of a key management service, e.g. `kms::KmsKey`. Signatures are those of
+/// JWS, e.g. `r || s` for ECDSA.
+pub trait RemoteKey: Send + Sync {
+    /// The one algorithm the key signs with
+    fn alg(&self) -> Algorithm;
+
+    fn sign<'a>(&'a self, message: &'a [u8]) -> RemoteFuture<'a, Vec<u8>>;
+
+    /// Whether `signature` of `message` is valid, errors meaning the service could
+    /// not tell
+    fn verify<'a>(&'a self, message: &'a [u8], signature: &'a [u8]) -> RemoteFuture<'a, bool>;
+
+    /// The public key, `None` for HMAC keys
+    fn public_key(&self) -> RemoteFuture<'_, Option<KeyParams>>;
+}
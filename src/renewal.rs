@@ -0,0 +1,172 @@
+use crate::claims::TokenClaims;
+use crate::config::Config;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, Ready};
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Middleware telling clients their token expires soon, so they can renew it before
+/// getting 401s. Wrap it around the `JwtAuth` middleware: it reads the claims the
+/// validator leaves on the request and adds the seconds left as a response header.
+#[derive(Clone)]
+pub struct RenewalHint {
+    threshold: Duration,
+    header: HeaderName,
+}
+
+impl RenewalHint {
+    /// Hint at tokens expiring within `threshold`, in `X-Token-Expires-In`
+    pub fn new(threshold: Duration) -> Self {
+        RenewalHint {
+            threshold,
+            header: HeaderName::from_static("x-token-expires-in"),
+        }
+    }
+
+    pub fn header(mut self, header: HeaderName) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// The hint configured by `TOKEN_RENEWAL_HINT_*` settings, if any
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let secs = match config.token_renewal_hint_secs {
+            Some(secs) => secs,
+            None => return Ok(None),
+        };
+        let mut hint = RenewalHint::new(Duration::from_secs(secs));
+        if let Some(name) = &config.token_renewal_hint_header {
+            hint = hint.header(HeaderName::from_bytes(name.as_bytes())?);
+        }
+        Ok(Some(hint))
+    }
+
+    /// Seconds until `exp` if they are few enough to hint at
+    fn expires_in(&self, exp: u64) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let left = exp.saturating_sub(now);
+        Some(left).filter(|left| *left <= self.threshold.as_secs())
+    }
+}
+
+impl<S, B> Transform<S> for RenewalHint
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RenewalHintMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RenewalHintMiddleware {
+            service,
+            hint: self.clone(),
+        })
+    }
+}
+
+pub struct RenewalHintMiddleware<S> {
+    service: S,
+    hint: RenewalHint,
+}
+
+impl<S, B> Service for RenewalHintMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let hint = self.hint.clone();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let expires_in = res
+                .request()
+                .extensions()
+                .get::<TokenClaims>()
+                .and_then(|claims| claims.get("exp"))
+                .and_then(Value::as_u64)
+                .and_then(|exp| hint.expires_in(exp));
+            if let Some(expires_in) = expires_in {
+                res.headers_mut()
+                    .insert(hint.header, HeaderValue::from(expires_in));
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_renewal_hint() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RenewalHint::new(Duration::from_secs(60))
+                        .header(HeaderName::from_static("x-renew")),
+                )
+                .route(
+                    "/{exp}",
+                    web::get().to(|req: HttpRequest, exp: web::Path<u64>| {
+                        let claims = json!({ "exp": *exp });
+                        req.extensions_mut()
+                            .insert(TokenClaims(claims.as_object().unwrap().clone()));
+                        async { "" }
+                    }),
+                ),
+        )
+        .await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}", now + 30))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let left: u64 = resp
+            .headers()
+            .get("x-renew")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((29..=30).contains(&left));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}", now + 3600))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.headers().get("x-renew").is_none());
+    }
+}
@@ -0,0 +1,197 @@
+//! Reporting operational failures of the auth subsystem, e.g. to Sentry. Rejected
+//! tokens are the clients' problem and never reported.
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// The part of the subsystem that failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Component {
+    Discovery,
+    Jwks,
+    /// Saving keys for warm starts
+    Persistence,
+    ApiKeys,
+    Credentials,
+    Revocation,
+    Groups,
+    Sessions,
+    Policy,
+    RateLimit,
+    TokenCache,
+    Secrets,
+    /// Reading the configuration again on SIGHUP
+    Config,
+}
+
+impl fmt::Display for Component {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Component::Discovery => "discovery",
+            Component::Jwks => "jwks",
+            Component::Persistence => "persistence",
+            Component::ApiKeys => "api_keys",
+            Component::Credentials => "credentials",
+            Component::Revocation => "revocation",
+            Component::Groups => "groups",
+            Component::Sessions => "sessions",
+            Component::Policy => "policy",
+            Component::RateLimit => "rate_limit",
+            Component::TokenCache => "token_cache",
+            Component::Secrets => "secrets",
+            Component::Config => "config",
+        })
+    }
+}
+
+/// An operational failure, already logged as a warning
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub component: Component,
+    pub message: String,
+}
+
+/// Where failures are reported, in addition to the log. Called on the request path,
+/// so implementations must not block.
+pub trait ErrorReporter: Send + Sync {
+    fn report(&self, failure: &Failure);
+}
+
+lazy_static! {
+    static ref REPORTER: RwLock<Option<Arc<dyn ErrorReporter>>> = RwLock::new(None);
+}
+
+/// Report failures of every `JwtAuth` and `Refresher` in the process to `reporter`
+pub fn set_reporter(reporter: impl ErrorReporter + 'static) {
+    *REPORTER.write().unwrap() = Some(Arc::new(reporter));
+}
+
+pub(crate) fn report(component: Component, error: impl fmt::Display) {
+    let reporter = REPORTER.read().unwrap().clone();
+    if let Some(reporter) = reporter {
+        reporter.report(&Failure {
+            component,
+            message: format!("{:#}", error),
+        });
+    }
+}
+
+/// Sends failures to Sentry as error events, tagged with the component
+#[cfg(feature = "sentry")]
+#[derive(Clone)]
+pub struct SentryReporter {
+    store_url: String,
+    auth: String,
+    environment: Option<String>,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "sentry")]
+impl SentryReporter {
+    /// Report to the project of `dsn`, `https://<key>@<host>/<project id>`
+    pub fn new(dsn: &str) -> anyhow::Result<Self> {
+        let dsn = url::Url::parse(dsn)?;
+        let project = dsn
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|project| !project.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("no project id in the DSN"))?;
+        if dsn.username().is_empty() {
+            anyhow::bail!("no public key in the DSN");
+        }
+        let mut store_url = dsn.clone();
+        store_url.set_username("").ok();
+        store_url.set_password(None).ok();
+        store_url.set_path(&format!("/api/{}/store/", project));
+        Ok(SentryReporter {
+            store_url: store_url.into(),
+            auth: format!(
+                "Sentry sentry_version=7, sentry_client=rapi/{}, sentry_key={}",
+                env!("CARGO_PKG_VERSION"),
+                dsn.username()
+            ),
+            environment: None,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    pub fn environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    fn event(failure: &Failure, environment: Option<&str>) -> serde_json::Value {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        serde_json::json!({
+            "event_id": format!("{:032x}", rand::random::<u128>()),
+            "timestamp": timestamp,
+            "level": "error",
+            "logger": "rapi",
+            "platform": "other",
+            "environment": environment,
+            "message": {"formatted": failure.message},
+            "tags": {"component": failure.component.to_string()},
+        })
+    }
+}
+
+#[cfg(feature = "sentry")]
+impl ErrorReporter for SentryReporter {
+    fn report(&self, failure: &Failure) {
+        let request = self
+            .client
+            .post(&self.store_url)
+            .header("X-Sentry-Auth", &self.auth)
+            .json(&Self::event(failure, self.environment.as_deref()));
+        actix_rt::spawn(async move {
+            let sent = match request.send().await {
+                Ok(resp) => resp.error_for_status().map(|_| ()),
+                Err(e) => Err(e),
+            };
+            // Not reported again, that could loop
+            if let Err(e) = sent {
+                log::debug!("reporting to Sentry failed: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<Failure>>>);
+
+    impl ErrorReporter for Recorder {
+        fn report(&self, failure: &Failure) {
+            self.0.lock().unwrap().push(failure.clone());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_report() {
+        let recorder = Recorder::default();
+        set_reporter(recorder.clone());
+        report(Component::RateLimit, anyhow::anyhow!("redis down"));
+        let failures = recorder.0.lock().unwrap();
+        let failure = failures
+            .iter()
+            .find(|failure| failure.component == Component::RateLimit)
+            .unwrap();
+        assert_eq!(failure.message, "redis down");
+
+        #[cfg(feature = "sentry")]
+        {
+            let sentry = SentryReporter::new("https://abc@sentry.example.com/42").unwrap();
+            assert_eq!(sentry.store_url, "https://sentry.example.com/api/42/store/");
+            assert!(sentry.auth.ends_with("sentry_key=abc"));
+            let event = SentryReporter::event(failure, Some("prod"));
+            assert_eq!(event["tags"]["component"], "rate_limit");
+            assert_eq!(event["event_id"].as_str().unwrap().len(), 32);
+            assert!(SentryReporter::new("https://sentry.example.com/42").is_err());
+        }
+    }
+}
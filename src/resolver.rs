@@ -0,0 +1,27 @@
+use jsonwebtoken::{DecodingKey, Header};
+use serde_json::{Map, Value};
+
+/// Picks the keys verifying a token, replacing the lookup by kid.
+///
+/// `claims` are decoded but not yet verified, so they may only guide the choice,
+/// e.g. by `iss`. Returning `None` rejects the token as having an unknown key.
+pub trait KeyResolver: Send + Sync {
+    fn resolve(
+        &self,
+        header: &Header,
+        claims: &Map<String, Value>,
+    ) -> Option<Vec<DecodingKey<'static>>>;
+}
+
+impl<F> KeyResolver for F
+where
+    F: Fn(&Header, &Map<String, Value>) -> Option<Vec<DecodingKey<'static>>> + Send + Sync,
+{
+    fn resolve(
+        &self,
+        header: &Header,
+        claims: &Map<String, Value>,
+    ) -> Option<Vec<DecodingKey<'static>>> {
+        self(header, claims)
+    }
+}
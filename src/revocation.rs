@@ -0,0 +1,166 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Where revocations are kept, e.g. in process or in a shared cache so that every
+/// instance sees them
+pub trait RevocationStore: Send + Sync {
+    /// Record `key` as revoked at `at`, in seconds since the epoch, for `ttl`
+    fn revoke<'a>(
+        &'a self,
+        key: &'a str,
+        at: u64,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>;
+
+    /// When `key` was last revoked, if it still is
+    fn revoked_at<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<u64>>> + 'a>>;
+}
+
+/// Revocations held in process memory
+#[derive(Default)]
+pub struct MemoryRevocations {
+    revoked: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl RevocationStore for MemoryRevocations {
+    fn revoke<'a>(
+        &'a self,
+        key: &'a str,
+        at: u64,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut revoked = self.revoked.lock().unwrap();
+            revoked.retain(|_, (_, until)| *until > now);
+            revoked.insert(key.into(), (at, now + ttl));
+            Ok(())
+        })
+    }
+
+    fn revoked_at<'a>(
+        &'a self,
+        key: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<u64>>> + 'a>> {
+        Box::pin(async move {
+            let revoked = self.revoked.lock().unwrap();
+            Ok(revoked
+                .get(key)
+                .filter(|(_, until)| *until > Instant::now())
+                .map(|(at, _)| *at))
+        })
+    }
+}
+
+/// Sessions (`sid`) and subjects (`sub`) whose tokens issued so far are refused,
+/// e.g. after a back-channel logout
+#[derive(Clone)]
+pub struct Revocations {
+    ttl: Duration,
+    store: Arc<dyn RevocationStore>,
+}
+
+impl Default for Revocations {
+    fn default() -> Self {
+        Revocations {
+            ttl: Duration::from_secs(24 * 3600),
+            store: Arc::new(MemoryRevocations::default()),
+        }
+    }
+}
+
+impl Revocations {
+    pub fn store(mut self, store: impl RevocationStore + 'static) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// How long revocations are remembered, at least the lifetime of the tokens
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Refuse tokens of session `sid` issued until now
+    pub async fn revoke_session(&self, sid: &str) -> anyhow::Result<()> {
+        self.store
+            .revoke(&format!("sid:{}", sid), now(), self.ttl)
+            .await
+    }
+
+    /// Refuse tokens of subject `sub` issued until now, in every session
+    pub async fn revoke_subject(&self, sub: &str) -> anyhow::Result<()> {
+        self.store
+            .revoke(&format!("sub:{}", sub), now(), self.ttl)
+            .await
+    }
+
+    /// Refuse the token with this `jti`
+    pub async fn revoke_token(&self, jti: &str) -> anyhow::Result<()> {
+        self.store
+            .revoke(&format!("jti:{}", jti), now(), self.ttl)
+            .await
+    }
+
+    /// Whether the token, session or subject of `claims` was revoked after the token
+    /// was issued. Tokens without `iat` count as issued before any revocation.
+    pub async fn is_revoked(&self, claims: &Map<String, Value>) -> anyhow::Result<bool> {
+        let iat = claims.get("iat").and_then(Value::as_u64).unwrap_or(0);
+        for claim in &["jti", "sid", "sub"] {
+            if let Some(value) = claims.get(*claim).and_then(Value::as_str) {
+                let key = format!("{}:{}", claim, value);
+                if matches!(self.store.revoked_at(&key).await?, Some(at) if at >= iat) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_revocations() {
+        let revocations = Revocations::default();
+        let old = json!({"sub": "alice", "sid": "1", "iat": 1});
+        let fresh = json!({"sub": "alice", "sid": "2", "iat": now() + 10});
+        let bob = json!({"sub": "bob", "sid": "3"});
+        let old = old.as_object().unwrap();
+        let fresh = fresh.as_object().unwrap();
+        let bob = bob.as_object().unwrap();
+
+        revocations.revoke_session("1").await.unwrap();
+        assert!(revocations.is_revoked(old).await.unwrap());
+        assert!(!revocations.is_revoked(fresh).await.unwrap());
+        assert!(!revocations.is_revoked(bob).await.unwrap());
+
+        revocations.revoke_subject("bob").await.unwrap();
+        assert!(revocations.is_revoked(bob).await.unwrap());
+
+        let carol = json!({"sub": "carol", "jti": "t1"});
+        let carol = carol.as_object().unwrap();
+        revocations.revoke_token("t1").await.unwrap();
+        assert!(revocations.is_revoked(carol).await.unwrap());
+
+        let revocations = Revocations::default().ttl(Duration::from_secs(0));
+        revocations.revoke_subject("bob").await.unwrap();
+        assert!(!revocations.is_revoked(bob).await.unwrap());
+    }
+}
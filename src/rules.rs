@@ -0,0 +1,162 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+
+/// A simple requirement on a single claim, checked after the token is validated
+#[derive(Clone, Debug)]
+pub struct ClaimRule {
+    claim: String,
+    check: Check,
+}
+
+#[derive(Clone, Debug)]
+enum Check {
+    Present,
+    Equals(Value),
+    Contains(String),
+    Matches(Regex),
+}
+
+impl ClaimRule {
+    /// The claim must be present, with any value but null
+    pub fn present(claim: impl Into<String>) -> Self {
+        ClaimRule {
+            claim: claim.into(),
+            check: Check::Present,
+        }
+    }
+
+    /// The claim must be present and equal to `value`
+    pub fn equals(claim: impl Into<String>, value: impl Into<Value>) -> Self {
+        ClaimRule {
+            claim: claim.into(),
+            check: Check::Equals(value.into()),
+        }
+    }
+
+    /// The claim must be an array containing `value`, or a space separated string with it
+    pub fn contains(claim: impl Into<String>, value: impl Into<String>) -> Self {
+        ClaimRule {
+            claim: claim.into(),
+            check: Check::Contains(value.into()),
+        }
+    }
+
+    /// The claim must be a string matching the regular expression `pattern`
+    pub fn matches(claim: impl Into<String>, pattern: &str) -> Result<Self, regex::Error> {
+        Ok(ClaimRule {
+            claim: claim.into(),
+            check: Check::Matches(Regex::new(pattern)?),
+        })
+    }
+
+    pub fn claim(&self) -> &str {
+        &self.claim
+    }
+
+    pub fn check(&self, claims: &Map<String, Value>) -> bool {
+        let value = match claims.get(&self.claim) {
+            Some(value) => value,
+            None => return false,
+        };
+        match (&self.check, value) {
+            (Check::Present, v) => !v.is_null(),
+            (Check::Equals(expected), v) => v == expected,
+            (Check::Contains(item), Value::Array(items)) => {
+                items.iter().any(|v| v.as_str() == Some(item))
+            }
+            (Check::Contains(item), Value::String(s)) => s.split(' ').any(|v| v == item),
+            (Check::Matches(re), Value::String(s)) => re.is_match(s),
+            _ => false,
+        }
+    }
+}
+
+/// Claim rules kept next to a claims struct, usually derived with
+/// `#[derive(ValidateClaims)]` and applied with `JwtAuth::validate_claims`:
+///
+/// ```ignore
+/// #[derive(Deserialize, ValidateClaims)]
+/// struct Claims {
+///     #[claim(required, equals = "acme")]
+///     tenant: String,
+///     #[claim(name = "scp", contains = "orders:read")]
+///     scopes: Vec<String>,
+/// }
+/// ```
+pub trait ClaimsValidator {
+    fn claim_rules() -> Vec<ClaimRule>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_rules() {
+        let claims = json!({
+            "tenant": "acme",
+            "groups": ["eng", "ops"],
+            "scope": "read write",
+            "email": "a@example.com",
+        });
+        let claims = claims.as_object().unwrap();
+
+        assert!(ClaimRule::equals("tenant", "acme").check(claims));
+        assert!(!ClaimRule::equals("tenant", "globex").check(claims));
+        assert!(!ClaimRule::equals("missing", "acme").check(claims));
+        assert!(ClaimRule::contains("groups", "eng").check(claims));
+        assert!(!ClaimRule::contains("groups", "sales").check(claims));
+        assert!(ClaimRule::contains("scope", "write").check(claims));
+        assert!(ClaimRule::matches("email", r".*@example\.com$")
+            .unwrap()
+            .check(claims));
+        assert!(!ClaimRule::matches("tenant", r"^g").unwrap().check(claims));
+        assert!(!ClaimRule::matches("groups", r"eng").unwrap().check(claims));
+        assert!(ClaimRule::matches("email", r"(").is_err());
+        assert!(ClaimRule::present("groups").check(claims));
+        assert!(!ClaimRule::present("missing").check(claims));
+
+        #[cfg(feature = "derive")]
+        {
+            #[derive(crate::ValidateClaims)]
+            #[allow(dead_code)]
+            struct Claims {
+                #[claim(required, equals = "acme")]
+                tenant: String,
+                #[claim(name = "scope", contains = "write")]
+                scopes: String,
+                #[claim(matches = r"@example\.com$")]
+                email: Option<String>,
+                sub: Option<String>,
+            }
+            let rules = Claims::claim_rules();
+            assert_eq!(rules.len(), 4);
+            assert!(rules.iter().all(|rule| rule.check(claims)));
+            let claims = json!({"tenant": "acme", "scope": "read"});
+            let failed: Vec<&str> = rules
+                .iter()
+                .filter(|rule| !rule.check(claims.as_object().unwrap()))
+                .map(ClaimRule::claim)
+                .collect();
+            assert_eq!(failed, vec!["scope", "email"]);
+
+            #[derive(crate::ValidateClaims)]
+            #[allow(dead_code)]
+            struct Versioned {
+                #[claim(equals = 2)]
+                ver: u64,
+                #[claim(name = "email_verified", equals = true)]
+                verified: bool,
+            }
+            let rules = Versioned::claim_rules();
+            let check = |claims: Value| {
+                let claims = claims.as_object().unwrap().clone();
+                rules.iter().all(|rule| rule.check(&claims))
+            };
+            assert!(check(json!({"ver": 2, "email_verified": true})));
+            assert!(!check(json!({"ver": 1, "email_verified": true})));
+            assert!(!check(json!({"ver": 2, "email_verified": "true"})));
+        }
+    }
+}
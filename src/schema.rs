@@ -0,0 +1,64 @@
+use crate::error::SchemaViolation;
+use jsonschema::JSONSchema;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// A compiled JSON Schema the claims of every accepted token must satisfy
+#[derive(Clone)]
+pub struct ClaimsSchema(Arc<JSONSchema>);
+
+impl ClaimsSchema {
+    pub fn new(schema: &Value) -> anyhow::Result<Self> {
+        let compiled =
+            JSONSchema::compile(schema).map_err(|e| anyhow::anyhow!("invalid schema: {}", e))?;
+        Ok(ClaimsSchema(Arc::new(compiled)))
+    }
+
+    pub fn from_file(path: &str) -> anyhow::Result<Self> {
+        let schema = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Self::new(&schema)
+    }
+
+    pub fn validate(&self, claims: &Value) -> Result<(), Vec<SchemaViolation>> {
+        self.0.validate(claims).map_err(|errors| {
+            errors
+                .map(|e| SchemaViolation {
+                    path: e.instance_path.to_string(),
+                    message: e.to_string(),
+                })
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_schema() {
+        let schema = ClaimsSchema::new(&json!({
+            "type": "object",
+            "required": ["tenant"],
+            "properties": {
+                "tenant": { "enum": ["acme", "globex"] },
+                "email": { "type": "string", "pattern": "@example\\.com$" }
+            }
+        }))
+        .unwrap();
+
+        assert!(schema
+            .validate(&json!({"tenant": "acme", "email": "a@example.com"}))
+            .is_ok());
+
+        let reasons = schema
+            .validate(&json!({"tenant": "initech", "email": 1}))
+            .unwrap_err();
+        assert_eq!(reasons.len(), 2);
+        assert!(reasons.iter().any(|r| r.path == "/tenant"));
+        assert!(reasons.iter().any(|r| r.path == "/email"));
+
+        assert!(ClaimsSchema::new(&json!({"type": 1})).is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! The scopes granted to a token
+use crate::claims::TokenClaims;
+use crate::error::AuthError;
+use actix_web::dev::{Extensions, Payload};
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// One scope, e.g. `orders:read`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Scope(pub String);
+
+impl Scope {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(scope: &str) -> Self {
+        Scope(scope.into())
+    }
+}
+
+/// How a claim lists scopes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeFormat {
+    /// A space separated string, as `scope` of RFC 8693
+    SpaceSeparated,
+    /// An array of strings, e.g. `permissions` of Auth0
+    Array,
+    /// Either of the two
+    Any,
+}
+
+impl FromStr for ScopeFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "space" | "space-separated" => Ok(ScopeFormat::SpaceSeparated),
+            "array" => Ok(ScopeFormat::Array),
+            "any" => Ok(ScopeFormat::Any),
+            _ => anyhow::bail!("unknown scope format {:?}", s),
+        }
+    }
+}
+
+/// The claims scopes are read from, by default `scope` and `scp` in any format, to
+/// cover most identity providers
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScopeClaims {
+    claims: Vec<(String, ScopeFormat)>,
+}
+
+impl Default for ScopeClaims {
+    fn default() -> Self {
+        ScopeClaims::new("scope", ScopeFormat::Any).or("scp", ScopeFormat::Any)
+    }
+}
+
+impl ScopeClaims {
+    /// Scopes only from `claim`
+    pub fn new(claim: impl Into<String>, format: ScopeFormat) -> Self {
+        ScopeClaims {
+            claims: vec![(claim.into(), format)],
+        }
+    }
+
+    /// Scopes from `claim` too
+    pub fn or(mut self, claim: impl Into<String>, format: ScopeFormat) -> Self {
+        self.claims.push((claim.into(), format));
+        self
+    }
+
+    /// The scopes in `claims`, skipping claims not in their format
+    pub fn scopes(&self, claims: &Map<String, Value>) -> Scopes {
+        let mut scopes = HashSet::new();
+        for (claim, format) in &self.claims {
+            match (claims.get(claim), format) {
+                (Some(Value::String(s)), ScopeFormat::SpaceSeparated | ScopeFormat::Any) => {
+                    scopes.extend(s.split_whitespace().map(Scope::from))
+                }
+                (Some(Value::Array(items)), ScopeFormat::Array | ScopeFormat::Any) => {
+                    scopes.extend(items.iter().filter_map(Value::as_str).map(Scope::from))
+                }
+                _ => {}
+            }
+        }
+        Scopes(scopes)
+    }
+}
+
+/// The scopes of the request's token, from the `ScopeClaims` of the `JwtAuth`.
+/// Extracting them from a request without claims fails with 401.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scopes(pub HashSet<Scope>);
+
+impl Scopes {
+    /// The scopes in the default `ScopeClaims`
+    pub fn from_claims(claims: &Map<String, Value>) -> Self {
+        ScopeClaims::default().scopes(claims)
+    }
+
+    /// The scopes the middleware or a `JwtAuth` in the `AuthChain` found for
+    /// `extensions`, or else those in the default claims, e.g. of an API key
+    /// accepted by the chain
+    pub(crate) fn of(extensions: &Extensions) -> Option<Self> {
+        extensions.get::<Scopes>().cloned().or_else(|| {
+            extensions
+                .get::<TokenClaims>()
+                .map(|claims| Scopes::from_claims(&claims.0))
+        })
+    }
+
+    pub fn contains(&self, scope: &str) -> bool {
+        self.0.contains(&Scope::from(scope))
+    }
+
+    /// Fail with 403, naming the missing scopes, unless every one of `scopes` is
+    /// granted, e.g. `scopes.require(["orders:read"])?` in a handler
+    pub fn require<'a>(&self, scopes: impl IntoIterator<Item = &'a str>) -> Result<(), AuthError> {
+        let missing: Vec<&str> = scopes
+            .into_iter()
+            .filter(|scope| !self.contains(scope))
+            .collect();
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientScope(missing.join(" ")))
+        }
+    }
+}
+
+impl FromRequest for Scopes {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Scopes::of(&req.extensions()).ok_or_else(|| AuthError::MissingToken.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::TestRequest;
+    use actix_web::ResponseError;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_scopes() {
+        let req = TestRequest::default().to_http_request();
+        assert!(Scopes::extract(&req).await.is_err());
+
+        let claims = json!({"scope": "orders:read  orders:write", "scp": ["admin"]});
+        req.extensions_mut()
+            .insert(TokenClaims(claims.as_object().unwrap().clone()));
+        let scopes = Scopes::extract(&req).await.unwrap();
+        assert_eq!(scopes.0.len(), 3);
+        assert!(scopes.require(vec!["orders:read", "admin"]).is_ok());
+
+        let err = scopes
+            .require(vec!["orders:read", "orders:delete", "billing"])
+            .unwrap_err();
+        let resp = err.error_response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            resp.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            r#"Bearer scope="orders:delete billing", error="insufficient_scope""#
+        );
+
+        let auth0 = ScopeClaims::new("permissions", "array".parse().unwrap());
+        let claims = json!({"permissions": ["read:orders"], "scope": "openid"});
+        let scopes = auth0.scopes(claims.as_object().unwrap());
+        assert_eq!(
+            scopes.0,
+            vec![Scope::from("read:orders")].into_iter().collect()
+        );
+        let claims = json!({"permissions": "read:orders"});
+        assert!(auth0.scopes(claims.as_object().unwrap()).0.is_empty());
+
+        // Scopes found by the middleware take precedence
+        req.extensions_mut().insert(scopes);
+        let scopes = Scopes::extract(&req).await.unwrap();
+        assert!(scopes.contains("read:orders") && !scopes.contains("admin"));
+    }
+
+    #[actix_rt::test]
+    async fn test_scope_claims() {
+        use crate::auth::JwtAuth;
+        use crate::guard::JwtGuard;
+        use crate::keystore::Keys;
+        use actix_web::{test, web, App};
+        use jsonwebtoken::{encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+
+        let mut keys = Keys::new();
+        keys.insert(
+            "0".into(),
+            vec![DecodingKey::from_secret(b"secret").into_static()],
+        );
+        let claims = ScopeClaims::new("permissions", ScopeFormat::Array)
+            .or("scope", ScopeFormat::SpaceSeparated);
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys).scope_claims(claims);
+        let resource = || {
+            web::resource("/")
+                .route(web::get().guard(JwtGuard::scope("read:orders")).to(
+                    |scopes: Scopes| async move {
+                        let mut scopes: Vec<_> = scopes.0.into_iter().collect();
+                        scopes.sort();
+                        let scopes: Vec<_> = scopes.iter().map(Scope::as_str).collect();
+                        scopes.join(" ")
+                    },
+                ))
+                .route(web::get().to(|| async { "not granted" }))
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.clone().middleware())
+                .service(resource()),
+        )
+        .await;
+        // The same scopes behind the chain
+        let chain = crate::chain::AuthChain::default().with(auth);
+        let mut chained =
+            test::init_service(App::new().wrap(chain.middleware()).service(resource())).await;
+        let get = |claims: Value| {
+            let header = Header {
+                kid: Some("0".into()),
+                ..Header::new(Algorithm::HS256)
+            };
+            let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+            TestRequest::get()
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request()
+        };
+
+        // `scp` is not among the claims, nor a string `permissions`
+        let exp = 4102444800u64;
+        for (claims, body) in [
+            (
+                json!({"exp": exp, "permissions": ["read:orders"], "scope": "openid",
+                    "scp": ["admin"]}),
+                "openid read:orders",
+            ),
+            (
+                json!({"exp": exp, "permissions": "read:orders", "scope": "openid",
+                    "scp": ["read:orders"]}),
+                "not granted",
+            ),
+        ] {
+            let resp = test::call_service(&mut app, get(claims.clone())).await;
+            assert_eq!(test::read_body(resp).await, body);
+            let resp = test::call_service(&mut chained, get(claims)).await;
+            assert_eq!(test::read_body(resp).await, body);
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! Secrets of the configuration fetched from a secret manager at startup, instead of
+//! kept in environment variables
+#[cfg(feature = "aws-secrets")]
+use crate::aws::AwsClient;
+use crate::config::Config;
+use crate::report::{report, Component};
+use crate::shutdown::Shutdown;
+use log::warn;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Prefix of configuration values naming a secret instead of holding it, e.g.
+/// `LOGIN_CLIENT_SECRET=secret:rapi/login#client_secret`
+pub const SECRET_PREFIX: &str = "secret:";
+
+/// A secret manager. Names may end in `#key` to pick a field of a secret holding
+/// several, as Vault secrets and JSON secret strings do.
+pub trait SecretSource: Send + Sync {
+    fn fetch<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + 'a>>;
+}
+
+/// The name and the field of `name`
+#[cfg(any(feature = "vault", feature = "aws-secrets", feature = "gcp-secrets"))]
+fn split_key(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('#') {
+        Some((name, key)) => (name, Some(key)),
+        None => (name, None),
+    }
+}
+
+/// The `key` field of a JSON `secret`, or all of it
+#[cfg(any(test, feature = "aws-secrets", feature = "gcp-secrets"))]
+fn field(secret: String, key: Option<&str>) -> anyhow::Result<String> {
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(secret),
+    };
+    let fields: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&secret)?;
+    match fields.get(key) {
+        Some(serde_json::Value::String(value)) => Ok(value.clone()),
+        Some(value) => Ok(value.to_string()),
+        None => anyhow::bail!("the secret has no field {}", key),
+    }
+}
+
+/// The source chosen by `secrets_source`: `vault`, `aws` or `gcp`, each needing its
+/// feature
+pub fn from_config(config: &Config) -> anyhow::Result<Option<Box<dyn SecretSource>>> {
+    let kind = match &config.secrets_source {
+        Some(kind) => kind.as_str(),
+        None => return Ok(None),
+    };
+    match kind {
+        #[cfg(feature = "vault")]
+        "vault" => {
+            let (addr, token) = match (&config.vault_addr, &config.vault_token) {
+                (Some(addr), Some(token)) => (addr, token),
+                _ => anyhow::bail!("the vault secrets source needs VAULT_ADDR and VAULT_TOKEN"),
+            };
+            let mut vault = Vault::new(addr, token);
+            if let Some(mount) = &config.vault_mount {
+                vault = vault.mount(mount);
+            }
+            Ok(Some(Box::new(vault)))
+        }
+        #[cfg(feature = "aws-secrets")]
+        "aws" => Ok(Some(Box::new(AwsSecretsManager::from_env()?))),
+        #[cfg(feature = "gcp-secrets")]
+        "gcp" => match &config.gcp_project {
+            Some(project) => Ok(Some(Box::new(GcpSecretManager::new(project)))),
+            None => anyhow::bail!("the gcp secrets source needs GCP_PROJECT"),
+        },
+        #[cfg(not(feature = "vault"))]
+        "vault" => anyhow::bail!("SECRETS_SOURCE=vault requires the vault feature"),
+        #[cfg(not(feature = "aws-secrets"))]
+        "aws" => anyhow::bail!("SECRETS_SOURCE=aws requires the aws-secrets feature"),
+        #[cfg(not(feature = "gcp-secrets"))]
+        "gcp" => anyhow::bail!("SECRETS_SOURCE=gcp requires the gcp-secrets feature"),
+        _ => anyhow::bail!("unknown secrets source {:?}", kind),
+    }
+}
+
+impl Config {
+    /// Replace the values of the secret settings naming a secret with the secret
+    /// fetched from `source`: the HMAC secrets, the client secrets, the login cookie
+    /// key and the token hash salt
+    pub async fn resolve_secrets(&mut self, source: &dyn SecretSource) -> anyhow::Result<()> {
+        let settings = [
+            ("AZURE_CLIENT_SECRET", &mut self.azure_client_secret),
+            (
+                "DANGEROUS_DEV_LOCAL_SECRET",
+                &mut self.dangerous_dev_local_secret,
+            ),
+            ("HS256_SECRET", &mut self.hs256_secret),
+            ("LOGIN_CLIENT_SECRET", &mut self.login_client_secret),
+            ("LOGIN_COOKIE_KEY", &mut self.login_cookie_key),
+            ("PKCS11_PIN", &mut self.pkcs11_pin),
+            ("SERVICE_CLIENT_SECRET", &mut self.service_client_secret),
+            ("TOKEN_HASH_SALT", &mut self.token_hash_salt),
+        ];
+        for (setting, value) in settings {
+            let name = match value.as_deref().and_then(|v| v.strip_prefix(SECRET_PREFIX)) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            let secret = source
+                .fetch(&name)
+                .await
+                .map_err(|e| e.context(format!("fetching {} from {}", setting, name)))?;
+            *value = Some(secret);
+        }
+        Ok(())
+    }
+}
+
+/// A secret fetched again on every interval, e.g. a key rotated in the secret
+/// manager. The last value is kept while fetches fail.
+#[derive(Clone)]
+pub struct SecretWatch {
+    value: Arc<RwLock<String>>,
+}
+
+impl SecretWatch {
+    /// Fetch `name` now, and again on every `interval` in the background until
+    /// `shutdown` stops
+    pub async fn new(
+        source: Arc<dyn SecretSource>,
+        name: impl Into<String>,
+        interval: Duration,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<Self> {
+        let name = name.into();
+        let watch = SecretWatch {
+            value: Arc::new(RwLock::new(source.fetch(&name).await?)),
+        };
+        let value = watch.value.clone();
+        shutdown.spawn(async move {
+            loop {
+                actix_rt::time::delay_for(interval).await;
+                match source.fetch(&name).await {
+                    Ok(secret) => *value.write().unwrap() = secret,
+                    Err(e) => {
+                        warn!("refetching secret {} failed: {}", name, e);
+                        report(Component::Secrets, &e);
+                    }
+                }
+            }
+        });
+        Ok(watch)
+    }
+
+    pub fn get(&self) -> String {
+        self.value.read().unwrap().clone()
+    }
+}
+
+/// HashiCorp Vault, reading `path#key` from a KV version 2 engine
+#[cfg(feature = "vault")]
+pub struct Vault {
+    addr: String,
+    token: String,
+    mount: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "vault")]
+impl Vault {
+    /// The Vault at `addr`, e.g. `https://vault:8200`, mounting the engine at `secret`
+    pub fn new(addr: impl Into<String>, token: impl Into<String>) -> Self {
+        Vault {
+            addr: addr.into().trim_end_matches('/').into(),
+            token: token.into(),
+            mount: "secret".into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn mount(mut self, mount: impl Into<String>) -> Self {
+        self.mount = mount.into();
+        self
+    }
+}
+
+#[cfg(feature = "vault")]
+impl SecretSource for Vault {
+    /// A missing `#key` reads the field `value`
+    fn fetch<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + 'a>> {
+        Box::pin(async move {
+            let (path, key) = split_key(name);
+            let url = format!("{}/v1/{}/data/{}", self.addr, self.mount, path);
+            let resp: serde_json::Value = self
+                .client
+                .get(&url)
+                .header("X-Vault-Token", &self.token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let key = key.unwrap_or("value");
+            match &resp["data"]["data"][key] {
+                serde_json::Value::String(value) => Ok(value.clone()),
+                serde_json::Value::Null => anyhow::bail!("{} has no field {}", path, key),
+                value => Ok(value.to_string()),
+            }
+        })
+    }
+}
+
+/// AWS Secrets Manager, reading the secret string of `id`, or its `#key` field if
+/// it holds JSON
+#[cfg(feature = "aws-secrets")]
+pub struct AwsSecretsManager {
+    aws: AwsClient,
+}
+
+#[cfg(feature = "aws-secrets")]
+impl AwsSecretsManager {
+    pub fn new(
+        region: impl Into<String>,
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+    ) -> Self {
+        AwsSecretsManager {
+            aws: AwsClient::new(
+                "secretsmanager",
+                region.into(),
+                access_key_id.into(),
+                secret_access_key.into(),
+            ),
+        }
+    }
+
+    /// Credentials and region from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+    /// `AWS_SESSION_TOKEN` and `AWS_REGION`
+    pub fn from_env() -> anyhow::Result<Self> {
+        Ok(AwsSecretsManager {
+            aws: AwsClient::from_env("secretsmanager", None)?,
+        })
+    }
+
+    /// Temporary credentials come with a session token
+    pub fn session_token(mut self, token: impl Into<String>) -> Self {
+        self.aws.session_token = Some(token.into());
+        self
+    }
+
+    /// Where requests go instead of the regional endpoint, e.g. a VPC endpoint
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.aws.endpoint = endpoint.into().trim_end_matches('/').into();
+        self
+    }
+}
+
+#[cfg(feature = "aws-secrets")]
+impl SecretSource for AwsSecretsManager {
+    fn fetch<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + 'a>> {
+        Box::pin(async move {
+            let (id, key) = split_key(name);
+            let resp = self
+                .aws
+                .call(
+                    "secretsmanager.GetSecretValue",
+                    &serde_json::json!({ "SecretId": id }),
+                )
+                .await?;
+            let secret = resp["SecretString"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("{} has no secret string", id))?;
+            field(secret.to_string(), key)
+        })
+    }
+}
+
+/// GCP Secret Manager, reading the latest version of `secret`, or its `#key` field
+/// if it holds JSON, as the service account of the instance
+#[cfg(feature = "gcp-secrets")]
+pub struct GcpSecretManager {
+    project: String,
+    endpoint: String,
+    metadata_endpoint: String,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "gcp-secrets")]
+impl GcpSecretManager {
+    pub fn new(project: impl Into<String>) -> Self {
+        GcpSecretManager {
+            project: project.into(),
+            endpoint: "https://secretmanager.googleapis.com".into(),
+            metadata_endpoint: "http://metadata.google.internal".into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = endpoint.into().trim_end_matches('/').into();
+        self
+    }
+
+    /// The metadata server handing out access tokens of the service account
+    pub fn metadata_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.metadata_endpoint = endpoint.into().trim_end_matches('/').into();
+        self
+    }
+}
+
+#[cfg(feature = "gcp-secrets")]
+impl SecretSource for GcpSecretManager {
+    fn fetch<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + 'a>> {
+        Box::pin(async move {
+            let (secret, key) = split_key(name);
+            let token: serde_json::Value = self
+                .client
+                .get(&format!(
+                    "{}/computeMetadata/v1/instance/service-accounts/default/token",
+                    self.metadata_endpoint
+                ))
+                .header("Metadata-Flavor", "Google")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let token = token["access_token"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("the metadata server returned no access token"))?;
+            let resp: serde_json::Value = self
+                .client
+                .get(&format!(
+                    "{}/v1/projects/{}/secrets/{}/versions/latest:access",
+                    self.endpoint, self.project, secret
+                ))
+                .bearer_auth(token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let data = resp["payload"]["data"]
+                .as_str()
+                .ok_or_else(|| anyhow::anyhow!("{} has no payload", secret))?;
+            field(String::from_utf8(base64::decode(data)?)?, key)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Memory(Mutex<HashMap<String, String>>);
+
+    impl SecretSource for Memory {
+        fn fetch<'a>(
+            &'a self,
+            name: &'a str,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + 'a>> {
+            let secret = self.0.lock().unwrap().get(name).cloned();
+            Box::pin(async move { secret.ok_or_else(|| anyhow::anyhow!("no secret {}", name)) })
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_resolve_secrets() {
+        let source = Arc::new(Memory::default());
+        source
+            .0
+            .lock()
+            .unwrap()
+            .insert("rapi/login".into(), "s3cret".into());
+        let mut config = Config {
+            login_client_secret: Some("secret:rapi/login".into()),
+            token_hash_salt: Some("plain".into()),
+            ..Config::default()
+        };
+        config.resolve_secrets(source.as_ref()).await.unwrap();
+        assert_eq!(config.login_client_secret.as_deref(), Some("s3cret"));
+        assert_eq!(config.token_hash_salt.as_deref(), Some("plain"));
+        config.service_client_secret = Some("secret:missing".into());
+        let err = config.resolve_secrets(source.as_ref()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("SERVICE_CLIENT_SECRET"));
+
+        let shutdown = Shutdown::new();
+        let watch = SecretWatch::new(
+            source.clone(),
+            "rapi/login",
+            Duration::from_millis(5),
+            &shutdown,
+        )
+        .await
+        .unwrap();
+        source
+            .0
+            .lock()
+            .unwrap()
+            .insert("rapi/login".into(), "rotated".into());
+        actix_rt::time::delay_for(Duration::from_millis(30)).await;
+        assert_eq!(watch.get(), "rotated");
+        shutdown.stop().await;
+
+        assert!(from_config(&Config::default()).unwrap().is_none());
+        let config = Config {
+            secrets_source: Some("keepass".into()),
+            ..Config::default()
+        };
+        assert!(from_config(&config).is_err());
+        assert_eq!(field(r#"{"a": "b"}"#.into(), Some("a")).unwrap(), "b");
+    }
+
+    #[cfg(all(feature = "vault", feature = "gcp-secrets"))]
+    #[actix_rt::test]
+    async fn test_vault_and_gcp() {
+        let vault_mock = mockito::mock("GET", "/v1/kv/data/rapi/login")
+            .match_header("x-vault-token", "root")
+            .with_body(r#"{"data": {"data": {"value": "v1", "client_secret": "v2"}}}"#)
+            .expect(2)
+            .create();
+        let vault = Vault::new(mockito::server_url(), "root").mount("kv");
+        assert_eq!(vault.fetch("rapi/login").await.unwrap(), "v1");
+        assert_eq!(vault.fetch("rapi/login#client_secret").await.unwrap(), "v2");
+        vault_mock.assert();
+
+        let _token = mockito::mock(
+            "GET",
+            "/computeMetadata/v1/instance/service-accounts/default/token",
+        )
+        .match_header("metadata-flavor", "Google")
+        .with_body(r#"{"access_token": "ya29", "expires_in": 3599}"#)
+        .create();
+        let secret = mockito::mock("GET", "/v1/projects/p/secrets/api/versions/latest:access")
+            .match_header("authorization", "Bearer ya29")
+            .with_body(format!(
+                r#"{{"payload": {{"data": "{}"}}}}"#,
+                base64::encode(r#"{"salt": "pepper"}"#)
+            ))
+            .create();
+        let gcp = GcpSecretManager::new("p")
+            .endpoint(mockito::server_url())
+            .metadata_endpoint(mockito::server_url());
+        assert_eq!(gcp.fetch("api#salt").await.unwrap(), "pepper");
+        secret.assert();
+    }
+}
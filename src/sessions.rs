@@ -0,0 +1,166 @@
+//! Limits on concurrent sessions per subject, e.g. for seat-limited licenses
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where active sessions are kept, e.g. in process or in a shared cache so that the
+/// limit holds across instances
+pub trait SessionStore: Send + Sync {
+    /// Record `session` of `subject` as active until `until`, in seconds since the
+    /// epoch, unless `subject` already has `max` other sessions active at `now`.
+    /// Returns whether the session is admitted. Must be atomic per subject.
+    fn admit<'a>(
+        &'a self,
+        subject: &'a str,
+        session: &'a str,
+        until: u64,
+        max: usize,
+        now: u64,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>>;
+
+    /// Forget `session` of `subject`, freeing its seat
+    fn release<'a>(
+        &'a self,
+        subject: &'a str,
+        session: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>>;
+}
+
+/// Active sessions held in process memory, until each expires
+#[derive(Default)]
+pub struct MemorySessions {
+    subjects: Mutex<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl SessionStore for MemorySessions {
+    fn admit<'a>(
+        &'a self,
+        subject: &'a str,
+        session: &'a str,
+        until: u64,
+        max: usize,
+        now: u64,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + 'a>> {
+        Box::pin(async move {
+            let mut subjects = self.subjects.lock().unwrap();
+            let sessions = subjects.entry(subject.into()).or_default();
+            sessions.retain(|_, until| *until > now);
+            if !sessions.contains_key(session) && sessions.len() >= max {
+                return Ok(false);
+            }
+            sessions.insert(session.into(), until);
+            Ok(true)
+        })
+    }
+
+    fn release<'a>(
+        &'a self,
+        subject: &'a str,
+        session: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + 'a>> {
+        Box::pin(async move {
+            let mut subjects = self.subjects.lock().unwrap();
+            if let Some(sessions) = subjects.get_mut(subject) {
+                sessions.remove(session);
+                if sessions.is_empty() {
+                    subjects.remove(subject);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// At most `max` distinct tokens in use at a time for each `sub`, identified by their
+/// `jti`. A token holds its seat until it expires or its session is released, so new
+/// tokens beyond the limit are refused while the earlier ones are still valid.
+#[derive(Clone)]
+pub struct SessionLimit {
+    max: usize,
+    store: Arc<dyn SessionStore>,
+}
+
+impl SessionLimit {
+    pub fn new(max: usize) -> Self {
+        SessionLimit {
+            max,
+            store: Arc::new(MemorySessions::default()),
+        }
+    }
+
+    pub fn store(mut self, store: impl SessionStore + 'static) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// Whether the token with `claims` may be used. `fallback` identifies tokens
+    /// without `jti`; tokens without `sub` are not limited, tokens without `exp` hold
+    /// their seat for an hour.
+    pub async fn check(&self, claims: &Map<String, Value>, fallback: &str) -> anyhow::Result<bool> {
+        let subject = match claims.get("sub").and_then(Value::as_str) {
+            Some(subject) => subject,
+            None => return Ok(true),
+        };
+        let session = claims
+            .get("jti")
+            .and_then(Value::as_str)
+            .unwrap_or(fallback);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let until = claims
+            .get("exp")
+            .and_then(Value::as_u64)
+            .unwrap_or(now + 3600);
+        self.store
+            .admit(subject, session, until, self.max, now)
+            .await
+    }
+
+    /// Free the seat of token `jti` of `sub`, e.g. at logout
+    pub async fn release(&self, sub: &str, jti: &str) -> anyhow::Result<()> {
+        self.store.release(sub, jti).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_session_limit() {
+        let limit = SessionLimit::new(2);
+        let token = |sub: &str, jti: &str| {
+            json!({"sub": sub, "jti": jti, "exp": 4102444800u64})
+                .as_object()
+                .unwrap()
+                .clone()
+        };
+
+        assert!(limit.check(&token("alice", "1"), "").await.unwrap());
+        assert!(limit.check(&token("alice", "2"), "").await.unwrap());
+        assert!(limit.check(&token("alice", "1"), "").await.unwrap());
+        assert!(!limit.check(&token("alice", "3"), "").await.unwrap());
+        assert!(limit.check(&token("bob", "3"), "").await.unwrap());
+
+        limit.release("alice", "1").await.unwrap();
+        assert!(limit.check(&token("alice", "3"), "").await.unwrap());
+
+        // Expired sessions free their seat
+        let expired = json!({"sub": "carol", "jti": "1", "exp": 1});
+        assert!(limit.check(expired.as_object().unwrap(), "").await.unwrap());
+        let limit = SessionLimit::new(1);
+        assert!(limit.check(expired.as_object().unwrap(), "").await.unwrap());
+        assert!(limit.check(&token("carol", "2"), "").await.unwrap());
+
+        let anonymous = json!({"jti": "x"});
+        assert!(limit
+            .check(anonymous.as_object().unwrap(), "")
+            .await
+            .unwrap());
+    }
+}
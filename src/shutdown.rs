@@ -0,0 +1,148 @@
+//! Stopping background tasks, e.g. the key refresh loop, with the server
+use futures::channel::oneshot;
+use futures::future::{select, FutureExt};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+/// Stops the background tasks spawned through it, so that tests and restarting
+/// instances do not leak them. Clones share the tasks, e.g. of the `Refresher`,
+/// `SecretRefresher` and `Reload`; `run` stops them all with the server.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    stopped: bool,
+    running: usize,
+    /// Dropped to signal the tasks
+    signals: Vec<oneshot::Sender<()>>,
+    /// Dropped once no task is running
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+
+    /// Resolves once `stop` is called, at once if it already was
+    pub fn signal(&self) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        if !state.stopped {
+            state.signals.push(tx);
+        }
+        rx.map(|_| ())
+    }
+
+    /// Run `task` on the current arbiter until it completes or `stop` is called.
+    /// Tasks spawned after `stop` do not run.
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        let signal = {
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                return;
+            }
+            state.running += 1;
+            let (tx, rx) = oneshot::channel();
+            state.signals.push(tx);
+            rx
+        };
+        let shutdown = self.clone();
+        actix_rt::spawn(async move {
+            select(Box::pin(task), signal).await;
+            let mut state = shutdown.state.lock().unwrap();
+            state.running -= 1;
+            if state.running == 0 {
+                state.waiters.clear();
+            }
+        });
+    }
+
+    /// Run `server` until it stops, on SIGINT or SIGTERM, which actix handles, or
+    /// through `Server::stop`, then stop every task
+    pub async fn run(&self, server: actix_web::dev::Server) -> std::io::Result<()> {
+        let result = server.await;
+        self.stop().await;
+        result
+    }
+
+    /// Signal every task to stop, and wait until they all did
+    pub async fn stop(&self) {
+        let done = {
+            let mut state = self.state.lock().unwrap();
+            state.stopped = true;
+            state.signals.clear();
+            if state.running == 0 {
+                return;
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push(tx);
+            rx
+        };
+        let _ = done.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::{JwksStore, Refresher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[actix_rt::test]
+    async fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let counter = ticks.clone();
+        shutdown.spawn(async move {
+            loop {
+                counter.fetch_add(1, Ordering::SeqCst);
+                actix_rt::time::delay_for(Duration::from_millis(1)).await;
+            }
+        });
+        Refresher::new("http://localhost/keys", JwksStore::default())
+            .shutdown(shutdown.clone())
+            .spawn();
+        let signal = shutdown.signal();
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        assert!(ticks.load(Ordering::SeqCst) > 0);
+
+        shutdown.stop().await;
+        signal.await;
+        assert_eq!(shutdown.state.lock().unwrap().running, 0);
+        let stopped_at = ticks.load(Ordering::SeqCst);
+        actix_rt::time::delay_for(Duration::from_millis(20)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+
+        // Too late to run
+        let counter = ticks.clone();
+        shutdown.spawn(async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        actix_rt::time::delay_for(Duration::from_millis(5)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), stopped_at);
+        assert!(shutdown.is_stopped());
+
+        // With the server
+        let shutdown = Shutdown::new();
+        shutdown.spawn(futures::future::pending());
+        let server = actix_web::HttpServer::new(actix_web::App::new)
+            .bind("127.0.0.1:0")
+            .unwrap()
+            .workers(1)
+            .run();
+        let handle = server.clone();
+        actix_rt::spawn(async move { handle.stop(true).await });
+        shutdown.run(server).await.unwrap();
+        assert!(shutdown.is_stopped());
+        assert_eq!(shutdown.state.lock().unwrap().running, 0);
+    }
+}
@@ -0,0 +1,219 @@
+use crate::claims::TokenClaims;
+use crate::cookie::TokenCookie;
+use crate::issuer::Issuer;
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures::future::{ok, Ready};
+use log::warn;
+use serde_json::{Map, Value};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Middleware implementing sliding sessions for tokens kept in a cookie: each
+/// authenticated request gets a fresh short-lived token, until the session reaches
+/// its absolute lifetime counted from `auth_time`. Wrap it around the `JwtAuth`
+/// middleware, which must accept the tokens of `issuer`.
+#[derive(Clone)]
+pub struct SlidingSession {
+    inner: Rc<Inner>,
+}
+
+struct Inner {
+    issuer: Issuer,
+    cookie: TokenCookie,
+    idle_timeout: Duration,
+    max_lifetime: Duration,
+}
+
+impl SlidingSession {
+    /// Re-issue tokens valid for `idle_timeout` after the last request, for at most
+    /// `max_lifetime` after login
+    pub fn new(
+        issuer: Issuer,
+        cookie: TokenCookie,
+        idle_timeout: Duration,
+        max_lifetime: Duration,
+    ) -> Self {
+        SlidingSession {
+            inner: Rc::new(Inner {
+                issuer,
+                cookie,
+                idle_timeout,
+                max_lifetime,
+            }),
+        }
+    }
+}
+
+impl Inner {
+    /// The claims of the token replacing one with `claims`, unless the session is over
+    fn renewed(&self, claims: &Map<String, Value>, now: u64) -> Option<Map<String, Value>> {
+        let auth_time = claims
+            .get("auth_time")
+            .or_else(|| claims.get("iat"))
+            .and_then(Value::as_u64)?;
+        let end = auth_time + self.max_lifetime.as_secs();
+        if now >= end {
+            return None;
+        }
+        let mut renewed = claims.clone();
+        for claim in &["iat", "nbf", "jti"] {
+            renewed.remove(*claim);
+        }
+        renewed.insert("auth_time".into(), auth_time.into());
+        renewed.insert(
+            "exp".into(),
+            (now + self.idle_timeout.as_secs()).min(end).into(),
+        );
+        Some(renewed)
+    }
+
+    /// The cookie with the renewed token, if the session goes on
+    fn set_cookie(&self, claims: &Map<String, Value>) -> anyhow::Result<Option<HeaderValue>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = match self.renewed(claims, now) {
+            Some(claims) => claims,
+            None => return Ok(None),
+        };
+        let token = self.issuer.mint(claims)?;
+        let cookie = self.cookie.cookie(&token)?;
+        Ok(Some(HeaderValue::from_str(&cookie.to_string())?))
+    }
+}
+
+impl<S, B> Transform<S> for SlidingSession
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = SlidingSessionMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SlidingSessionMiddleware {
+            service,
+            session: self.clone(),
+        })
+    }
+}
+
+pub struct SlidingSessionMiddleware<S> {
+    service: S,
+    session: SlidingSession,
+}
+
+impl<S, B> Service for SlidingSessionMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let session = self.session.clone();
+        // Only sessions kept in the cookie slide, not bearer tokens
+        let from_cookie = req.cookie(session.inner.cookie.name()).is_some()
+            && !req.headers().contains_key(header::AUTHORIZATION);
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let claims = res
+                .request()
+                .extensions()
+                .get::<TokenClaims>()
+                .filter(|_| from_cookie)
+                .map(|claims| claims.0.clone());
+            let claims = match claims {
+                Some(claims) => claims,
+                None => return Ok(res),
+            };
+            match session.inner.set_cookie(&claims) {
+                Ok(Some(value)) => {
+                    res.headers_mut().append(header::SET_COOKIE, value);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("session not renewed: {}", e),
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtAuth;
+    use crate::keystore::Keys;
+    use actix_web::cookie::Cookie;
+    use actix_web::{test, web, App};
+    use jsonwebtoken::{dangerous_insecure_decode, Algorithm, DecodingKey, Validation};
+
+    #[actix_rt::test]
+    async fn test_sliding_session() {
+        let mut keys = Keys::new();
+        keys.insert("0".into(), vec![DecodingKey::from_secret(b"secret")]);
+        let cookie = TokenCookie::new("session");
+        let auth = JwtAuth::new(Validation::new(Algorithm::HS256), keys)
+            .token_cookie(cookie.clone())
+            .without_csrf();
+        let issuer = Issuer::hmac(b"secret").kid("0");
+        let session = SlidingSession::new(
+            issuer.clone(),
+            cookie,
+            Duration::from_secs(300),
+            Duration::from_secs(3600),
+        );
+        let mut app = test::init_service(
+            App::new()
+                .wrap(auth.middleware())
+                .wrap(session)
+                .route("/", web::get().to(|| async { "" })),
+        )
+        .await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let login = |auth_time: u64| {
+            let claims = serde_json::json!({"sub": "alice", "auth_time": auth_time});
+            issuer.mint(claims.as_object().unwrap().clone()).unwrap()
+        };
+
+        let req = test::TestRequest::get()
+            .cookie(Cookie::new("session", login(now - 60)))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        let renewed = resp.response().cookies().next().unwrap();
+        let claims = dangerous_insecure_decode::<Map<String, Value>>(renewed.value())
+            .unwrap()
+            .claims;
+        assert_eq!(claims["auth_time"], now - 60);
+        let exp = claims["exp"].as_u64().unwrap();
+        assert!((now + 299..=now + 300).contains(&exp));
+
+        // Past the absolute lifetime
+        let req = test::TestRequest::get()
+            .cookie(Cookie::new("session", login(now - 3600)))
+            .to_request();
+        let resp = test::call_service(&mut app, req).await;
+        assert!(resp.response().cookies().next().is_none());
+    }
+}